@@ -0,0 +1,56 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Block hashes known in advance at given heights, protecting against deep
+//! reorgs while we sync with peers we can't trust yet.
+
+use std::collections::BTreeMap;
+
+use core::core::hash::Hash;
+
+/// Expected block hashes by height. Our chain has to go through all of them
+/// and can't be rewound below the last one it passed.
+#[derive(Debug, Clone, Default)]
+pub struct Checkpoints {
+	hashes: BTreeMap<u64, Hash>,
+}
+
+impl Checkpoints {
+	/// No checkpoints at all, any chain goes.
+	pub fn new() -> Checkpoints {
+		Checkpoints { hashes: BTreeMap::new() }
+	}
+
+	/// Builds checkpoints out of a list of heights and expected hashes.
+	pub fn from_vec(hashes: Vec<(u64, Hash)>) -> Checkpoints {
+		Checkpoints { hashes: hashes.into_iter().collect() }
+	}
+
+	/// Adds the expected hash of the block at the provided height, replacing
+	/// any previous one.
+	pub fn add(&mut self, height: u64, h: Hash) {
+		self.hashes.insert(height, h);
+	}
+
+	/// Whether a block with the provided hash can be at the provided height,
+	/// which is always the case when there's no checkpoint at that height.
+	pub fn matches(&self, height: u64, h: &Hash) -> bool {
+		self.hashes.get(&height).map(|cp| cp == h).unwrap_or(true)
+	}
+
+	/// The highest checkpoint at or below the provided height, if any.
+	pub fn last_at(&self, height: u64) -> Option<(u64, Hash)> {
+		self.hashes.range(0..height + 1).next_back().map(|(height, h)| (*height, *h))
+	}
+}
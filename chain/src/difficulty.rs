@@ -0,0 +1,72 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Difficulty retargeting based on how long the last blocks took to be
+//! mined.
+
+use std::cmp;
+
+use bigint::BigUint;
+
+use core::consensus::{self, BLOCK_TIME_SEC};
+use core::core::target::Difficulty;
+use grin_store::Error;
+use types::{ChainStore, Tip};
+
+/// Number of block intervals the next difficulty is calculated over.
+pub const DIFFICULTY_ADJUST_WINDOW: u64 = 23;
+
+/// Largest factor the difficulty can move by in a single retarget, up or
+/// down.
+pub const MAX_DIFFICULTY_CHANGE: i64 = 4;
+
+/// Difficulty the block following the provided head should have. Compares
+/// the time the last DIFFICULTY_ADJUST_WINDOW blocks took against the time
+/// they should have taken and scales the difficulty of the head
+/// accordingly, by no more than MAX_DIFFICULTY_CHANGE in either direction.
+/// Without any history to go by, the difficulty stays the same.
+pub fn next_difficulty(store: &ChainStore, head: &Tip) -> Result<Difficulty, Error> {
+	let last = try!(store.get_block_header(&head.last_block_h));
+
+	// walk back the fork from the head, not the main chain, so the head being
+	// validated doesn't have to be on it
+	let mut first = last.clone();
+	while first.height > 0 && last.height - first.height < DIFFICULTY_ADJUST_WINDOW {
+		first = try!(store.get_block_header(&first.previous));
+	}
+	let intervals = (last.height - first.height) as i64;
+	if intervals == 0 {
+		return Ok(last.difficulty);
+	}
+
+	let target_span = intervals * (BLOCK_TIME_SEC as i64);
+	let actual_span = (last.timestamp - first.timestamp).num_seconds();
+	let actual_span = cmp::min(cmp::max(actual_span, target_span / MAX_DIFFICULTY_CHANGE),
+	                           target_span * MAX_DIFFICULTY_CHANGE);
+
+	let num = last.difficulty.num * BigUint::from(target_span as u64) /
+	          BigUint::from(actual_span as u64);
+	let next = Difficulty { num: num };
+	Ok(cmp::max(next, Difficulty::one()))
+}
+
+/// Difficulty and Cuckoo Cycle size shift the block following the provided
+/// head should have. The difficulty gets retargeted as `next_difficulty`
+/// does, then moves to the next size shift once it gets high enough, as
+/// `consensus::next_sizeshift` decides.
+pub fn next_target(store: &ChainStore, head: &Tip) -> Result<(Difficulty, u8), Error> {
+	let last = try!(store.get_block_header(&head.last_block_h));
+	let difficulty = try!(next_difficulty(store, head));
+	Ok(consensus::next_sizeshift(difficulty, last.cuckoo_len))
+}
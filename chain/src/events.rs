@@ -0,0 +1,59 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Notifications of changes to our chain, for the components that need to
+//! react to them (miner, Stratum server, etc.) without polling the head.
+
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use types::Tip;
+
+/// Something that happened to our chain.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChainEvent {
+	/// Our chain head moved to the provided tip, either extended or
+	/// following a reorg
+	HeadChanged(Tip),
+}
+
+/// Sends the chain events to any number of subscribers, each getting its own
+/// copy. Subscribers that went away get forgotten on the next event.
+pub struct ChainEvents {
+	subscribers: Mutex<Vec<Sender<ChainEvent>>>,
+}
+
+impl ChainEvents {
+	/// Creates a new event fan-out without any subscriber.
+	pub fn new() -> ChainEvents {
+		ChainEvents { subscribers: Mutex::new(vec![]) }
+	}
+
+	/// Subscribes to all the events from now on.
+	pub fn subscribe(&self) -> Receiver<ChainEvent> {
+		let (tx, rx) = mpsc::channel();
+		self.subscribers.lock().unwrap().push(tx);
+		rx
+	}
+
+	/// Notifies all subscribers our chain head changed.
+	pub fn head_changed(&self, tip: &Tip) {
+		self.send(ChainEvent::HeadChanged(tip.clone()));
+	}
+
+	fn send(&self, event: ChainEvent) {
+		let mut subscribers = self.subscribers.lock().unwrap();
+		subscribers.retain(|s| s.send(event.clone()).is_ok());
+	}
+}
@@ -0,0 +1,106 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Verification of the headers our chain is made of, walking back from the
+//! head, to catch a store left inconsistent before building on it.
+
+use std::fmt;
+
+use core::core::BlockHeader;
+use core::core::hash::{Hash, Hashed};
+use grin_store::Error;
+use types::ChainStore;
+
+/// First problem found walking back our chain, each carrying the height of
+/// the offending header.
+#[derive(Debug)]
+pub enum Inconsistency {
+	/// The header at this height can't be found in store
+	MissingHeader(u64),
+	/// The previous hash of the header at this height isn't the one of the
+	/// header we have at the height below
+	BrokenLink(u64, Hash),
+	/// The header found at this height claims another one
+	WrongHeight(u64, u64),
+	/// Store failure while reading the header at this height
+	StoreErr(u64, Error),
+}
+
+impl Inconsistency {
+	/// Height of the offending header.
+	pub fn height(&self) -> u64 {
+		match *self {
+			Inconsistency::MissingHeader(h) |
+			Inconsistency::BrokenLink(h, _) |
+			Inconsistency::WrongHeight(h, _) |
+			Inconsistency::StoreErr(h, _) => h,
+		}
+	}
+}
+
+impl fmt::Display for Inconsistency {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Inconsistency::MissingHeader(h) => write!(f, "missing header at height {}", h),
+			Inconsistency::BrokenLink(h, ref prev) => {
+				write!(f, "header at height {} links to {}, not the header below", h, prev)
+			}
+			Inconsistency::WrongHeight(h, claimed) => {
+				write!(f, "header at height {} claims height {}", h, claimed)
+			}
+			Inconsistency::StoreErr(h, ref e) => {
+				write!(f, "could not read header at height {}: {:?}", h, e)
+			}
+		}
+	}
+}
+
+/// Walks back up to depth blocks from our head, or down to the genesis
+/// block if closer, checking every header links to the one we have at the
+/// height below and that heights go down by one. Stops at the first
+/// inconsistency found.
+pub fn verify_chain(store: &ChainStore, depth: u64) -> Result<(), Inconsistency> {
+	let head = try!(store.head().map_err(|e| Inconsistency::StoreErr(0, e)));
+	let mut bh = try!(read_header(store, head.height, &head.last_block_h));
+	let lowest = head.height.saturating_sub(depth);
+	while bh.height > lowest {
+		let below = bh.height - 1;
+		let prev = match store.get_header_by_height(below) {
+			Ok(prev) => prev,
+			Err(Error::NotFoundErr) => return Err(Inconsistency::MissingHeader(below)),
+			Err(e) => return Err(Inconsistency::StoreErr(below, e)),
+		};
+		if prev.hash() != bh.previous {
+			return Err(Inconsistency::BrokenLink(bh.height, bh.previous));
+		}
+		if prev.height != below {
+			return Err(Inconsistency::WrongHeight(below, prev.height));
+		}
+		bh = prev;
+	}
+	Ok(())
+}
+
+// Header with the provided hash, expected at the provided height.
+fn read_header(store: &ChainStore, height: u64, h: &Hash) -> Result<BlockHeader, Inconsistency> {
+	let bh = match store.get_block_header(h) {
+		Ok(bh) => bh,
+		Err(Error::NotFoundErr) => return Err(Inconsistency::MissingHeader(height)),
+		Err(e) => return Err(Inconsistency::StoreErr(height, e)),
+	};
+	if bh.height != height {
+		return Err(Inconsistency::WrongHeight(height, bh.height));
+	}
+	Ok(bh)
+}
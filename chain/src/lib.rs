@@ -26,16 +26,35 @@ extern crate byteorder;
 #[macro_use]
 extern crate log;
 extern crate time;
+extern crate num_bigint as bigint;
 
 extern crate grin_core as core;
 extern crate grin_store;
 extern crate secp256k1zkp as secp;
 
+pub mod checkpoints;
+pub mod difficulty;
+pub mod events;
+pub mod integrity;
+pub mod orphans;
 pub mod pipe;
+pub mod rejects;
 pub mod store;
 pub mod types;
 
+/// Target of all the logs of the chain, so their level can be set apart
+/// from the other subsystems'.
+pub const LOG_TARGET: &'static str = "grin::chain";
+
 // Re-export the base interface
 
-pub use types::{ChainStore, Tip, ChainAdapter};
-pub use pipe::{SYNC, NONE, process_block, process_block_header, Error};
+pub use types::{ChainStore, Tip, ChainAdapter, HeaderCacheStats, HEADER_CACHE_SIZE, MAX_LOCATORS,
+                MAX_REORG_DEPTH, MAX_FUTURE_TIME, PRUNE_SAFETY_WINDOW};
+pub use checkpoints::Checkpoints;
+pub use difficulty::{next_difficulty, next_target};
+pub use events::{ChainEvent, ChainEvents};
+pub use integrity::{Inconsistency, verify_chain};
+pub use orphans::OrphanPool;
+pub use rejects::RejectCache;
+pub use pipe::{SYNC, NONE, process_block, process_block_orphans, process_block_header,
+               validate_transaction, Error, PoolError};
@@ -0,0 +1,119 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pool of the blocks we received before their parent, kept around until the
+//! parent shows up so they don't have to be requested again.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::RwLock;
+use std::time::Instant;
+
+use core::core::Block;
+use core::core::hash::Hash;
+use LOG_TARGET;
+
+/// A block whose parent we don't know about yet.
+pub struct Orphan {
+	/// The orphaned block
+	pub block: Block,
+	/// When the block was added to the pool
+	pub added: Instant,
+}
+
+struct Orphans {
+	// orphans by the hash of their missing parent
+	by_parent: HashMap<Hash, Vec<Orphan>>,
+	// orphan hashes and their parent hash, oldest first
+	order: VecDeque<(Hash, Hash)>,
+}
+
+/// Size-limited pool of orphan blocks, keyed by the hash of their missing
+/// parent. Once full, the oldest orphans get evicted to make room for new
+/// ones.
+pub struct OrphanPool {
+	capacity: usize,
+	orphans: RwLock<Orphans>,
+}
+
+impl OrphanPool {
+	/// Creates a new pool holding at most capacity orphans.
+	pub fn new(capacity: usize) -> OrphanPool {
+		OrphanPool {
+			capacity: capacity,
+			orphans: RwLock::new(Orphans {
+				by_parent: HashMap::new(),
+				order: VecDeque::new(),
+			}),
+		}
+	}
+
+	/// Adds an orphan block to the pool, evicting the oldest orphan if the
+	/// pool is full. Blocks already in the pool are ignored.
+	pub fn add(&self, b: Block) {
+		if self.capacity == 0 || self.contains(&b.hash()) {
+			return;
+		}
+		let mut orphans = self.orphans.write().unwrap();
+		while orphans.order.len() >= self.capacity {
+			let (h, parent) = orphans.order.pop_front().unwrap();
+			remove_orphan(&mut orphans, &h, &parent);
+			debug!(target: LOG_TARGET, "Evicted orphan block {} from the pool.", h);
+		}
+
+		let (h, parent) = (b.hash(), b.header.previous);
+		orphans.order.push_back((h, parent));
+		orphans.by_parent.entry(parent).or_insert(vec![]).push(Orphan {
+			block: b,
+			added: Instant::now(),
+		});
+	}
+
+	/// Takes all the orphans whose missing parent is the provided block out of
+	/// the pool, oldest first.
+	pub fn remove_children(&self, parent: &Hash) -> Vec<Block> {
+		let mut orphans = self.orphans.write().unwrap();
+		match orphans.by_parent.remove(parent) {
+			Some(children) => {
+				orphans.order.retain(|&(_, p)| p != *parent);
+				children.into_iter().map(|o| o.block).collect()
+			}
+			None => vec![],
+		}
+	}
+
+	/// Whether the block with the provided hash is in the pool.
+	pub fn contains(&self, h: &Hash) -> bool {
+		let orphans = self.orphans.read().unwrap();
+		orphans.order.iter().any(|&(oh, _)| oh == *h)
+	}
+
+	/// Number of orphans in the pool.
+	pub fn len(&self) -> usize {
+		self.orphans.read().unwrap().order.len()
+	}
+}
+
+// Removes a single orphan from the parent index
+fn remove_orphan(orphans: &mut Orphans, h: &Hash, parent: &Hash) {
+	let empty = match orphans.by_parent.get_mut(parent) {
+		Some(children) => {
+			children.retain(|o| o.block.hash() != *h);
+			children.is_empty()
+		}
+		None => false,
+	};
+	if empty {
+		orphans.by_parent.remove(parent);
+	}
+}
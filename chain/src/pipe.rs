@@ -14,22 +14,26 @@
 
 //! Implementation of the chain block acceptance (or refusal) pipeline.
 
+use std::collections::HashSet;
 use std::convert::From;
 use std::sync::{Arc, Mutex};
 
 use secp;
 use time;
 
-use core::consensus;
 use core::core::hash::{Hash, Hashed};
 use core::core::target::Difficulty;
-use core::core::{BlockHeader, Block, Proof};
+use core::core::{BlockHeader, Block, Proof, Transaction};
 use core::pow;
 use core::ser;
+use difficulty::next_target;
 use grin_store;
 use types;
+use orphans::OrphanPool;
+use rejects::RejectCache;
 use types::{Tip, ChainStore, ChainAdapter, NoopAdapter};
 use store;
+use LOG_TARGET;
 
 bitflags! {
   /// Options for block validation
@@ -55,8 +59,10 @@ pub struct BlockContext {
 pub enum Error {
 	/// The block doesn't fit anywhere in our chain
 	Unfit(String),
-	/// Difficulty is too low either compared to ours or the block PoW hash
-	DifficultyTooLow,
+	/// We don't know the parent of the block (yet)
+	Orphan,
+	/// Difficulty isn't the one retargeting gives after the previous block
+	WrongDifficulty,
 	/// Addition of difficulties on all previous block is wrong
 	WrongTotalDifficulty,
 	/// Size of the Cuckoo graph in block header doesn't match PoW requirements
@@ -69,11 +75,40 @@ pub enum Error {
 	InvalidBlockTime,
 	/// Block height is invalid (not previous + 1)
 	InvalidBlockHeight,
+	/// Block hash doesn't match the checkpoint at its height
+	InvalidCheckpoint,
+	/// The block is on a fork that would rewind our chain below a checkpoint
+	ForkBelowCheckpoint,
+	/// The block is on a fork that would rewind our chain by more than the
+	/// maximum reorg depth
+	ReorgTooDeep,
+	/// We recently rejected the same block, for the provided reason
+	KnownBad(String),
 	/// Internal issue when trying to save or load data from store
 	StoreErr(grin_store::Error),
 	SerErr(ser::Error),
 }
 
+impl Error {
+	/// Whether the error makes the block invalid no matter what else we
+	/// receive later on, as opposed to a block that just doesn't fit our
+	/// chain at the moment. Blocks too far in the future may become fine, as
+	/// may forks too deep to switch to once our own chain moves.
+	pub fn is_bad_block(&self) -> bool {
+		match *self {
+			Error::WrongDifficulty |
+			Error::WrongTotalDifficulty |
+			Error::WrongCuckooSize |
+			Error::InvalidPow |
+			Error::InvalidBlockProof(_) |
+			Error::InvalidBlockHeight |
+			Error::InvalidCheckpoint |
+			Error::KnownBad(_) => true,
+			_ => false,
+		}
+	}
+}
+
 impl From<grin_store::Error> for Error {
 	fn from(e: grin_store::Error) -> Error {
 		Error::StoreErr(e)
@@ -85,6 +120,41 @@ impl From<ser::Error> for Error {
 	}
 }
 
+/// Why a transaction can't be accepted in the pool.
+#[derive(Debug)]
+pub enum PoolError {
+	/// The transaction doesn't sum correctly, its signature or one of its
+	/// range proofs is invalid
+	InvalidTransaction(secp::Error),
+	/// The transaction spends the same output more than once
+	DuplicateInput(Hash),
+	/// The transaction spends an output that isn't in our UTXO set, either
+	/// because it never existed or because it's already spent
+	UnknownOutput(Hash),
+	/// Internal issue when trying to load data from store
+	StoreErr(grin_store::Error),
+}
+
+/// Checks a transaction could go in our next block: it sums correctly, its
+/// signature and range proofs are valid and it only spends outputs of our
+/// current UTXO set, each of them once.
+pub fn validate_transaction(tx: &Transaction, store: &ChainStore) -> Result<(), PoolError> {
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	try!(tx.verify_sig(&secp).map_err(&PoolError::InvalidTransaction));
+
+	let mut spent = HashSet::new();
+	for input in &tx.inputs {
+		let h = input.output_hash();
+		if !spent.insert(h) {
+			return Err(PoolError::DuplicateInput(h));
+		}
+		if !try!(store.is_unspent(&h).map_err(&PoolError::StoreErr)) {
+			return Err(PoolError::UnknownOutput(h));
+		}
+	}
+	Ok(())
+}
+
 /// Runs the block processing pipeline, including validation and finding a
 /// place for the new block in the chain. Returns the new
 /// chain head if updated.
@@ -105,7 +175,7 @@ pub fn process_block(b: &Block,
 		head: head,
 	};
 
-	info!("Starting validation pipeline for block {} at {}.",
+	info!(target: LOG_TARGET, "Starting validation pipeline for block {} at {}.",
 	      b.hash(),
 	      b.header.height);
 	try!(check_known(b.hash(), &mut ctx));
@@ -115,7 +185,9 @@ pub fn process_block(b: &Block,
 		try!(validate_header(&b.header, &mut ctx));
 	}
 	try!(validate_block(b, &mut ctx));
-	info!("Block at {} with hash {} is valid, going to save and append.",
+	try!(check_reorg_depth(&b.header, &ctx));
+	try!(check_fork_point(&b.header, &ctx));
+	info!(target: LOG_TARGET, "Block at {} with hash {} is valid, going to save and append.",
 	      b.header.height,
 	      b.hash());
 	try!(add_block(b, &mut ctx));
@@ -123,6 +195,65 @@ pub fn process_block(b: &Block,
 	update_head(b, &mut ctx)
 }
 
+/// Same as process_block, except that a block whose parent we don't know
+/// yet is kept in the provided orphan pool. Once a block gets accepted, the
+/// orphans it was the missing parent of are processed in turn. Invalid
+/// blocks are remembered in the provided cache and refused right away when
+/// they come again. Returns the new chain head if updated.
+pub fn process_block_orphans(b: Block,
+                             store: Arc<ChainStore>,
+                             adapter: Arc<ChainAdapter>,
+                             orphans: &OrphanPool,
+                             rejects: &RejectCache,
+                             opts: Options)
+                             -> Result<Option<Tip>, Error> {
+	if let Some(reason) = rejects.get(&b.hash()) {
+		debug!(target: LOG_TARGET, "Block {} already rejected: {}", b.hash(), reason);
+		return Err(Error::KnownBad(reason));
+	}
+	let mut head = match process_block(&b, store.clone(), adapter.clone(), opts) {
+		Ok(head) => head,
+		Err(Error::Orphan) => {
+			debug!(target: LOG_TARGET, "Block {} is an orphan, adding it to the pool.", b.hash());
+			orphans.add(b);
+			return Err(Error::Orphan);
+		}
+		Err(e) => {
+			reject(&b, &e, rejects);
+			return Err(e);
+		}
+	};
+
+	// walk down the orphans that were waiting on the accepted blocks
+	let mut parents = vec![b.hash()];
+	while let Some(parent) = parents.pop() {
+		for child in orphans.remove_children(&parent) {
+			debug!(target: LOG_TARGET, "Processing orphan block {} now that its parent is known.",
+			       child.hash());
+			match process_block(&child, store.clone(), adapter.clone(), opts) {
+				Ok(child_head) => {
+					if child_head.is_some() {
+						head = child_head;
+					}
+					parents.push(child.hash());
+				}
+				Err(e) => {
+					debug!(target: LOG_TARGET, "Orphan block {} refused: {:?}", child.hash(), e);
+					reject(&child, &e, rejects);
+				}
+			}
+		}
+	}
+	Ok(head)
+}
+
+// Remembers the block as rejected if the error makes it invalid for good
+fn reject(b: &Block, e: &Error, rejects: &RejectCache) {
+	if e.is_bad_block() {
+		rejects.add(b.hash(), format!("{:?}", e));
+	}
+}
+
 pub fn process_block_header(bh: &BlockHeader,
                             store: Arc<ChainStore>,
                             adapter: Arc<ChainAdapter>,
@@ -138,23 +269,43 @@ pub fn process_block_header(bh: &BlockHeader,
 		head: head,
 	};
 
-	info!("Starting validation pipeline for block header {} at {}.",
+	info!(target: LOG_TARGET, "Starting validation pipeline for block header {} at {}.",
 	      bh.hash(),
 	      bh.height);
-	try!(check_known(bh.hash(), &mut ctx));
+	try!(check_header_known(bh.hash(), &mut ctx));
 	try!(validate_header(&bh, &mut ctx));
+	try!(check_reorg_depth(bh, &ctx));
+	try!(check_fork_point(bh, &ctx));
 	try!(add_block_header(bh, &mut ctx));
 	// TODO a global lock should be set before that step or even earlier
 	update_header_head(bh, &mut ctx)
 }
 
-/// Quick in-memory check to fast-reject any block we've already handled
-/// recently. Keeps duplicates from the network in check.
+/// Quick check to fast-reject any block we've already handled, before any
+/// validation or broadcast. Keeps duplicates from the network in check.
+/// Orphans only get saved once their parent shows up, so they still go
+/// through and get reprocessed then.
 fn check_known(bh: Hash, ctx: &mut BlockContext) -> Result<(), Error> {
 	// TODO ring buffer of the last few blocks that came through here
 	if bh == ctx.head.last_block_h || bh == ctx.head.prev_block_h {
 		return Err(Error::Unfit("already known".to_string()));
 	}
+	// in our chain or a fork of it, no need to go any further either way
+	if try!(ctx.store.block_exists(&bh).map_err(&Error::StoreErr)) {
+		return Err(Error::Unfit("already known".to_string()));
+	}
+	Ok(())
+}
+
+/// Same as check_known for the header pipeline, where the head is the header
+/// head and only the header needs to be there, whether its block is or not.
+fn check_header_known(bh: Hash, ctx: &mut BlockContext) -> Result<(), Error> {
+	if bh == ctx.head.last_block_h || bh == ctx.head.prev_block_h {
+		return Err(Error::Unfit("already known".to_string()));
+	}
+	if try!(ctx.store.header_exists(&bh).map_err(&Error::StoreErr)) {
+		return Err(Error::Unfit("already known".to_string()));
+	}
 	Ok(())
 }
 
@@ -164,23 +315,30 @@ fn check_known(bh: Hash, ctx: &mut BlockContext) -> Result<(), Error> {
 /// TODO require only the block header (with length information)
 fn validate_header(header: &BlockHeader, ctx: &mut BlockContext) -> Result<(), Error> {
 	if header.height > ctx.head.height + 1 {
-		// TODO actually handle orphans and add them to a size-limited set
-		return Err(Error::Unfit("orphan".to_string()));
+		return Err(Error::Orphan);
 	}
 
-	let prev = try!(ctx.store.get_block_header(&header.previous).map_err(&Error::StoreErr));
+	let prev = match ctx.store.get_block_header(&header.previous) {
+		Ok(prev) => prev,
+		Err(ref e) if e.is_not_found() => return Err(Error::Orphan),
+		Err(e) => return Err(Error::StoreErr(e)),
+	};
 
 	if header.height != prev.height + 1 {
 		return Err(Error::InvalidBlockHeight);
 	}
+	if !ctx.store.checkpoints().matches(header.height, &header.hash()) {
+		return Err(Error::InvalidCheckpoint);
+	}
 	if header.timestamp <= prev.timestamp {
 		// prevent time warp attacks and some timestamp manipulations by forcing strict
 		// time progression
 		return Err(Error::InvalidBlockTime);
 	}
-	if header.timestamp >
-	   time::now() + time::Duration::seconds(12 * (consensus::BLOCK_TIME_SEC as i64)) {
-		// refuse blocks more than 12 blocks intervals in future (as in bitcoin)
+	let max_future = time::Duration::seconds(ctx.store.max_future_time() as i64);
+	if header.timestamp > time::now() + max_future {
+		// refuse blocks too far in the future, 12 blocks intervals by default
+		// (as in bitcoin)
 		// TODO add warning in p2p code if local time is too different from peers
 		return Err(Error::InvalidBlockTime);
 	}
@@ -192,12 +350,10 @@ fn validate_header(header: &BlockHeader, ctx: &mut BlockContext) -> Result<(), E
 			return Err(Error::WrongTotalDifficulty);
 		}
 
-		let (difficulty, cuckoo_sz) = consensus::next_target(header.timestamp.to_timespec().sec,
-		                                                     prev.timestamp.to_timespec().sec,
-		                                                     prev.difficulty,
-		                                                     prev.cuckoo_len);
-		if header.difficulty < difficulty {
-			return Err(Error::DifficultyTooLow);
+		let (difficulty, cuckoo_sz) = try!(next_target(&*ctx.store, &Tip::from_block(&prev))
+			.map_err(&Error::StoreErr));
+		if header.difficulty != difficulty {
+			return Err(Error::WrongDifficulty);
 		}
 		if header.cuckoo_len != cuckoo_sz {
 			return Err(Error::WrongCuckooSize);
@@ -243,18 +399,61 @@ fn update_head(b: &Block, ctx: &mut BlockContext) -> Result<Option<Tip>, Error>
 	// if we made a fork with more work than the head (which should also be true
 	// when extending the head), update it
 	let tip = Tip::from_block(&b.header);
-	if tip.total_difficulty > ctx.head.total_difficulty {
-		ctx.store.setup_height(&b.header).map_err(&Error::StoreErr)?;
-		ctx.store.save_head(&tip).map_err(&Error::StoreErr)?;
+	if tip.is_better_than(&ctx.head) {
+		let reorg = if b.header.previous != ctx.head.last_block_h {
+			Some(try!(fork_blocks(b, ctx)))
+		} else {
+			None
+		};
+		// the height index and the head move together, extending the head
+		// being a reorg onto it of a single block
+		let (fork_point, new_headers) = match reorg {
+			Some((_, ref connected)) => {
+				(connected[0].header.previous,
+				 connected.iter().map(|b| b.header.clone()).collect::<Vec<_>>())
+			}
+			None => (ctx.head.last_block_h, vec![b.header.clone()]),
+		};
+		ctx.store.reorg(&tip, &fork_point, &new_headers).map_err(&Error::StoreErr)?;
 
 		ctx.head = tip.clone();
-		info!("Updated head to {} at {}.", b.hash(), b.header.height);
+		info!(target: LOG_TARGET, "Updated head to {} at {}.", b.hash(), b.header.height);
+		if let Some((disconnected, connected)) = reorg {
+			info!(target: LOG_TARGET, "Reorg undid {} blocks and added {}.",
+			      disconnected.len(),
+			      connected.len());
+			ctx.adapter.chain_reorg(&disconnected, &connected);
+		}
+		ctx.adapter.head_changed(&tip);
 		Ok(Some(tip))
 	} else {
 		Ok(None)
 	}
 }
 
+/// Blocks of our chain the fork ending with the provided block replaces,
+/// from our head down, and the blocks of the fork replacing them up to the
+/// provided one, both walked back to the common ancestor.
+fn fork_blocks(b: &Block, ctx: &BlockContext) -> Result<(Vec<Block>, Vec<Block>), Error> {
+	let mut disconnected = vec![];
+	// blocks can't be cloned, the new one has just been saved anyway
+	let mut connected = vec![try!(ctx.store.get_block(&b.hash()))];
+	let mut ours = try!(ctx.store.get_block_header(&ctx.head.last_block_h));
+	let mut theirs = try!(ctx.store.get_block_header(&b.header.previous));
+	while ours.hash() != theirs.hash() {
+		if theirs.height >= ours.height {
+			connected.push(try!(ctx.store.get_block(&theirs.hash())));
+			theirs = try!(ctx.store.get_block_header(&theirs.previous));
+		}
+		if ours.height > theirs.height {
+			disconnected.push(try!(ctx.store.get_block(&ours.hash())));
+			ours = try!(ctx.store.get_block_header(&ours.previous));
+		}
+	}
+	connected.reverse();
+	Ok((disconnected, connected))
+}
+
 /// Directly updates the head if we've just appended a new block to it or handle
 /// the situation where we've just added enough work to have a fork with more
 /// work than the head.
@@ -262,11 +461,11 @@ fn update_header_head(bh: &BlockHeader, ctx: &mut BlockContext) -> Result<Option
 	// if we made a fork with more work than the head (which should also be true
 	// when extending the head), update it
 	let tip = Tip::from_block(bh);
-	if tip.total_difficulty > ctx.head.total_difficulty {
+	if tip.is_better_than(&ctx.head) {
 		ctx.store.save_header_head(&tip).map_err(&Error::StoreErr)?;
 
 		ctx.head = tip.clone();
-		info!("Updated block header head to {} at {}.",
+		info!(target: LOG_TARGET, "Updated block header head to {} at {}.",
 		      bh.hash(),
 		      bh.height);
 		Ok(Some(tip))
@@ -274,3 +473,67 @@ fn update_header_head(bh: &BlockHeader, ctx: &mut BlockContext) -> Result<Option
 		Ok(None)
 	}
 }
+
+/// Refuses, before anything gets saved or relayed, a block on a fork that
+/// branches off our chain below the last checkpoint we passed, no matter how
+/// much work it has. Extending the current head is always fine.
+fn check_fork_point(bh: &BlockHeader, ctx: &BlockContext) -> Result<(), Error> {
+	if bh.previous == ctx.head.last_block_h {
+		return Ok(());
+	}
+	let (cp_height, cp_hash) = match ctx.store.checkpoints().last_at(ctx.head.height) {
+		Some(cp) => cp,
+		None => return Ok(()),
+	};
+	if bh.height < cp_height {
+		return Err(Error::ForkBelowCheckpoint);
+	}
+
+	// walk the fork back down to the checkpoint height, stopping early if we
+	// get back on our chain
+	let mut header = bh.clone();
+	while header.height > cp_height {
+		match ctx.store.get_header_by_height(header.height) {
+			Ok(ref ours) if ours.hash() == header.hash() => return Ok(()),
+			Ok(_) => {}
+			Err(ref e) if e.is_not_found() => {}
+			Err(e) => return Err(Error::StoreErr(e)),
+		}
+		header = try!(ctx.store.get_block_header(&header.previous));
+	}
+	if header.hash() != cp_hash {
+		return Err(Error::ForkBelowCheckpoint);
+	}
+	Ok(())
+}
+
+/// Refuses, before anything gets saved, a block with more work than our head
+/// on a fork that branches off more than the maximum reorg depth below it.
+/// Switching to such a fork has to be done by hand.
+fn check_reorg_depth(bh: &BlockHeader, ctx: &BlockContext) -> Result<(), Error> {
+	if bh.previous == ctx.head.last_block_h || !Tip::from_block(bh).is_better_than(&ctx.head) {
+		return Ok(());
+	}
+	let max_depth = ctx.store.max_reorg_depth();
+
+	// walk both chains back until they meet, giving up once ours went too far
+	let mut ours = try!(ctx.store.get_block_header(&ctx.head.last_block_h));
+	let mut theirs = try!(ctx.store.get_block_header(&bh.previous));
+	while ours.hash() != theirs.hash() {
+		if theirs.height >= ours.height {
+			theirs = try!(ctx.store.get_block_header(&theirs.previous));
+		}
+		if ours.height > theirs.height {
+			ours = try!(ctx.store.get_block_header(&ours.previous));
+		}
+		if ctx.head.height - ours.height > max_depth {
+			warn!(target: LOG_TARGET,
+			      "Refusing block {} at {}, its fork would rewind more than {} blocks.",
+			      bh.hash(),
+			      bh.height,
+			      max_depth);
+			return Err(Error::ReorgTooDeep);
+		}
+	}
+	Ok(())
+}
@@ -0,0 +1,93 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cache of the blocks we recently found invalid, so a peer sending the same
+//! bad block over and over doesn't get us to validate it every time.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::RwLock;
+
+use core::core::hash::Hash;
+
+struct Rejects {
+	// rejection reason by block hash
+	reasons: HashMap<Hash, String>,
+	// rejected block hashes, least recently seen first
+	order: VecDeque<Hash>,
+}
+
+/// Size-limited cache of rejected block hashes along with the reason they
+/// were rejected for. Once full, the least recently seen blocks get evicted.
+pub struct RejectCache {
+	capacity: usize,
+	rejects: RwLock<Rejects>,
+}
+
+impl RejectCache {
+	/// Creates a new cache holding at most capacity blocks.
+	pub fn new(capacity: usize) -> RejectCache {
+		RejectCache {
+			capacity: capacity,
+			rejects: RwLock::new(Rejects {
+				reasons: HashMap::new(),
+				order: VecDeque::new(),
+			}),
+		}
+	}
+
+	/// Records the block with the provided hash as rejected, evicting the
+	/// least recently seen block if the cache is full.
+	pub fn add(&self, h: Hash, reason: String) {
+		if self.capacity == 0 {
+			return;
+		}
+		let mut rejects = self.rejects.write().unwrap();
+		if rejects.reasons.insert(h, reason).is_some() {
+			touch(&mut rejects, &h);
+			return;
+		}
+		while rejects.order.len() >= self.capacity {
+			let evicted = rejects.order.pop_front().unwrap();
+			rejects.reasons.remove(&evicted);
+		}
+		rejects.order.push_back(h);
+	}
+
+	/// The reason the block with the provided hash was rejected for, if it
+	/// was recently.
+	pub fn get(&self, h: &Hash) -> Option<String> {
+		let mut rejects = self.rejects.write().unwrap();
+		let reason = rejects.reasons.get(h).cloned();
+		if reason.is_some() {
+			touch(&mut rejects, h);
+		}
+		reason
+	}
+
+	/// Whether the block with the provided hash was recently rejected.
+	pub fn contains(&self, h: &Hash) -> bool {
+		self.rejects.read().unwrap().reasons.contains_key(h)
+	}
+
+	/// Number of rejected blocks in the cache.
+	pub fn len(&self) -> usize {
+		self.rejects.read().unwrap().order.len()
+	}
+}
+
+// Moves a block to the most recently seen end
+fn touch(rejects: &mut Rejects, h: &Hash) {
+	rejects.order.retain(|oh| oh != h);
+	rejects.order.push_back(*h);
+}
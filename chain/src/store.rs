@@ -14,29 +14,102 @@
 
 //! Implements storage primitives required by the chain
 
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use checkpoints::Checkpoints;
 use types::*;
 use core::core::hash::{Hash, Hashed};
 use core::core::{Block, BlockHeader};
-use grin_store::{self, Error, to_key, u64_to_key, option_to_not_found};
-
-const STORE_SUBPATH: &'static str = "chain";
+use core::ser;
+use grin_store::{self, Error, Key, u64_to_key, option_to_not_found, option_to_not_found_ctx};
+use LOG_TARGET;
 
 const BLOCK_HEADER_PREFIX: u8 = 'h' as u8;
 const BLOCK_PREFIX: u8 = 'b' as u8;
 const HEAD_PREFIX: u8 = 'H' as u8;
 const HEADER_HEAD_PREFIX: u8 = 'I' as u8;
 const HEADER_HEIGHT_PREFIX: u8 = '8' as u8;
+const HASH_HEIGHT_PREFIX: u8 = 'i' as u8;
+const PRUNED_HEIGHT_PREFIX: u8 = 'P' as u8;
+const OUTPUT_PREFIX: u8 = 'o' as u8;
+const SPENT_PREFIX: u8 = 's' as u8;
+const FORK_TIP_PREFIX: u8 = 'f' as u8;
 
 /// An implementation of the ChainStore trait backed by a simple key-value
 /// store.
 pub struct ChainKVStore {
-	db: grin_store::Store,
+	db: Box<grin_store::KeyValueStore>,
+	checkpoints: Checkpoints,
+	prune_window: u64,
+	max_reorg_depth: u64,
+	max_future_time: u64,
+	header_cache: Mutex<HeaderCache>,
 }
 
 impl ChainKVStore {
-	pub fn new(root_path: String) -> Result<ChainKVStore, Error> {
-		let db = grin_store::Store::open(format!("{}/{}", root_path, STORE_SUBPATH).as_str())?;
-		Ok(ChainKVStore { db: db })
+	/// Opens the chain store at the provided path, repairing it if RocksDB
+	/// finds it corrupted.
+	pub fn new(path: String) -> Result<ChainKVStore, Error> {
+		let (db, repaired) = grin_store::Store::open_or_repair(path.as_str(), Default::default())?;
+		if let Some(corruption) = repaired {
+			// whatever couldn't be salvaged will just get synced again
+			warn!(target: LOG_TARGET, "Repaired corrupted chain store at {}: {}", path, corruption);
+		}
+		Ok(ChainKVStore::with_store(Box::new(db)))
+	}
+
+	/// Builds a chain store on top of any key-value backend, for example an
+	/// in-memory one for tests.
+	pub fn with_store(db: Box<grin_store::KeyValueStore>) -> ChainKVStore {
+		ChainKVStore {
+			db: db,
+			checkpoints: Checkpoints::new(),
+			prune_window: PRUNE_SAFETY_WINDOW,
+			max_reorg_depth: MAX_REORG_DEPTH,
+			max_future_time: MAX_FUTURE_TIME,
+			header_cache: Mutex::new(HeaderCache::new(HEADER_CACHE_SIZE)),
+		}
+	}
+
+	/// Sets the checkpoints blocks and headers are checked against.
+	pub fn set_checkpoints(&mut self, checkpoints: Checkpoints) {
+		self.checkpoints = checkpoints;
+	}
+
+	/// Sets the number of blocks below our head that are never pruned.
+	pub fn set_prune_window(&mut self, window: u64) {
+		self.prune_window = window;
+	}
+
+	/// Sets the largest number of blocks a fork can make us rewind.
+	pub fn set_max_reorg_depth(&mut self, depth: u64) {
+		self.max_reorg_depth = depth;
+	}
+
+	/// Sets the number of seconds block timestamps can be ahead of our time.
+	pub fn set_max_future_time(&mut self, secs: u64) {
+		self.max_future_time = secs;
+	}
+
+	/// Sets the number of block headers kept in memory, 0 disabling the
+	/// cache entirely. Drops the headers cached so far.
+	pub fn set_header_cache_size(&mut self, size: usize) {
+		self.header_cache = Mutex::new(HeaderCache::new(size));
+	}
+
+	/// Compacts the underlying store, reclaiming the space left by pruned
+	/// blocks and abandoned forks.
+	pub fn compact(&self) -> Result<(), Error> {
+		self.db.compact()
+	}
+
+	/// How long ago the chain was last written to, None if it wasn't since
+	/// the store was opened or last compacted.
+	pub fn write_idle_time(&self) -> Option<Duration> {
+		self.db.write_idle_time()
 	}
 }
 
@@ -51,11 +124,12 @@ impl ChainStore for ChainKVStore {
 	}
 
 	fn save_head(&self, t: &Tip) -> Result<(), Error> {
+		// our new head gets announced to peers, make sure we don't forget it
 		self.db
 			.batch()
 			.put_ser(&vec![HEAD_PREFIX], t)?
 			.put_ser(&vec![HEADER_HEAD_PREFIX], t)?
-			.write()
+			.write_sync()
 	}
 
 	fn get_header_head(&self) -> Result<Tip, Error> {
@@ -67,43 +141,144 @@ impl ChainStore for ChainKVStore {
 	}
 
 	fn get_block(&self, h: &Hash) -> Result<Block, Error> {
-		option_to_not_found(self.db.get_ser(&to_key(BLOCK_PREFIX, &mut h.to_vec())))
+		option_to_not_found_ctx(self.db.get_ser(&hash_key(BLOCK_PREFIX, h)),
+		                        &format!("block@{}", h))
+	}
+
+	fn block_exists(&self, h: &Hash) -> Result<bool, Error> {
+		self.db.exists(&hash_key(BLOCK_PREFIX, h))
+	}
+
+	fn header_exists(&self, h: &Hash) -> Result<bool, Error> {
+		if self.header_cache.lock().unwrap().get(h).is_some() {
+			return Ok(true);
+		}
+		self.db.exists(&hash_key(BLOCK_HEADER_PREFIX, h))
 	}
 
 	fn get_block_header(&self, h: &Hash) -> Result<BlockHeader, Error> {
-		option_to_not_found(self.db.get_ser(&to_key(BLOCK_HEADER_PREFIX, &mut h.to_vec())))
+		if let Some(bh) = self.header_cache.lock().unwrap().get(h) {
+			return Ok(bh);
+		}
+		let bh: BlockHeader =
+			try!(option_to_not_found_ctx(self.db.get_ser(&hash_key(BLOCK_HEADER_PREFIX, h)),
+			                             &format!("block_header@{}", h)));
+		self.header_cache.lock().unwrap().insert(bh.clone());
+		Ok(bh)
 	}
 
 	fn save_block(&self, b: &Block) -> Result<(), Error> {
-		self.db
-			.batch()
-			.put_ser(&to_key(BLOCK_PREFIX, &mut b.hash().to_vec())[..], b)?
-			.put_ser(&to_key(BLOCK_HEADER_PREFIX, &mut b.hash().to_vec())[..],
-			         &b.header)?
-			.write()
+		let bhash = b.hash();
+		let batch = try!(self.track_fork_tip(self.db.batch(), &b.header));
+		let mut batch = batch.put_ser(&hash_key(BLOCK_PREFIX, &bhash), b)?
+			.put_ser(&hash_key(BLOCK_HEADER_PREFIX, &bhash), &b.header)?
+			.put_ser(&hash_key(HASH_HEIGHT_PREFIX, &bhash), &Height(b.header.height))?;
+		// blocks creating and spending each output, whichever fork they're on
+		for out in &b.outputs {
+			batch = batch.put_ser(&output_key(OUTPUT_PREFIX, &out.hash(), &bhash), &bhash)?;
+		}
+		for input in &b.inputs {
+			batch = batch.put_ser(&output_key(SPENT_PREFIX, &input.output_hash(), &bhash), &bhash)?;
+		}
+		batch.write()
 	}
 
 	fn save_block_header(&self, bh: &BlockHeader) -> Result<(), Error> {
-		self.db.put_ser(
-      &to_key(BLOCK_HEADER_PREFIX, &mut bh.hash().to_vec())[..], bh)
+		try!(self.track_fork_tip(self.db.batch(), bh))
+			.put_ser(&hash_key(BLOCK_HEADER_PREFIX, &bh.hash()), bh)?
+			.put_ser(&hash_key(HASH_HEIGHT_PREFIX, &bh.hash()), &Height(bh.height))?
+			.write()
+	}
+
+	fn save_block_headers(&self, headers: &[BlockHeader]) -> Result<(), Error> {
+		let mut batch = self.db.batch();
+		let mut prev: Option<(Hash, u64)> = None;
+		for bh in headers {
+			let bhash = bh.hash();
+			if let Some((prev_h, prev_height)) = prev {
+				if bh.previous != prev_h || bh.height != prev_height + 1 {
+					let msg = format!("header {} at {} doesn't follow {} at {}",
+					                  bhash,
+					                  bh.height,
+					                  prev_h,
+					                  prev_height);
+					return Err(Error::InconsistentErr(msg));
+				}
+			}
+			batch = try!(self.track_fork_tip(batch, bh))
+				.put_ser(&hash_key(BLOCK_HEADER_PREFIX, &bhash), bh)?
+				.put_ser(&hash_key(HASH_HEIGHT_PREFIX, &bhash), &Height(bh.height))?;
+			prev = Some((bhash, bh.height));
+		}
+		batch.write()
 	}
 
 	fn get_header_by_height(&self, height: u64) -> Result<BlockHeader, Error> {
-		option_to_not_found(self.db.get_ser(&u64_to_key(HEADER_HEIGHT_PREFIX, height)))
+		let generation = {
+			let mut cache = self.header_cache.lock().unwrap();
+			if let Some(bh) = cache.get_by_height(height) {
+				return Ok(bh);
+			}
+			cache.generation
+		};
+		let bh = try!(self.read_header_by_height(height));
+		self.header_cache.lock().unwrap().insert_at_height(bh.clone(), generation);
+		Ok(bh)
+	}
+
+	fn get_block_height(&self, h: &Hash) -> Result<u64, Error> {
+		let height: Height =
+			try!(option_to_not_found(self.db.get_ser(&hash_key(HASH_HEIGHT_PREFIX, h))));
+		Ok(height.0)
+	}
+
+	fn reorg(&self,
+	         new_head: &Tip,
+	         rewind_to: &Hash,
+	         new_headers: &[BlockHeader])
+	         -> Result<(), Error> {
+		// all changes go in a single batch so they're applied atomically
+		let ancestor = try!(self.get_block_header(rewind_to));
+		let old_head = try!(self.head());
+		let mut batch = self.db.batch();
+
+		// unindex the abandoned fork
+		for height in (ancestor.height + 1)..(old_head.height + 1) {
+			match self.read_header_by_height(height) {
+				Ok(old) => {
+					batch = batch.delete(&hash_key(HASH_HEIGHT_PREFIX, &old.hash()))?
+						.delete(&u64_to_key(HEADER_HEIGHT_PREFIX, height))?;
+				}
+				Err(ref e) if e.is_not_found() => {}
+				Err(e) => return Err(e),
+			}
+		}
+
+		// and index the new one instead
+		for bh in new_headers {
+			batch = batch.put_ser(&u64_to_key(HEADER_HEIGHT_PREFIX, bh.height), bh)?
+				.put_ser(&hash_key(HASH_HEIGHT_PREFIX, &bh.hash()),
+				         &Height(bh.height))?;
+		}
+
+		// our new head gets announced to peers, make sure we don't forget it
+		let res = batch.put_ser(&vec![HEAD_PREFIX], new_head)?
+			.put_ser(&vec![HEADER_HEAD_PREFIX], new_head)?
+			.write_sync();
+		self.header_cache.lock().unwrap().forget_heights_from(ancestor.height + 1);
+		res
 	}
 
 	fn setup_height(&self, bh: &BlockHeader) -> Result<(), Error> {
-		self.db.put_ser(&u64_to_key(HEADER_HEIGHT_PREFIX, bh.height), bh)?;
+		self.index_height(bh)?;
 
 		let mut prev_h = bh.previous;
-		let mut prev_height = bh.height - 1;
+		let mut prev_height = bh.height.saturating_sub(1);
 		while prev_height > 0 {
 			let prev = self.get_header_by_height(prev_height)?;
 			if prev.hash() != prev_h {
 				let real_prev = self.get_block_header(&prev_h)?;
-				self.db
-					.put_ser(&u64_to_key(HEADER_HEIGHT_PREFIX, real_prev.height),
-					         &real_prev);
+				self.index_height(&real_prev)?;
 				prev_h = real_prev.previous;
 				prev_height = real_prev.height - 1;
 			} else {
@@ -112,4 +287,333 @@ impl ChainStore for ChainKVStore {
 		}
 		Ok(())
 	}
+
+	fn rebuild_height_index(&self) -> Result<u64, Error> {
+		let head = try!(self.get_header_head());
+		let mut fixed = 0;
+		let mut batch = self.db.batch();
+
+		// leftovers from a longer fork we've moved away from
+		let mut height = head.height + 1;
+		loop {
+			match self.read_header_by_height(height) {
+				Ok(stale) => {
+					batch = batch.delete(&hash_key(HASH_HEIGHT_PREFIX, &stale.hash()))?
+						.delete(&u64_to_key(HEADER_HEIGHT_PREFIX, height))?;
+					fixed += 1;
+					height += 1;
+				}
+				Err(ref e) if e.is_not_found() => break,
+				Err(e) => return Err(e),
+			}
+		}
+
+		let mut bh = try!(self.get_block_header(&head.last_block_h));
+		loop {
+			let bhash = bh.hash();
+			let indexed = match self.read_header_by_height(bh.height) {
+				Ok(indexed) => Some(indexed.hash()),
+				Err(ref e) if e.is_not_found() => None,
+				Err(e) => return Err(e),
+			};
+			let hash_height = match self.get_block_height(&bhash) {
+				Ok(h) => Some(h),
+				Err(ref e) if e.is_not_found() => None,
+				Err(e) => return Err(e),
+			};
+			if indexed != Some(bhash) || hash_height != Some(bh.height) {
+				if let Some(replaced) = indexed {
+					if replaced != bhash {
+						batch = batch.delete(&hash_key(HASH_HEIGHT_PREFIX, &replaced))?;
+					}
+				}
+				batch = batch.put_ser(&u64_to_key(HEADER_HEIGHT_PREFIX, bh.height), &bh)?
+					.put_ser(&hash_key(HASH_HEIGHT_PREFIX, &bhash), &Height(bh.height))?;
+				fixed += 1;
+			}
+			if bh.height == 0 {
+				break;
+			}
+			bh = try!(self.get_block_header(&bh.previous));
+		}
+
+		let res = batch.write();
+		self.header_cache.lock().unwrap().forget_heights_from(0);
+		res.map(|_| fixed)
+	}
+
+	fn known_forks(&self, within_depth: u64) -> Result<Vec<Tip>, Error> {
+		let head = try!(self.head());
+		let min_height = head.height.saturating_sub(within_depth);
+		let tips = try!(try!(self.db.iter::<Tip>(&vec![FORK_TIP_PREFIX]))
+			.collect::<Result<Vec<_>, _>>());
+		let mut tips = tips.into_iter().filter(|t| t.height >= min_height).collect::<Vec<_>>();
+		tips.sort_by(|a, b| b.cmp(a));
+		Ok(tips)
+	}
+
+	fn checkpoints(&self) -> &Checkpoints {
+		&self.checkpoints
+	}
+
+	fn max_reorg_depth(&self) -> u64 {
+		self.max_reorg_depth
+	}
+
+	fn max_future_time(&self) -> u64 {
+		self.max_future_time
+	}
+
+	fn prune_block_data(&self, before_height: u64) -> Result<u64, Error> {
+		let head = try!(self.head());
+		let before_height = cmp::min(before_height, head.height.saturating_sub(self.prune_window));
+
+		// everything below the last pruned height is already gone
+		let from = try!(self.db.get_ser::<Height>(&vec![PRUNED_HEIGHT_PREFIX]))
+			.map(|h| h.0)
+			.unwrap_or(0);
+		if from >= before_height {
+			return Ok(0);
+		}
+
+		let mut pruned = 0;
+		let mut batch = self.db.batch();
+		for height in from..before_height {
+			let bh = try!(self.get_header_by_height(height));
+			let key = hash_key(BLOCK_PREFIX, &bh.hash());
+			if try!(self.db.get(&key)).is_some() {
+				batch = batch.delete(&key)?;
+				pruned += 1;
+			}
+		}
+		batch.put_ser(&vec![PRUNED_HEIGHT_PREFIX], &Height(before_height))?
+			.write()?;
+		Ok(pruned)
+	}
+
+	fn header_cache_stats(&self) -> HeaderCacheStats {
+		self.header_cache.lock().unwrap().stats
+	}
+
+	fn is_unspent(&self, output: &Hash) -> Result<bool, Error> {
+		let head = try!(self.head());
+		let created = try!(try!(self.db.iter::<Hash>(&hash_key(OUTPUT_PREFIX, output)))
+			.collect::<Result<Vec<_>, _>>());
+		if !try!(self.any_on_chain(&created, &head)) {
+			return Ok(false);
+		}
+		let spent = try!(try!(self.db.iter::<Hash>(&hash_key(SPENT_PREFIX, output)))
+			.collect::<Result<Vec<_>, _>>());
+		Ok(!try!(self.any_on_chain(&spent, &head)))
+	}
+}
+
+impl ChainKVStore {
+	// Keeps track of the tips of our forks in the batch saving the header,
+	// which replaces its parent as a tip. Headers we already had are left
+	// alone, they may have children of their own.
+	fn track_fork_tip<'a>(&self,
+	                      batch: grin_store::Batch<'a>,
+	                      bh: &BlockHeader)
+	                      -> Result<grin_store::Batch<'a>, Error> {
+		let bhash = bh.hash();
+		if try!(self.db.exists(&hash_key(BLOCK_HEADER_PREFIX, &bhash))) {
+			return Ok(batch);
+		}
+		batch.delete(&hash_key(FORK_TIP_PREFIX, &bh.previous))?
+			.put_ser(&hash_key(FORK_TIP_PREFIX, &bhash), &Tip::from_block(bh))
+	}
+
+	// Puts the header at its height in the height index, removing the header
+	// it replaces (if any) from the hash to height index as it's now on an
+	// abandoned fork.
+	fn index_height(&self, bh: &BlockHeader) -> Result<(), Error> {
+		let bhash = bh.hash();
+		let mut batch = self.db.batch();
+		let mut replacing = false;
+		match self.read_header_by_height(bh.height) {
+			Ok(replaced) => {
+				if replaced.hash() != bhash {
+					batch = batch.delete(&hash_key(HASH_HEIGHT_PREFIX, &replaced.hash()))?;
+					replacing = true;
+				}
+			}
+			Err(ref e) if e.is_not_found() => {}
+			Err(e) => return Err(e),
+		}
+		let res = batch.put_ser(&u64_to_key(HEADER_HEIGHT_PREFIX, bh.height), bh)?
+			.put_ser(&hash_key(HASH_HEIGHT_PREFIX, &bhash), &Height(bh.height))?
+			.write();
+		// extending the index leaves what's cached below as it is
+		if replacing {
+			self.header_cache.lock().unwrap().forget_heights_from(bh.height);
+		}
+		res
+	}
+
+	// Whether any of the blocks is on our full chain, at or below our head.
+	fn any_on_chain(&self, blocks: &[Hash], head: &Tip) -> Result<bool, Error> {
+		for h in blocks {
+			let height = match self.get_block_height(h) {
+				Ok(height) => height,
+				Err(ref e) if e.is_not_found() => continue,
+				Err(e) => return Err(e),
+			};
+			if height > head.height {
+				continue;
+			}
+			match self.get_header_by_height(height) {
+				Ok(ref bh) if bh.hash() == *h => return Ok(true),
+				Ok(_) => {}
+				Err(ref e) if e.is_not_found() => {}
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(false)
+	}
+
+	// Reads the height index without going through the cache, for the
+	// operations rewriting it.
+	fn read_header_by_height(&self, height: u64) -> Result<BlockHeader, Error> {
+		option_to_not_found_ctx(self.db.get_ser(&u64_to_key(HEADER_HEIGHT_PREFIX, height)),
+		                        &format!("block_header@height={}", height))
+	}
+}
+
+// Most recently read headers by hash and, for our chain, by height. Headers
+// never change once saved under their hash but the height index moves with
+// reorgs, so cached heights from the fork point up are forgotten whenever
+// it's rewritten. The generation guards against caching a height read before
+// such a rewrite once it's over.
+struct HeaderCache {
+	capacity: usize,
+	// cached headers, with when they were last used
+	headers: HashMap<Hash, (BlockHeader, u64)>,
+	heights: HashMap<u64, Hash>,
+	// cached header hashes with when they were used, least recently used
+	// first. Using a header again queues it anew and the entry it leaves
+	// behind goes stale, skipped over on eviction.
+	order: VecDeque<(Hash, u64)>,
+	// ticks on every use of a header
+	clock: u64,
+	generation: u64,
+	stats: HeaderCacheStats,
+}
+
+impl HeaderCache {
+	fn new(capacity: usize) -> HeaderCache {
+		HeaderCache {
+			capacity: capacity,
+			headers: HashMap::new(),
+			heights: HashMap::new(),
+			order: VecDeque::new(),
+			clock: 0,
+			generation: 0,
+			stats: HeaderCacheStats::default(),
+		}
+	}
+
+	fn get(&mut self, h: &Hash) -> Option<BlockHeader> {
+		let bh = self.headers.get(h).map(|&(ref bh, _)| bh.clone());
+		if bh.is_some() {
+			self.touch(h);
+			self.stats.hits += 1;
+		} else {
+			self.stats.misses += 1;
+		}
+		bh
+	}
+
+	fn get_by_height(&mut self, height: u64) -> Option<BlockHeader> {
+		match self.heights.get(&height).cloned() {
+			Some(h) => self.get(&h),
+			None => {
+				self.stats.misses += 1;
+				None
+			}
+		}
+	}
+
+	fn insert(&mut self, bh: BlockHeader) {
+		if self.capacity == 0 {
+			return;
+		}
+		let h = bh.hash();
+		if self.headers.contains_key(&h) {
+			self.touch(&h);
+			return;
+		}
+		while self.headers.len() >= self.capacity {
+			let (evicted, used) = self.order.pop_front().unwrap();
+			if self.headers.get(&evicted).map(|&(_, last_used)| last_used != used).unwrap_or(true) {
+				continue;
+			}
+			if let Some((evicted_bh, _)) = self.headers.remove(&evicted) {
+				if self.heights.get(&evicted_bh.height) == Some(&evicted) {
+					self.heights.remove(&evicted_bh.height);
+				}
+			}
+		}
+		self.headers.insert(h, (bh, 0));
+		self.touch(&h);
+	}
+
+	// Caches a header read from the height index, unless the index was
+	// rewritten since the read started.
+	fn insert_at_height(&mut self, bh: BlockHeader, generation: u64) {
+		if self.capacity == 0 || generation != self.generation {
+			return;
+		}
+		self.heights.insert(bh.height, bh.hash());
+		self.insert(bh);
+	}
+
+	fn forget_heights_from(&mut self, from: u64) {
+		self.heights.retain(|&height, _| height < from);
+		self.generation += 1;
+	}
+
+	// Moves a header to the most recently used end
+	fn touch(&mut self, h: &Hash) {
+		self.clock += 1;
+		let clock = self.clock;
+		if let Some(entry) = self.headers.get_mut(h) {
+			entry.1 = clock;
+		}
+		self.order.push_back((*h, clock));
+		// once stale entries are as many as the live ones at most, dropping
+		// them all keeps touching in constant time on average
+		if self.order.len() > 2 * self.capacity {
+			let headers = &self.headers;
+			self.order.retain(|&(oh, used)| {
+				headers.get(&oh).map(|&(_, last_used)| last_used == used).unwrap_or(false)
+			});
+		}
+	}
+}
+
+// Key of the value saved under the provided hash.
+fn hash_key(prefix: u8, h: &Hash) -> Vec<u8> {
+	Key::prefix(prefix).append_hash(h).build()
+}
+
+// Key of the block creating or spending an output, so all the blocks doing
+// so can be iterated over from the output hash.
+fn output_key(prefix: u8, output: &Hash, block: &Hash) -> Vec<u8> {
+	Key::prefix(prefix).append_hash(output).append_hash(block).build()
+}
+
+/// Height of a block, as saved in the hash to height index.
+struct Height(u64);
+
+impl ser::Writeable for Height {
+	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
+		writer.write_u64(self.0)
+	}
+}
+
+impl ser::Readable<Height> for Height {
+	fn read(reader: &mut ser::Reader) -> Result<Height, ser::Error> {
+		Ok(Height(try!(reader.read_u64())))
+	}
 }
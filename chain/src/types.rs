@@ -14,8 +14,12 @@
 
 //! Base types that the block chain pipeline requires.
 
+use std::cmp::Ordering;
+
+use checkpoints::Checkpoints;
 use grin_store::Error;
-use core::core::{Block, BlockHeader};
+use core::consensus;
+use core::core::{Block, BlockHeader, Transaction};
 use core::core::hash::{Hash, Hashed};
 use core::core::target::Difficulty;
 use core::ser;
@@ -56,11 +60,48 @@ impl Tip {
 			total_difficulty: bh.total_difficulty.clone(),
 		}
 	}
+
+	/// Whether this tip wins over the other one in fork choice. The tip with
+	/// the most total difficulty wins, ties are broken by the lowest last
+	/// block hash so all nodes make the same decision.
+	pub fn is_better_than(&self, other: &Tip) -> bool {
+		self > other
+	}
+}
+
+/// Tips are ordered by fork choice, better tips being greater. Two tips are
+/// equal when they point to the same block with the same total difficulty.
+impl PartialEq for Tip {
+	fn eq(&self, other: &Tip) -> bool {
+		self.total_difficulty == other.total_difficulty && self.last_block_h == other.last_block_h
+	}
+}
+
+impl Eq for Tip {}
+
+impl PartialOrd for Tip {
+	fn partial_cmp(&self, other: &Tip) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
 }
 
-/// Serialization of a tip, required to save to datastore.
+impl Ord for Tip {
+	fn cmp(&self, other: &Tip) -> Ordering {
+		self.total_difficulty
+			.cmp(&other.total_difficulty)
+			.then_with(|| other.last_block_h.cmp(&self.last_block_h))
+	}
+}
+
+/// Version of the tip serialization, bumped when its layout changes so tips
+/// saved by older versions can still be read.
+pub const TIP_VERSION: u8 = 1;
+
+/// Serialization of a tip, required to save to datastore. Starts with the
+/// layout version, tips without one being read with the original layout.
 impl ser::Writeable for Tip {
 	fn write(&self, writer: &mut ser::Writer) -> Result<(), ser::Error> {
+		try!(writer.write_u8(TIP_VERSION));
 		try!(writer.write_u64(self.height));
 		try!(writer.write_fixed_bytes(&self.last_block_h));
 		try!(writer.write_fixed_bytes(&self.prev_block_h));
@@ -70,7 +111,23 @@ impl ser::Writeable for Tip {
 
 impl ser::Readable<Tip> for Tip {
 	fn read(reader: &mut ser::Reader) -> Result<Tip, ser::Error> {
-		let height = try!(reader.read_u64());
+		// tips saved before the layout got versioned start right away with
+		// their height, whose first byte is 0 for any height we'll ever reach
+		let version = try!(reader.read_u8());
+		let height = match version {
+			0 => {
+				let rest = try!(reader.read_fixed_bytes(7));
+				rest.iter().fold(0, |h, b| (h << 8) | *b as u64)
+			}
+			TIP_VERSION => try!(reader.read_u64()),
+			_ => {
+				return Err(ser::Error::UnexpectedData {
+					expected: vec![TIP_VERSION],
+					received: vec![version],
+				})
+			}
+		};
+		// fields added by later versions get read, or defaulted, here
 		let last = try!(Hash::read(reader));
 		let prev = try!(Hash::read(reader));
 		let diff = try!(Difficulty::read(reader));
@@ -83,6 +140,48 @@ impl ser::Readable<Tip> for Tip {
 	}
 }
 
+/// Maximum number of hashes in a block locator, same as what our peers accept
+pub const MAX_LOCATORS: u32 = 10;
+
+/// Number of blocks below our head that always keep their full data when
+/// pruning, a reorg deeper than the cut-through horizon being unlikely.
+pub const PRUNE_SAFETY_WINDOW: u64 = consensus::CUT_THROUGH_HORIZON as u64;
+
+/// Number of blocks below our head a fork can branch off for us to switch to
+/// it, deeper reorgs have to be done by hand. Nothing below the prune safety
+/// window could be rewound anyway.
+pub const MAX_REORG_DEPTH: u64 = PRUNE_SAFETY_WINDOW;
+
+/// Number of seconds a block timestamp can be ahead of our local time, 12
+/// block intervals.
+pub const MAX_FUTURE_TIME: u64 = 12 * consensus::BLOCK_TIME_SEC as u64;
+
+/// Number of recently read block headers the chain store keeps in memory,
+/// enough for difficulty calculations and locators near our head.
+pub const HEADER_CACHE_SIZE: usize = 1024;
+
+/// Hits and misses of the in-memory header cache of a chain store, for
+/// lookups by hash and by height together.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HeaderCacheStats {
+	/// Lookups answered from memory
+	pub hits: u64,
+	/// Lookups that had to go to the store
+	pub misses: u64,
+}
+
+impl HeaderCacheStats {
+	/// Share of the lookups answered from memory, 0 before any lookup.
+	pub fn hit_rate(&self) -> f64 {
+		let total = self.hits + self.misses;
+		if total == 0 {
+			0.0
+		} else {
+			self.hits as f64 / total as f64
+		}
+	}
+}
+
 /// Trait the chain pipeline requires an implementor for in order to process
 /// blocks.
 pub trait ChainStore: Send + Sync {
@@ -98,6 +197,14 @@ pub trait ChainStore: Send + Sync {
 	/// Gets a block header by hash
 	fn get_block(&self, h: &Hash) -> Result<Block, Error>;
 
+	/// Whether we have the full block with the provided hash, on our chain
+	/// or on a fork. Doesn't read the block itself.
+	fn block_exists(&self, h: &Hash) -> Result<bool, Error>;
+
+	/// Whether we have the block header with the provided hash, whether we
+	/// also have its full block or not.
+	fn header_exists(&self, h: &Hash) -> Result<bool, Error>;
+
 	/// Gets a block header by hash
 	fn get_block_header(&self, h: &Hash) -> Result<BlockHeader, Error>;
 
@@ -107,6 +214,11 @@ pub trait ChainStore: Send + Sync {
 	/// Save the provided block header in store
 	fn save_block_header(&self, bh: &BlockHeader) -> Result<(), Error>;
 
+	/// Saves a list of headers at once, in a single write. The headers must
+	/// form a contiguous chain, each following the previous one, otherwise
+	/// none of them are saved and InconsistentErr is returned.
+	fn save_block_headers(&self, headers: &[BlockHeader]) -> Result<(), Error>;
+
 	/// Get the tip of the header chain
 	fn get_header_head(&self) -> Result<Tip, Error>;
 
@@ -116,11 +228,133 @@ pub trait ChainStore: Send + Sync {
 	/// Gets the block header at the provided height
 	fn get_header_by_height(&self, height: u64) -> Result<BlockHeader, Error>;
 
+	/// Gets the height of the block with the provided hash. Blocks left on
+	/// an abandoned fork by a reorg aren't found anymore.
+	fn get_block_height(&self, h: &Hash) -> Result<u64, Error>;
+
 	/// Saves the provided block header at the corresponding height. Also check
 	/// the consistency of the height chain in store by assuring previous
 	/// headers
 	/// are also at their respective heights.
 	fn setup_height(&self, bh: &BlockHeader) -> Result<(), Error>;
+
+	/// Rebuilds the height index from our header head, following previous
+	/// links all the way back to genesis and rewriting every height entry
+	/// that doesn't point to the header we went through. Entries above the
+	/// header head are dropped. Repairs an index left inconsistent by a
+	/// crash in the middle of a reorg, returns the number of entries fixed.
+	fn rebuild_height_index(&self) -> Result<u64, Error>;
+
+	/// Atomically switches our chain to a new fork. Rewinds the height index
+	/// back to the provided common ancestor, indexes the new headers (that
+	/// must follow the ancestor, in order) and saves the new head. Either all
+	/// of it is applied or none of it is. The pipeline goes through it every
+	/// time the head moves, extending the head being a reorg onto it.
+	fn reorg(&self,
+	         new_head: &Tip,
+	         rewind_to: &Hash,
+	         new_headers: &[BlockHeader])
+	         -> Result<(), Error>;
+
+	/// Tips of all the forks we have headers for, that is headers without
+	/// any child, down to within_depth blocks below our head. Tips are kept
+	/// track of as headers get saved, which only goes through the ones of
+	/// forks we know of. The best tip comes first, a single linear chain only
+	/// has the main tip.
+	fn known_forks(&self, within_depth: u64) -> Result<Vec<Tip>, Error>;
+
+	/// Block hashes our chain has to go through at given heights.
+	fn checkpoints(&self) -> &Checkpoints;
+
+	/// Largest number of blocks we accept to rewind from our head to switch
+	/// to a fork with more work.
+	fn max_reorg_depth(&self) -> u64;
+
+	/// Number of seconds ahead of our local time a block timestamp can be.
+	fn max_future_time(&self) -> u64;
+
+	/// Deletes the full blocks of our chain below the provided height,
+	/// keeping their headers. Never goes above our head minus the prune
+	/// safety window, to still be able to process reorgs. Returns the number
+	/// of blocks pruned.
+	fn prune_block_data(&self, before_height: u64) -> Result<u64, Error>;
+
+	/// How often header lookups by hash or height were answered without
+	/// going to the underlying store.
+	fn header_cache_stats(&self) -> HeaderCacheStats;
+
+	/// Whether the output with the provided hash is in our UTXO set: created
+	/// by a block of our full chain and not spent by another one.
+	fn is_unspent(&self, output: &Hash) -> Result<bool, Error>;
+
+	/// Gets the full block of our chain at the provided height. Fails with
+	/// NotFoundErr if we don't have a block at that height or only have its
+	/// header, its body having been pruned.
+	fn get_block_by_height(&self, height: u64) -> Result<Block, Error> {
+		let bh = try!(self.get_header_by_height(height));
+		match self.get_block(&bh.hash()) {
+			Err(ref e) if e.is_not_found() => Err(Error::NotFoundErr),
+			res => res,
+		}
+	}
+
+	/// Gets the headers at heights from (included) to to (excluded), in
+	/// order. Fails on the first height we don't have a header for.
+	fn get_block_headers(&self, from: u64, to: u64) -> Result<Vec<BlockHeader>, Error> {
+		let mut headers = Vec::with_capacity(to.saturating_sub(from) as usize);
+		for height in from..to {
+			match self.get_header_by_height(height) {
+				Ok(bh) => headers.push(bh),
+				Err(ref e) if e.is_not_found() => {
+					return Err(Error::NotFoundKeyErr(format!("block_header@height={}", height)));
+				}
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(headers)
+	}
+
+	/// Builds a block locator from the header head, the hashes of the headers
+	/// at heights head, head-1, head-2, head-4, head-8, etc. doubling the gap
+	/// each time. The genesis hash always comes last and there are never more
+	/// than MAX_LOCATORS hashes. The header chain can be ahead of the chain
+	/// our height index covers, or on another fork, so it's walked back from
+	/// the header head until it joins the indexed chain.
+	fn get_locator(&self) -> Result<Vec<Hash>, Error> {
+		let head = try!(self.get_header_head());
+		let mut header = try!(self.get_block_header(&head.last_block_h));
+		let mut indexed = false;
+		let mut locator = vec![];
+		let mut gap = 0;
+		while gap < head.height && locator.len() < (MAX_LOCATORS - 1) as usize {
+			let height = head.height - gap;
+			while header.height > height {
+				// past the fork point, ancestors are the ones at their height
+				if !indexed {
+					indexed = try!(is_indexed(self, &header));
+				}
+				header = if indexed {
+					try!(self.get_header_by_height(height))
+				} else {
+					try!(self.get_block_header(&header.previous))
+				};
+			}
+			locator.push(header.hash());
+			gap = if gap == 0 { 1 } else { gap * 2 };
+		}
+		let genesis = try!(self.get_header_by_height(0));
+		locator.push(genesis.hash());
+		Ok(locator)
+	}
+}
+
+/// Whether the header is the one our height index has at its height.
+fn is_indexed<S: ChainStore + ?Sized>(store: &S, bh: &BlockHeader) -> Result<bool, Error> {
+	match store.get_header_by_height(bh.height) {
+		Ok(indexed) => Ok(indexed.hash() == bh.hash()),
+		Err(ref e) if e.is_not_found() => Ok(false),
+		Err(e) => Err(e),
+	}
 }
 
 /// Bridge between the chain pipeline and the rest of the system. Handles
@@ -130,9 +364,25 @@ pub trait ChainAdapter {
 	/// The blockchain pipeline has accepted this block as valid and added
 	/// it to our chain.
 	fn block_accepted(&self, b: &Block);
+
+	/// A new transaction has been accepted as valid, so it can be relayed to
+	/// the rest of the network.
+	fn transaction_accepted(&self, tx: &Transaction);
+
+	/// Our chain switched to another fork, undoing the disconnected blocks,
+	/// from our old head down, and adding the connected ones, in chain order
+	/// up to our new head. Called once the new head has been saved, right
+	/// before `head_changed`.
+	fn chain_reorg(&self, disconnected: &[Block], connected: &[Block]);
+
+	/// Our chain head moved to the provided tip, after the block extending
+	/// it or the fork replacing it has been saved.
+	fn head_changed(&self, tip: &Tip) {}
 }
 
 pub struct NoopAdapter { }
 impl ChainAdapter for NoopAdapter {
 	fn block_accepted(&self, b: &Block) {}
+	fn transaction_accepted(&self, tx: &Transaction) {}
+	fn chain_reorg(&self, disconnected: &[Block], connected: &[Block]) {}
 }
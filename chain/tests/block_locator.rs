@@ -0,0 +1,102 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+
+use grin_chain::{ChainStore, Tip, MAX_LOCATORS};
+use grin_chain::store::ChainKVStore;
+use grin_core::core::BlockHeader;
+use grin_core::core::hash::Hashed;
+
+// Builds a header chain of the provided length, only the first indexed
+// headers are indexed by height like our full chain would be.
+fn header_chain(len: u64, indexed: u64) -> (ChainKVStore, Vec<BlockHeader>) {
+	let store = ChainKVStore::with_store(Box::new(grin_store::MemStore::new()));
+	let headers = headers_from(&store, None, len, 20);
+	for bh in &headers[..indexed as usize] {
+		store.setup_height(bh).unwrap();
+	}
+	store.save_header_head(&Tip::from_block(headers.last().unwrap())).unwrap();
+	(store, headers)
+}
+
+// Saves count headers following the provided one, set apart from other
+// forks by their cuckoo length.
+fn headers_from(store: &ChainKVStore,
+                prev: Option<&BlockHeader>,
+                count: u64,
+                cuckoo_len: u8)
+                -> Vec<BlockHeader> {
+	let mut headers: Vec<BlockHeader> = vec![];
+	for n in 0..count {
+		let mut bh = BlockHeader::default();
+		bh.cuckoo_len = cuckoo_len;
+		match headers.last().or(prev) {
+			Some(prev) => {
+				bh.height = prev.height + 1;
+				bh.previous = prev.hash();
+			}
+			None => bh.height = n,
+		}
+		store.save_block_header(&bh).unwrap();
+		headers.push(bh);
+	}
+	headers
+}
+
+#[test]
+fn locator_spacing() {
+	let (store, headers) = header_chain(1000, 1000);
+	let locator = store.get_locator().unwrap();
+
+	let heights = vec![999, 998, 997, 995, 991, 983, 967, 935, 871, 0];
+	assert_eq!(locator.len(), MAX_LOCATORS as usize);
+	assert_eq!(locator,
+	           heights.iter().map(|&h| headers[h].hash()).collect::<Vec<_>>());
+}
+
+#[test]
+fn short_chain_locator() {
+	let (store, headers) = header_chain(5, 5);
+	let locator = store.get_locator().unwrap();
+	assert_eq!(locator,
+	           vec![4, 3, 2, 0].iter().map(|&h| headers[h].hash()).collect::<Vec<_>>());
+
+	// a chain with only genesis still gets it
+	let (store, headers) = header_chain(1, 1);
+	assert_eq!(store.get_locator().unwrap(), vec![headers[0].hash()]);
+}
+
+// The header chain is way ahead of our full chain, which is on another fork
+// past height 500. The locator has to follow the header chain.
+#[test]
+fn locator_ahead_of_full_chain() {
+	let (store, headers) = header_chain(1000, 500);
+	let fork = headers_from(&store, Some(&headers[499]), 10, 21);
+	for bh in &fork {
+		store.setup_height(bh).unwrap();
+	}
+
+	let heights = vec![999, 998, 997, 995, 991, 983, 967, 935, 871, 0];
+	assert_eq!(store.get_locator().unwrap(),
+	           heights.iter().map(|&h| headers[h].hash()).collect::<Vec<_>>());
+
+	// the header chain not indexed at all, locating works all the same
+	let (store, headers) = header_chain(20, 1);
+	let heights = vec![19, 18, 17, 15, 11, 3, 0];
+	assert_eq!(store.get_locator().unwrap(),
+	           heights.iter().map(|&h| headers[h].hash()).collect::<Vec<_>>());
+}
@@ -0,0 +1,103 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+extern crate time;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use grin_chain::store::ChainKVStore;
+use grin_chain::types::*;
+use grin_core::core::hash::{Hash, Hashed};
+use grin_core::core;
+
+use common::child;
+
+// Remembers the hashes of the blocks of each reorg, along with the head the
+// store had when told about it and how many head changes came before.
+struct ReorgAdapter {
+	store: Arc<ChainKVStore>,
+	reorgs: Mutex<Vec<(Vec<Hash>, Vec<Hash>)>>,
+	reorg_heads: Mutex<Vec<(Hash, usize)>>,
+	head_changes: Mutex<usize>,
+}
+
+impl ChainAdapter for ReorgAdapter {
+	fn block_accepted(&self, _: &core::Block) {}
+	fn transaction_accepted(&self, _: &core::Transaction) {}
+	fn chain_reorg(&self, disconnected: &[core::Block], connected: &[core::Block]) {
+		let hashes = |bs: &[core::Block]| bs.iter().map(|b| b.hash()).collect::<Vec<_>>();
+		self.reorgs.lock().unwrap().push((hashes(disconnected), hashes(connected)));
+		let head = self.store.head().unwrap().last_block_h;
+		self.reorg_heads.lock().unwrap().push((head, *self.head_changes.lock().unwrap()));
+	}
+	fn head_changed(&self, _: &Tip) {
+		*self.head_changes.lock().unwrap() += 1;
+	}
+}
+
+// Our chain of 3 blocks gets replaced by a heavier fork branching off after
+// the first one, only once the fork has more work.
+#[test]
+fn reorg_blocks() {
+	let store = ChainKVStore::with_store(Box::new(grin_store::MemStore::new()));
+	let gen = grin_core::genesis::genesis();
+	store.save_block(&gen).unwrap();
+	store.setup_height(&gen.header).unwrap();
+	store.save_head(&Tip::new(gen.hash())).unwrap();
+	let store = Arc::new(store);
+
+	let adapter = Arc::new(ReorgAdapter {
+		store: store.clone(),
+		reorgs: Mutex::new(vec![]),
+		reorg_heads: Mutex::new(vec![]),
+		head_changes: Mutex::new(0),
+	});
+	let process = |b: &core::Block| {
+		grin_chain::process_block(b, store.clone(), adapter.clone(), grin_chain::pipe::SKIP_POW)
+	};
+
+	let a1 = child(&gen.header, 2);
+	let a2 = child(&a1.header, 4);
+	let a3 = child(&a2.header, 6);
+	for b in &[&a1, &a2, &a3] {
+		process(*b).unwrap().unwrap();
+	}
+
+	let b2 = child(&a1.header, 3);
+	let b3 = child(&b2.header, 5);
+	let b4 = child(&b3.header, 7);
+	assert!(process(&b2).unwrap().is_none());
+	assert!(process(&b3).unwrap().is_none());
+	assert!(adapter.reorgs.lock().unwrap().is_empty());
+
+	let tip = process(&b4).unwrap().unwrap();
+	assert_eq!(tip.last_block_h, b4.hash());
+	assert_eq!(*adapter.reorgs.lock().unwrap(),
+	           vec![(vec![a3.hash(), a2.hash()], vec![b2.hash(), b3.hash(), b4.hash()])]);
+	// the new head was already saved, and the head change for it was still
+	// to come after the 3 of our first chain
+	assert_eq!(*adapter.reorg_heads.lock().unwrap(), vec![(b4.hash(), 3)]);
+	assert_eq!(*adapter.head_changes.lock().unwrap(), 4);
+
+	// extending the new head isn't a reorg
+	process(&child(&b4.header, 8)).unwrap().unwrap();
+	assert_eq!(adapter.reorgs.lock().unwrap().len(), 1);
+}
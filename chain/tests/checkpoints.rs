@@ -0,0 +1,97 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+extern crate time;
+
+use std::sync::Arc;
+
+use grin_chain::{Checkpoints, ChainStore, Error, Tip};
+use grin_chain::pipe::SKIP_POW;
+use grin_chain::store::ChainKVStore;
+use grin_chain::types::NoopAdapter;
+use grin_core::core::BlockHeader;
+use grin_core::core::hash::Hashed;
+use grin_core::core::target::Difficulty;
+
+fn header(prev: &BlockHeader, nonce: u64, diff: u32) -> BlockHeader {
+	let mut bh = BlockHeader::default();
+	bh.height = prev.height + 1;
+	bh.previous = prev.hash();
+	bh.timestamp = prev.timestamp + time::Duration::seconds(60);
+	bh.nonce = nonce;
+	bh.total_difficulty = prev.total_difficulty.clone() + Difficulty::from_num(diff);
+	bh
+}
+
+// Store with only the genesis header, checked against the provided
+// checkpoints.
+fn store(genesis: &BlockHeader, checkpoints: Checkpoints) -> Arc<ChainKVStore> {
+	let mut store = ChainKVStore::with_store(Box::new(grin_store::MemStore::new()));
+	store.set_checkpoints(checkpoints);
+	store.save_block_header(genesis).unwrap();
+	store.setup_height(genesis).unwrap();
+	store.save_header_head(&Tip::from_block(genesis)).unwrap();
+	Arc::new(store)
+}
+
+fn process(store: &Arc<ChainKVStore>, bh: &BlockHeader) -> Result<Option<Tip>, Error> {
+	grin_chain::process_block_header(bh, store.clone(), Arc::new(NoopAdapter {}), SKIP_POW)
+}
+
+#[test]
+fn conflicting_checkpoint_rejected() {
+	let genesis = BlockHeader::default();
+	let h1 = header(&genesis, 1, 1);
+	let other = header(&genesis, 2, 1);
+	let store = store(&genesis, Checkpoints::from_vec(vec![(1, h1.hash())]));
+
+	match process(&store, &other) {
+		Err(Error::InvalidCheckpoint) => {}
+		r => panic!("header contradicting a checkpoint accepted: {:?}", r),
+	}
+	assert_eq!(store.get_header_head().unwrap().last_block_h, genesis.hash());
+
+	assert!(process(&store, &h1).unwrap().is_some());
+	assert_eq!(store.get_header_head().unwrap().last_block_h, h1.hash());
+}
+
+#[test]
+fn rewind_below_checkpoint_refused() {
+	let genesis = BlockHeader::default();
+	let h1 = header(&genesis, 1, 1);
+	let h2 = header(&h1, 1, 1);
+	let h3 = header(&h2, 1, 1);
+	let store = store(&genesis, Checkpoints::from_vec(vec![(2, h2.hash())]));
+	for bh in &[&h1, &h2, &h3] {
+		process(&store, bh).unwrap();
+	}
+
+	// a lot more work, but branching off before the checkpoint
+	let below = header(&genesis, 2, 10);
+	match process(&store, &below) {
+		Err(Error::ForkBelowCheckpoint) => {}
+		r => panic!("reorg below a checkpoint accepted: {:?}", r),
+	}
+	assert_eq!(store.get_header_head().unwrap().last_block_h, h3.hash());
+	// refused before being saved, so never relayed either
+	assert!(store.get_block_header(&below.hash()).is_err());
+
+	// branching off after the checkpoint is fine
+	let above = header(&h2, 2, 10);
+	assert!(process(&store, &above).unwrap().is_some());
+	assert_eq!(store.get_header_head().unwrap().last_block_h, above.hash());
+}
@@ -0,0 +1,129 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fixtures shared by the chain tests, not all of them are used by every
+//! test.
+
+#![allow(dead_code)]
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use rand::os::OsRng;
+use secp;
+use time;
+
+use grin_chain::{ChainStore, Tip};
+use grin_chain::store::ChainKVStore;
+use grin_core::core::BlockHeader;
+use grin_core::core::hash::Hashed;
+use grin_core::core::target::Difficulty;
+use grin_core::core;
+use grin_store::{BatchOp, Error, KeyValueStore, MemStore};
+
+/// Memory store that can still be reached through its clones once handed to
+/// the chain store, to tamper with it. Counts the reads and batch writes
+/// reaching it, its batch writes can also be made to fail.
+#[derive(Clone)]
+pub struct TestStore {
+	pub inner: Arc<MemStore>,
+	pub fail: Arc<AtomicBool>,
+	pub gets: Arc<AtomicUsize>,
+	pub writes: Arc<AtomicUsize>,
+}
+
+impl TestStore {
+	pub fn new() -> TestStore {
+		TestStore {
+			inner: Arc::new(MemStore::new()),
+			fail: Arc::new(AtomicBool::new(false)),
+			gets: Arc::new(AtomicUsize::new(0)),
+			writes: Arc::new(AtomicUsize::new(0)),
+		}
+	}
+}
+
+impl KeyValueStore for TestStore {
+	fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+		self.gets.fetch_add(1, Ordering::SeqCst);
+		self.inner.get(key)
+	}
+
+	fn put(&self, key: &[u8], value: Vec<u8>) -> Result<(), Error> {
+		self.inner.put(key, value)
+	}
+
+	fn delete(&self, key: &[u8]) -> Result<(), Error> {
+		self.inner.delete(key)
+	}
+
+	fn write(&self, ops: Vec<BatchOp>) -> Result<(), Error> {
+		self.writes.fetch_add(1, Ordering::SeqCst);
+		if self.fail.load(Ordering::SeqCst) {
+			return Err(Error::RocksDbErr("injected failure".to_string()));
+		}
+		self.inner.write(ops)
+	}
+
+	fn iter_raw<'a>(&'a self,
+	                prefix: &[u8])
+	                -> Result<Box<Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>, Error> {
+		self.inner.iter_raw(prefix)
+	}
+}
+
+/// Builds headers on top of prev, each with the provided nonce so forks get
+/// different hashes.
+pub fn extend(store: &ChainKVStore,
+              prev: &BlockHeader,
+              len: u64,
+              nonce: u64)
+              -> Vec<BlockHeader> {
+	let mut headers: Vec<BlockHeader> = vec![];
+	for n in 0..len {
+		let mut bh = BlockHeader::default();
+		bh.height = prev.height + n + 1;
+		bh.nonce = nonce;
+		bh.previous = headers.last().unwrap_or(prev).hash();
+		store.save_block_header(&bh).unwrap();
+		headers.push(bh);
+	}
+	headers
+}
+
+/// Genesis and a main chain of 3 headers all indexed, with the head at the
+/// last one.
+pub fn main_chain(store: &ChainKVStore) -> (BlockHeader, Vec<BlockHeader>) {
+	let genesis = BlockHeader::default();
+	store.save_block_header(&genesis).unwrap();
+	store.setup_height(&genesis).unwrap();
+	let main = extend(store, &genesis, 3, 1);
+	for bh in &main {
+		store.setup_height(bh).unwrap();
+	}
+	store.save_head(&Tip::from_block(&main[2])).unwrap();
+	(genesis, main)
+}
+
+/// Empty block on top of prev, a block interval later, claiming the
+/// provided total difficulty.
+pub fn child(prev: &core::BlockHeader, total_diff: u32) -> core::Block {
+	let mut rng = OsRng::new().unwrap();
+	let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let reward_key = secp::key::SecretKey::new(&secp, &mut rng);
+	let mut b = core::Block::new(prev, vec![], reward_key).unwrap();
+	b.header.timestamp = prev.timestamp + time::Duration::seconds(60);
+	b.header.total_difficulty = Difficulty::from_num(total_diff);
+	b
+}
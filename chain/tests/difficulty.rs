@@ -0,0 +1,92 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+extern crate time;
+
+use grin_chain::{ChainStore, Tip, next_difficulty, next_target};
+use grin_chain::store::ChainKVStore;
+use grin_core::core::BlockHeader;
+use grin_core::core::hash::Hashed;
+use grin_core::core::target::Difficulty;
+use grin_store::MemStore;
+
+// Saves a chain of len headers all at the provided difficulty and mined
+// spacing seconds apart, returning the tip of the last one.
+fn chain(store: &ChainKVStore, len: u64, spacing: i64, diff: u32) -> Tip {
+	let mut prev = BlockHeader::default();
+	prev.difficulty = Difficulty::from_num(diff);
+	store.save_block_header(&prev).unwrap();
+	for n in 1..len {
+		let mut bh = BlockHeader::default();
+		bh.height = n;
+		bh.previous = prev.hash();
+		bh.timestamp = time::at_utc(time::Timespec {
+			sec: n as i64 * spacing,
+			nsec: 0,
+		});
+		bh.difficulty = Difficulty::from_num(diff);
+		store.save_block_header(&bh).unwrap();
+		prev = bh;
+	}
+	Tip::from_block(&prev)
+}
+
+fn next(len: u64, spacing: i64, diff: u32) -> Difficulty {
+	let store = ChainKVStore::with_store(Box::new(MemStore::new()));
+	let head = chain(&store, len, spacing, diff);
+	next_difficulty(&store, &head).unwrap()
+}
+
+#[test]
+fn on_target_blocks() {
+	assert_eq!(next(50, 60, 1000), Difficulty::from_num(1000));
+	// nothing to go by with just the genesis
+	assert_eq!(next(1, 60, 1000), Difficulty::from_num(1000));
+}
+
+#[test]
+fn fast_blocks() {
+	assert_eq!(next(50, 30, 1000), Difficulty::from_num(2000));
+	// a short chain still gets adjusted over what it has
+	assert_eq!(next(4, 30, 1000), Difficulty::from_num(2000));
+}
+
+#[test]
+fn slow_blocks() {
+	assert_eq!(next(50, 120, 1000), Difficulty::from_num(500));
+	// can't go lower than one
+	assert_eq!(next(50, 120, 1), Difficulty::one());
+}
+
+#[test]
+fn clamped_change() {
+	assert_eq!(next(50, 1, 1000), Difficulty::from_num(4000));
+	assert_eq!(next(50, 600, 1000), Difficulty::from_num(250));
+}
+
+// Once retargeting takes the difficulty over the soft max of the current
+// Cuckoo size, the next block moves up a size at half the difficulty.
+#[test]
+fn size_shift_follows_retarget() {
+	let store = ChainKVStore::with_store(Box::new(MemStore::new()));
+	let head = chain(&store, 50, 60, 40_000);
+	assert_eq!(next_target(&store, &head).unwrap(), (Difficulty::from_num(40_000), 20));
+
+	let store = ChainKVStore::with_store(Box::new(MemStore::new()));
+	let head = chain(&store, 50, 30, 40_000);
+	assert_eq!(next_target(&store, &head).unwrap(), (Difficulty::from_num(40_000), 21));
+}
@@ -0,0 +1,80 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+extern crate time;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+
+mod common;
+
+use std::sync::Arc;
+use std::sync::mpsc::TryRecvError;
+
+use grin_chain::types::*;
+use grin_chain::{ChainEvent, ChainEvents};
+use grin_core::core::hash::Hashed;
+use grin_core::core;
+
+use common::child;
+
+// Forwards the head changes of the chain to its subscribers.
+struct EventsAdapter {
+	events: ChainEvents,
+}
+
+impl ChainAdapter for EventsAdapter {
+	fn block_accepted(&self, _: &core::Block) {}
+	fn transaction_accepted(&self, _: &core::Transaction) {}
+	fn chain_reorg(&self, _: &[core::Block], _: &[core::Block]) {}
+	fn head_changed(&self, tip: &Tip) {
+		self.events.head_changed(tip);
+	}
+}
+
+// Both subscribers hear about the new head once a block extends the chain.
+#[test]
+fn head_changed_to_all_subscribers() {
+	let store = grin_chain::store::ChainKVStore::with_store(Box::new(grin_store::MemStore::new()));
+	let gen = grin_core::genesis::genesis();
+	store.save_block(&gen).unwrap();
+	store.setup_height(&gen.header).unwrap();
+	store.save_head(&Tip::new(gen.hash())).unwrap();
+	let store = Arc::new(store);
+
+	let adapter = Arc::new(EventsAdapter { events: ChainEvents::new() });
+	let process = |b: &core::Block| {
+		grin_chain::process_block(b, store.clone(), adapter.clone(), grin_chain::pipe::SKIP_POW)
+	};
+	let (miner, stratum) = (adapter.events.subscribe(), adapter.events.subscribe());
+
+	let b = child(&gen.header, 2);
+	let tip = process(&b).unwrap().unwrap();
+	assert_eq!(tip.last_block_h, b.hash());
+	for events in &[&miner, &stratum] {
+		assert_eq!(events.try_recv(), Ok(ChainEvent::HeadChanged(tip.clone())));
+		assert_eq!(events.try_recv(), Err(TryRecvError::Empty));
+	}
+
+	// a subscriber going away doesn't prevent the others from being notified
+	drop(stratum);
+	let next = child(&b.header, 3);
+	process(&next).unwrap();
+	match miner.try_recv() {
+		Ok(ChainEvent::HeadChanged(tip)) => assert_eq!(tip.last_block_h, next.hash()),
+		res => panic!("Expected a head change, got {:?}", res),
+	}
+}
@@ -0,0 +1,74 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+
+use grin_chain::Tip;
+use grin_chain::types::TIP_VERSION;
+use grin_core::core::hash::Hash;
+use grin_core::core::target::Difficulty;
+use grin_core::ser;
+
+fn tip(diff: u32, h: u8) -> Tip {
+	let mut tip = Tip::new(Hash([h; 32]));
+	tip.total_difficulty = Difficulty::from_num(diff);
+	tip
+}
+
+#[test]
+fn more_difficulty_wins() {
+	let (low, high) = (tip(10, 1), tip(11, 2));
+	assert!(high.is_better_than(&low));
+	assert!(!low.is_better_than(&high));
+	assert!(high > low);
+}
+
+#[test]
+fn equal_difficulty_lowest_hash_wins() {
+	let (a, b) = (tip(10, 1), tip(10, 2));
+	assert!(a.is_better_than(&b));
+	assert!(!b.is_better_than(&a));
+	assert!(!a.is_better_than(&a.clone()));
+
+	let mut tips = vec![tip(10, 2), tip(12, 3), tip(10, 1)];
+	tips.sort();
+	assert_eq!(tips.last().unwrap().last_block_h, Hash([3; 32]));
+	assert_eq!(tips[0].last_block_h, Hash([2; 32]));
+}
+
+#[test]
+fn tip_ser_versioned() {
+	let mut t = tip(42, 7);
+	t.height = 12;
+	t.prev_block_h = Hash([6; 32]);
+	let data = ser::ser_vec(&t).unwrap();
+	assert_eq!(data[0], TIP_VERSION);
+
+	let read = ser::deserialize::<Tip>(&mut &data[..]).unwrap();
+	assert_eq!(read, t);
+	assert_eq!(read.height, 12);
+	assert_eq!(read.prev_block_h, Hash([6; 32]));
+
+	// a tip written by a later version we don't know how to read
+	let mut future = data.clone();
+	future[0] = TIP_VERSION + 1;
+	match ser::deserialize::<Tip>(&mut &future[..]) {
+		Err(ser::Error::UnexpectedData { expected, received }) => {
+			assert_eq!(expected, vec![TIP_VERSION]);
+			assert_eq!(received, vec![TIP_VERSION + 1]);
+		}
+		res => panic!("Expected an unknown version error, got {:?}", res),
+	}
+}
@@ -0,0 +1,119 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+extern crate time;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+
+mod common;
+
+use std::fs;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use grin_chain::ChainStore;
+use grin_chain::store::ChainKVStore;
+use grin_core::core::BlockHeader;
+use grin_core::core::hash::Hashed;
+use grin_store::{Error, MemStore};
+
+use common::TestStore;
+
+// A chain of headers on top of genesis, not saved anywhere.
+fn header_chain(len: u64) -> Vec<BlockHeader> {
+	let mut headers = vec![BlockHeader::default()];
+	for n in 1..len {
+		let mut bh = BlockHeader::default();
+		bh.height = n;
+		bh.previous = headers[n as usize - 1].hash();
+		headers.push(bh);
+	}
+	headers
+}
+
+fn saved(store: &ChainKVStore, bh: &BlockHeader) -> bool {
+	match store.get_block_header(&bh.hash()) {
+		Ok(_) => true,
+		Err(ref e) if e.is_not_found() => false,
+		Err(e) => panic!("unexpected store error: {:?}", e),
+	}
+}
+
+// A failed write leaves none of the headers behind, a successful one saves
+// all of them in a single write.
+#[test]
+fn batch_saved_at_once() {
+	let db = TestStore::new();
+	db.fail.store(true, Ordering::SeqCst);
+	let store = ChainKVStore::with_store(Box::new(db.clone()));
+	let headers = header_chain(1000);
+
+	assert!(store.save_block_headers(&headers).is_err());
+	assert!(headers.iter().all(|bh| !saved(&store, bh)));
+
+	db.fail.store(false, Ordering::SeqCst);
+	store.save_block_headers(&headers).unwrap();
+	assert_eq!(db.writes.load(Ordering::SeqCst), 2);
+	for bh in &headers {
+		assert_eq!(store.get_block_header(&bh.hash()).unwrap().height, bh.height);
+		assert_eq!(store.get_block_height(&bh.hash()).unwrap(), bh.height);
+	}
+}
+
+// Headers that don't follow each other are refused before anything is
+// written.
+#[test]
+fn gap_refused() {
+	let store = ChainKVStore::with_store(Box::new(MemStore::new()));
+	let mut headers = header_chain(10);
+	headers.remove(5);
+	match store.save_block_headers(&headers) {
+		Err(Error::InconsistentErr(_)) => {}
+		r => panic!("headers with a gap saved: {:?}", r),
+	}
+	assert!(headers.iter().all(|bh| !saved(&store, bh)));
+
+	// nor can they go backward
+	let mut headers = header_chain(3);
+	headers.reverse();
+	assert!(store.save_block_headers(&headers).is_err());
+	assert!(store.save_block_headers(&[]).is_ok());
+}
+
+// On RocksDB, a single batch beats saving the headers one at a time.
+#[test]
+fn batch_faster_than_loop() {
+	let headers = header_chain(1000);
+	let open = |name: &str| {
+		let path = format!("target/{}", name);
+		let _ = fs::remove_dir_all(&path);
+		ChainKVStore::new(path).unwrap()
+	};
+
+	let store = open("chain-header-loop");
+	let start = Instant::now();
+	for bh in &headers {
+		store.save_block_header(bh).unwrap();
+	}
+	let looped = start.elapsed();
+
+	let store = open("chain-header-batch");
+	let start = Instant::now();
+	store.save_block_headers(&headers).unwrap();
+	let batched = start.elapsed();
+
+	assert!(batched < looped, "batch took {:?}, loop {:?}", batched, looped);
+}
@@ -0,0 +1,124 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+extern crate time;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+
+mod common;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use grin_chain::{ChainStore, HeaderCacheStats, Tip};
+use grin_chain::store::ChainKVStore;
+use grin_core::core::hash::Hashed;
+
+use common::{TestStore, extend, main_chain};
+
+// Chain store over a memory store counting the reads that reach it.
+fn counting_store() -> (ChainKVStore, Arc<AtomicUsize>) {
+	let db = TestStore::new();
+	let gets = db.gets.clone();
+	(ChainKVStore::with_store(Box::new(db)), gets)
+}
+
+#[test]
+fn second_lookup_from_memory() {
+	let (mut store, gets) = counting_store();
+	let (_, main) = main_chain(&store);
+	// starting afresh, without what building the chain cached
+	store.set_header_cache_size(2);
+
+	let reads = gets.load(Ordering::SeqCst);
+	assert_eq!(store.get_block_header(&main[0].hash()).unwrap().hash(), main[0].hash());
+	assert_eq!(gets.load(Ordering::SeqCst), reads + 1);
+	assert_eq!(store.get_block_header(&main[0].hash()).unwrap().hash(), main[0].hash());
+	assert_eq!(gets.load(Ordering::SeqCst), reads + 1);
+
+	assert_eq!(store.get_header_by_height(2).unwrap().hash(), main[1].hash());
+	assert_eq!(gets.load(Ordering::SeqCst), reads + 2);
+	assert_eq!(store.get_header_by_height(2).unwrap().hash(), main[1].hash());
+	assert_eq!(store.get_block_header(&main[1].hash()).unwrap().hash(), main[1].hash());
+	assert_eq!(gets.load(Ordering::SeqCst), reads + 2);
+
+	assert_eq!(store.header_cache_stats(),
+	           HeaderCacheStats {
+		           hits: 3,
+		           misses: 2,
+	           });
+
+	// a third header evicts the least recently used one
+	store.get_block_header(&main[2].hash()).unwrap();
+	store.get_block_header(&main[1].hash()).unwrap();
+	assert_eq!(gets.load(Ordering::SeqCst), reads + 3);
+	store.get_block_header(&main[0].hash()).unwrap();
+	assert_eq!(gets.load(Ordering::SeqCst), reads + 4);
+}
+
+#[test]
+fn heights_forgotten_on_reorg() {
+	let (store, gets) = counting_store();
+	let (_, main) = main_chain(&store);
+	for bh in &main {
+		assert_eq!(store.get_header_by_height(bh.height).unwrap().hash(), bh.hash());
+	}
+
+	let fork = extend(&store, &main[0], 3, 2);
+	store.reorg(&Tip::from_block(&fork[2]), &main[0].hash(), &fork).unwrap();
+	// below the fork point, heights are still cached
+	let reads = gets.load(Ordering::SeqCst);
+	assert_eq!(store.get_header_by_height(1).unwrap().hash(), main[0].hash());
+	assert_eq!(gets.load(Ordering::SeqCst), reads);
+	for bh in &fork {
+		assert_eq!(store.get_header_by_height(bh.height).unwrap().hash(), bh.hash());
+	}
+	// headers stay cached by hash on whatever fork they are
+	assert_eq!(store.get_block_header(&main[2].hash()).unwrap().hash(), main[2].hash());
+}
+
+// Indexing the header extending our chain doesn't drop the cached heights.
+#[test]
+fn heights_kept_when_extending() {
+	let (store, gets) = counting_store();
+	let (_, main) = main_chain(&store);
+	for bh in &main {
+		store.get_header_by_height(bh.height).unwrap();
+	}
+
+	let next = extend(&store, &main[2], 1, 1);
+	store.setup_height(&next[0]).unwrap();
+	let reads = gets.load(Ordering::SeqCst);
+	for bh in &main {
+		assert_eq!(store.get_header_by_height(bh.height).unwrap().hash(), bh.hash());
+	}
+	assert_eq!(gets.load(Ordering::SeqCst), reads);
+}
+
+#[test]
+fn disabled_cache() {
+	let (mut store, gets) = counting_store();
+	store.set_header_cache_size(0);
+	let (_, main) = main_chain(&store);
+
+	let reads = gets.load(Ordering::SeqCst);
+	store.get_block_header(&main[0].hash()).unwrap();
+	store.get_block_header(&main[0].hash()).unwrap();
+	assert_eq!(gets.load(Ordering::SeqCst), reads + 2);
+	assert_eq!(store.header_cache_stats().hits, 0);
+	assert_eq!(store.header_cache_stats().hit_rate(), 0.0);
+}
@@ -0,0 +1,116 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+extern crate time;
+
+use std::sync::Arc;
+
+use grin_chain::{ChainStore, Error, Tip, next_target};
+use grin_chain::store::ChainKVStore;
+use grin_chain::types::NoopAdapter;
+use grin_core::core::BlockHeader;
+use grin_core::core::hash::Hashed;
+use grin_core::core::target::Difficulty;
+use grin_core::pow;
+
+// Store with only a genesis header small enough to mine on, accepting
+// timestamps at most a minute ahead.
+fn store() -> (Arc<ChainKVStore>, BlockHeader) {
+	let mut store = ChainKVStore::with_store(Box::new(grin_store::MemStore::new()));
+	store.set_max_future_time(60);
+	let mut genesis = grin_core::genesis::genesis().header;
+	genesis.cuckoo_len = 12;
+	store.save_block_header(&genesis).unwrap();
+	store.setup_height(&genesis).unwrap();
+	store.save_header_head(&Tip::from_block(&genesis)).unwrap();
+	(Arc::new(store), genesis)
+}
+
+// Header following prev a block interval later, with everything but its
+// proof of work matching what the chain expects.
+fn child(store: &ChainKVStore, prev: &BlockHeader) -> BlockHeader {
+	let mut bh = BlockHeader::default();
+	bh.height = prev.height + 1;
+	bh.previous = prev.hash();
+	bh.timestamp = prev.timestamp + time::Duration::seconds(60);
+	bh.total_difficulty = prev.total_difficulty.clone() + prev.pow.to_difficulty();
+	let (difficulty, cuckoo_len) = next_target(store, &Tip::from_block(prev)).unwrap();
+	bh.difficulty = difficulty;
+	bh.cuckoo_len = cuckoo_len;
+	bh
+}
+
+fn process(store: &Arc<ChainKVStore>, bh: &BlockHeader) -> Result<Option<Tip>, Error> {
+	grin_chain::process_block_header(bh, store.clone(), Arc::new(NoopAdapter {}), grin_chain::NONE)
+}
+
+#[test]
+fn future_header_rejected() {
+	let (store, genesis) = store();
+	let mut bh = child(&store, &genesis);
+	bh.timestamp = time::now() + time::Duration::seconds(120);
+	match process(&store, &bh) {
+		Err(Error::InvalidBlockTime) => {}
+		r => panic!("header from the future accepted: {:?}", r),
+	}
+	assert_eq!(store.get_header_head().unwrap().last_block_h, genesis.hash());
+}
+
+#[test]
+fn wrong_difficulty_rejected() {
+	let (store, genesis) = store();
+	let mut bh = child(&store, &genesis);
+	bh.difficulty = bh.difficulty + Difficulty::one();
+	match process(&store, &bh) {
+		Err(ref e @ Error::WrongDifficulty) => assert!(e.is_bad_block()),
+		r => panic!("header with the wrong difficulty accepted: {:?}", r),
+	}
+	assert_eq!(store.get_header_head().unwrap().last_block_h, genesis.hash());
+}
+
+#[test]
+fn valid_header_accepted() {
+	let (store, genesis) = store();
+	let mut bh = child(&store, &genesis);
+	let diff = bh.difficulty.clone();
+	pow::pow(&mut bh, diff).unwrap();
+	let tip = process(&store, &bh).unwrap().unwrap();
+	assert_eq!(tip.last_block_h, bh.hash());
+}
+
+// A header we already have, without its block, is known to the header
+// pipeline even once it's neither the header head nor right below it.
+#[test]
+fn known_header_skipped() {
+	let (store, genesis) = store();
+	let mut headers = vec![];
+	let mut prev = genesis;
+	for _ in 0..3 {
+		let mut bh = child(&store, &prev);
+		let diff = bh.difficulty.clone();
+		pow::pow(&mut bh, diff).unwrap();
+		process(&store, &bh).unwrap();
+		prev = bh.clone();
+		headers.push(bh);
+	}
+	assert!(!store.block_exists(&headers[0].hash()).unwrap());
+	match process(&store, &headers[0]) {
+		Err(Error::Unfit(_)) => {}
+		r => panic!("Expected an already known header, got {:?}", r),
+	}
+	assert_eq!(store.get_header_head().unwrap().last_block_h, headers[2].hash());
+}
@@ -0,0 +1,159 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+extern crate time;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+
+mod common;
+
+use grin_chain::{ChainStore, Tip};
+use grin_chain::store::ChainKVStore;
+use grin_core::core::{Block, BlockHeader};
+use grin_core::core::hash::Hashed;
+use grin_core::ser;
+use grin_store::{Error, KeyValueStore};
+
+use common::{TestStore, extend};
+
+#[test]
+fn hash_height_index() {
+	let store = ChainKVStore::with_store(Box::new(grin_store::MemStore::new()));
+	let genesis = BlockHeader::default();
+	store.save_block_header(&genesis).unwrap();
+	store.setup_height(&genesis).unwrap();
+
+	let main = extend(&store, &genesis, 2, 1);
+	for bh in &main {
+		store.setup_height(bh).unwrap();
+	}
+	assert_eq!(store.get_block_height(&genesis.hash()).unwrap(), 0);
+	assert_eq!(store.get_block_height(&main[0].hash()).unwrap(), 1);
+	assert_eq!(store.get_block_height(&main[1].hash()).unwrap(), 2);
+
+	// a longer fork takes over, the old branch isn't indexed anymore
+	let fork = extend(&store, &genesis, 3, 2);
+	store.setup_height(&fork[2]).unwrap();
+	for (n, bh) in fork.iter().enumerate() {
+		assert_eq!(store.get_block_height(&bh.hash()).unwrap(), n as u64 + 1);
+	}
+	for bh in &main {
+		match store.get_block_height(&bh.hash()) {
+			Err(Error::NotFoundErr) => {}
+			res => panic!("Abandoned block should be gone from the index, got {:?}", res),
+		}
+	}
+	assert_eq!(store.get_block_height(&genesis.hash()).unwrap(), 0);
+}
+
+#[test]
+fn header_range() {
+	let db = TestStore::new();
+	let mut store = ChainKVStore::with_store(Box::new(db.clone()));
+	// the index gets tampered with behind the cache's back
+	store.set_header_cache_size(0);
+	let genesis = BlockHeader::default();
+	store.save_block_header(&genesis).unwrap();
+	store.setup_height(&genesis).unwrap();
+	let main = extend(&store, &genesis, 5, 1);
+	for bh in &main {
+		store.setup_height(bh).unwrap();
+	}
+
+	let headers = store.get_block_headers(1, 4).unwrap();
+	assert_eq!(headers.iter().map(|bh| bh.hash()).collect::<Vec<_>>(),
+	           main[..3].iter().map(|bh| bh.hash()).collect::<Vec<_>>());
+	assert_eq!(store.get_block_headers(2, 2).unwrap().len(), 0);
+
+	// drop height 3 from the height index, the range stops right there
+	db.delete(&grin_store::u64_to_key('8' as u8, 3)).unwrap();
+	match store.get_block_headers(0, 6) {
+		Err(Error::NotFoundKeyErr(ref key)) => assert_eq!(key, "block_header@height=3"),
+		Err(e) => panic!("Expected a missing header at height 3, got {:?}", e),
+		Ok(_) => panic!("Expected a missing header at height 3"),
+	}
+	assert_eq!(store.get_block_headers(4, 6).unwrap().len(), 2);
+}
+
+// A main chain with a short side branch a couple blocks below its head, both
+// tips should show up until the branch is deeper than we look.
+#[test]
+fn known_forks() {
+	let store = ChainKVStore::with_store(Box::new(grin_store::MemStore::new()));
+	let genesis = BlockHeader::default();
+	store.save_block_header(&genesis).unwrap();
+	store.setup_height(&genesis).unwrap();
+
+	let main = extend(&store, &genesis, 5, 1);
+	let main_tip = Tip::from_block(&main[4]);
+	for bh in &main {
+		store.setup_height(bh).unwrap();
+	}
+	store.save_head(&main_tip).unwrap();
+	assert_eq!(store.known_forks(10).unwrap(), vec![main_tip.clone()]);
+
+	let side = extend(&store, &main[1], 2, 2);
+	let side_tip = Tip::from_block(&side[1]);
+	let forks = store.known_forks(10).unwrap();
+	assert_eq!(forks.len(), 2);
+	assert!(forks.contains(&main_tip));
+	assert!(forks.contains(&side_tip));
+	assert_eq!(forks.iter().find(|t| **t == side_tip).unwrap().height, 4);
+
+	// getting the full block of a header we had doesn't make it a tip again
+	store.save_block(&Block { header: main[1].clone(), ..Default::default() }).unwrap();
+	assert_eq!(store.known_forks(10).unwrap().len(), 2);
+
+	// the side branch tip is one block below our head
+	assert_eq!(store.known_forks(1).unwrap().len(), 2);
+	assert_eq!(store.known_forks(0).unwrap(), vec![main_tip]);
+}
+
+// Breaks the height index the way a crash halfway through a reorg could:
+// a header from another fork at one height, a missing one at another and a
+// leftover above the header head. The rebuild should fix all three.
+#[test]
+fn rebuild_height_index() {
+	let db = TestStore::new();
+	let store = ChainKVStore::with_store(Box::new(db.clone()));
+	let genesis = BlockHeader::default();
+	store.save_block_header(&genesis).unwrap();
+	store.setup_height(&genesis).unwrap();
+	let main = extend(&store, &genesis, 5, 1);
+	for bh in &main[..4] {
+		store.setup_height(bh).unwrap();
+	}
+	store.save_header_head(&Tip::from_block(&main[3])).unwrap();
+	assert_eq!(store.rebuild_height_index().unwrap(), 0);
+
+	let fork = extend(&store, &genesis, 2, 2);
+	let height_key = |h| grin_store::u64_to_key('8' as u8, h);
+	db.put(&height_key(2), ser::ser_vec(&fork[1]).unwrap()).unwrap();
+	db.delete(&height_key(3)).unwrap();
+	db.put(&height_key(5), ser::ser_vec(&main[4]).unwrap()).unwrap();
+
+	assert_eq!(store.rebuild_height_index().unwrap(), 3);
+	for (n, bh) in main[..4].iter().enumerate() {
+		let height = n as u64 + 1;
+		assert_eq!(store.get_header_by_height(height).unwrap().hash(), bh.hash());
+		assert_eq!(store.get_block_height(&bh.hash()).unwrap(), height);
+	}
+	assert!(store.get_header_by_height(5).is_err());
+	assert!(store.get_block_height(&main[4].hash()).is_err());
+	assert!(store.get_block_height(&fork[1].hash()).is_err());
+	assert_eq!(store.rebuild_height_index().unwrap(), 0);
+}
@@ -0,0 +1,78 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+
+use grin_chain::{ChainStore, Inconsistency, Tip, verify_chain};
+use grin_chain::store::ChainKVStore;
+use grin_core::core::BlockHeader;
+use grin_core::core::hash::Hashed;
+
+fn child(prev: &BlockHeader) -> BlockHeader {
+	let mut bh = BlockHeader::default();
+	bh.height = prev.height + 1;
+	bh.previous = prev.hash();
+	bh
+}
+
+// Saves the header as our new head.
+fn extend(store: &ChainKVStore, bh: &BlockHeader) {
+	store.save_block_header(bh).unwrap();
+	store.setup_height(bh).unwrap();
+	store.save_head(&Tip::from_block(bh)).unwrap();
+}
+
+// Chain of 5 blocks after the genesis.
+fn chain() -> (ChainKVStore, Vec<BlockHeader>) {
+	let store = ChainKVStore::with_store(Box::new(grin_store::MemStore::new()));
+	let mut headers = vec![grin_core::genesis::genesis().header];
+	extend(&store, &headers[0]);
+	for _ in 0..5 {
+		let bh = child(headers.last().unwrap());
+		extend(&store, &bh);
+		headers.push(bh);
+	}
+	(store, headers)
+}
+
+#[test]
+fn consistent_chain() {
+	let (store, _) = chain();
+	verify_chain(&store, 3).unwrap();
+	verify_chain(&store, 1000).unwrap();
+}
+
+#[test]
+fn broken_link_reported() {
+	let (store, headers) = chain();
+
+	// a new block 3 pointing to block 1 instead of block 2, with blocks
+	// building on it
+	let mut bad = BlockHeader::default();
+	bad.height = 3;
+	bad.previous = headers[1].hash();
+	extend(&store, &bad);
+	let bh4 = child(&bad);
+	extend(&store, &bh4);
+	extend(&store, &child(&bh4));
+
+	match verify_chain(&store, 1000) {
+		Err(ref e @ Inconsistency::BrokenLink(3, _)) => assert_eq!(e.height(), 3),
+		r => panic!("broken link at height 3 not found: {:?}", r),
+	}
+	// not walking back far enough to see it
+	verify_chain(&store, 2).unwrap();
+}
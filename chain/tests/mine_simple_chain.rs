@@ -28,7 +28,6 @@ use grin_core::core::hash::Hashed;
 use grin_core::core::target::Difficulty;
 use grin_core::pow;
 use grin_core::core;
-use grin_core::consensus;
 
 #[test]
 fn mine_empty_chain() {
@@ -58,11 +57,11 @@ fn mine_empty_chain() {
 		let mut b = core::Block::new(&prev.header, vec![], reward_key).unwrap();
 		b.header.timestamp = prev.header.timestamp + time::Duration::seconds(60);
 
-		let (difficulty, _) = consensus::next_target(b.header.timestamp.to_timespec().sec,
-		                                             prev.header.timestamp.to_timespec().sec,
-		                                             prev.header.difficulty.clone(),
-		                                             prev.header.cuckoo_len);
+		let (difficulty, cuckoo_len) = grin_chain::next_target(&*arc_store,
+		                                                       &Tip::from_block(&prev.header))
+			.unwrap();
 		b.header.difficulty = difficulty.clone();
+		b.header.cuckoo_len = cuckoo_len;
 
 		pow::pow(&mut b.header, difficulty).unwrap();
 		grin_chain::pipe::process_block(&b,
@@ -0,0 +1,96 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+extern crate time;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+
+mod common;
+
+use std::sync::Arc;
+
+use grin_chain::types::*;
+use grin_chain::{OrphanPool, RejectCache};
+use grin_core::core::hash::Hashed;
+use grin_core::core;
+
+use common::child;
+
+// Receives a block before its parent, it should wait in the orphan pool and
+// get processed once the parent is accepted.
+#[test]
+fn process_orphan_after_parent() {
+	let store = grin_chain::store::ChainKVStore::with_store(Box::new(grin_store::MemStore::new()));
+	let gen = grin_core::genesis::genesis();
+	store.save_block(&gen).unwrap();
+	store.save_head(&Tip::new(gen.hash())).unwrap();
+
+	let store = Arc::new(store);
+	let adapter = Arc::new(NoopAdapter {});
+	let orphans = OrphanPool::new(10);
+	let rejects = RejectCache::new(10);
+
+	let b1 = child(&gen.header, 2);
+	let b2 = child(&b1.header, 3);
+	let (h1, h2) = (b1.hash(), b2.hash());
+
+	match grin_chain::process_block_orphans(b2,
+	                                        store.clone(),
+	                                        adapter.clone(),
+	                                        &orphans,
+	                                        &rejects,
+	                                        grin_chain::pipe::SKIP_POW) {
+		Err(grin_chain::Error::Orphan) => {}
+		res => panic!("Expected an orphan, got {:?}", res),
+	}
+	assert!(orphans.contains(&h2));
+	assert_eq!(store.head().unwrap().last_block_h, gen.hash());
+
+	let head = grin_chain::process_block_orphans(b1,
+	                                             store.clone(),
+	                                             adapter.clone(),
+	                                             &orphans,
+	                                             &rejects,
+	                                             grin_chain::pipe::SKIP_POW)
+		.unwrap()
+		.unwrap();
+	assert_eq!(head.last_block_h, h2);
+	assert_eq!(head.prev_block_h, h1);
+	assert_eq!(store.head().unwrap().last_block_h, h2);
+	assert_eq!(orphans.len(), 0);
+}
+
+// Fills up the orphan pool, the oldest orphan should make room for the new
+// one.
+#[test]
+fn evict_oldest_orphan() {
+	let gen = grin_core::genesis::genesis();
+	let orphans = OrphanPool::new(2);
+
+	let blocks = (0..3).map(|n| child(&gen.header, n + 2)).collect::<Vec<_>>();
+	let hashes = blocks.iter().map(|b| b.hash()).collect::<Vec<_>>();
+	for b in blocks {
+		orphans.add(b);
+	}
+
+	assert_eq!(orphans.len(), 2);
+	assert!(!orphans.contains(&hashes[0]));
+	assert!(orphans.contains(&hashes[1]));
+	assert!(orphans.contains(&hashes[2]));
+	assert_eq!(orphans.remove_children(&gen.hash()).len(), 2);
+	assert_eq!(orphans.len(), 0);
+}
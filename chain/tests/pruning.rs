@@ -0,0 +1,99 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+
+use grin_chain::{ChainStore, Tip};
+use grin_chain::store::ChainKVStore;
+use grin_store::Error;
+use grin_core::core::Block;
+use grin_core::core::hash::Hashed;
+
+// Saves a chain of the provided length, indexing it and pointing the head
+// to its last block.
+fn build_chain(store: &ChainKVStore, len: u64) -> Vec<Block> {
+	let mut blocks: Vec<Block> = vec![];
+	for height in 0..len {
+		let mut b = Block::default();
+		b.header.height = height;
+		if let Some(prev) = blocks.last() {
+			b.header.previous = prev.hash();
+		}
+		store.save_block(&b).unwrap();
+		store.setup_height(&b.header).unwrap();
+		blocks.push(b);
+	}
+	let mut head = Tip::new(blocks[len as usize - 1].hash());
+	head.height = len - 1;
+	store.save_head(&head).unwrap();
+	blocks
+}
+
+#[test]
+fn prune_below_height() {
+	let mut store = ChainKVStore::with_store(Box::new(grin_store::MemStore::new()));
+	store.set_prune_window(3);
+	let blocks = build_chain(&store, 10);
+
+	assert_eq!(store.prune_block_data(4).unwrap(), 4);
+	for b in &blocks[..4] {
+		match store.get_block(&b.hash()) {
+			Err(ref e) if e.is_not_found() => {}
+			_ => panic!("Block at {} should have been pruned", b.header.height),
+		}
+		assert_eq!(store.get_block_header(&b.hash()).unwrap().hash(), b.hash());
+		assert_eq!(store.get_block_height(&b.hash()).unwrap(), b.header.height);
+	}
+	for b in &blocks[4..] {
+		assert_eq!(store.get_block(&b.hash()).unwrap().hash(), b.hash());
+	}
+
+	// already pruned blocks aren't counted again
+	assert_eq!(store.prune_block_data(4).unwrap(), 0);
+	assert_eq!(store.prune_block_data(5).unwrap(), 1);
+}
+
+#[test]
+fn prune_safety_window() {
+	let mut store = ChainKVStore::with_store(Box::new(grin_store::MemStore::new()));
+	store.set_prune_window(3);
+	let blocks = build_chain(&store, 10);
+
+	// head is at 9, nothing at or above 6 can go
+	assert_eq!(store.prune_block_data(100).unwrap(), 6);
+	for b in &blocks[6..] {
+		assert!(store.get_block(&b.hash()).is_ok());
+	}
+	assert!(store.get_block(&blocks[5].hash()).is_err());
+}
+
+#[test]
+fn block_by_height() {
+	let mut store = ChainKVStore::with_store(Box::new(grin_store::MemStore::new()));
+	store.set_prune_window(3);
+	let blocks = build_chain(&store, 10);
+
+	let b = store.get_block_by_height(7).unwrap();
+	assert_eq!(b.hash(), blocks[7].hash());
+	assert_eq!(b.header.height, 7);
+
+	store.prune_block_data(4).unwrap();
+	match store.get_block_by_height(2) {
+		Err(Error::NotFoundErr) => {}
+		res => panic!("Pruned block should not be found, got {:?}", res.map(|b| b.hash())),
+	}
+	assert!(store.get_block_by_height(10).map(|b| b.hash()).unwrap_err().is_not_found());
+}
@@ -0,0 +1,167 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+extern crate time;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+
+mod common;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use grin_chain::types::*;
+use grin_chain::{Error, OrphanPool, RejectCache};
+use grin_core::core::hash::Hashed;
+use grin_core::core;
+use grin_core::ser;
+
+use common::child;
+
+// Counts the blocks getting accepted, and so broadcast
+struct CountingAdapter {
+	accepted: AtomicUsize,
+}
+
+impl ChainAdapter for CountingAdapter {
+	fn block_accepted(&self, _: &core::Block) {
+		self.accepted.fetch_add(1, Ordering::SeqCst);
+	}
+	fn transaction_accepted(&self, _: &core::Transaction) {}
+	fn chain_reorg(&self, _: &[core::Block], _: &[core::Block]) {}
+}
+
+// Blocks can't be cloned, goes through serialization instead
+fn copy(b: &core::Block) -> core::Block {
+	ser::deserialize(&mut &ser::ser_vec(b).unwrap()[..]).unwrap()
+}
+
+// Submits an invalid block twice, the second time it should be refused
+// before going through validation again. Orphans are never cached.
+#[test]
+fn refuse_known_bad_block() {
+	let store = grin_chain::store::ChainKVStore::with_store(Box::new(grin_store::MemStore::new()));
+	let gen = grin_core::genesis::genesis();
+	store.save_block(&gen).unwrap();
+	store.save_head(&Tip::new(gen.hash())).unwrap();
+
+	let store = Arc::new(store);
+	let adapter = Arc::new(NoopAdapter {});
+	let orphans = OrphanPool::new(10);
+	let rejects = RejectCache::new(10);
+	let process = |b: &core::Block| {
+		grin_chain::process_block_orphans(copy(b),
+		                                  store.clone(),
+		                                  adapter.clone(),
+		                                  &orphans,
+		                                  &rejects,
+		                                  grin_chain::pipe::SKIP_POW)
+	};
+
+	let mut bad = child(&gen.header, 2);
+	bad.header.height = 0;
+	match process(&bad) {
+		Err(Error::InvalidBlockHeight) => {}
+		res => panic!("Expected an invalid height, got {:?}", res),
+	}
+	assert!(rejects.contains(&bad.hash()));
+	match process(&bad) {
+		Err(Error::KnownBad(_)) => {}
+		res => panic!("Expected a known bad block, got {:?}", res),
+	}
+
+	let orphan = child(&child(&gen.header, 2).header, 3);
+	for _ in 0..2 {
+		match process(&orphan) {
+			Err(Error::Orphan) => {}
+			res => panic!("Expected an orphan, got {:?}", res),
+		}
+	}
+	assert!(!rejects.contains(&orphan.hash()));
+	assert_eq!(rejects.len(), 1);
+}
+
+// Fills up the cache, the least recently seen block should make room for
+// the new one.
+#[test]
+fn evict_least_recent_reject() {
+	let rejects = RejectCache::new(2);
+	let hashes = (0..3).map(|n| [n as u8][..].hash()).collect::<Vec<_>>();
+	rejects.add(hashes[0], "InvalidPow".to_string());
+	rejects.add(hashes[1], "InvalidPow".to_string());
+	assert_eq!(rejects.get(&hashes[0]), Some("InvalidPow".to_string()));
+	rejects.add(hashes[2], "WrongCuckooSize".to_string());
+
+	assert_eq!(rejects.len(), 2);
+	assert!(rejects.contains(&hashes[0]));
+	assert!(!rejects.contains(&hashes[1]));
+	assert_eq!(rejects.get(&hashes[2]), Some("WrongCuckooSize".to_string()));
+}
+
+// Submits blocks we already have again, they should be dropped before going
+// through validation (the proof of work isn't even checked) and without being
+// broadcast. An orphan seen twice still gets added once its parent comes.
+#[test]
+fn skip_known_block() {
+	let store = grin_chain::store::ChainKVStore::with_store(Box::new(grin_store::MemStore::new()));
+	let gen = grin_core::genesis::genesis();
+	store.save_block(&gen).unwrap();
+	store.save_head(&Tip::new(gen.hash())).unwrap();
+
+	let store = Arc::new(store);
+	let adapter = Arc::new(CountingAdapter { accepted: AtomicUsize::new(0) });
+	let orphans = OrphanPool::new(10);
+	let rejects = RejectCache::new(10);
+	let process = |b: &core::Block, opts: grin_chain::pipe::Options| {
+		grin_chain::process_block_orphans(copy(b),
+		                                  store.clone(),
+		                                  adapter.clone(),
+		                                  &orphans,
+		                                  &rejects,
+		                                  opts)
+	};
+
+	let mut chain = vec![child(&gen.header, 2)];
+	for n in 0..2 {
+		let next = child(&chain[n].header, n as u32 + 3);
+		chain.push(next);
+	}
+	for b in &chain {
+		process(b, grin_chain::pipe::SKIP_POW).unwrap();
+	}
+	assert_eq!(adapter.accepted.load(Ordering::SeqCst), 3);
+
+	// neither our head nor right below it, only the store knows of it
+	match process(&chain[0], grin_chain::NONE) {
+		Err(Error::Unfit(_)) => {}
+		res => panic!("Expected an already known block, got {:?}", res),
+	}
+	assert_eq!(adapter.accepted.load(Ordering::SeqCst), 3);
+	assert_eq!(rejects.len(), 0);
+
+	let parent = child(&chain[2].header, 5);
+	let orphan = child(&parent.header, 6);
+	for _ in 0..2 {
+		match process(&orphan, grin_chain::pipe::SKIP_POW) {
+			Err(Error::Orphan) => {}
+			res => panic!("Expected an orphan, got {:?}", res),
+		}
+	}
+	let head = process(&parent, grin_chain::pipe::SKIP_POW).unwrap().unwrap();
+	assert_eq!(head.last_block_h, orphan.hash());
+	assert_eq!(adapter.accepted.load(Ordering::SeqCst), 5);
+}
@@ -0,0 +1,79 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+extern crate time;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+
+mod common;
+
+use std::sync::atomic::Ordering;
+
+use grin_chain::{ChainStore, Tip};
+use grin_chain::store::ChainKVStore;
+use grin_core::core::hash::Hashed;
+use grin_store::MemStore;
+
+use common::{TestStore, extend, main_chain};
+
+#[test]
+fn reorg_to_fork() {
+	let store = ChainKVStore::with_store(Box::new(MemStore::new()));
+	let (genesis, main) = main_chain(&store);
+
+	let fork = extend(&store, &main[0], 3, 2);
+	let new_head = Tip::from_block(&fork[2]);
+	store.reorg(&new_head, &main[0].hash(), &fork).unwrap();
+
+	assert_eq!(store.head().unwrap().last_block_h, fork[2].hash());
+	assert_eq!(store.get_header_head().unwrap().last_block_h, fork[2].hash());
+	assert_eq!(store.get_header_by_height(0).unwrap().hash(), genesis.hash());
+	assert_eq!(store.get_header_by_height(1).unwrap().hash(), main[0].hash());
+	for bh in &fork {
+		assert_eq!(store.get_header_by_height(bh.height).unwrap().hash(), bh.hash());
+		assert_eq!(store.get_block_height(&bh.hash()).unwrap(), bh.height);
+	}
+	for bh in &main[1..] {
+		match store.get_block_height(&bh.hash()) {
+			Err(grin_store::Error::NotFoundErr) => {}
+			r => panic!("abandoned header still indexed: {:?}", r),
+		}
+	}
+}
+
+#[test]
+fn failed_reorg_leaves_store_untouched() {
+	let db = TestStore::new();
+	let store = ChainKVStore::with_store(Box::new(db.clone()));
+	let (_, main) = main_chain(&store);
+	let fork = extend(&store, &main[0], 3, 2);
+
+	db.fail.store(true, Ordering::SeqCst);
+	assert!(store.reorg(&Tip::from_block(&fork[2]), &main[0].hash(), &fork).is_err());
+	db.fail.store(false, Ordering::SeqCst);
+
+	assert_eq!(store.head().unwrap().last_block_h, main[2].hash());
+	for bh in &main {
+		assert_eq!(store.get_header_by_height(bh.height).unwrap().hash(), bh.hash());
+		assert_eq!(store.get_block_height(&bh.hash()).unwrap(), bh.height);
+	}
+	match store.get_header_by_height(4) {
+		Err(ref e) if e.is_not_found() => {}
+		Err(e) => panic!("unexpected store error: {:?}", e),
+		Ok(_) => panic!("fork header indexed after failed reorg"),
+	}
+}
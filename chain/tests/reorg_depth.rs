@@ -0,0 +1,87 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+extern crate time;
+
+use std::sync::Arc;
+
+use grin_chain::{ChainStore, Error, Tip};
+use grin_chain::pipe::SKIP_POW;
+use grin_chain::store::ChainKVStore;
+use grin_chain::types::NoopAdapter;
+use grin_core::core::BlockHeader;
+use grin_core::core::hash::Hashed;
+use grin_core::core::target::Difficulty;
+
+fn header(prev: &BlockHeader, nonce: u64, diff: u32) -> BlockHeader {
+	let mut bh = BlockHeader::default();
+	bh.height = prev.height + 1;
+	bh.previous = prev.hash();
+	bh.timestamp = prev.timestamp + time::Duration::seconds(60);
+	bh.nonce = nonce;
+	bh.total_difficulty = prev.total_difficulty.clone() + Difficulty::from_num(diff);
+	bh
+}
+
+fn process(store: &Arc<ChainKVStore>, bh: &BlockHeader) -> Result<Option<Tip>, Error> {
+	grin_chain::process_block_header(bh, store.clone(), Arc::new(NoopAdapter {}), SKIP_POW)
+}
+
+// A chain of 3 headers on top of genesis, only allowing reorgs of 2 blocks.
+// A heavier fork off genesis would rewind 3 and is refused outright, one off
+// the first header is fine.
+#[test]
+fn deep_reorg_refused() {
+	let genesis = BlockHeader::default();
+	let mut store = ChainKVStore::with_store(Box::new(grin_store::MemStore::new()));
+	store.set_max_reorg_depth(2);
+	store.save_block_header(&genesis).unwrap();
+	store.setup_height(&genesis).unwrap();
+	store.save_header_head(&Tip::from_block(&genesis)).unwrap();
+	let store = Arc::new(store);
+
+	let h1 = header(&genesis, 1, 1);
+	let h2 = header(&h1, 1, 1);
+	let h3 = header(&h2, 1, 1);
+	for bh in &[&h1, &h2, &h3] {
+		process(&store, bh).unwrap();
+	}
+
+	let deep = header(&genesis, 2, 10);
+	match process(&store, &deep) {
+		Err(Error::ReorgTooDeep) => {}
+		r => panic!("reorg deeper than the limit accepted: {:?}", r),
+	}
+	// only deep for our chain as it is now, not invalid in itself
+	assert!(!Error::ReorgTooDeep.is_bad_block());
+	match process(&store, &deep) {
+		Err(Error::ReorgTooDeep) => {}
+		r => panic!("deep reorg not refused again the same way: {:?}", r),
+	}
+	assert_eq!(store.get_header_head().unwrap().last_block_h, h3.hash());
+	match store.get_block_header(&deep.hash()) {
+		Err(ref e) if e.is_not_found() => {}
+		_ => panic!("refused header saved"),
+	}
+
+	// a lighter fork is kept around as usual, nothing to rewind for it yet
+	let light = header(&genesis, 3, 1);
+	assert!(process(&store, &light).unwrap().is_none());
+
+	let shallow = header(&h1, 2, 10);
+	assert!(process(&store, &shallow).unwrap().is_some());
+	assert_eq!(store.get_header_head().unwrap().last_block_h, shallow.hash());
+}
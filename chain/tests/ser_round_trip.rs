@@ -0,0 +1,257 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+extern crate time;
+
+use std::fs;
+
+use rand::{Rng, SeedableRng, StdRng};
+
+use grin_chain::Tip;
+use grin_core::consensus::PROOFSIZE;
+use grin_core::core::{Block, BlockHeader, Input, Output, Proof, TxProof};
+use grin_core::core::hash::Hash;
+use grin_core::core::target::Difficulty;
+use grin_core::ser::{self, Readable, Writeable};
+use grin_store::{u64_to_key, Store};
+use secp::constants::{MAX_PROOF_SIZE, PEDERSEN_COMMITMENT_SIZE};
+use secp::pedersen::{Commitment, RangeProof};
+
+// Number of random values of each type going through the store.
+const ROUNDS: u64 = 200;
+
+// Types that can be generated randomly, compared field by field and
+// described when they don't come back from the store the way they went in.
+trait Arbitrary: Writeable + Readable<Self> + Sized {
+	fn arbitrary(rng: &mut StdRng) -> Self;
+	fn same(&self, other: &Self) -> bool;
+	fn describe(&self) -> String;
+}
+
+impl Arbitrary for Tip {
+	fn arbitrary(rng: &mut StdRng) -> Tip {
+		Tip {
+			height: rng.gen(),
+			last_block_h: Hash(rng.gen()),
+			prev_block_h: Hash(rng.gen()),
+			total_difficulty: Difficulty::from_u64(rng.gen()),
+		}
+	}
+
+	fn same(&self, other: &Tip) -> bool {
+		self.height == other.height && self.last_block_h == other.last_block_h &&
+		self.prev_block_h == other.prev_block_h &&
+		self.total_difficulty == other.total_difficulty
+	}
+
+	fn describe(&self) -> String {
+		format!("{:?}", self)
+	}
+}
+
+impl Arbitrary for BlockHeader {
+	fn arbitrary(rng: &mut StdRng) -> BlockHeader {
+		let mut pow = [0; PROOFSIZE];
+		for n in 0..PROOFSIZE {
+			pow[n] = rng.gen();
+		}
+		BlockHeader {
+			height: rng.gen(),
+			previous: Hash(rng.gen()),
+			timestamp: time::at_utc(time::Timespec {
+				sec: rng.gen_range(0, 1 << 40),
+				nsec: 0,
+			}),
+			cuckoo_len: rng.gen(),
+			utxo_merkle: Hash(rng.gen()),
+			tx_merkle: Hash(rng.gen()),
+			nonce: rng.gen(),
+			pow: Proof(pow),
+			difficulty: Difficulty::from_u64(rng.gen()),
+			total_difficulty: Difficulty::from_u64(rng.gen()),
+		}
+	}
+
+	fn same(&self, other: &BlockHeader) -> bool {
+		self.height == other.height && self.previous == other.previous &&
+		self.timestamp == other.timestamp && self.cuckoo_len == other.cuckoo_len &&
+		self.utxo_merkle == other.utxo_merkle && self.tx_merkle == other.tx_merkle &&
+		self.nonce == other.nonce && self.pow.to_u32s() == other.pow.to_u32s() &&
+		self.difficulty == other.difficulty &&
+		self.total_difficulty == other.total_difficulty
+	}
+
+	fn describe(&self) -> String {
+		format!("BlockHeader {{ height: {}, previous: {}, timestamp: {}, cuckoo_len: {}, \
+		         utxo_merkle: {}, tx_merkle: {}, nonce: {}, pow: {:?}, difficulty: {:?}, \
+		         total_difficulty: {:?} }}",
+		        self.height,
+		        self.previous,
+		        self.timestamp.to_timespec().sec,
+		        self.cuckoo_len,
+		        self.utxo_merkle,
+		        self.tx_merkle,
+		        self.nonce,
+		        self.pow.to_u32s(),
+		        self.difficulty,
+		        self.total_difficulty)
+	}
+}
+
+// Only the blind versions of inputs and outputs get serialized.
+impl Arbitrary for Block {
+	fn arbitrary(rng: &mut StdRng) -> Block {
+		let inputs = (0..rng.gen_range(0, 10))
+			.map(|_| Input::BareInput { output: Hash(rng.gen()) })
+			.collect();
+		let outputs = (0..rng.gen_range(0, 5))
+			.map(|_| {
+				let mut commit = [0; PEDERSEN_COMMITMENT_SIZE];
+				rng.fill_bytes(&mut commit);
+				let mut proof = [0; MAX_PROOF_SIZE];
+				let plen = rng.gen_range(0, MAX_PROOF_SIZE + 1);
+				rng.fill_bytes(&mut proof[..plen]);
+				Output::BlindOutput {
+					commit: Commitment(commit),
+					proof: RangeProof {
+						proof: proof,
+						plen: plen,
+					},
+				}
+			})
+			.collect();
+		let proofs = (0..rng.gen_range(0, 5))
+			.map(|_| {
+				let mut remainder = [0; PEDERSEN_COMMITMENT_SIZE];
+				rng.fill_bytes(&mut remainder);
+				let sig_len = rng.gen_range(0, 80);
+				TxProof {
+					remainder: Commitment(remainder),
+					sig: rng.gen_iter().take(sig_len).collect(),
+					fee: rng.gen(),
+				}
+			})
+			.collect();
+		Block {
+			header: BlockHeader::arbitrary(rng),
+			inputs: inputs,
+			outputs: outputs,
+			proofs: proofs,
+		}
+	}
+
+	fn same(&self, other: &Block) -> bool {
+		let inputs = |b: &Block| b.inputs.iter().map(|i| i.output_hash()).collect::<Vec<_>>();
+		let outputs = |b: &Block| {
+			b.outputs
+				.iter()
+				.map(|o| (o.commitment().unwrap().0.to_vec(), o.proof().unwrap().bytes().to_vec()))
+				.collect::<Vec<_>>()
+		};
+		let proofs = |b: &Block| {
+			b.proofs
+				.iter()
+				.map(|p| (p.remainder.0.to_vec(), p.sig.clone(), p.fee))
+				.collect::<Vec<_>>()
+		};
+		self.header.same(&other.header) && inputs(self) == inputs(other) &&
+		outputs(self) == outputs(other) && proofs(self) == proofs(other)
+	}
+
+	fn describe(&self) -> String {
+		format!("Block {{ header: {}, inputs: {:?}, outputs: {:?}, proofs: {:?} }}",
+		        self.header.describe(),
+		        self.inputs.iter().map(|i| i.output_hash()).collect::<Vec<_>>(),
+		        self.outputs,
+		        self.proofs)
+	}
+}
+
+// Saves randomly generated values in a store and reads them back, failing
+// with the seed to reproduce the run and the first value that didn't come
+// back the same.
+fn round_trip<T: Arbitrary>(name: &str) {
+	let path = format!("target/chain-ser-{}", name);
+	let _ = fs::remove_dir_all(&path);
+	let store = Store::open(&path).unwrap();
+
+	let seed = rand::thread_rng().gen::<usize>();
+	let mut rng = StdRng::from_seed(&[seed]);
+	for n in 0..ROUNDS {
+		let value = T::arbitrary(&mut rng);
+		let key = u64_to_key('t' as u8, n);
+		store.put_ser(&key, &value).unwrap();
+		let read = match store.get_ser::<T>(&key) {
+			Ok(Some(read)) => read,
+			r => {
+				panic!("{} (seed {}) not read back: {:?}\n{}",
+				       name,
+				       seed,
+				       r.map(|v| v.map(|v| v.describe())),
+				       value.describe())
+			}
+		};
+		if !read.same(&value) {
+			panic!("{} (seed {}) changed going through the store:\n{}\nread back as\n{}",
+			       name,
+			       seed,
+			       value.describe(),
+			       read.describe());
+		}
+		// and serialize to the same bytes again
+		assert_eq!(ser::ser_vec(&read).unwrap(), ser::ser_vec(&value).unwrap());
+	}
+}
+
+#[test]
+fn tip_round_trip() {
+	round_trip::<Tip>("tip");
+}
+
+#[test]
+fn block_header_round_trip() {
+	round_trip::<BlockHeader>("block-header");
+}
+
+#[test]
+fn block_round_trip() {
+	round_trip::<Block>("block");
+}
+
+// Tips saved before their layout got a version are still read.
+#[test]
+fn legacy_tip_read() {
+	let last = Hash([1; 32]);
+	let prev = Hash([2; 32]);
+	let diff = Difficulty::from_num(1000);
+	let mut data = vec![0, 0, 0, 0, 0, 0, 1, 2];
+	data.extend(ser::ser_vec(&last).unwrap());
+	data.extend(ser::ser_vec(&prev).unwrap());
+	data.extend(ser::ser_vec(&diff).unwrap());
+
+	let tip = ser::deserialize::<Tip>(&mut &data[..]).unwrap();
+	assert_eq!(tip.height, 258);
+	assert_eq!(tip.last_block_h, last);
+	assert_eq!(tip.prev_block_h, prev);
+	assert_eq!(tip.total_difficulty, diff);
+
+	// and gets saved again with the current layout
+	let mut current = vec![grin_chain::types::TIP_VERSION];
+	current.extend(&data);
+	assert_eq!(ser::ser_vec(&tip).unwrap(), current);
+}
@@ -0,0 +1,104 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core;
+extern crate grin_chain;
+extern crate grin_store;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+
+use rand::os::OsRng;
+use secp::Secp256k1;
+use secp::key::SecretKey;
+
+use grin_chain::{ChainStore, PoolError, Tip, validate_transaction};
+use grin_chain::store::ChainKVStore;
+use grin_core::consensus::REWARD;
+use grin_core::core::{Block, BlockHeader, Input, Output, Transaction};
+use grin_core::core::hash::{Hash, Hashed, ZERO_HASH};
+use grin_store::MemStore;
+
+// Chain of a genesis header and a block with a single reward output, spent
+// with the returned key.
+fn chain_with_reward(secp: &Secp256k1) -> (ChainKVStore, Block, SecretKey) {
+	let store = ChainKVStore::with_store(Box::new(MemStore::new()));
+	let genesis = BlockHeader::default();
+	store.save_block_header(&genesis).unwrap();
+	store.setup_height(&genesis).unwrap();
+
+	let key = SecretKey::new(secp, &mut OsRng::new().unwrap());
+	let b = Block::new(&genesis, vec![], key).unwrap();
+	store.save_block(&b).unwrap();
+	store.setup_height(&b.header).unwrap();
+	store.save_head(&Tip::from_block(&b.header)).unwrap();
+	(store, b, key)
+}
+
+// Blinded transaction spending the provided output, worth REWARD.
+fn spend(secp: &Secp256k1, output: Hash, key: SecretKey) -> Transaction {
+	let mut rng = OsRng::new().unwrap();
+	Transaction::new(vec![Input::OvertInput {
+		                      output: output,
+		                      value: REWARD,
+		                      blindkey: key,
+	                      }],
+	                 vec![Output::OvertOutput {
+		                      value: REWARD - 1,
+		                      blindkey: SecretKey::new(secp, &mut rng),
+	                      }],
+	                 1)
+		.blind(secp)
+		.unwrap()
+}
+
+#[test]
+fn spends_utxo() {
+	let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let (store, b, key) = chain_with_reward(&secp);
+	let tx = spend(&secp, b.outputs[0].hash(), key);
+	validate_transaction(&tx, &store).unwrap();
+}
+
+#[test]
+fn spends_nonexistent_output() {
+	let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let (store, _, key) = chain_with_reward(&secp);
+	let tx = spend(&secp, ZERO_HASH, key);
+	match validate_transaction(&tx, &store) {
+		Err(PoolError::UnknownOutput(h)) => assert_eq!(h, ZERO_HASH),
+		res => panic!("expected an unknown output, got {:?}", res),
+	}
+}
+
+#[test]
+fn spends_spent_output() {
+	let secp = Secp256k1::with_caps(secp::ContextFlag::Commit);
+	let (store, b, key) = chain_with_reward(&secp);
+	let output = b.outputs[0].hash();
+
+	// a block spending the reward, only counts once on our chain
+	let mut tx = spend(&secp, output, key);
+	let next_key = SecretKey::new(&secp, &mut OsRng::new().unwrap());
+	let next = Block::new(&b.header, vec![&mut tx], next_key).unwrap();
+	store.save_block(&next).unwrap();
+	assert!(store.is_unspent(&output).unwrap());
+
+	store.setup_height(&next.header).unwrap();
+	store.save_head(&Tip::from_block(&next.header)).unwrap();
+	assert!(!store.is_unspent(&output).unwrap());
+	match validate_transaction(&tx, &store) {
+		Err(PoolError::UnknownOutput(h)) => assert_eq!(h, output),
+		res => panic!("expected an unknown output, got {:?}", res),
+	}
+}
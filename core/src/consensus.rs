@@ -63,21 +63,10 @@ pub fn next_target(ts: i64,
                    prev_cuckoo_sz: u8)
                    -> (Difficulty, u8) {
 	let one = BigInt::new(Sign::Plus, vec![1]);
-	let two = BigInt::new(Sign::Plus, vec![2]);
 	let ten = BigInt::new(Sign::Plus, vec![10]);
 
-	// increase the cuckoo size when the target gets lower than the soft min as
-	// long as we're not at the max size already; target gets 2x to compensate for
-	// increased next_target
-	let soft_min = one.clone() <<
-	               (((prev_cuckoo_sz - cmp::min(DEFAULT_SIZESHIFT, prev_cuckoo_sz)) *
-	                 8 + 16) as usize);
-	let prev_diff = BigInt::from_biguint(Sign::Plus, prev_diff.num);
-	let (pdiff, clen) = if prev_diff > soft_min && prev_cuckoo_sz < MAX_SIZESHIFT {
-		(prev_diff / two, prev_cuckoo_sz + 1)
-	} else {
-		(prev_diff, prev_cuckoo_sz)
-	};
+	let (prev_diff, clen) = next_sizeshift(prev_diff, prev_cuckoo_sz);
+	let pdiff = BigInt::from_biguint(Sign::Plus, prev_diff.num);
 
 	// signed deviation from desired value divided by ten and bounded in [-6, 6]
 	let delta = cmp::max(cmp::min((ts - prev_ts - (BLOCK_TIME_SEC as i64)), 60), -60);
@@ -93,6 +82,19 @@ pub fn next_target(ts: i64,
 	}
 }
 
+/// Increases the Cuckoo size shift by one when the difficulty gets over the
+/// soft max of the current size, as long as we're not at the max size
+/// already. The difficulty gets halved to compensate for the larger size.
+pub fn next_sizeshift(diff: Difficulty, cuckoo_sz: u8) -> (Difficulty, u8) {
+	let soft_max = BigUint::new(vec![1]) <<
+	               (((cuckoo_sz - cmp::min(DEFAULT_SIZESHIFT, cuckoo_sz)) * 8 + 16) as usize);
+	if diff.num > soft_max && cuckoo_sz < MAX_SIZESHIFT {
+		(Difficulty { num: diff.num / BigUint::new(vec![2]) }, cuckoo_sz + 1)
+	} else {
+		(diff, cuckoo_sz)
+	}
+}
+
 /// Default number of blocks in the past when cross-block cut-through will start
 /// happening. Needs to be long enough to not overlap with a long reorg.
 /// Rational
@@ -47,6 +47,11 @@ impl Difficulty {
 		Difficulty { num: BigUint::new(vec![num]) }
 	}
 
+	/// Same as `from_num` for a 64 bits number, like a block height.
+	pub fn from_u64(num: u64) -> Difficulty {
+		Difficulty { num: BigUint::new(vec![num as u32, (num >> 32) as u32]) }
+	}
+
 	/// Computes the difficulty from a hash. Divides the maximum target by the
 	/// provided hash.
 	pub fn from_hash(h: &Hash) -> Difficulty {
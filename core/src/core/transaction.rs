@@ -74,7 +74,7 @@ impl TxProof {
 }
 
 /// A transaction
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Transaction {
 	hash_mem: Option<Hash>,
 	pub fee: u64,
@@ -41,6 +41,8 @@ pub enum Error {
 	CorruptedData,
 	/// When asked to read too much data
 	TooLargeReadErr,
+	/// When asked to write more data than the receiving end would accept
+	TooLargeWriteErr,
 }
 
 impl From<io::Error> for Error {
@@ -58,6 +60,7 @@ impl fmt::Display for Error {
 			}
 			Error::CorruptedData => f.write_str("corrupted data"),
 			Error::TooLargeReadErr => f.write_str("too large read"),
+			Error::TooLargeWriteErr => f.write_str("too large write"),
 		}
 	}
 }
@@ -76,6 +79,7 @@ impl error::Error for Error {
 			Error::UnexpectedData { expected: _, received: _ } => "unexpected data",
 			Error::CorruptedData => "corrupted data",
 			Error::TooLargeReadErr => "too large read",
+			Error::TooLargeWriteErr => "too large write",
 		}
 	}
 }
@@ -12,15 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::{HashMap, VecDeque};
 use std::ops::Deref;
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
 use std::thread;
 
-use chain::{self, ChainAdapter};
+use chain::{self, ChainAdapter, ChainEvent, ChainEvents};
 use core::core;
 use core::core::hash::{Hash, Hashed};
 use core::core::target::Difficulty;
-use p2p::{self, NetAdapter, Server};
+use logging::SERVER_TARGET as LOG_TARGET;
+use mempool::Mempool;
+use p2p::{self, NetAdapter, Server, TxStatus};
 use util::OneTime;
 use sync;
 
@@ -32,8 +36,11 @@ pub struct NetToChainAdapter {
 	chain_head: Arc<Mutex<chain::Tip>>,
 	chain_store: Arc<chain::ChainStore>,
 	chain_adapter: Arc<ChainToNetAdapter>,
+	orphans: chain::OrphanPool,
+	rejects: chain::RejectCache,
 
 	syncer: OneTime<Arc<sync::Syncer>>,
+	p2p: OneTime<Arc<Server>>,
 }
 
 impl NetAdapter for NetToChainAdapter {
@@ -41,13 +48,43 @@ impl NetAdapter for NetToChainAdapter {
 		self.chain_head.lock().unwrap().clone().total_difficulty
 	}
 
-	fn transaction_received(&self, tx: core::Transaction) {
-		unimplemented!();
+	fn height(&self) -> u64 {
+		self.chain_head.lock().unwrap().height
+	}
+
+	fn transaction_received(&self, tx: core::Transaction) -> TxStatus {
+		debug!(target: LOG_TARGET, "Received transaction {} from network.", tx.hash());
+
+		// check the transaction against our chain before adding it to the pool
+		// and relaying it
+		match chain::validate_transaction(&tx, self.chain_store.as_ref()) {
+			Ok(()) => {
+				self.chain_adapter.transaction_accepted(&tx);
+				TxStatus::Accepted
+			}
+			Err(chain::PoolError::StoreErr(e)) => {
+				// not the peer's fault
+				error!(target: LOG_TARGET, "Could not validate transaction {}: {:?}", tx.hash(), e);
+				TxStatus::Dropped
+			}
+			Err(chain::PoolError::UnknownOutput(h)) => {
+				// already spent or not yet in our chain, peers can see it differently
+				debug!(target: LOG_TARGET,
+				       "Transaction {} spends unknown output {}, dropping it.",
+				       tx.hash(),
+				       h);
+				TxStatus::Dropped
+			}
+			Err(e) => {
+				debug!(target: LOG_TARGET, "Transaction {} refused: {:?}", tx.hash(), e);
+				TxStatus::Invalid
+			}
+		}
 	}
 
-	fn block_received(&self, b: core::Block) {
-		debug!("Received block {} from network, going to process.",
-		       b.hash());
+	fn block_received(&self, b: core::Block) -> bool {
+		let bhash = b.hash();
+		debug!(target: LOG_TARGET, "Received block {} from network, going to process.", bhash);
 
 		// pushing the new block through the chain pipeline
 		let store = self.chain_store.clone();
@@ -57,23 +94,42 @@ impl NetAdapter for NetToChainAdapter {
 		} else {
 			chain::NONE
 		};
-		let res = chain::process_block(&b, store, chain_adapter, opts);
+		let res = chain::process_block_orphans(b,
+		                                       store,
+		                                       chain_adapter,
+		                                       &self.orphans,
+		                                       &self.rejects,
+		                                       opts);
 
-		// log errors and update the shared head reference on success
-		if let Err(e) = res {
-			debug!("Block {} refused by chain: {:?}", b.hash(), e);
-		} else if let Ok(Some(tip)) = res {
-			let chain_head = self.chain_head.clone();
-			let mut head = chain_head.lock().unwrap();
-			*head = tip;
-		}
+		// log errors and update the shared head reference on success, the peer
+		// is only at fault for invalid blocks and forks too deep to follow
+		let valid = match res {
+			Ok(Some(tip)) => {
+				let chain_head = self.chain_head.clone();
+				let mut head = chain_head.lock().unwrap();
+				*head = tip;
+				true
+			}
+			Ok(None) => true,
+			Err(chain::Error::ReorgTooDeep) => {
+				warn!(target: LOG_TARGET,
+				      "Block {} is on a fork too deep to switch to.",
+				      bhash);
+				false
+			}
+			Err(e) => {
+				debug!(target: LOG_TARGET, "Block {} refused by chain: {:?}", bhash, e);
+				!e.is_bad_block()
+			}
+		};
 
 		if self.syncer.borrow().syncing() {
-			self.syncer.borrow().block_received(b.hash());
+			self.syncer.borrow().block_received(bhash);
 		}
+		valid
 	}
 
-	fn headers_received(&self, bhs: Vec<core::BlockHeader>) {
+	fn headers_received(&self, bhs: Vec<core::BlockHeader>) -> bool {
 		let opts = if self.syncer.borrow().syncing() {
 			chain::SYNC
 		} else {
@@ -82,6 +138,7 @@ impl NetAdapter for NetToChainAdapter {
 
 		// try to add each header to our header chain
 		let mut added_hs = vec![];
+		let mut valid = true;
 		for bh in bhs {
 			let store = self.chain_store.clone();
 			let chain_adapter = self.chain_adapter.clone();
@@ -91,26 +148,55 @@ impl NetAdapter for NetToChainAdapter {
 				Ok(_) => {
 					added_hs.push(bh.hash());
 				}
-				Err(chain::Error::Unfit(_)) => {
-					info!("Received unfit block header {} at {}.",
+				Err(chain::Error::Unfit(_)) |
+				Err(chain::Error::Orphan) => {
+					info!(target: LOG_TARGET, "Received unfit block header {} at {}.",
+					      bh.hash(),
+					      bh.height);
+				}
+				Err(chain::Error::InvalidCheckpoint) |
+				Err(chain::Error::ForkBelowCheckpoint) => {
+					// the rest of the headers are on the same fork, no point following it
+					warn!(target: LOG_TARGET,
+					      "Block header {} at {} contradicts our checkpoints, dropping the rest.",
+					      bh.hash(),
+					      bh.height);
+					valid = false;
+					break;
+				}
+				Err(chain::Error::ReorgTooDeep) => {
+					warn!(target: LOG_TARGET,
+					      "Block header {} at {} is on a fork too deep to switch to, dropping the \
+					       rest.",
 					      bh.hash(),
 					      bh.height);
+					valid = false;
+					break;
 				}
 				Err(chain::Error::StoreErr(e)) => {
-					error!("Store error processing block header {}: {:?}", bh.hash(), e);
-					return;
+					error!(target: LOG_TARGET,
+					       "Store error processing block header {}: {:?}",
+					       bh.hash(),
+					       e);
+					return true;
 				}
 				Err(e) => {
-					info!("Invalid block header {}: {:?}.", bh.hash(), e);
-					// TODO penalize peer somehow
+					// bad timestamp or difficulty, the rest builds on it
+					info!(target: LOG_TARGET,
+					      "Invalid block header {}: {:?}, dropping the rest.",
+					      bh.hash(),
+					      e);
+					valid = false;
+					break;
 				}
 			}
 		}
-		info!("Added {} headers to the header chain.", added_hs.len());
+		info!(target: LOG_TARGET, "Added {} headers to the header chain.", added_hs.len());
 
 		if self.syncer.borrow().syncing() {
 			self.syncer.borrow().headers_received(added_hs);
 		}
+		valid
 	}
 
 	fn locate_headers(&self, locator: Vec<Hash>) -> Vec<core::BlockHeader> {
@@ -122,11 +208,11 @@ impl NetAdapter for NetToChainAdapter {
 		let known = self.chain_store.get_block_header(&locator[0]);
 		let header = match known {
 			Ok(header) => header,
-			Err(chain::types::Error::NotFoundErr) => {
+			Err(ref e) if e.is_not_found() => {
 				return self.locate_headers(locator[1..].to_vec());
 			}
 			Err(e) => {
-				error!("Could not build header locator: {:?}", e);
+				error!(target: LOG_TARGET, "Could not build header locator: {:?}", e);
 				return vec![];
 			}
 		};
@@ -134,13 +220,13 @@ impl NetAdapter for NetToChainAdapter {
 		// looks like we know one, getting as many following headers as allowed
 		let hh = header.height;
 		let mut headers = vec![];
-		for h in (hh + 1)..(hh + (p2p::MAX_BLOCK_HEADERS as u64)) {
+		for h in (hh + 1)..(hh + 1 + (p2p::MAX_BLOCK_HEADERS as u64)) {
 			let header = self.chain_store.get_header_by_height(h);
 			match header {
 				Ok(head) => headers.push(head),
-				Err(chain::types::Error::NotFoundErr) => break,
+				Err(ref e) if e.is_not_found() => break,
 				Err(e) => {
-					error!("Could not build header locator: {:?}", e);
+					error!(target: LOG_TARGET, "Could not build header locator: {:?}", e);
 					return vec![];
 				}
 			}
@@ -156,21 +242,78 @@ impl NetAdapter for NetToChainAdapter {
 			_ => None,
 		}
 	}
+
+	fn get_compact_block(&self, h: Hash) -> Option<p2p::CompactBlock> {
+		self.get_block(h).map(|b| self.chain_adapter.compact_block(&b))
+	}
+
+	fn get_blocks(&self, start: Hash, count: u32) -> Vec<core::Block> {
+		// follows our chain from the start block, which has to be on it
+		let height = match self.chain_store.get_block_height(&start) {
+			Ok(height) => height,
+			Err(_) => return vec![],
+		};
+		let mut blocks = vec![];
+		for n in height..(height + count as u64) {
+			let b = self.chain_store
+				.get_header_by_height(n)
+				.and_then(|bh| self.chain_store.get_block(&bh.hash()));
+			match b {
+				Ok(b) => blocks.push(b),
+				Err(_) => break,
+			}
+		}
+		blocks
+	}
+
+	fn get_pruned_header(&self, h: Hash) -> Option<core::BlockHeader> {
+		// only headers on our chain get pruned
+		match self.chain_store.get_block_height(&h) {
+			Ok(_) => self.chain_store.get_block_header(&h).ok(),
+			Err(_) => None,
+		}
+	}
+
+	fn get_transaction(&self, id: u64) -> Option<core::Transaction> {
+		self.chain_adapter.get_transaction(id)
+	}
+
+	fn find_peer_addrs(&self, capab: p2p::Capabilities) -> Vec<p2p::PeerAddr> {
+		self.p2p.borrow().find_peer_addrs(capab)
+	}
+
+	fn is_bad_block(&self, h: &Hash) -> bool {
+		self.rejects.contains(h)
+	}
+
+	fn peer_addrs_received(&self, addrs: Vec<p2p::PeerAddr>) {
+		debug!(target: LOG_TARGET, "Received {} peer addresses from network.", addrs.len());
+		self.p2p.borrow().peer_addrs_received(addrs);
+	}
 }
 
 impl NetToChainAdapter {
 	pub fn new(chain_head: Arc<Mutex<chain::Tip>>,
 	           chain_store: Arc<chain::ChainStore>,
-	           chain_adapter: Arc<ChainToNetAdapter>)
+	           chain_adapter: Arc<ChainToNetAdapter>,
+	           max_orphans: usize,
+	           max_rejects: usize)
 	           -> NetToChainAdapter {
 		NetToChainAdapter {
 			chain_head: chain_head,
 			chain_store: chain_store,
 			chain_adapter: chain_adapter,
+			orphans: chain::OrphanPool::new(max_orphans),
+			rejects: chain::RejectCache::new(max_rejects),
 			syncer: OneTime::new(),
+			p2p: OneTime::new(),
 		}
 	}
 
+	pub fn init(&self, p2p: Arc<Server>) {
+		self.p2p.init(p2p);
+	}
+
 	pub fn start_sync(&self, sync: sync::Syncer) {
 		let arc_sync = Arc::new(sync);
 		self.syncer.init(arc_sync.clone());
@@ -180,24 +323,136 @@ impl NetToChainAdapter {
 	}
 }
 
+/// Number of recently accepted transactions we keep around to build and
+/// rebuild compact blocks.
+const MAX_RECENT_TXS: usize = 1000;
+
+/// Recently accepted transactions by short id, until we have a proper
+/// transaction pool. Only the most recent ones are kept.
+struct RecentTxs {
+	txs: HashMap<u64, core::Transaction>,
+	order: VecDeque<u64>,
+}
+
+impl RecentTxs {
+	fn new() -> RecentTxs {
+		RecentTxs {
+			txs: HashMap::new(),
+			order: VecDeque::new(),
+		}
+	}
+
+	fn add(&mut self, tx: &core::Transaction) {
+		let id = p2p::short_id(tx);
+		if self.txs.insert(id, tx.clone()).is_some() {
+			return;
+		}
+		self.order.push_back(id);
+		if self.order.len() > MAX_RECENT_TXS {
+			if let Some(old) = self.order.pop_front() {
+				self.txs.remove(&old);
+			}
+		}
+	}
+}
+
 /// Implementation of the ChainAdapter for the network. Gets notified when the
 /// blockchain accepted a new block and forwards it to the network for
 /// broadcast.
 pub struct ChainToNetAdapter {
 	p2p: OneTime<Arc<Server>>,
+	chain_store: Arc<chain::ChainStore>,
+	recent_txs: Mutex<RecentTxs>,
+	mempool: Mutex<Mempool>,
+	events: ChainEvents,
 }
 
 impl ChainAdapter for ChainToNetAdapter {
 	fn block_accepted(&self, b: &core::Block) {
-		self.p2p.borrow().broadcast_block(b);
+		// only the hash goes out, peers that don't have the block yet ask for
+		// it and get its compact version if they can rebuild it
+		self.p2p.borrow().announce_block(b.hash());
+	}
+
+	fn transaction_accepted(&self, tx: &core::Transaction) {
+		// transactions we already have or that conflict with ours aren't worth
+		// relaying
+		if let Err(e) = self.mempool.lock().unwrap().add(tx.clone()) {
+			debug!(target: LOG_TARGET, "Transaction {} not added to the pool: {:?}", tx.hash(), e);
+			return;
+		}
+		self.recent_txs.lock().unwrap().add(tx);
+		self.p2p.borrow().broadcast_transaction(tx);
+	}
+
+	fn chain_reorg(&self, disconnected: &[core::Block], connected: &[core::Block]) {
+		// transactions of the undone blocks are valid again, unless the new
+		// blocks spend the same outputs
+		let mut mempool = self.mempool.lock().unwrap();
+		let readded = disconnected.iter().map(|b| mempool.block_disconnected(b)).sum::<usize>();
+		let dropped = connected.iter().map(|b| mempool.block_accepted(b)).sum::<usize>();
+		debug!(target: LOG_TARGET, "Reorg put back {} transactions in the pool and dropped {}.",
+		       readded,
+		       dropped);
+	}
+
+	fn head_changed(&self, tip: &chain::Tip) {
+		// only blocks making it to our chain spend anything, the ones left on a
+		// fork don't, after a reorg the new blocks already got accounted for
+		match self.chain_store.get_block(&tip.last_block_h) {
+			Ok(b) => {
+				let dropped = self.mempool.lock().unwrap().block_accepted(&b);
+				if dropped > 0 {
+					debug!(target: LOG_TARGET,
+					       "Dropped {} transactions spent by block {}.",
+					       dropped,
+					       b.hash());
+				}
+			}
+			Err(e) => {
+				error!(target: LOG_TARGET, "Could not load our new head block: {:?}", e);
+			}
+		}
+		self.events.head_changed(tip);
 	}
 }
 
 impl ChainToNetAdapter {
-	pub fn new() -> ChainToNetAdapter {
-		ChainToNetAdapter { p2p: OneTime::new() }
+	pub fn new(chain_store: Arc<chain::ChainStore>) -> ChainToNetAdapter {
+		ChainToNetAdapter {
+			p2p: OneTime::new(),
+			chain_store: chain_store,
+			recent_txs: Mutex::new(RecentTxs::new()),
+			mempool: Mutex::new(Mempool::new()),
+			events: ChainEvents::new(),
+		}
 	}
 	pub fn init(&self, p2p: Arc<Server>) {
 		self.p2p.init(p2p);
 	}
+
+	/// Subscribes to the changes of our chain head, and whatever else
+	/// happens to our chain from now on.
+	pub fn subscribe(&self) -> Receiver<ChainEvent> {
+		self.events.subscribe()
+	}
+
+	/// A recently accepted transaction by its short id.
+	pub fn get_transaction(&self, id: u64) -> Option<core::Transaction> {
+		self.recent_txs.lock().unwrap().txs.get(&id).cloned()
+	}
+
+	/// The compact version of a block, our peers most likely got the same
+	/// transactions as we did so only their ids need to go along with it.
+	pub fn compact_block(&self, b: &core::Block) -> p2p::CompactBlock {
+		let recent = self.recent_txs.lock().unwrap();
+		let txs = recent.txs.values().cloned().collect::<Vec<_>>();
+		p2p::CompactBlock::new(b, &txs)
+	}
+
+	/// Transactions from the pool to mine in our next block, the best paying
+	/// ones within the provided weight.
+	pub fn select_transactions(&self, max_weight: u64) -> Vec<core::Transaction> {
+		self.mempool.lock().unwrap().select_for_block(max_weight)
+	}
 }
@@ -0,0 +1,167 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal HTTP API to query the state of a running node. Only knows about
+//! GET requests and answers them with JSON:
+//!
+//! * `/status` our chain head, peer count, sync progress, header cache hit
+//!   rate and whether we're mining
+//! * `/peers` the peers we're connected to and the traffic we exchanged
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+use serde_json::{self, Value};
+use serde_json::builder::{ArrayBuilder, ObjectBuilder};
+
+use chain;
+use logging::SERVER_TARGET as LOG_TARGET;
+use miner::Miner;
+use p2p;
+use sync::SyncStatus;
+
+/// HTTP server answering queries about our node state.
+pub struct ApiServer {
+	chain_store: Arc<chain::ChainStore>,
+	chain_head: Arc<Mutex<chain::Tip>>,
+	p2p: Arc<p2p::Server>,
+	sync_status: Arc<RwLock<SyncStatus>>,
+	miner: Arc<Miner>,
+}
+
+impl ApiServer {
+	/// Creates a new API server reporting on the provided chain store and
+	/// head, peers, sync progress and miner.
+	pub fn new(chain_store: Arc<chain::ChainStore>,
+	           chain_head: Arc<Mutex<chain::Tip>>,
+	           p2p: Arc<p2p::Server>,
+	           sync_status: Arc<RwLock<SyncStatus>>,
+	           miner: Arc<Miner>)
+	           -> ApiServer {
+		ApiServer {
+			chain_store: chain_store,
+			chain_head: chain_head,
+			p2p: p2p,
+			sync_status: sync_status,
+			miner: miner,
+		}
+	}
+
+	/// Starts listening for requests on the provided address, handling them
+	/// on separate threads.
+	pub fn start(server: Arc<ApiServer>, addr: SocketAddr) -> io::Result<()> {
+		let listener = try!(TcpListener::bind(addr));
+		info!(target: LOG_TARGET, "API server listening on {}.", addr);
+
+		try!(thread::Builder::new().name("api".to_string()).spawn(move || {
+			for stream in listener.incoming() {
+				match stream {
+					Ok(stream) => {
+						let s = server.clone();
+						thread::spawn(move || if let Err(e) = s.handle(stream) {
+							debug!(target: LOG_TARGET, "Error answering API request: {}", e);
+						});
+					}
+					Err(e) => debug!(target: LOG_TARGET, "Could not accept API connection: {}", e),
+				}
+			}
+		}));
+		Ok(())
+	}
+
+	// Reads the request line, skips the headers and writes the response, one
+	// request per connection.
+	fn handle(&self, mut stream: TcpStream) -> io::Result<()> {
+		let mut reader = BufReader::new(try!(stream.try_clone()));
+		let mut line = String::new();
+		try!(reader.read_line(&mut line));
+		let req = line.split_whitespace().collect::<Vec<_>>();
+		loop {
+			let mut header = String::new();
+			if try!(reader.read_line(&mut header)) == 0 || header.trim().is_empty() {
+				break;
+			}
+		}
+
+		let (status, body) = match (req.get(0).cloned(), req.get(1).cloned()) {
+			(Some("GET"), Some("/status")) => ("200 OK", self.status()),
+			(Some("GET"), Some("/peers")) => ("200 OK", self.peers()),
+			(Some("GET"), _) => ("404 Not Found", error("not found")),
+			_ => ("405 Method Not Allowed", error("method not allowed")),
+		};
+		let body = serde_json::to_string(&body).unwrap();
+		write!(stream,
+		       "HTTP/1.0 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+		       status,
+		       body.len(),
+		       body)
+	}
+
+	fn status(&self) -> Value {
+		let head = self.chain_head.lock().unwrap().clone();
+		let sync = self.sync_status.read().unwrap().clone();
+		let cache = self.chain_store.header_cache_stats();
+		ObjectBuilder::new()
+			.insert("height", head.height)
+			.insert("hash", head.last_block_h.to_string())
+			.insert("total_difficulty", head.total_difficulty.num.to_string())
+			.insert("peer_count", self.p2p.peer_count() as u64)
+			.insert("mining", self.miner.is_mining())
+			.insert_object("sync", |s| {
+				s.insert("state", format!("{:?}", sync.state))
+					.insert("current_height", sync.current_height)
+					.insert("target_height", sync.target_height)
+					.insert("headers_downloaded", sync.headers_downloaded)
+					.insert("blocks_downloaded", sync.blocks_downloaded)
+					.insert("headers_behind", sync.headers_behind)
+					.insert("stalled", sync.stalled)
+			})
+			.insert_object("header_cache", |c| {
+				c.insert("hits", cache.hits)
+					.insert("misses", cache.misses)
+					.insert("hit_rate", cache.hit_rate())
+			})
+			.unwrap()
+	}
+
+	// Heights are the ones our peers claim, suspicious ones flag a height
+	// their total difficulty can't account for.
+	fn peers(&self) -> Value {
+		let mut peers = ArrayBuilder::new();
+		for p in self.p2p.connected_peers() {
+			let stats = p.stats();
+			let direction = match p.info.direction {
+				p2p::Direction::Inbound => "inbound",
+				p2p::Direction::Outbound => "outbound",
+			};
+			peers = peers.push_object(|o| {
+				o.insert("addr", p.info.addr.to_string())
+					.insert("direction", direction)
+					.insert("user_agent", p.info.user_agent.clone())
+					.insert("total_difficulty", p.total_difficulty().num.to_string())
+					.insert("height", p.height())
+					.insert("suspicious", p.is_suspicious())
+					.insert("sent_bytes", stats.sent_bytes)
+					.insert("received_bytes", stats.received_bytes)
+			});
+		}
+		peers.unwrap()
+	}
+}
+
+fn error(msg: &str) -> Value {
+	ObjectBuilder::new().insert("error", msg).unwrap()
+}
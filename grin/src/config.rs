@@ -0,0 +1,324 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Loading of the server configuration from a TOML file, so operators can
+//! set up their node without recompiling. Looks like:
+//!
+//! ```toml
+//! data_dir = "/var/lib/grin"
+//!
+//! [chain]
+//! max_reorg_depth = 1000
+//! max_future_time = 720
+//! verify_chain_depth = 1000
+//! compact_idle_time = 600
+//!
+//! [p2p]
+//! host = "0.0.0.0"
+//! port = 13414
+//! external_addr = "1.2.3.4:13414"
+//! seeds = ["seed.grin-tech.org"]
+//!
+//! [api]
+//! host = "127.0.0.1"
+//! port = 13415
+//!
+//! [mining]
+//! enabled = true
+//! threads = 2
+//!
+//! [log]
+//! level = "info"
+//!
+//! [log.targets]
+//! "grin::p2p" = "debug"
+//! "grin::chain" = "warn"
+//! ```
+//!
+//! Every key is optional, missing ones keep their default value.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+use log::LogLevelFilter;
+use toml;
+
+use server::ServerConfig;
+
+/// Errors reading a configuration file.
+#[derive(Debug)]
+pub enum ConfigError {
+	/// The file couldn't be read
+	IOErr(io::Error),
+	/// The file isn't valid TOML
+	ParseErr(String),
+	/// A key has a value of the wrong type or out of range
+	InvalidValue(String, String),
+	/// The data directory can't be created or written to
+	DataDirErr(String, io::Error),
+}
+
+impl ServerConfig {
+	/// Reads the server configuration from the TOML file at the provided
+	/// path. Missing keys keep their default value, unknown keys are kept in
+	/// `unknown_keys` and ignored. Also makes sure the configured data
+	/// directory can be written to, creating it if needed.
+	pub fn from_file<P: AsRef<Path>>(path: P) -> Result<ServerConfig, ConfigError> {
+		let mut content = String::new();
+		try!(File::open(path)
+			.and_then(|mut f| f.read_to_string(&mut content))
+			.map_err(ConfigError::IOErr));
+		let config = try!(ServerConfig::from_toml(&content));
+		try!(check_writable(&config.data_dir));
+		Ok(config)
+	}
+
+	/// Same as `from_file` with the TOML content directly, without touching
+	/// the data directory.
+	pub fn from_toml(content: &str) -> Result<ServerConfig, ConfigError> {
+		let mut parser = toml::Parser::new(content);
+		let root = match parser.parse() {
+			Some(root) => root,
+			None => {
+				let msg = parser.errors
+					.iter()
+					.map(|e| {
+						let (line, col) = parser.to_linecol(e.lo);
+						format!("{} at line {}, column {}", e.desc, line + 1, col + 1)
+					})
+					.collect::<Vec<_>>()
+					.join(", ");
+				return Err(ConfigError::ParseErr(msg));
+			}
+		};
+
+		let mut config = ServerConfig::default();
+		let mut unknown = vec![];
+		let top = Section::new("", &root);
+		top.unknown(&["data_dir", "chain", "p2p", "api", "mining", "log"], &mut unknown);
+		if let Some(dir) = try!(top.string("data_dir")) {
+			config.data_dir = dir;
+		}
+
+		if let Some(chain) = try!(top.section("chain")) {
+			chain.unknown(&["max_reorg_depth",
+			                "max_future_time",
+			                "verify_chain_depth",
+			                "compact_idle_time"],
+			              &mut unknown);
+			if let Some(depth) = try!(chain.integer("max_reorg_depth", 1, i64::max_value())) {
+				config.max_reorg_depth = depth as u64;
+			}
+			if let Some(secs) = try!(chain.integer("max_future_time", 0, 24 * 3600)) {
+				config.max_future_time = secs as u64;
+			}
+			if let Some(depth) = try!(chain.integer("verify_chain_depth", 1, i64::max_value())) {
+				config.verify_chain_depth = Some(depth as u64);
+			}
+			if let Some(secs) = try!(chain.integer("compact_idle_time", 1, 7 * 24 * 3600)) {
+				config.compact_idle_time = Some(secs as u64);
+			}
+		}
+
+		if let Some(p2p) = try!(top.section("p2p")) {
+			p2p.unknown(&["host", "port", "listen_addr", "external_addr", "seeds"], &mut unknown);
+			if let Some(host) = try!(p2p.ip("host")) {
+				config.p2p_config.host = host;
+			}
+			if let Some(port) = try!(p2p.port("port")) {
+				config.p2p_config.port = port;
+			}
+			if let Some(addr) = try!(p2p.socket_addr("listen_addr")) {
+				config.p2p_config.p2p_listen_addr = Some(addr);
+			}
+			if let Some(addr) = try!(p2p.socket_addr("external_addr")) {
+				config.p2p_config.p2p_external_addr = Some(addr);
+			}
+			if let Some(seeds) = try!(p2p.strings("seeds")) {
+				config.p2p_config.dns_seeds = seeds;
+			}
+		}
+
+		if let Some(api) = try!(top.section("api")) {
+			// the API only runs when asked to, giving its port is enough
+			api.unknown(&["host", "port"], &mut unknown);
+			let host = try!(api.ip("host")).unwrap_or("127.0.0.1".parse().unwrap());
+			if let Some(port) = try!(api.port("port")) {
+				config.api_addr = Some(SocketAddr::new(host, port));
+			}
+		}
+
+		if let Some(mining) = try!(top.section("mining")) {
+			mining.unknown(&["enabled", "threads"], &mut unknown);
+			if let Some(enabled) = try!(mining.boolean("enabled")) {
+				config.enable_mining = enabled;
+			}
+			if let Some(threads) = try!(mining.integer("threads", 1, 1024)) {
+				config.miner_threads = threads as usize;
+			}
+		}
+
+		if let Some(log) = try!(top.section("log")) {
+			log.unknown(&["level", "targets"], &mut unknown);
+			if let Some(level) = try!(log.level("level")) {
+				config.log_level = level;
+			}
+			if let Some(targets) = try!(log.section("targets")) {
+				config.log_targets = try!(targets.levels());
+			}
+		}
+
+		config.unknown_keys = unknown;
+		Ok(config)
+	}
+}
+
+// Makes sure we'll be able to create our stores under the data directory,
+// creating it if needed.
+fn check_writable(dir: &str) -> Result<(), ConfigError> {
+	let probe = Path::new(dir).join(".write_check");
+	fs::create_dir_all(dir)
+		.and_then(|_| File::create(&probe))
+		.and_then(|_| fs::remove_file(&probe))
+		.map_err(|e| ConfigError::DataDirErr(dir.to_string(), e))
+}
+
+// A table of the configuration file, giving typed access to its values.
+struct Section<'a> {
+	name: &'a str,
+	table: &'a toml::Table,
+}
+
+impl<'a> Section<'a> {
+	fn new(name: &'a str, table: &'a toml::Table) -> Section<'a> {
+		Section {
+			name: name,
+			table: table,
+		}
+	}
+
+	// adds the keys of the table that aren't known to the provided ones
+	fn unknown(&self, known: &[&str], unknown: &mut Vec<String>) {
+		for key in self.table.keys() {
+			if !known.contains(&&key[..]) {
+				unknown.push(self.path(key));
+			}
+		}
+	}
+
+	fn section(&self, key: &'a str) -> Result<Option<Section<'a>>, ConfigError> {
+		match self.table.get(key) {
+			None => Ok(None),
+			Some(v) => {
+				match v.as_table() {
+					Some(t) => Ok(Some(Section::new(key, t))),
+					None => Err(self.invalid(key, "a table")),
+				}
+			}
+		}
+	}
+
+	fn string(&self, key: &str) -> Result<Option<String>, ConfigError> {
+		match self.table.get(key) {
+			None => Ok(None),
+			Some(v) => {
+				v.as_str()
+					.map(|s| Some(s.to_string()))
+					.ok_or_else(|| self.invalid(key, "a string"))
+			}
+		}
+	}
+
+	fn strings(&self, key: &str) -> Result<Option<Vec<String>>, ConfigError> {
+		let values = match self.table.get(key) {
+			None => return Ok(None),
+			Some(v) => try!(v.as_slice().ok_or_else(|| self.invalid(key, "a list of strings"))),
+		};
+		let mut res = vec![];
+		for v in values {
+			let s = try!(v.as_str().ok_or_else(|| self.invalid(key, "a list of strings")));
+			res.push(s.to_string());
+		}
+		Ok(Some(res))
+	}
+
+	fn boolean(&self, key: &str) -> Result<Option<bool>, ConfigError> {
+		match self.table.get(key) {
+			None => Ok(None),
+			Some(v) => v.as_bool().map(Some).ok_or_else(|| self.invalid(key, "true or false")),
+		}
+	}
+
+	fn integer(&self, key: &str, min: i64, max: i64) -> Result<Option<i64>, ConfigError> {
+		match self.table.get(key).map(|v| v.as_integer()) {
+			None => Ok(None),
+			Some(Some(n)) if n >= min && n <= max => Ok(Some(n)),
+			Some(_) => Err(self.invalid(key, &format!("a number between {} and {}", min, max))),
+		}
+	}
+
+	fn port(&self, key: &str) -> Result<Option<u16>, ConfigError> {
+		Ok(try!(self.integer(key, 1, 65535)).map(|n| n as u16))
+	}
+
+	fn ip(&self, key: &str) -> Result<Option<IpAddr>, ConfigError> {
+		match try!(self.string(key)) {
+			None => Ok(None),
+			Some(s) => s.parse().map(Some).map_err(|_| self.invalid(key, "an IP address")),
+		}
+	}
+
+	fn socket_addr(&self, key: &str) -> Result<Option<SocketAddr>, ConfigError> {
+		match try!(self.string(key)) {
+			None => Ok(None),
+			Some(s) => s.parse().map(Some).map_err(|_| self.invalid(key, "an IP address and port")),
+		}
+	}
+
+	fn level(&self, key: &str) -> Result<Option<LogLevelFilter>, ConfigError> {
+		match try!(self.string(key)) {
+			None => Ok(None),
+			Some(s) => {
+				s.parse()
+					.map(Some)
+					.map_err(|_| self.invalid(key, "off, error, warn, info, debug or trace"))
+			}
+		}
+	}
+
+	// every key of the table with the level it's set to
+	fn levels(&self) -> Result<Vec<(String, LogLevelFilter)>, ConfigError> {
+		let mut res = vec![];
+		for key in self.table.keys() {
+			if let Some(level) = try!(self.level(key)) {
+				res.push((key.clone(), level));
+			}
+		}
+		Ok(res)
+	}
+
+	fn invalid(&self, key: &str, expected: &str) -> ConfigError {
+		ConfigError::InvalidValue(self.path(key), format!("expected {}", expected))
+	}
+
+	fn path(&self, key: &str) -> String {
+		if self.name.is_empty() {
+			key.to_string()
+		} else {
+			format!("{}.{}", self.name, key)
+		}
+	}
+}
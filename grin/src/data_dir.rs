@@ -0,0 +1,75 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Layout of the directory a server keeps all its data under:
+//!
+//! ```text
+//! <data_dir>/
+//!   chain/        the chain store
+//!   peers/        the peer store
+//!   logs/grin.log
+//! ```
+//!
+//! Every store gets its own subdirectory so two of them can't end up opened
+//! at the same path.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const CHAIN_SUBDIR: &'static str = "chain";
+const PEERS_SUBDIR: &'static str = "peers";
+const LOGS_SUBDIR: &'static str = "logs";
+const LOG_FILE: &'static str = "grin.log";
+
+/// Paths of everything under a data directory. Subdirectories are created
+/// when their path is first asked for.
+#[derive(Debug, Clone)]
+pub struct DataDir {
+	root: PathBuf,
+}
+
+impl DataDir {
+	/// Layout under the provided directory, nothing gets created until a
+	/// path is asked for.
+	pub fn new<P: AsRef<Path>>(root: P) -> DataDir {
+		DataDir { root: root.as_ref().to_path_buf() }
+	}
+
+	/// The data directory itself.
+	pub fn root(&self) -> &Path {
+		&self.root
+	}
+
+	/// Directory of the chain store.
+	pub fn chain_db_path(&self) -> io::Result<PathBuf> {
+		self.subdir(CHAIN_SUBDIR)
+	}
+
+	/// Directory of the peer store.
+	pub fn peer_db_path(&self) -> io::Result<PathBuf> {
+		self.subdir(PEERS_SUBDIR)
+	}
+
+	/// File the server appends its logs to. Only its directory gets created.
+	pub fn log_path(&self) -> io::Result<PathBuf> {
+		self.subdir(LOGS_SUBDIR).map(|dir| dir.join(LOG_FILE))
+	}
+
+	fn subdir(&self, name: &str) -> io::Result<PathBuf> {
+		let dir = self.root.join(name);
+		try!(fs::create_dir_all(&dir));
+		Ok(dir)
+	}
+}
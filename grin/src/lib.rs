@@ -26,7 +26,9 @@ extern crate log;
 extern crate env_logger;
 extern crate futures;
 extern crate rand;
+extern crate serde_json;
 extern crate time;
+extern crate toml;
 extern crate tokio_core;
 
 extern crate grin_chain as chain;
@@ -37,8 +39,24 @@ extern crate grin_util as util;
 extern crate secp256k1zkp as secp;
 
 mod adapters;
+mod api;
+mod config;
+mod data_dir;
+mod logging;
+mod mempool;
 mod miner;
 mod server;
+mod stratum;
 mod sync;
 
-pub use server::{Server, ServerConfig};
+pub use api::ApiServer;
+pub use config::ConfigError;
+pub use data_dir::DataDir;
+pub use logging::{CHAIN_TARGET, P2P_TARGET, STORE_TARGET, MINER_TARGET, SERVER_TARGET, log_builder,
+                  init_logging};
+pub use mempool::{Mempool, MempoolError, MAX_BLOCK_WEIGHT, tx_weight};
+pub use miner::Miner;
+pub use server::{Error, Server, ServerConfig};
+pub use stratum::{StratumServer, SubmitError, WorkerStats};
+pub use sync::{BlockPeer, BlockScheduler, ProgressCallback, StallDetector, SyncProgress, SyncState,
+               SyncStatus};
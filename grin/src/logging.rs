@@ -0,0 +1,109 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Log targets of the subsystems a node is made of, and setup of the logger
+//! with a level for each, so operators can for example raise the verbosity
+//! of the p2p layer while keeping the chain quiet.
+
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+
+use env_logger::{LogBuilder, Logger};
+use log::{self, Log, LogLevelFilter, LogMetadata, LogRecord, SetLoggerError};
+use time;
+
+use chain;
+use p2p;
+use store;
+
+/// Target of the logs of the chain
+pub const CHAIN_TARGET: &'static str = chain::LOG_TARGET;
+
+/// Target of the logs of the peer-to-peer layer
+pub const P2P_TARGET: &'static str = p2p::LOG_TARGET;
+
+/// Target of the logs about our stores
+pub const STORE_TARGET: &'static str = store::LOG_TARGET;
+
+/// Target of the logs of our miner and of the Stratum server
+pub const MINER_TARGET: &'static str = "grin::miner";
+
+/// Target of the logs of the server tying everything together, including
+/// sync and the API
+pub const SERVER_TARGET: &'static str = "grin::server";
+
+/// Logger configuration letting everything through at the provided level,
+/// except for the provided targets that get their own. Directives from the
+/// RUST_LOG environment variable, if set, take precedence.
+pub fn log_builder(level: LogLevelFilter, targets: &[(String, LogLevelFilter)]) -> LogBuilder {
+	let mut builder = LogBuilder::new();
+	builder.filter(None, level);
+	for &(ref target, target_level) in targets {
+		builder.filter(Some(&target[..]), target_level);
+	}
+	if let Ok(directives) = env::var("RUST_LOG") {
+		builder.parse(&directives);
+	}
+	builder
+}
+
+/// Sets up the global logger as `log_builder` configures it, logging to
+/// stderr and, if provided, appending to a log file as well. Fails if a
+/// logger has already been set up, only one can be for the whole process.
+pub fn init_logging(level: LogLevelFilter,
+                    targets: &[(String, LogLevelFilter)],
+                    log_file: Option<File>)
+                    -> Result<(), SetLoggerError> {
+	let file = match log_file {
+		Some(file) => file,
+		None => return log_builder(level, targets).init(),
+	};
+	log::set_logger(|max_level| {
+		let logger = FileLogger {
+			stderr: log_builder(level, targets).build(),
+			file: Mutex::new(file),
+		};
+		max_level.set(logger.stderr.filter());
+		Box::new(logger)
+	})
+}
+
+// Logs to stderr like env_logger alone would, and also writes the same logs,
+// timestamped, to a file.
+struct FileLogger {
+	stderr: Logger,
+	file: Mutex<File>,
+}
+
+impl Log for FileLogger {
+	fn enabled(&self, metadata: &LogMetadata) -> bool {
+		Log::enabled(&self.stderr, metadata)
+	}
+
+	fn log(&self, record: &LogRecord) {
+		if !self.enabled(record.metadata()) {
+			return;
+		}
+		Log::log(&self.stderr, record);
+		// nowhere left to report a failed write to
+		let _ = writeln!(self.file.lock().unwrap(),
+		                 "{} {}:{}: {}",
+		                 time::now_utc().rfc3339(),
+		                 record.level(),
+		                 record.target(),
+		                 record.args());
+	}
+}
@@ -0,0 +1,180 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pool of valid transactions waiting to be mined, the miner picking the
+//! ones paying the most for the room they take in a block.
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+
+use core::core::{Block, Transaction};
+use core::core::hash::{Hash, Hashed};
+
+/// Weight of each input of a transaction
+pub const INPUT_WEIGHT: u64 = 1;
+
+/// Weight of each output of a transaction, outputs carry a range proof
+pub const OUTPUT_WEIGHT: u64 = 4;
+
+/// Weight of a transaction regardless of its inputs and outputs
+pub const TX_BASE_WEIGHT: u64 = 1;
+
+/// Maximum weight of the transactions the miner puts in a block
+pub const MAX_BLOCK_WEIGHT: u64 = 40_000;
+
+/// Number of recent blocks we remember the transactions they took out of the
+/// pool of, a reorg undoing older blocks doesn't bring those back
+const MAX_TAKEN_BLOCKS: usize = 100;
+
+/// Weight of a transaction, roughly how much room it takes in a block.
+pub fn tx_weight(tx: &Transaction) -> u64 {
+	TX_BASE_WEIGHT + tx.inputs.len() as u64 * INPUT_WEIGHT +
+	tx.outputs.len() as u64 * OUTPUT_WEIGHT
+}
+
+/// Why a transaction couldn't get in the pool.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MempoolError {
+	/// The transaction is already in the pool
+	AlreadyInPool,
+	/// The transaction spends an output another transaction of the pool
+	/// already spends
+	DoubleSpend(Hash),
+}
+
+/// Validated transactions by hash, along with the outputs they spend.
+pub struct Mempool {
+	txs: HashMap<Hash, Transaction>,
+	spent: HashMap<Hash, Hash>,
+	taken: HashMap<Hash, Vec<Transaction>>,
+	taken_order: VecDeque<Hash>,
+}
+
+impl Mempool {
+	/// An empty pool.
+	pub fn new() -> Mempool {
+		Mempool {
+			txs: HashMap::new(),
+			spent: HashMap::new(),
+			taken: HashMap::new(),
+			taken_order: VecDeque::new(),
+		}
+	}
+
+	/// Number of transactions in the pool.
+	pub fn len(&self) -> usize {
+		self.txs.len()
+	}
+
+	/// Whether the pool has the transaction with the provided hash.
+	pub fn contains(&self, h: &Hash) -> bool {
+		self.txs.contains_key(h)
+	}
+
+	/// Adds a transaction, that should have been validated already. Refused
+	/// if it spends any output a transaction of the pool already spends.
+	pub fn add(&mut self, tx: Transaction) -> Result<(), MempoolError> {
+		let txh = tx.hash();
+		if self.txs.contains_key(&txh) {
+			return Err(MempoolError::AlreadyInPool);
+		}
+		for input in &tx.inputs {
+			if let Some(other) = self.spent.get(&input.output_hash()) {
+				return Err(MempoolError::DoubleSpend(*other));
+			}
+		}
+		for input in &tx.inputs {
+			self.spent.insert(input.output_hash(), txh);
+		}
+		self.txs.insert(txh, tx);
+		Ok(())
+	}
+
+	/// Removes the transaction with the provided hash, if we have it.
+	pub fn remove(&mut self, h: &Hash) -> Option<Transaction> {
+		let tx = self.txs.remove(h);
+		if let Some(ref tx) = tx {
+			for input in &tx.inputs {
+				self.spent.remove(&input.output_hash());
+			}
+		}
+		tx
+	}
+
+	/// Drops all the transactions spending an output the accepted block
+	/// spends, either because they made it in the block or because they
+	/// conflict with it. Returns the number of transactions dropped.
+	pub fn block_accepted(&mut self, b: &Block) -> usize {
+		let mut conflicts = b.inputs
+			.iter()
+			.filter_map(|input| self.spent.get(&input.output_hash()).cloned())
+			.collect::<Vec<_>>();
+		conflicts.sort();
+		conflicts.dedup();
+		let removed = conflicts.iter().filter_map(|h| self.remove(h)).collect::<Vec<_>>();
+		let count = removed.len();
+
+		// kept around in case the block gets undone by a reorg
+		if count > 0 {
+			let bh = b.hash();
+			if !self.taken.contains_key(&bh) {
+				self.taken_order.push_back(bh);
+				if self.taken_order.len() > MAX_TAKEN_BLOCKS {
+					if let Some(old) = self.taken_order.pop_front() {
+						self.taken.remove(&old);
+					}
+				}
+			}
+			self.taken.entry(bh).or_insert(vec![]).extend(removed);
+		}
+		count
+	}
+
+	/// Puts back the transactions the provided block took out of the pool,
+	/// now that a reorg undid it. Transactions that only made it in the
+	/// block without going through our pool can't be told apart in it and
+	/// are lost. Returns the number of transactions added back.
+	pub fn block_disconnected(&mut self, b: &Block) -> usize {
+		let bh = b.hash();
+		let txs = match self.taken.remove(&bh) {
+			Some(txs) => txs,
+			None => return 0,
+		};
+		self.taken_order.retain(|h| *h != bh);
+		txs.into_iter().filter(|tx| self.add(tx.clone()).is_ok()).count()
+	}
+
+	/// Transactions to include in a block, the ones with the highest fee for
+	/// their weight first, as many as fit in the provided weight.
+	pub fn select_for_block(&self, max_weight: u64) -> Vec<Transaction> {
+		let mut candidates = self.txs.values().map(|tx| (tx, tx_weight(tx))).collect::<Vec<_>>();
+		candidates.sort_by(|&(a, wa), &(b, wb)| by_fee_rate(b, wb, a, wa));
+
+		let mut weight = 0;
+		let mut selected = vec![];
+		for (tx, w) in candidates {
+			if weight + w <= max_weight {
+				weight += w;
+				selected.push(tx.clone());
+			}
+		}
+		selected
+	}
+}
+
+// Compares the fee per weight of two transactions without losing precision
+// to a division
+fn by_fee_rate(a: &Transaction, wa: u64, b: &Transaction, wb: u64) -> Ordering {
+	a.fee.saturating_mul(wb).cmp(&b.fee.saturating_mul(wa))
+}
@@ -16,122 +16,401 @@
 //! block and mine the block to produce a valid header with its proof-of-work.
 
 use rand::{self, Rng};
-use std::sync::{Arc, Mutex};
+use std::cmp;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
 use time;
 
 use adapters::ChainToNetAdapter;
 use core::consensus;
 use core::core;
-use core::core::hash::{Hash, Hashed};
+use core::core::{BlockHeader, Proof};
+use core::core::hash::Hashed;
+use core::core::target::Difficulty;
 use core::pow::cuckoo;
 use chain;
+use logging::MINER_TARGET as LOG_TARGET;
+use mempool;
 use secp;
 
+/// How often, in milliseconds, we check whether the block being mined got
+/// stale
+const STALE_CHECK_INTERVAL: u64 = 100;
+
+/// Switch the miner loop and its worker threads idle on while mining is
+/// paused.
+struct Pause {
+	paused: Mutex<bool>,
+	resumed: Condvar,
+}
+
+impl Pause {
+	fn new() -> Pause {
+		Pause {
+			paused: Mutex::new(false),
+			resumed: Condvar::new(),
+		}
+	}
+
+	fn is_paused(&self) -> bool {
+		*self.paused.lock().unwrap()
+	}
+
+	fn set(&self, paused: bool) {
+		*self.paused.lock().unwrap() = paused;
+		self.resumed.notify_all();
+	}
+
+	// Blocks for as long as we're paused, or until stop gets set.
+	fn wait(&self, stop: &AtomicBool) {
+		let mut paused = self.paused.lock().unwrap();
+		while *paused && !stop.load(Ordering::Relaxed) {
+			let timeout = Duration::from_millis(STALE_CHECK_INTERVAL);
+			paused = self.resumed.wait_timeout(paused, timeout).unwrap().0;
+		}
+	}
+}
+
 pub struct Miner {
 	chain_head: Arc<Mutex<chain::Tip>>,
 	chain_store: Arc<chain::ChainStore>,
 	/// chain adapter to net
 	chain_adapter: Arc<ChainToNetAdapter>,
+	/// number of threads looking for a proof of work in parallel
+	threads: usize,
+	/// whether the mining loop has been started
+	running: AtomicBool,
+	pause: Arc<Pause>,
 }
 
 impl Miner {
 	/// Creates a new Miner. Needs references to the chain state and its
-	/// storage.
+	/// storage, as well as the number of threads to mine with.
 	pub fn new(chain_head: Arc<Mutex<chain::Tip>>,
 	           chain_store: Arc<chain::ChainStore>,
-	           chain_adapter: Arc<ChainToNetAdapter>)
+	           chain_adapter: Arc<ChainToNetAdapter>,
+	           threads: usize)
 	           -> Miner {
 		Miner {
 			chain_head: chain_head,
 			chain_store: chain_store,
 			chain_adapter: chain_adapter,
+			threads: cmp::max(threads, 1),
+			running: AtomicBool::new(false),
+			pause: Arc::new(Pause::new()),
 		}
 	}
 
+	/// Stops looking for proofs of work until resumed, the mining threads
+	/// idling in the meantime.
+	pub fn pause(&self) {
+		if !self.pause.is_paused() {
+			info!(target: LOG_TARGET, "Pausing the miner.");
+		}
+		self.pause.set(true);
+	}
+
+	/// Resumes mining after a pause, on top of our latest chain head.
+	pub fn resume(&self) {
+		if self.pause.is_paused() {
+			info!(target: LOG_TARGET, "Resuming the miner.");
+		}
+		self.pause.set(false);
+	}
+
+	/// Whether the mining loop has been started, paused or not.
+	pub fn is_started(&self) -> bool {
+		self.running.load(Ordering::SeqCst)
+	}
+
+	/// Whether the mining loop is started and not paused.
+	pub fn is_mining(&self) -> bool {
+		self.running.load(Ordering::Relaxed) && !self.pause.is_paused()
+	}
+
 	/// Starts the mining loop, building a new block on top of the existing
-	/// chain anytime required and looking for PoW solution.
+	/// chain anytime required and looking for PoW solution. Restarts on a
+	/// new block as soon as our chain head changes. Idles while paused.
+	/// Returns right away if the loop is already running.
 	pub fn run_loop(&self) {
-		info!("Starting miner loop.");
+		if self.running.swap(true, Ordering::SeqCst) {
+			debug!(target: LOG_TARGET, "Miner loop already running.");
+			return;
+		}
+		info!(target: LOG_TARGET, "Starting miner loop with {} threads.", self.threads);
+		let head_events = self.chain_adapter.subscribe();
+		let never = AtomicBool::new(false);
 		loop {
+			self.pause.wait(&never);
+
+			// whatever happened until now, we're building on the latest head
+			while head_events.try_recv().is_ok() {}
+
 			// get the latest chain state and build a block on top of it
-			let head: core::BlockHeader;
-			let mut latest_hash: Hash;
-			{
-				head = self.chain_store.head_header().unwrap();
-				latest_hash = self.chain_head.lock().unwrap().last_block_h;
-			}
-			let mut b = self.build_block(&head);
+			let head = self.chain_store.head_header().unwrap();
+			let head_tip = chain::Tip::from_block(&head);
+			let (difficulty, cuckoo_len) =
+				chain::next_target(&*self.chain_store, &head_tip).unwrap();
+			let latest_hash = self.chain_head.lock().unwrap().last_block_h;
+			let txs = self.chain_adapter.select_transactions(mempool::MAX_BLOCK_WEIGHT);
+			let mut b = build_block(&head, difficulty, cuckoo_len, txs);
 
 			// look for a pow for at most 2 sec on the same block (to give a chance to new
 			// transactions) and as long as the head hasn't changed
 			let deadline = time::get_time().sec + 2;
-			let mut sol = None;
-			debug!("Mining at Cuckoo{} for at most 2 secs on block {}.",
+			debug!(target: LOG_TARGET, "Mining at Cuckoo{} for at most 2 secs on block {}.",
 			       b.header.cuckoo_len,
 			       latest_hash);
-			let mut iter_count = 0;
-			while head.hash() == latest_hash && time::get_time().sec < deadline {
-				let pow_hash = b.hash();
-				let mut miner = cuckoo::Miner::new(pow_hash.to_slice(),
-				                                   consensus::EASINESS,
-				                                   b.header.cuckoo_len as u32);
-				if let Ok(proof) = miner.mine() {
-					if proof.to_difficulty() >= b.header.difficulty {
-						sol = Some(proof);
-						break;
-					}
-				}
-				b.header.nonce += 1;
-				{
-					latest_hash = self.chain_head.lock().unwrap().last_block_h;
+
+			let cuckoo_len = b.header.cuckoo_len as u32;
+			let difficulty = b.header.difficulty.clone();
+			let attempt = Arc::new(move |bh: &BlockHeader| {
+				let pow_hash = bh.hash();
+				let mut miner =
+					cuckoo::Miner::new(pow_hash.to_slice(), consensus::EASINESS, cuckoo_len);
+				match miner.mine() {
+					Ok(proof) if proof.to_difficulty() >= difficulty => Some(proof),
+					_ => None,
 				}
-				iter_count += 1;
-			}
+			});
+			let stale = || time::get_time().sec >= deadline || head_events.try_recv().is_ok();
+			let sol = mine_parallel(&b.header, self.threads, attempt, stale, self.pause.clone());
 
 			// if we found a solution, push our block out
-			if let Some(proof) = sol {
-				info!("Found valid proof of work, adding block {}.", b.hash());
-				b.header.pow = proof;
+			if let Some(header) = sol {
+				b.header = header;
+				info!(target: LOG_TARGET, "Found valid proof of work, adding block {}.", b.hash());
 				let res = chain::process_block(&b,
 				                               self.chain_store.clone(),
 				                               self.chain_adapter.clone(),
 				                               chain::NONE);
 				if let Err(e) = res {
-					error!("Error validating mined block: {:?}", e);
+					error!(target: LOG_TARGET, "Error validating mined block: {:?}", e);
 				} else if let Ok(Some(tip)) = res {
 					let chain_head = self.chain_head.clone();
 					let mut head = chain_head.lock().unwrap();
 					*head = tip;
 				}
 			} else {
-				debug!("No solution found after {} iterations, continuing...",
-				       iter_count)
+				debug!(target: LOG_TARGET, "No solution found, continuing...")
+			}
+		}
+	}
+}
+
+/// Builds a new block with the provided chain head as previous and eligible
+/// transactions from the pool, ready to be mined at the provided difficulty
+/// and Cuckoo size shift, as retargeted after the head.
+pub fn build_block(head: &core::BlockHeader,
+                   difficulty: Difficulty,
+                   cuckoo_len: u8,
+                   mut txs: Vec<core::Transaction>)
+                   -> core::Block {
+	let mut now_sec = time::get_time().sec;
+	let head_sec = head.timestamp.to_timespec().sec;
+	if now_sec == head_sec {
+		now_sec += 1;
+	}
+
+	let mut rng = rand::OsRng::new().unwrap();
+	let secp_inst = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+	// TODO get a new key from the user's wallet or something
+	let skey = secp::key::SecretKey::new(&secp_inst, &mut rng);
+
+	let mut b = match core::Block::new(head, txs.iter_mut().collect(), skey) {
+		Ok(b) => b,
+		Err(e) => {
+			warn!(target: LOG_TARGET,
+			      "Could not build block from pool transactions, mining an empty one: {:?}",
+			      e);
+			core::Block::new(head, vec![], skey).unwrap()
+		}
+	};
+	b.header.nonce = rng.gen();
+	b.header.cuckoo_len = cuckoo_len;
+	b.header.difficulty = difficulty;
+	b.header.timestamp = time::at(time::Timespec::new(now_sec, 0));
+	b
+}
+
+/// Splits the nonces following start in as many disjoint ranges as we have
+/// threads, returning the first nonce and the length of each range.
+fn nonce_ranges(start: u64, threads: usize) -> Vec<(u64, u64)> {
+	let len = u64::max_value() / threads as u64;
+	(0..threads as u64).map(|n| (start.wrapping_add(n * len), len)).collect()
+}
+
+/// Looks for a proof of work for the provided header over several threads,
+/// each trying the nonces of its own range. The first thread to find a
+/// solution stops the others, as does stale returning true, which gets
+/// checked regularly. Threads idle while paused. Returns the solved header,
+/// if any.
+fn mine_parallel<F, S>(header: &BlockHeader,
+                       threads: usize,
+                       attempt: Arc<F>,
+                       stale: S,
+                       pause: Arc<Pause>)
+                       -> Option<BlockHeader>
+	where F: Fn(&BlockHeader) -> Option<Proof> + Send + Sync + 'static,
+	      S: Fn() -> bool
+{
+	let stop = Arc::new(AtomicBool::new(false));
+	let (sol_tx, sol_rx) = mpsc::channel();
+
+	let workers = nonce_ranges(header.nonce, threads)
+		.into_iter()
+		.map(|(first, len)| {
+			let mut bh = header.clone();
+			let stop = stop.clone();
+			let sol_tx = sol_tx.clone();
+			let attempt = attempt.clone();
+			let pause = pause.clone();
+			thread::spawn(move || {
+				bh.nonce = first;
+				for _ in 0..len {
+					pause.wait(&stop);
+					if stop.load(Ordering::Relaxed) {
+						return;
+					}
+					if let Some(proof) = (*attempt)(&bh) {
+						bh.pow = proof;
+						let _ = sol_tx.send(bh);
+						return;
+					}
+					bh.nonce = bh.nonce.wrapping_add(1);
+				}
+			})
+		})
+		.collect::<Vec<_>>();
+	drop(sol_tx);
+
+	let mut sol = None;
+	loop {
+		match sol_rx.recv_timeout(Duration::from_millis(STALE_CHECK_INTERVAL)) {
+			Ok(bh) => {
+				sol = Some(bh);
+				break;
+			}
+			Err(RecvTimeoutError::Timeout) => {
+				if stale() {
+					break;
+				}
 			}
+			Err(RecvTimeoutError::Disconnected) => break,
 		}
 	}
 
-	/// Builds a new block with the chain head as previous and eligible
-	/// transactions from the pool.
-	fn build_block(&self, head: &core::BlockHeader) -> core::Block {
-		let mut now_sec = time::get_time().sec;
-		let head_sec = head.timestamp.to_timespec().sec;
-		if now_sec == head_sec {
-			now_sec += 1;
+	// whatever happened, no need for the other threads to go on
+	stop.store(true, Ordering::Relaxed);
+	for worker in workers {
+		let _ = worker.join();
+	}
+	sol
+}
+
+#[cfg(test)]
+mod test {
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::thread;
+	use std::time::{Duration, Instant};
+
+	use core::core::{BlockHeader, Proof};
+	use super::*;
+
+	#[test]
+	fn disjoint_nonce_ranges() {
+		for &start in &[0, 12345, u64::max_value() - 2] {
+			let ranges = nonce_ranges(start, 3);
+			assert_eq!(ranges.len(), 3);
+			assert_eq!(ranges[0].0, start);
+			// offsets from the start must never go back
+			for w in ranges.windows(2) {
+				let (prev, next) = (w[0].0.wrapping_sub(start), w[1].0.wrapping_sub(start));
+				assert!(prev + w[0].1 <= next);
+			}
+			let last = ranges[2];
+			assert!(last.0.wrapping_sub(start).checked_add(last.1).is_some());
+		}
+	}
+
+	#[test]
+	fn solution_stops_siblings() {
+		let attempts = Arc::new(AtomicUsize::new(0));
+		let counter = attempts.clone();
+		let target = nonce_ranges(0, 4)[2].0 + 5;
+		let attempt = Arc::new(move |bh: &BlockHeader| {
+			counter.fetch_add(1, Ordering::SeqCst);
+			if bh.nonce == target {
+				Some(Proof::zero())
+			} else {
+				thread::sleep(Duration::from_millis(1));
+				None
+			}
+		});
+
+		let start = Instant::now();
+		let sol = mine_parallel(&BlockHeader::default(), 4, attempt, || false, pause()).unwrap();
+		assert_eq!(sol.nonce, target);
+		assert!(start.elapsed() < Duration::from_secs(1));
+
+		// all threads are done, nothing is being tried anymore
+		let count = attempts.load(Ordering::SeqCst);
+		thread::sleep(Duration::from_millis(50));
+		assert_eq!(attempts.load(Ordering::SeqCst), count);
+	}
+
+	#[test]
+	fn stale_stops_all() {
+		let attempt = Arc::new(|_: &BlockHeader| {
+			thread::sleep(Duration::from_millis(1));
+			None
+		});
+		let start = Instant::now();
+		assert!(mine_parallel(&BlockHeader::default(), 2, attempt, || true, pause()).is_none());
+		assert!(start.elapsed() < Duration::from_secs(1));
+	}
+
+	#[test]
+	fn pause_halts_scanning() {
+		let attempts = Arc::new(AtomicUsize::new(0));
+		let counter = attempts.clone();
+		let attempt = Arc::new(move |bh: &BlockHeader| {
+			counter.fetch_add(1, Ordering::SeqCst);
+			thread::sleep(Duration::from_millis(1));
+			if bh.nonce == 300 { Some(Proof::zero()) } else { None }
+		});
+		let pause = pause();
+		pause.set(true);
+		let p = pause.clone();
+		let mining = thread::spawn(move || {
+			mine_parallel(&BlockHeader::default(), 1, attempt, || false, p)
+		});
+
+		thread::sleep(Duration::from_millis(50));
+		assert_eq!(attempts.load(Ordering::SeqCst), 0);
+
+		// paused again midway, the nonce being tried gets finished
+		pause.set(false);
+		while attempts.load(Ordering::SeqCst) < 10 {
+			thread::sleep(Duration::from_millis(1));
 		}
-		let (difficulty, cuckoo_len) =
-			consensus::next_target(now_sec, head_sec, head.difficulty.clone(), head.cuckoo_len);
-
-		let mut rng = rand::OsRng::new().unwrap();
-		let secp_inst = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
-		// TODO get a new key from the user's wallet or something
-		let skey = secp::key::SecretKey::new(&secp_inst, &mut rng);
-
-		// TODO populate inputs and outputs from pool transactions
-		let mut b = core::Block::new(head, vec![], skey).unwrap();
-		b.header.nonce = rng.gen();
-		b.header.cuckoo_len = cuckoo_len;
-		b.header.difficulty = difficulty;
-		b.header.timestamp = time::at(time::Timespec::new(now_sec, 0));
-		b
+		pause.set(true);
+		thread::sleep(Duration::from_millis(20));
+		let count = attempts.load(Ordering::SeqCst);
+		thread::sleep(Duration::from_millis(50));
+		assert_eq!(attempts.load(Ordering::SeqCst), count);
+
+		// picks up where it stopped
+		pause.set(false);
+		assert_eq!(mining.join().unwrap().unwrap().nonce, 300);
+		assert_eq!(attempts.load(Ordering::SeqCst), 301);
+	}
+
+	fn pause() -> Arc<Pause> {
+		Arc::new(Pause::new())
 	}
 }
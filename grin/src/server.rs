@@ -16,21 +16,38 @@
 //! the peer-to-peer server, the blockchain and the transaction pool) and acts
 //! as a facade.
 
+use std::cmp;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
+use std::time::Duration;
 
 use futures::Future;
+use log::LogLevelFilter;
 use tokio_core::reactor;
 
 use adapters::{NetToChainAdapter, ChainToNetAdapter};
+use api::ApiServer;
+use data_dir::DataDir;
+use logging::{self, SERVER_TARGET as LOG_TARGET, STORE_TARGET};
 use chain;
 use chain::ChainStore;
 use core;
+use core::core::hash::{Hash, Hashed, ZERO_HASH};
+use core::ser;
 use miner;
 use p2p;
+use stratum::StratumServer;
 use sync;
 
+/// Longest, in seconds, we wait before checking whether the chain store has
+/// been idle long enough to be compacted
+const COMPACTION_CHECK_INTERVAL: u64 = 60;
+
 /// Errors than can be reported by a server implementation, mostly wraps
 /// underlying components errors.
 #[derive(Debug)]
@@ -38,29 +55,96 @@ pub enum Error {
 	/// Error when trying to add a block to the chain
 	ChainErr(chain::pipe::Error),
 	/// Peer connection error
-	PeerErr(core::ser::Error),
+	PeerErr(p2p::Error),
 	/// Data store error
 	StoreErr(chain::types::Error),
+	/// Network IO error
+	IOErr(io::Error),
+	/// The genesis block file couldn't be read or doesn't hold a valid
+	/// genesis block
+	GenesisErr(String),
+	/// Our chain doesn't hold together when walking back from its head
+	InconsistentChain(chain::Inconsistency),
 }
 
 /// Full server configuration, aggregating configurations required for the
 /// different components.
 #[derive(Debug, Clone)]
 pub struct ServerConfig {
-	/// Directory under which the rocksdb stores will be created
-	pub db_root: String,
+	/// Directory under which all our data is kept, laid out by `DataDir`
+	pub data_dir: String,
 	/// Allows overriding the default cuckoo cycle size
 	pub cuckoo_size: u8,
 	/// Configuration for the peer-to-peer server
 	pub p2p_config: p2p::P2PConfig,
+	/// Maximum number of blocks received before their parent we keep around
+	pub max_orphans: usize,
+	/// Maximum number of recently rejected blocks we remember, to refuse
+	/// them cheaply when they come again
+	pub max_rejects: usize,
+	/// Block hashes our chain has to go through at given heights
+	pub checkpoints: chain::Checkpoints,
+	/// Number of recently read block headers kept in memory
+	pub header_cache_size: usize,
+	/// Largest number of blocks we rewind from our head to switch to a fork
+	/// with more work, deeper forks are refused
+	pub max_reorg_depth: u64,
+	/// Number of seconds block timestamps can be ahead of our local time
+	pub max_future_time: u64,
+	/// Whether to start mining as soon as the server is up
+	pub enable_mining: bool,
+	/// Number of threads the miner looks for a proof of work with
+	pub miner_threads: usize,
+	/// Address the HTTP status API listens on, if it should be started
+	pub api_addr: Option<SocketAddr>,
+	/// Maximum number of full blocks we wait for from a single peer while
+	/// syncing
+	pub max_inflight_blocks: usize,
+	/// Seconds sync can go without receiving any header or block before
+	/// switching to another peer
+	pub sync_stall_timeout: u64,
+	/// Number of blocks back from our head whose headers get checked for
+	/// consistency on startup, if any
+	pub verify_chain_depth: Option<u64>,
+	/// Seconds our chain store has to go without writes before it gets
+	/// compacted in the background, never if not set
+	pub compact_idle_time: Option<u64>,
+	/// File holding a serialized genesis block to start the chain from
+	/// instead of the mainnet one, for isolated test networks
+	pub genesis_file: Option<PathBuf>,
+	/// Level of the logs we keep, for targets without a level of their own
+	pub log_level: LogLevelFilter,
+	/// Levels of the logs we keep for given targets, usually the one of a
+	/// subsystem like `P2P_TARGET`
+	pub log_targets: Vec<(String, LogLevelFilter)>,
+	/// Keys of the configuration file we don't know of, reported once our
+	/// logging is set up
+	pub unknown_keys: Vec<String>,
 }
 
 impl Default for ServerConfig {
 	fn default() -> ServerConfig {
 		ServerConfig {
-			db_root: ".grin".to_string(),
+			data_dir: ".grin".to_string(),
 			cuckoo_size: 0,
 			p2p_config: p2p::P2PConfig::default(),
+			max_orphans: 100,
+			max_rejects: 256,
+			checkpoints: chain::Checkpoints::new(),
+			header_cache_size: chain::HEADER_CACHE_SIZE,
+			max_reorg_depth: chain::MAX_REORG_DEPTH,
+			max_future_time: chain::MAX_FUTURE_TIME,
+			enable_mining: false,
+			miner_threads: 1,
+			api_addr: None,
+			max_inflight_blocks: 4,
+			sync_stall_timeout: sync::SYNC_STALL_TIMEOUT,
+			verify_chain_depth: None,
+			compact_idle_time: None,
+			genesis_file: None,
+			log_level: LogLevelFilter::Info,
+			log_targets: vec![],
+			unknown_keys: vec![],
 		}
 	}
 }
@@ -78,65 +162,111 @@ pub struct Server {
 	/// chain adapter to net, required for miner and anything that submits
 	/// blocks
 	chain_adapter: Arc<ChainToNetAdapter>,
+	/// progress of the chain synchronization
+	sync_status: Arc<RwLock<sync::SyncStatus>>,
+	/// our own miner, only running once started
+	miner: Arc<miner::Miner>,
+	/// reports block download progress while syncing, once a callback is set
+	sync_progress: Arc<sync::SyncProgress>,
+	/// background compaction of the chain store, if configured
+	compaction: Option<Compaction>,
 }
 
 impl Server {
-	/// Instantiates and starts a new server.
-	pub fn start(config: ServerConfig) -> Result<Server, Error> {
-		let (chain_store, head) = try!(store_head(&config));
-		let shared_head = Arc::new(Mutex::new(head));
-
-		let chain_adapter = Arc::new(ChainToNetAdapter::new());
-		let net_adapter = Arc::new(NetToChainAdapter::new(shared_head.clone(),
-		                                                  chain_store.clone(),
-		                                                  chain_adapter.clone()));
-		let server = Arc::new(p2p::Server::new(config.p2p_config, net_adapter.clone()));
-		chain_adapter.init(server.clone());
-
-		let sync = sync::Syncer::new(chain_store.clone(), server.clone());
-		net_adapter.start_sync(sync);
-
-		let mut evtlp = reactor::Core::new().unwrap();
-		let handle = evtlp.handle();
-		evtlp.run(server.start(handle.clone())).unwrap();
-
-		warn!("Grin server started.");
-		Ok(Server {
-			config: config,
-			evt_handle: handle.clone(),
-			p2p: server,
-			chain_head: shared_head,
-			chain_store: chain_store,
-			chain_adapter: chain_adapter,
-		})
+	/// Instantiates and starts a new server on an event loop of its own, run
+	/// on the current thread until our network server stops.
+	pub fn start(config: ServerConfig) -> Result<(), Error> {
+		let mut evtlp = try!(reactor::Core::new().map_err(Error::IOErr));
+		let (_server, run_p2p) = try!(Server::setup(config, &evtlp.handle()));
+		evtlp.run(run_p2p).map_err(Error::PeerErr)
 	}
 
 	/// Instantiates a new server associated with the provided future reactor.
 	pub fn future(config: ServerConfig, evt_handle: &reactor::Handle) -> Result<Server, Error> {
-		let (chain_store, head) = try!(store_head(&config));
+		let (server, run_p2p) = try!(Server::setup(config, evt_handle));
+		evt_handle.spawn(run_p2p.map_err(|e| {
+			error!(target: LOG_TARGET, "Network server failed: {:?}", e);
+		}));
+		Ok(server)
+	}
+
+	// Sets up everything the server is made of, starting the API and the miner
+	// if configured, and leaves running our network server on the provided
+	// reactor to the caller.
+	fn setup(config: ServerConfig,
+	         evt_handle: &reactor::Handle)
+	         -> Result<(Server, Box<Future<Item = (), Error = p2p::Error>>), Error> {
+		setup_logging(&config);
+		let (chain_store, head, genesis) = try!(store_head(&config));
 		let shared_head = Arc::new(Mutex::new(head));
+		let compaction = config.compact_idle_time
+			.map(|secs| Compaction::start(chain_store.clone(), Duration::from_secs(secs)));
 
-		let chain_adapter = Arc::new(ChainToNetAdapter::new());
+		let chain_adapter = Arc::new(ChainToNetAdapter::new(chain_store.clone()));
 		let net_adapter = Arc::new(NetToChainAdapter::new(shared_head.clone(),
 		                                                  chain_store.clone(),
-		                                                  chain_adapter.clone()));
-		let server = Arc::new(p2p::Server::new(config.p2p_config, net_adapter.clone()));
+		                                                  chain_adapter.clone(),
+		                                                  config.max_orphans,
+		                                                  config.max_rejects));
+		let peer_db_path = try!(DataDir::new(&config.data_dir)
+			.peer_db_path()
+			.map_err(Error::IOErr));
+		let server = Arc::new(try!(p2p::Server::new(peer_db_path.to_string_lossy().into_owned(),
+		                                            config.p2p_config.clone(),
+		                                            net_adapter.clone(),
+		                                            genesis)
+			.map_err(Error::PeerErr)));
 		chain_adapter.init(server.clone());
+		net_adapter.init(server.clone());
 
-		let sync = sync::Syncer::new(chain_store.clone(), server.clone());
+		let sync_status = Arc::new(RwLock::new(sync::SyncStatus::new()));
+		let sync_progress = Arc::new(sync::SyncProgress::new());
+		let sync = sync::Syncer::new(chain_store.clone(),
+		                             server.clone(),
+		                             sync_status.clone(),
+		                             sync_progress.clone(),
+		                             config.max_inflight_blocks,
+		                             Duration::from_secs(config.sync_stall_timeout));
 		net_adapter.start_sync(sync);
 
-		evt_handle.spawn(server.start(evt_handle.clone()).map_err(|_| ()));
+		let run_p2p = server.start(evt_handle.clone());
+		let miner = Arc::new(miner::Miner::new(shared_head.clone(),
+		                                       chain_store.clone(),
+		                                       chain_adapter.clone(),
+		                                       config.miner_threads));
+		try!(start_api(&config,
+		               chain_store.clone(),
+		               shared_head.clone(),
+		               server.clone(),
+		               sync_status.clone(),
+		               miner.clone()));
 
-		warn!("Grin server started.");
-		Ok(Server {
+		warn!(target: LOG_TARGET, "Grin server started.");
+		let server = Server {
 			config: config,
 			evt_handle: evt_handle.clone(),
 			p2p: server,
 			chain_head: shared_head,
 			chain_store: chain_store,
 			chain_adapter: chain_adapter,
-		})
+			sync_status: sync_status,
+			miner: miner,
+			sync_progress: sync_progress,
+			compaction: compaction,
+		};
+		if server.config.enable_mining {
+			server.start_miner();
+		}
+		Ok((server, run_p2p))
+	}
+
+	/// Stops the server, our network server first and then the background
+	/// work on our chain store. The event loop `start` runs returns.
+	pub fn stop(&self) {
+		self.p2p.stop();
+		if let Some(ref compaction) = self.compaction {
+			compaction.stop();
+		}
 	}
 
 	/// Asks the server to connect to a peer at the provided network address.
@@ -147,47 +277,234 @@ impl Server {
 	}
 
 	/// Start mining for blocks on a separate thread. Relies on a toy miner,
-	/// mostly for testing.
+	/// mostly for testing. Does nothing if the miner is already started.
 	pub fn start_miner(&self) {
-		let miner = miner::Miner::new(self.chain_head.clone(),
-		                              self.chain_store.clone(),
-		                              self.chain_adapter.clone());
+		if self.miner.is_started() {
+			return;
+		}
+		let miner = self.miner.clone();
 		thread::spawn(move || {
 			miner.run_loop();
 		});
 	}
 
+	/// Our miner, to pause and resume it once started.
+	pub fn miner(&self) -> &miner::Miner {
+		&self.miner
+	}
+
+	/// Starts a Stratum server on the provided address, handing out mining
+	/// jobs to external miners and adding the blocks they solve to our chain.
+	pub fn start_stratum(&self, addr: SocketAddr) -> Result<Arc<StratumServer>, Error> {
+		let mut stratum = StratumServer::new(self.chain_head.clone(),
+		                                     self.chain_store.clone(),
+		                                     self.chain_adapter.clone());
+		stratum.set_head_events(self.chain_adapter.subscribe());
+		let stratum = Arc::new(stratum);
+		try!(StratumServer::start(stratum.clone(), addr).map_err(&Error::IOErr));
+		Ok(stratum)
+	}
+
 	pub fn head(&self) -> chain::Tip {
 		let head = self.chain_head.clone();
 		let h = head.lock().unwrap();
 		h.clone()
 	}
+
+	/// Checks the headers of the last depth blocks of our chain link to each
+	/// other, walking back from the head. Returns the first inconsistency
+	/// found, with the height it's at.
+	pub fn verify_chain_integrity(&self, depth: u64) -> Result<(), Error> {
+		chain::verify_chain(&*self.chain_store, depth).map_err(Error::InconsistentChain)
+	}
+
+	/// How far along the synchronization of our chain with the network is.
+	pub fn sync_status(&self) -> sync::SyncStatus {
+		self.sync_status.read().unwrap().clone()
+	}
+
+	/// Registers a callback told about our full chain height and the height
+	/// we're syncing up to as blocks get downloaded, at most once every
+	/// provided number of blocks. Replaces any previous callback.
+	pub fn set_progress_callback(&self, callback: sync::ProgressCallback, every: u64) {
+		self.sync_progress.set_callback(callback, every);
+	}
+}
+
+// Starts the HTTP status API if the configuration asks for it.
+fn start_api(config: &ServerConfig,
+             chain_store: Arc<chain::ChainStore>,
+             chain_head: Arc<Mutex<chain::Tip>>,
+             p2p: Arc<p2p::Server>,
+             sync_status: Arc<RwLock<sync::SyncStatus>>,
+             miner: Arc<miner::Miner>)
+             -> Result<(), Error> {
+	if let Some(addr) = config.api_addr {
+		let api = Arc::new(ApiServer::new(chain_store, chain_head, p2p, sync_status, miner));
+		try!(ApiServer::start(api, addr).map_err(&Error::IOErr));
+	}
+	Ok(())
+}
+
+// Sets up logging with the configured levels, to stderr and to the log file
+// of our data directory, unless whoever embeds us already set up a logger of
+// their own.
+fn setup_logging(config: &ServerConfig) {
+	let log_file = DataDir::new(&config.data_dir)
+		.log_path()
+		.and_then(|path| OpenOptions::new().create(true).append(true).open(path));
+	let (log_file, file_err) = match log_file {
+		Ok(file) => (Some(file), None),
+		Err(e) => (None, Some(e)),
+	};
+	if logging::init_logging(config.log_level, &config.log_targets, log_file).is_err() {
+		debug!(target: LOG_TARGET, "Logger already set up, keeping it.");
+	}
+	if let Some(e) = file_err {
+		warn!(target: LOG_TARGET, "Could not open the log file, only logging to stderr: {}", e);
+	}
+	for key in &config.unknown_keys {
+		warn!(target: LOG_TARGET, "Unknown configuration key {}, ignoring it.", key);
+	}
+}
+
+impl Drop for Server {
+	fn drop(&mut self) {
+		// the compaction thread would otherwise keep the chain store open
+		if let Some(ref compaction) = self.compaction {
+			compaction.stop();
+		}
+	}
+}
+
+// Compacts the chain store on its own thread whenever it went without being
+// written to for the provided idle time, typically once a big sync is done,
+// until stopped.
+struct Compaction {
+	stop: Arc<AtomicBool>,
+	thread: Option<thread::Thread>,
+}
+
+impl Compaction {
+	fn start(chain_store: Arc<chain::store::ChainKVStore>, idle: Duration) -> Compaction {
+		let check_interval = cmp::min(idle, Duration::from_secs(COMPACTION_CHECK_INTERVAL));
+		let stop = Arc::new(AtomicBool::new(false));
+		let stopped = stop.clone();
+		let handle = thread::Builder::new().name("compaction".to_string()).spawn(move || {
+			loop {
+				// woken up early when stopped
+				thread::park_timeout(check_interval);
+				if stopped.load(Ordering::SeqCst) {
+					break;
+				}
+				match chain_store.write_idle_time() {
+					Some(t) if t >= idle => {}
+					_ => continue,
+				}
+				debug!(target: STORE_TARGET, "Chain store idle, compacting it.");
+				if let Err(e) = chain_store.compact() {
+					warn!(target: STORE_TARGET, "Could not compact the chain store: {:?}", e);
+				}
+			}
+		});
+		Compaction {
+			stop: stop,
+			thread: handle.ok().map(|h| h.thread().clone()),
+		}
+	}
+
+	// Has the compaction thread exit, after the compaction in progress if any
+	fn stop(&self) {
+		self.stop.store(true, Ordering::SeqCst);
+		if let Some(ref t) = self.thread {
+			t.unpark();
+		}
+	}
 }
 
 // Helper function to create the chain storage and check if it already has a
-// genesis block
+// genesis block. Also returns the hash of our genesis block, which peers are
+// checked against.
 fn store_head(config: &ServerConfig)
-              -> Result<(Arc<chain::store::ChainKVStore>, chain::Tip), Error> {
-	let chain_store = try!(chain::store::ChainKVStore::new(config.db_root.clone())
-		.map_err(&Error::StoreErr));
+              -> Result<(Arc<chain::store::ChainKVStore>, chain::Tip, Hash), Error> {
+	let chain_db_path = try!(DataDir::new(&config.data_dir).chain_db_path().map_err(Error::IOErr));
+	let mut chain_store =
+		try!(chain::store::ChainKVStore::new(chain_db_path.to_string_lossy().into_owned())
+			.map_err(&Error::StoreErr));
+	chain_store.set_checkpoints(config.checkpoints.clone());
+	chain_store.set_header_cache_size(config.header_cache_size);
+	chain_store.set_max_reorg_depth(config.max_reorg_depth);
+	chain_store.set_max_future_time(config.max_future_time);
+
+	let gen = try!(genesis(config));
 
 	// check if we have a head in store, otherwise the genesis block is it
 	let head = match chain_store.head() {
-		Ok(tip) => tip,
+		Ok(tip) => {
+			// a chain from another network would go unnoticed until our peers
+			// all refuse it
+			if config.genesis_file.is_some() {
+				let stored = try!(chain_store.get_header_by_height(0).map_err(&Error::StoreErr));
+				if stored.hash() != gen.hash() {
+					return Err(Error::GenesisErr(format!("chain stored from genesis block {}, \
+					                                      not {}",
+					                                     stored.hash(),
+					                                     gen.hash())));
+				}
+			}
+			tip
+		}
 		Err(chain::types::Error::NotFoundErr) => {
-			debug!("No genesis block found, creating and saving one.");
+			debug!(target: LOG_TARGET, "No genesis block found, creating and saving one.");
+			try!(chain_store.save_block(&gen).map_err(&Error::StoreErr));
+			try!(chain_store.setup_height(&gen.header).map_err(&Error::StoreErr));
+			let tip = chain::types::Tip::new(gen.hash());
+			try!(chain_store.save_head(&tip).map_err(&Error::StoreErr));
+			tip
+		}
+		Err(e) => return Err(Error::StoreErr(e)),
+	};
+	if let Some(depth) = config.verify_chain_depth {
+		try!(chain::verify_chain(&chain_store, depth).map_err(Error::InconsistentChain));
+		info!(target: LOG_TARGET, "Verified the last {} blocks of our chain.", depth);
+	}
+	Ok((Arc::new(chain_store), head, gen.hash()))
+}
+
+// The genesis block of our chain, read from the configured file if any and
+// otherwise the mainnet one. The mainnet genesis block is deterministic, so
+// we can always rebuild it.
+fn genesis(config: &ServerConfig) -> Result<core::core::Block, Error> {
+	let path = match config.genesis_file {
+		Some(ref path) => path,
+		None => {
 			let mut gen = core::genesis::genesis();
 			if config.cuckoo_size > 0 {
 				gen.header.cuckoo_len = config.cuckoo_size;
 				let diff = gen.header.difficulty.clone();
 				core::pow::pow(&mut gen.header, diff).unwrap();
 			}
-			try!(chain_store.save_block(&gen).map_err(&Error::StoreErr));
-			let tip = chain::types::Tip::new(gen.hash());
-			try!(chain_store.save_head(&tip).map_err(&Error::StoreErr));
-			tip
+			return Ok(gen);
 		}
-		Err(e) => return Err(Error::StoreErr(e)),
 	};
-	Ok((Arc::new(chain_store), head))
+
+	let mut data = vec![];
+	try!(File::open(path)
+		.and_then(|mut f| f.read_to_end(&mut data))
+		.map_err(|e| Error::GenesisErr(format!("could not read {}: {}", path.display(), e))));
+	let gen = try!(ser::deserialize::<core::core::Block>(&mut &data[..])
+		.map_err(|e| Error::GenesisErr(format!("invalid block in {}: {}", path.display(), e))));
+
+	// the mainnet genesis block marks its lack of a previous block with all
+	// bits set
+	let no_previous = core::genesis::genesis().header.previous;
+	if gen.header.height != 0 {
+		return Err(Error::GenesisErr(format!("genesis block at height {}", gen.header.height)));
+	}
+	if gen.header.previous != ZERO_HASH && gen.header.previous != no_previous {
+		return Err(Error::GenesisErr(format!("genesis block has previous block {}",
+		                                     gen.header.previous)));
+	}
+	info!(target: LOG_TARGET, "Using genesis block {} from {}.", gen.hash(), path.display());
+	Ok(gen)
 }
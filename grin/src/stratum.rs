@@ -0,0 +1,502 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Stratum server external miners connect to, so mining rigs can work for
+//! our node instead of the built-in miner. Speaks line-delimited JSON-RPC
+//! over TCP.
+//!
+//! Workers first log in with a `login` request, after which they get sent
+//! a `job` notification every time our chain head changes. A job carries the
+//! pre-PoW header (everything the proof of work hash covers) in hex, the
+//! offset of the 8 bytes big-endian nonce miners vary in it, the Cuckoo Cycle
+//! size and the difficulty a solution has to reach. Solutions get sent back
+//! with a `submit` request holding the job id, the nonce and the cycle.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{self, Value};
+use serde_json::builder::ObjectBuilder;
+
+use chain::{self, ChainEvent};
+use core::consensus::PROOFSIZE;
+use core::core::{Block, BlockHeader, Proof};
+use core::core::hash::{Hash, Hashed};
+use core::core::target::Difficulty;
+use core::pow;
+use core::ser::{self, AsFixedBytes, Writer};
+use logging::MINER_TARGET as LOG_TARGET;
+use miner;
+
+/// Interval, in milliseconds, at which we check whether the chain head
+/// changed and workers need a new job, when not told about it
+const JOB_REFRESH_INTERVAL: u64 = 500;
+
+/// Time, in seconds, we give a worker to take what we send it before giving
+/// up on the write
+const WRITE_TIMEOUT: u64 = 10;
+
+/// Offset of the nonce in the pre-PoW header, after the height, previous
+/// hash, timestamp, cuckoo size and both Merkle roots
+const NONCE_OFFSET: usize = 8 + 32 + 8 + 1 + 32 + 32;
+
+/// Why a submitted solution got rejected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubmitError {
+	/// The job doesn't exist or isn't the current one anymore
+	StaleJob,
+	/// The solution doesn't reach the difficulty of the job
+	LowDifficulty,
+	/// The proof isn't a valid cycle for the job header and nonce
+	InvalidPow,
+}
+
+impl SubmitError {
+	fn code(&self) -> i64 {
+		match *self {
+			SubmitError::StaleJob => -32503,
+			SubmitError::LowDifficulty => -32501,
+			SubmitError::InvalidPow => -32502,
+		}
+	}
+
+	fn message(&self) -> &'static str {
+		match *self {
+			SubmitError::StaleJob => "stale job",
+			SubmitError::LowDifficulty => "solution below job difficulty",
+			SubmitError::InvalidPow => "invalid proof of work",
+		}
+	}
+}
+
+/// Shares a worker submitted since it connected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkerStats {
+	/// Name the worker logged in with
+	pub login: String,
+	/// Number of solutions accepted
+	pub accepted: u64,
+	/// Number of solutions rejected
+	pub rejected: u64,
+	/// Sum of the difficulties of the jobs the accepted solutions were for
+	pub share_difficulty: Difficulty,
+	/// Number of accepted solutions that made it into a block
+	pub blocks_found: u64,
+}
+
+struct Worker {
+	// locked on its own so writing to a slow worker doesn't hold all others
+	stream: Arc<Mutex<TcpStream>>,
+	stats: WorkerStats,
+}
+
+/// Block currently being mined by the workers.
+struct Job {
+	id: u64,
+	block: Block,
+}
+
+/// Stratum server handing mining jobs to the workers connected to it and
+/// adding the blocks they solve to our chain.
+pub struct StratumServer {
+	chain_head: Arc<Mutex<chain::Tip>>,
+	chain_store: Arc<chain::ChainStore>,
+	chain_adapter: Arc<chain::ChainAdapter + Send + Sync>,
+	head_events: Mutex<Option<Receiver<ChainEvent>>>,
+
+	job: RwLock<Option<Job>>,
+	workers: Mutex<HashMap<usize, Worker>>,
+	next_worker: AtomicUsize,
+}
+
+impl StratumServer {
+	/// Creates a new Stratum server. Needs references to the chain state and
+	/// its storage.
+	pub fn new(chain_head: Arc<Mutex<chain::Tip>>,
+	           chain_store: Arc<chain::ChainStore>,
+	           chain_adapter: Arc<chain::ChainAdapter + Send + Sync>)
+	           -> StratumServer {
+		StratumServer {
+			chain_head: chain_head,
+			chain_store: chain_store,
+			chain_adapter: chain_adapter,
+			head_events: Mutex::new(None),
+			job: RwLock::new(None),
+			workers: Mutex::new(HashMap::new()),
+			next_worker: AtomicUsize::new(0),
+		}
+	}
+
+	/// Sets the chain events telling us about head changes, so workers get
+	/// their new job right away instead of on our next check.
+	pub fn set_head_events(&mut self, events: Receiver<ChainEvent>) {
+		self.head_events = Mutex::new(Some(events));
+	}
+
+	/// Starts listening for workers on the provided address, refreshing
+	/// their job as our chain grows. Everything runs on its own threads.
+	pub fn start(server: Arc<StratumServer>, addr: SocketAddr) -> io::Result<()> {
+		let listener = try!(TcpListener::bind(addr));
+		info!(target: LOG_TARGET, "Stratum server listening on {}.", addr);
+
+		let jobs = server.clone();
+		let mut events = server.head_events.lock().unwrap().take();
+		try!(thread::Builder::new().name("stratum-jobs".to_string()).spawn(move || {
+			let interval = Duration::from_millis(JOB_REFRESH_INTERVAL);
+			let mut head = jobs.chain_head.lock().unwrap().last_block_h;
+			loop {
+				jobs.refresh_job(head);
+				let event = match events {
+					Some(ref events) => events.recv_timeout(interval),
+					None => {
+						thread::sleep(interval);
+						Err(RecvTimeoutError::Timeout)
+					}
+				};
+				head = match event {
+					// the event can come before the shared head gets updated
+					Ok(ChainEvent::HeadChanged(tip)) => tip.last_block_h,
+					Err(RecvTimeoutError::Timeout) => jobs.chain_head.lock().unwrap().last_block_h,
+					Err(RecvTimeoutError::Disconnected) => {
+						events = None;
+						jobs.chain_head.lock().unwrap().last_block_h
+					}
+				};
+			}
+		}));
+
+		try!(thread::Builder::new().name("stratum".to_string()).spawn(move || {
+			for stream in listener.incoming() {
+				match stream {
+					Ok(stream) => {
+						let s = server.clone();
+						thread::spawn(move || s.handle_worker(stream));
+					}
+					Err(e) => debug!(target: LOG_TARGET, "Could not accept Stratum worker: {}", e),
+				}
+			}
+		}));
+		Ok(())
+	}
+
+	/// Stats of all the workers currently connected.
+	pub fn worker_stats(&self) -> Vec<WorkerStats> {
+		let workers = self.workers.lock().unwrap();
+		workers.values().map(|w| w.stats.clone()).collect()
+	}
+
+	/// Builds a new job on top of the provided head if it isn't the one the
+	/// current job was built on and sends it to all workers.
+	fn refresh_job(&self, head: Hash) {
+		{
+			let job = self.job.read().unwrap();
+			if let Some(ref job) = *job {
+				if job.block.header.previous == head {
+					return;
+				}
+			}
+		}
+
+		let header = match self.chain_store.get_block_header(&head) {
+			Ok(header) => header,
+			Err(e) => {
+				error!(target: LOG_TARGET,
+				       "Could not get head header to build a Stratum job: {:?}",
+				       e);
+				return;
+			}
+		};
+		let (difficulty, cuckoo_len) = match chain::next_target(&*self.chain_store,
+		                                                        &chain::Tip::from_block(&header)) {
+			Ok(target) => target,
+			Err(e) => {
+				error!(target: LOG_TARGET, "Could not retarget to build a Stratum job: {:?}", e);
+				return;
+			}
+		};
+		let block = miner::build_block(&header, difficulty, cuckoo_len, vec![]);
+		let msg = {
+			let mut job = self.job.write().unwrap();
+			let id = job.as_ref().map(|j| j.id + 1).unwrap_or(0);
+			*job = Some(Job {
+				id: id,
+				block: block,
+			});
+			job_notification(job.as_ref().unwrap())
+		};
+		debug!(target: LOG_TARGET, "New Stratum job on top of block {}.", head);
+
+		let ids = self.workers.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+		for id in ids {
+			self.send(id, &msg);
+		}
+	}
+
+	/// Reads and answers the requests of a worker until it disconnects.
+	fn handle_worker(&self, stream: TcpStream) {
+		let id = self.next_worker.fetch_add(1, Ordering::Relaxed);
+		let reader = match stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT)))
+			.and_then(|_| stream.try_clone()) {
+			Ok(s) => BufReader::new(s),
+			Err(e) => {
+				debug!(target: LOG_TARGET, "Could not set up Stratum worker connection: {}", e);
+				return;
+			}
+		};
+		self.workers.lock().unwrap().insert(id,
+		                                    Worker {
+			                                    stream: Arc::new(Mutex::new(stream)),
+			                                    stats: WorkerStats {
+				                                    login: String::new(),
+				                                    accepted: 0,
+				                                    rejected: 0,
+				                                    share_difficulty: Difficulty::from_num(0),
+				                                    blocks_found: 0,
+			                                    },
+		                                    });
+
+		for line in reader.lines() {
+			let line = match line {
+				Ok(line) => line,
+				Err(_) => break,
+			};
+			let req = match serde_json::from_str::<Value>(&line) {
+				Ok(req) => req,
+				Err(e) => {
+					debug!(target: LOG_TARGET, "Invalid Stratum request from worker {}: {}", id, e);
+					break;
+				}
+			};
+			self.handle_request(id, &req);
+		}
+		self.workers.lock().unwrap().remove(&id);
+		debug!(target: LOG_TARGET, "Stratum worker {} disconnected.", id);
+	}
+
+	fn handle_request(&self, id: usize, req: &Value) {
+		let req_id = req.find("id").cloned().unwrap_or(Value::Null);
+		let params = req.find("params");
+		let res = match req.find("method").and_then(|m| m.as_string()) {
+			Some("login") => {
+				let login = params.and_then(|p| p.find("login")).and_then(|l| l.as_string());
+				if let Some(w) = self.workers.lock().unwrap().get_mut(&id) {
+					w.stats.login = login.unwrap_or("").to_string();
+				}
+				Ok(Value::String("ok".to_string()))
+			}
+			Some("getjobtemplate") => {
+				let job = self.job.read().unwrap();
+				match *job {
+					Some(ref job) => Ok(job_params(job)),
+					None => Err((-32000, "no job yet")),
+				}
+			}
+			Some("submit") => {
+				match params.and_then(parse_submit) {
+					Some((job_id, nonce, proof)) => {
+						self.submit(id, job_id, nonce, proof)
+							.map(|_| Value::String("ok".to_string()))
+							.map_err(|e| (e.code(), e.message()))
+					}
+					None => Err((-32602, "invalid submit params")),
+				}
+			}
+			_ => Err((-32601, "method not found")),
+		};
+
+		let resp = match res {
+			Ok(result) => {
+				ObjectBuilder::new()
+					.insert("id", req_id)
+					.insert("result", result)
+					.unwrap()
+			}
+			Err((code, message)) => {
+				ObjectBuilder::new()
+					.insert("id", req_id)
+					.insert_object("error",
+					               |e| e.insert("code", code).insert("message", message))
+					.unwrap()
+			}
+		};
+		self.send(id, &resp);
+
+		// a worker that just logged in needs something to work on
+		if req.find("method").and_then(|m| m.as_string()) == Some("login") {
+			let job = self.job.read().unwrap();
+			if let Some(ref job) = *job {
+				self.send(id, &job_notification(job));
+			}
+		}
+	}
+
+	/// Checks a solution against the current job and, if it's valid, adds
+	/// the solved block to the chain. The solution counts as a share for the
+	/// worker as long as it reaches the job difficulty, whether the chain
+	/// ends up accepting the block or not.
+	fn submit(&self, id: usize, job_id: u64, nonce: u64, proof: Proof) -> Result<(), SubmitError> {
+		let res = self.check_solution(job_id, nonce, proof);
+		{
+			let mut workers = self.workers.lock().unwrap();
+			let stats = match workers.get_mut(&id) {
+				Some(w) => &mut w.stats,
+				None => return res.map(|_| ()),
+			};
+			match res {
+				Ok(ref b) => {
+					stats.accepted += 1;
+					stats.share_difficulty = stats.share_difficulty.clone() +
+					                         b.header.difficulty.clone();
+				}
+				Err(ref e) => {
+					stats.rejected += 1;
+					debug!(target: LOG_TARGET,
+					       "Rejected solution from Stratum worker {}: {:?}",
+					       id,
+					       e);
+				}
+			}
+		}
+		let b = try!(res);
+
+		info!(target: LOG_TARGET, "Stratum worker {} found a valid proof of work, adding block {}.",
+		      id,
+		      b.hash());
+		let res =
+			chain::process_block(&b, self.chain_store.clone(), self.chain_adapter.clone(), chain::NONE);
+		match res {
+			Ok(tip) => {
+				if let Some(w) = self.workers.lock().unwrap().get_mut(&id) {
+					w.stats.blocks_found += 1;
+				}
+				if let Some(tip) = tip {
+					*self.chain_head.lock().unwrap() = tip;
+				}
+			}
+			Err(e) => error!(target: LOG_TARGET,
+			                 "Error validating block from Stratum worker: {:?}",
+			                 e),
+		}
+		Ok(())
+	}
+
+	// Builds the block solved by the provided nonce and proof, as long as
+	// they're a valid solution for the current job.
+	fn check_solution(&self, job_id: u64, nonce: u64, proof: Proof) -> Result<Block, SubmitError> {
+		let job = self.job.read().unwrap();
+		let job = match *job {
+			Some(ref job) if job.id == job_id => job,
+			_ => return Err(SubmitError::StaleJob),
+		};
+
+		let mut header = job.block.header.clone();
+		header.nonce = nonce;
+		header.pow = proof;
+		if proof.to_difficulty() < header.difficulty {
+			return Err(SubmitError::LowDifficulty);
+		}
+		if !pow::verify(&header) {
+			return Err(SubmitError::InvalidPow);
+		}
+		Ok(Block {
+			header: header,
+			inputs: job.block.inputs.clone(),
+			outputs: job.block.outputs.clone(),
+			proofs: job.block.proofs.clone(),
+		})
+	}
+
+	fn send(&self, id: usize, msg: &Value) {
+		let stream = match self.workers.lock().unwrap().get(&id) {
+			Some(w) => w.stream.clone(),
+			None => return,
+		};
+		let mut line = serde_json::to_string(msg).unwrap();
+		line.push('\n');
+		if let Err(e) = stream.lock().unwrap().write_all(line.as_bytes()) {
+			debug!(target: LOG_TARGET, "Could not send to Stratum worker {}: {}", id, e);
+		}
+	}
+}
+
+fn job_notification(job: &Job) -> Value {
+	ObjectBuilder::new()
+		.insert("method", "job")
+		.insert("params", job_params(job))
+		.unwrap()
+}
+
+fn job_params(job: &Job) -> Value {
+	let header = &job.block.header;
+	ObjectBuilder::new()
+		.insert("job_id", job.id)
+		.insert("height", header.height)
+		.insert("pre_pow", to_hex(&pre_pow(header)))
+		.insert("nonce_offset", NONCE_OFFSET as u64)
+		.insert("cuckoo_len", header.cuckoo_len as u64)
+		.insert("difficulty", header.difficulty.num.to_string())
+		.unwrap()
+}
+
+// Extracts the job id, nonce and cycle of a submit request.
+fn parse_submit(params: &Value) -> Option<(u64, u64, Proof)> {
+	let job_id = params.find("job_id").and_then(|j| j.as_u64());
+	let nonce = params.find("nonce").and_then(|n| n.as_u64());
+	let cycle = params.find("pow").and_then(|p| p.as_array());
+	let (job_id, nonce, cycle) = match (job_id, nonce, cycle) {
+		(Some(j), Some(n), Some(c)) if c.len() == PROOFSIZE => (j, n, c),
+		_ => return None,
+	};
+	let mut proof = Proof::zero();
+	for (n, v) in cycle.iter().enumerate() {
+		match v.as_u64() {
+			Some(v) if v <= u32::max_value() as u64 => proof.0[n] = v as u32,
+			_ => return None,
+		}
+	}
+	Some((job_id, nonce, proof))
+}
+
+/// Header bytes covered by the proof of work hash, everything but the proof
+/// itself.
+fn pre_pow(header: &BlockHeader) -> Vec<u8> {
+	let mut writer = PrePowWriter { bytes: vec![] };
+	ser::Writeable::write(header, &mut writer).unwrap();
+	writer.bytes
+}
+
+struct PrePowWriter {
+	bytes: Vec<u8>,
+}
+
+impl Writer for PrePowWriter {
+	fn serialization_mode(&self) -> ser::SerializationMode {
+		ser::SerializationMode::Hash
+	}
+
+	fn write_fixed_bytes(&mut self, fixed: &AsFixedBytes) -> Result<(), ser::Error> {
+		self.bytes.extend_from_slice(fixed.as_fixed_bytes());
+		Ok(())
+	}
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join("")
+}
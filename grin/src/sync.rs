@@ -17,37 +17,506 @@
 //! Always starts by downloading the header chain before asking either for full
 //! blocks or a full UTXO set with related information.
 
-/// How many block bodies to download in parallel
-const MAX_BODY_DOWNLOADS: usize = 8;
+/// How long, in seconds, we wait for a peer to send a block we asked for
+/// before asking another peer
+const BLOCK_DOWNLOAD_TIMEOUT: u64 = 20;
 
-use std::ops::Deref;
-use std::sync::{Arc, Mutex};
+/// How long, in seconds, sync can go without receiving any header or block
+/// before we give up on the peer we sync from
+pub const SYNC_STALL_TIMEOUT: u64 = 60;
+
+use std::cmp::max;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::{self, Sender};
 use std::thread;
 use std::time::{Instant, Duration};
 
 use core::core::hash::{Hash, Hashed};
 use chain;
+use logging::SERVER_TARGET as LOG_TARGET;
 use p2p;
 
+/// Phase of the synchronization process we're in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncState {
+	/// Waiting for enough peers to connect before starting
+	AwaitingPeers,
+	/// Downloading the header chain
+	HeaderSync,
+	/// Downloading the full blocks for the headers we have
+	BlockSync,
+	/// Caught up with the rest of the network
+	Synced,
+}
+
+/// How far along synchronization is, enough to show a progress bar.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncStatus {
+	/// Height of our full block chain
+	pub current_height: u64,
+	/// Height we're syncing up to, the highest one our peers advertised
+	/// while downloading headers and our header chain height afterward
+	pub target_height: u64,
+	/// Number of headers downloaded so far
+	pub headers_downloaded: u64,
+	/// Number of full blocks downloaded so far
+	pub blocks_downloaded: u64,
+	/// Current sync phase
+	pub state: SyncState,
+	/// Number of headers between our header chain and the highest height our
+	/// peers advertised
+	pub headers_behind: u64,
+	/// Whether sync went without any progress for too long, until headers
+	/// or blocks arrive again
+	pub stalled: bool,
+}
+
+impl SyncStatus {
+	/// Status before sync has started.
+	pub fn new() -> SyncStatus {
+		SyncStatus {
+			current_height: 0,
+			target_height: 0,
+			headers_downloaded: 0,
+			blocks_downloaded: 0,
+			state: SyncState::AwaitingPeers,
+			headers_behind: 0,
+			stalled: false,
+		}
+	}
+
+	/// Moves to the provided phase with the latest heights of our full and
+	/// header chains.
+	pub fn update(&mut self, state: SyncState, current: u64, target: u64) {
+		self.state = state;
+		self.current_height = current;
+		self.target_height = target;
+		self.check_synced();
+	}
+
+	/// Accounts for newly downloaded headers.
+	pub fn headers_received(&mut self, count: u64) {
+		self.headers_downloaded += count;
+	}
+
+	/// Accounts for a newly downloaded full block, with the resulting height
+	/// of our full chain.
+	pub fn block_received(&mut self, current: u64) {
+		self.blocks_downloaded += 1;
+		if current > self.current_height {
+			self.current_height = current;
+		}
+		self.check_synced();
+	}
+
+	// once we're downloading blocks, reaching the header chain height means
+	// we're done
+	fn check_synced(&mut self) {
+		if self.state == SyncState::BlockSync && self.current_height >= self.target_height {
+			self.state = SyncState::Synced;
+		}
+	}
+}
+
+/// Something full blocks can be asked for, usually one of our peers.
+pub trait BlockPeer {
+	/// Address identifying the peer.
+	fn addr(&self) -> SocketAddr;
+
+	/// Sends a request for the block with the provided hash.
+	fn request_block(&self, h: Hash) -> Result<(), p2p::Error>;
+
+	/// Most blocks the peer can send in a single bundle, 1 if it can't send
+	/// bundles at all.
+	fn max_bundle(&self) -> usize {
+		1
+	}
+
+	/// Sends a request for the provided blocks, following each other on our
+	/// header chain, as a single bundle. Falls back to requesting them one
+	/// by one.
+	fn request_blocks(&self, hs: &[Hash]) -> Result<(), p2p::Error> {
+		for h in hs {
+			try!(self.request_block(*h));
+		}
+		Ok(())
+	}
+}
+
+impl BlockPeer for Arc<p2p::Peer> {
+	fn addr(&self) -> SocketAddr {
+		self.info.addr
+	}
+
+	fn request_block(&self, h: Hash) -> Result<(), p2p::Error> {
+		self.send_block_request(h)
+	}
+
+	fn max_bundle(&self) -> usize {
+		if self.info.capabilities.contains(p2p::BLOCK_BUNDLES) {
+			p2p::MAX_BUNDLE_BLOCKS as usize
+		} else {
+			1
+		}
+	}
+
+	fn request_blocks(&self, hs: &[Hash]) -> Result<(), p2p::Error> {
+		match hs.len() {
+			0 => Ok(()),
+			1 => self.send_block_request(hs[0]),
+			n => self.send_block_bundle_request(hs[0], n as u32),
+		}
+	}
+}
+
+/// Schedules the download of full blocks over several peers. Blocks are
+/// assigned to peers round-robin, each peer never having more than a given
+/// number of requests in flight. Peers that can send bundles get the blocks
+/// that follow as part of the same request. Blocks that don't arrive in time
+/// are asked to another peer.
+pub struct BlockScheduler {
+	max_inflight: usize,
+	timeout: Duration,
+	next_peer: usize,
+	pending: VecDeque<Hash>,
+	inflight: HashMap<Hash, (SocketAddr, Instant)>,
+	stalled: HashMap<Hash, SocketAddr>,
+	served: HashMap<Hash, SocketAddr>,
+	served_counts: HashMap<SocketAddr, u64>,
+}
+
+impl BlockScheduler {
+	/// New scheduler allowing max_inflight requests per peer and waiting
+	/// for timeout before asking a block to someone else.
+	pub fn new(max_inflight: usize, timeout: Duration) -> BlockScheduler {
+		BlockScheduler {
+			max_inflight: max_inflight,
+			timeout: timeout,
+			next_peer: 0,
+			pending: VecDeque::new(),
+			inflight: HashMap::new(),
+			stalled: HashMap::new(),
+			served: HashMap::new(),
+			served_counts: HashMap::new(),
+		}
+	}
+
+	/// Adds a block to download, blocks being requested in the order they're
+	/// added.
+	pub fn push(&mut self, h: Hash) {
+		if !self.inflight.contains_key(&h) && !self.pending.contains(&h) {
+			self.pending.push_back(h);
+		}
+	}
+
+	/// Number of blocks still to request.
+	pub fn pending(&self) -> usize {
+		self.pending.len()
+	}
+
+	/// Number of blocks requested that haven't arrived yet.
+	pub fn inflight(&self) -> usize {
+		self.inflight.len()
+	}
+
+	/// Whether all blocks we were asked to download arrived.
+	pub fn is_done(&self) -> bool {
+		self.pending.is_empty() && self.inflight.is_empty()
+	}
+
+	/// Puts back the blocks that took longer than the timeout to arrive,
+	/// then requests as many pending blocks as the provided peers have room
+	/// for. Returns the number of blocks requested.
+	pub fn schedule<P: BlockPeer>(&mut self, peers: &[P], now: Instant) -> usize {
+		self.expire(now);
+
+		let mut requested = 0;
+		let mut failed = vec![];
+		let mut skipped = vec![];
+		while let Some(h) = self.pending.pop_front() {
+			if !peers.iter().any(|p| !failed.contains(&p.addr()) && self.has_room(p.addr())) {
+				self.pending.push_front(h);
+				break;
+			}
+			let n = match self.pick_peer(peers, &h, &failed) {
+				Some(n) => n,
+				None => {
+					skipped.push(h);
+					continue;
+				}
+			};
+			let addr = peers[n].addr();
+			let hs = self.bundle(h, peers[n].max_bundle());
+			match peers[n].request_blocks(&hs) {
+				Ok(()) => {
+					for h in &hs {
+						self.inflight.insert(*h, (addr, now));
+					}
+					requested += hs.len();
+				}
+				Err(e) => {
+					debug!(target: LOG_TARGET,
+					       "Could not request block {} from peer {}: {}",
+					       h,
+					       addr,
+					       e);
+					failed.push(addr);
+					skipped.extend(hs);
+				}
+			}
+		}
+		for h in skipped.into_iter().rev() {
+			self.pending.push_front(h);
+		}
+		requested
+	}
+
+	/// A block we asked for arrived, returns the peer we asked it to.
+	pub fn received(&mut self, h: Hash) -> Option<SocketAddr> {
+		self.stalled.remove(&h);
+		self.pending.retain(|p| *p != h);
+		self.inflight.remove(&h).map(|(addr, _)| {
+			self.served.insert(h, addr);
+			*self.served_counts.entry(addr).or_insert(0) += 1;
+			addr
+		})
+	}
+
+	/// The peer that served the block with the provided hash.
+	pub fn served_by(&self, h: &Hash) -> Option<SocketAddr> {
+		self.served.get(h).cloned()
+	}
+
+	/// Number of blocks the peer at the provided address served.
+	pub fn served_count(&self, addr: SocketAddr) -> u64 {
+		self.served_counts.get(&addr).cloned().unwrap_or(0)
+	}
+
+	// Moves the requests that timed out back to the front of the pending
+	// blocks, remembering which peer failed us
+	fn expire(&mut self, now: Instant) {
+		let timeout = self.timeout;
+		let expired = self.inflight
+			.iter()
+			.filter(|&(_, &(_, at))| now.duration_since(at) >= timeout)
+			.map(|(h, &(addr, _))| (*h, addr))
+			.collect::<Vec<_>>();
+		for (h, addr) in expired {
+			debug!(target: LOG_TARGET,
+			       "Peer {} didn't send block {} in time, asking someone else.",
+			       addr,
+			       h);
+			self.inflight.remove(&h);
+			self.stalled.insert(h, addr);
+			self.pending.push_front(h);
+		}
+	}
+
+	// Next peer in turn with room for one more request, skipping the peer
+	// that already failed to send us the block unless it's the only one
+	fn pick_peer<P: BlockPeer>(&mut self,
+	                           peers: &[P],
+	                           h: &Hash,
+	                           failed: &[SocketAddr])
+	                           -> Option<usize> {
+		let stalled = self.stalled.get(h).cloned();
+		for i in 0..peers.len() {
+			let n = (self.next_peer + i) % peers.len();
+			let addr = peers[n].addr();
+			if failed.contains(&addr) || !self.has_room(addr) {
+				continue;
+			}
+			if stalled == Some(addr) && peers.len() > 1 {
+				continue;
+			}
+			self.next_peer = n + 1;
+			return Some(n);
+		}
+		None
+	}
+
+	// The block with the provided hash along with the pending blocks right
+	// after it, as many as the peer can send at once. Blocks that already
+	// failed to arrive are asked for on their own, in case they didn't fit
+	// in a bundle.
+	fn bundle(&mut self, h: Hash, max_bundle: usize) -> Vec<Hash> {
+		let mut hs = vec![h];
+		if self.stalled.contains_key(&h) {
+			return hs;
+		}
+		while hs.len() < max_bundle {
+			match self.pending.front() {
+				Some(next) if !self.stalled.contains_key(next) => {}
+				_ => break,
+			}
+			hs.extend(self.pending.pop_front());
+		}
+		hs
+	}
+
+	fn has_room(&self, addr: SocketAddr) -> bool {
+		self.inflight.values().filter(|&&(a, _)| a == addr).count() < self.max_inflight
+	}
+}
+
+/// Notices when sync stops making progress, no header or block arriving for
+/// longer than a timeout, usually because the peer we sync from went silent.
+pub struct StallDetector {
+	timeout: Duration,
+	last_progress: Instant,
+	peer: Option<SocketAddr>,
+	stalled: bool,
+}
+
+impl StallDetector {
+	/// New detector allowing for timeout without progress, counting from now.
+	pub fn new(timeout: Duration, now: Instant) -> StallDetector {
+		StallDetector {
+			timeout: timeout,
+			last_progress: now,
+			peer: None,
+			stalled: false,
+		}
+	}
+
+	/// The peer we're now syncing from. A different peer than the previous
+	/// one gets the whole timeout to make progress.
+	pub fn syncing_from(&mut self, addr: SocketAddr, now: Instant) {
+		if self.peer != Some(addr) {
+			self.peer = Some(addr);
+			self.last_progress = now;
+		}
+	}
+
+	/// The peer we're syncing from, if any.
+	pub fn sync_peer(&self) -> Option<SocketAddr> {
+		self.peer
+	}
+
+	/// Headers or blocks arrived, clears any stall.
+	pub fn progress(&mut self, now: Instant) {
+		self.last_progress = now;
+		self.stalled = false;
+	}
+
+	/// Whether sync is stalled, from the moment it's noticed until progress
+	/// resumes.
+	pub fn is_stalled(&self) -> bool {
+		self.stalled
+	}
+
+	/// Whether we went longer than the timeout without progress, true again
+	/// after each further timeout. The peer we were syncing from is then
+	/// forgotten and the clock starts over for the next one.
+	pub fn check(&mut self, now: Instant) -> bool {
+		if now.duration_since(self.last_progress) < self.timeout {
+			return false;
+		}
+		self.stalled = true;
+		self.peer = None;
+		self.last_progress = now;
+		true
+	}
+}
+
+/// Called with the height of our full chain and the height we're syncing up
+/// to as blocks get connected while syncing, to show progress in a UI.
+pub type ProgressCallback = Box<Fn(u64, u64) + Send>;
+
+/// Reports block download progress to a registered callback, at most once
+/// every few blocks and always for the block reaching the target. The
+/// callback runs on its own thread so a slow one never holds sync back,
+/// only getting the latest progress once it falls behind.
+pub struct SyncProgress {
+	reporter: Mutex<Option<ProgressReporter>>,
+}
+
+struct ProgressReporter {
+	tx: Sender<(u64, u64)>,
+	every: u64,
+	// height we last reported
+	last: u64,
+}
+
+impl SyncProgress {
+	/// No callback registered yet, progress goes unreported.
+	pub fn new() -> SyncProgress {
+		SyncProgress { reporter: Mutex::new(None) }
+	}
+
+	/// Registers the callback, replacing any previous one, to be called at
+	/// most once every provided number of blocks.
+	pub fn set_callback(&self, callback: ProgressCallback, every: u64) {
+		let (tx, rx) = mpsc::channel::<(u64, u64)>();
+		let _ = thread::Builder::new().name("sync-progress".to_string()).spawn(move || {
+			while let Ok(mut progress) = rx.recv() {
+				while let Ok(latest) = rx.try_recv() {
+					progress = latest;
+				}
+				callback(progress.0, progress.1);
+			}
+		});
+		*self.reporter.lock().unwrap() = Some(ProgressReporter {
+			tx: tx,
+			every: max(every, 1),
+			last: 0,
+		});
+	}
+
+	/// A block got connected while syncing, taking our full chain to the
+	/// provided height out of the target one. Heights that don't move past
+	/// the last reported one are never reported.
+	pub fn block_connected(&self, current: u64, target: u64) {
+		let mut reporter = self.reporter.lock().unwrap();
+		let gone = match *reporter {
+			Some(ref mut r) => {
+				if current <= r.last || (current < r.last + r.every && current < target) {
+					return;
+				}
+				r.last = current;
+				r.tx.send((current, target)).is_err()
+			}
+			None => return,
+		};
+		// the callback panicked, taking its thread down
+		if gone {
+			*reporter = None;
+		}
+	}
+}
+
 pub struct Syncer {
 	chain_store: Arc<chain::ChainStore>,
 	p2p: Arc<p2p::Server>,
+	status: Arc<RwLock<SyncStatus>>,
+	progress: Arc<SyncProgress>,
 
 	sync: Mutex<bool>,
 	last_header_req: Mutex<Instant>,
-	blocks_to_download: Mutex<Vec<Hash>>,
-	blocks_downloading: Mutex<Vec<(Hash, Instant)>>,
+	downloads: Mutex<BlockScheduler>,
+	stall: Mutex<StallDetector>,
 }
 
 impl Syncer {
-	pub fn new(chain_store: Arc<chain::ChainStore>, p2p: Arc<p2p::Server>) -> Syncer {
+	pub fn new(chain_store: Arc<chain::ChainStore>,
+	           p2p: Arc<p2p::Server>,
+	           status: Arc<RwLock<SyncStatus>>,
+	           progress: Arc<SyncProgress>,
+	           max_inflight_blocks: usize,
+	           stall_timeout: Duration)
+	           -> Syncer {
 		Syncer {
 			chain_store: chain_store,
 			p2p: p2p,
+			status: status,
+			progress: progress,
 			sync: Mutex::new(true),
 			last_header_req: Mutex::new(Instant::now() - Duration::from_secs(2)),
-			blocks_to_download: Mutex::new(vec![]),
-			blocks_downloading: Mutex::new(vec![]),
+			downloads: Mutex::new(BlockScheduler::new(max_inflight_blocks,
+			                                          Duration::from_secs(BLOCK_DOWNLOAD_TIMEOUT))),
+			stall: Mutex::new(StallDetector::new(stall_timeout, Instant::now())),
 		}
 	}
 
@@ -58,7 +527,7 @@ impl Syncer {
 	/// Checks the local chain state, comparing it with our peers and triggers
 	/// syncing if required.
 	pub fn run(&self) -> Result<(), chain::Error> {
-		debug!("Starting syncer.");
+		debug!(target: LOG_TARGET, "Starting syncer.");
 		let start = Instant::now();
 		loop {
 			let pc = self.p2p.peer_count();
@@ -76,18 +545,37 @@ impl Syncer {
 
 		// main syncing loop, requests more headers and bodies periodically as long
 		// as a peer with higher difficulty exists and we're not fully caught up
-		info!("Starting sync loop.");
+		info!(target: LOG_TARGET, "Starting sync loop.");
 		loop {
 			let tip = self.chain_store.get_header_head()?;
-			// TODO do something better (like trying to get more) if we lose peers
-			let peer = self.p2p.most_work_peer().unwrap();
-
-			let more_headers = peer.info.total_difficulty > tip.total_difficulty;
-			let more_bodies = {
-				let blocks_to_download = self.blocks_to_download.lock().unwrap();
-				let blocks_downloading = self.blocks_downloading.lock().unwrap();
-				blocks_to_download.len() > 0 || blocks_downloading.len() > 0
+			let head = self.chain_store.head()?;
+			// only a peer ahead of our full chain can have more headers for us
+			let more_headers = match self.p2p.most_work_peer() {
+				Some(peer) => peer.total_difficulty() > tip.total_difficulty,
+				None => false,
+			};
+			let more_bodies = !self.downloads.lock().unwrap().is_done();
+
+			let state = if more_headers {
+				SyncState::HeaderSync
+			} else if more_bodies {
+				SyncState::BlockSync
+			} else {
+				SyncState::Synced
 			};
+			let target = if state == SyncState::HeaderSync {
+				max(tip.height, self.p2p.max_peer_height())
+			} else {
+				tip.height
+			};
+			{
+				let mut status = self.status.write().unwrap();
+				status.update(state, head.height, target);
+				status.headers_behind = target.saturating_sub(tip.height);
+			}
+			if more_headers || more_bodies {
+				self.check_stall();
+			}
 
 			{
 				let last_header_req = self.last_header_req.lock().unwrap().clone();
@@ -107,63 +595,116 @@ impl Syncer {
 
 			thread::sleep(Duration::from_secs(2));
 		}
-		info!("Sync done.");
+		info!(target: LOG_TARGET, "Sync done.");
 		Ok(())
 	}
 
+	/// Drops the peer we sync from if we haven't received anything for too
+	/// long, the next header request going to the peer with the most work
+	/// left.
+	fn check_stall(&self) {
+		let mut stall = self.stall.lock().unwrap();
+		let peer = stall.sync_peer();
+		if !stall.check(Instant::now()) {
+			return;
+		}
+		self.status.write().unwrap().stalled = true;
+		if let Some(addr) = peer {
+			warn!(target: LOG_TARGET,
+			      "Sync with peer {} stalled, switching to another peer.",
+			      addr);
+			if let Some(p) = self.p2p.connected_peers().into_iter().find(|p| p.info.addr == addr) {
+				p.stop();
+			}
+			// asks the next peer right away
+			*self.last_header_req.lock().unwrap() = Instant::now() - Duration::from_secs(2);
+		} else {
+			warn!(target: LOG_TARGET, "Sync made no progress for a while.");
+		}
+	}
+
+	// Headers or blocks arrived, sync isn't stalled
+	fn progress(&self) {
+		let mut stall = self.stall.lock().unwrap();
+		stall.progress(Instant::now());
+		self.status.write().unwrap().stalled = false;
+	}
+
 	/// Checks the gap between the header chain and the full block chain and
-	/// initializes the blocks_to_download structure with the missing full
-	/// blocks
+	/// schedules the download of the missing full blocks
 	fn init_download(&self) -> Result<(), chain::Error> {
 		// compare the header's head to the full one to see what we're missing
 		let header_head = self.chain_store.get_header_head()?;
 		let full_head = self.chain_store.head()?;
-		let mut blocks_to_download = self.blocks_to_download.lock().unwrap();
 
 		// go back the chain and insert for download all blocks we only have the
 		// head for
+		let mut missing = vec![];
 		let mut prev_h = header_head.last_block_h;
 		while prev_h != full_head.last_block_h {
 			let header = self.chain_store.get_block_header(&prev_h)?;
-			blocks_to_download.push(header.hash());
+			missing.push(header.hash());
 			prev_h = header.previous;
 		}
 
-		debug!("Added {} full block hashes to download.",
-		       blocks_to_download.len());
+		debug!(target: LOG_TARGET, "Added {} full block hashes to download.", missing.len());
+		let mut downloads = self.downloads.lock().unwrap();
+		for h in missing.into_iter().rev() {
+			downloads.push(h);
+		}
 		Ok(())
 	}
 
-	/// Asks for the blocks we haven't downloaded yet and place them in the
-	/// downloading structure.
+	/// Asks for the blocks we haven't downloaded yet. Requests are spread
+	/// over all the peers that have more work than us, so bodies download
+	/// from several of them in parallel.
 	fn request_bodies(&self) {
-		let mut blocks_downloading = self.blocks_downloading.lock().unwrap();
-		if blocks_downloading.len() > MAX_BODY_DOWNLOADS {
-			// clean up potentially dead downloads
-			let twenty_sec_ago = Instant::now() - Duration::from_secs(20);
-			blocks_downloading.iter()
-				.position(|&h| h.1 < twenty_sec_ago)
-				.map(|n| blocks_downloading.remove(n));
+		let peers = self.body_peers();
+		if peers.is_empty() {
+			debug!(target: LOG_TARGET, "No peer to download full blocks from.");
+			return;
+		}
+		let mut downloads = self.downloads.lock().unwrap();
+		let requested = downloads.schedule(&peers, Instant::now());
+		debug!(target: LOG_TARGET, "Requested {} full blocks, {} more to download.",
+		       requested,
+		       downloads.pending());
+	}
+
+	// Peers worth asking for full blocks, the ones ahead of us or at least
+	// one of our peers if none of them is.
+	fn body_peers(&self) -> Vec<Arc<p2p::Peer>> {
+		let head = match self.chain_store.head() {
+			Ok(head) => head,
+			Err(_) => return self.p2p.random_peer().into_iter().collect(),
+		};
+		let peers = self.p2p
+			.connected_peers()
+			.into_iter()
+			.filter(|p| p.total_difficulty() > head.total_difficulty)
+			.collect::<Vec<_>>();
+		if peers.is_empty() {
+			self.p2p.random_peer().into_iter().collect()
 		} else {
-			// consume hashes from blocks to download, place them in downloading and
-			// request them from the network
-			let mut blocks_to_download = self.blocks_to_download.lock().unwrap();
-			while blocks_to_download.len() > 0 && blocks_downloading.len() < MAX_BODY_DOWNLOADS {
-				let h = blocks_to_download.pop().unwrap();
-				let peer = self.p2p.random_peer().unwrap();
-				peer.send_block_request(h);
-				blocks_downloading.push((h, Instant::now()));
-			}
-			debug!("Requesting more full block hashes to download, total: {}.",
-			       blocks_to_download.len());
+			peers
 		}
 	}
 
 	/// We added a block, clean up the downloading structure
 	pub fn block_received(&self, bh: Hash) {
-		// just clean up the downloading list
-		let mut bds = self.blocks_downloading.lock().unwrap();
-		bds.iter().position(|&h| h.0 == bh).map(|n| bds.remove(n));
+		if let Some(addr) = self.downloads.lock().unwrap().received(bh) {
+			debug!(target: LOG_TARGET, "Got block {} from peer {}.", bh, addr);
+		}
+		self.progress();
+
+		if let Ok(head) = self.chain_store.head() {
+			let target = {
+				let mut status = self.status.write().unwrap();
+				status.block_received(head.height);
+				status.target_height
+			};
+			self.progress.block_connected(head.height, target);
+		}
 	}
 
 	/// Request some block headers from a peer to advance us
@@ -173,61 +714,33 @@ impl Syncer {
 			*last_header_req = Instant::now();
 		}
 
-		let tip = self.chain_store.get_header_head()?;
 		let peer = self.p2p.most_work_peer();
-		let locator = self.get_locator(&tip)?;
+		let locator = self.chain_store.get_locator()?;
 		if let Some(p) = peer {
-			debug!("Asking peer {} for more block headers.", p.info.addr);
+			debug!(target: LOG_TARGET, "Asking peer {} for more block headers.", p.info.addr);
+			self.stall.lock().unwrap().syncing_from(p.info.addr, Instant::now());
 			p.send_header_request(locator)?;
 		} else {
-			warn!("Could not get most worked peer to request headers.");
+			warn!(target: LOG_TARGET, "Could not get most worked peer to request headers.");
 		}
 		Ok(())
 	}
 
 	/// We added a header, add it to the full block download list
 	pub fn headers_received(&self, bhs: Vec<Hash>) {
-		let mut blocks_to_download = self.blocks_to_download.lock().unwrap();
 		let hs_len = bhs.len();
-		for h in bhs {
+		self.status.write().unwrap().headers_received(hs_len as u64);
+		self.progress();
+		{
 			// enlist for full block download
-			blocks_to_download.insert(0, h);
+			let mut downloads = self.downloads.lock().unwrap();
+			for h in bhs {
+				downloads.push(h);
+			}
 		}
 		// ask for more headers if we got as many as required
 		if hs_len == (p2p::MAX_BLOCK_HEADERS as usize) {
 			self.request_headers();
 		}
 	}
-
-	/// Builds a vector of block hashes that should help the remote peer sending
-	/// us the right block headers.
-	fn get_locator(&self, tip: &chain::Tip) -> Result<Vec<Hash>, chain::Error> {
-		// Prepare the heights we want as the latests height minus increasing powers
-		// of 2 up to max.
-		let mut heights = vec![tip.height];
-		let mut tail = (1..p2p::MAX_LOCATORS)
-			.map(|n| 2u64.pow(n))
-			.filter_map(|n| if n > tip.height {
-				None
-			} else {
-				Some(tip.height - n)
-			})
-			.collect::<Vec<_>>();
-		heights.append(&mut tail);
-
-		// Iteratively travel the header chain back from our head and retain the
-		// headers at the wanted heights.
-		let mut header = self.chain_store.get_block_header(&tip.last_block_h)?;
-		let mut locator = vec![];
-		while heights.len() > 0 {
-			if header.height == heights[0] {
-				heights = heights[1..].to_vec();
-				locator.push(header.hash());
-			}
-			if header.height > 0 {
-				header = self.chain_store.get_block_header(&header.previous)?;
-			}
-		}
-		Ok(locator)
-	}
 }
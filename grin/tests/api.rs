@@ -0,0 +1,143 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_grin as grin;
+extern crate grin_p2p as p2p;
+
+extern crate futures;
+extern crate serde_json;
+extern crate tokio_core;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use futures::{Future, Poll, Async};
+use futures::task::park;
+use serde_json::Value;
+use tokio_core::reactor;
+
+#[test]
+fn status_and_peers() {
+  let mut evtlp = reactor::Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut servers = vec![];
+  for n in 0..2 {
+    let s = grin::Server::future(
+        grin::ServerConfig{
+          data_dir: format!("target/grin-api-{}", n),
+          cuckoo_size: 12,
+          p2p_config: p2p::P2PConfig{port: 12000+n, ..p2p::P2PConfig::default()},
+          api_addr: Some(format!("127.0.0.1:{}", 12100+n).parse().unwrap()),
+          ..grin::ServerConfig::default()
+        }, &handle).unwrap();
+    servers.push(s);
+  }
+
+  // a couple of blocks on top of whatever a previous run left
+  let start = servers[0].head().height;
+  servers[0].start_miner();
+  evtlp.run(until(|| servers[0].head().height >= start + 2)).unwrap();
+
+  let before = servers[0].head();
+  let status = get(12100, "/status");
+  let after = servers[0].head();
+  let height = status.find("height").unwrap().as_u64().unwrap();
+  assert!(height >= before.height && height <= after.height);
+  if before.height == after.height {
+    assert_eq!(status.find("hash").unwrap().as_string(),
+               Some(&before.last_block_h.to_string()[..]));
+  }
+  assert_eq!(status.find("peer_count").unwrap().as_u64(), Some(0));
+  assert!(status.find("sync").and_then(|s| s.find("state")).is_some());
+  // mining reads recent headers again and again
+  let cache = status.find("header_cache").unwrap();
+  assert!(cache.find("hits").unwrap().as_u64().unwrap() > 0);
+  assert_eq!(get(12100, "/peers").as_array().map(|p| p.len()), Some(0));
+  assert_eq!(status.find("mining").unwrap().as_boolean(), Some(true));
+  assert_eq!(get(12101, "/status").find("mining").unwrap().as_boolean(), Some(false));
+
+  // mining stops until resumed, past a block that may be getting added
+  servers[0].miner().pause();
+  assert!(!servers[0].miner().is_mining());
+  // starting again leaves the running miner alone
+  servers[0].start_miner();
+  assert!(servers[0].miner().is_started());
+  assert!(!servers[0].miner().is_mining());
+  assert_eq!(get(12100, "/status").find("mining").unwrap().as_boolean(), Some(false));
+  thread::sleep(Duration::from_millis(200));
+  let paused = servers[0].head();
+  thread::sleep(Duration::from_millis(500));
+  assert_eq!(servers[0].head().last_block_h, paused.last_block_h);
+
+  servers[0].miner().resume();
+  assert!(servers[0].miner().is_mining());
+  evtlp.run(until(|| servers[0].head().height > paused.height)).unwrap();
+
+  // once connected, each side sees the other in the right direction
+  servers[0].connect_peer("127.0.0.1:12001".parse().unwrap()).unwrap();
+  evtlp.run(until(|| peer_count(12100) > 0 && peer_count(12101) > 0)).unwrap();
+
+  let peers = get(12100, "/peers");
+  let peers = peers.as_array().unwrap();
+  assert_eq!(peers.len(), 1);
+  assert_eq!(peers[0].find("addr").unwrap().as_string(), Some("127.0.0.1:12001"));
+  assert_eq!(peers[0].find("direction").unwrap().as_string(), Some("outbound"));
+  assert!(peers[0].find("sent_bytes").and_then(|b| b.as_u64()).is_some());
+
+  let peers = get(12101, "/peers");
+  let peers = peers.as_array().unwrap();
+  assert_eq!(peers[0].find("direction").unwrap().as_string(), Some("inbound"));
+  assert_eq!(peer_count(12101), 1);
+}
+
+fn peer_count(port: u16) -> u64 {
+  get(port, "/status").find("peer_count").unwrap().as_u64().unwrap()
+}
+
+fn get(port: u16, path: &str) -> Value {
+  let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+  write!(stream, "GET {} HTTP/1.0\r\n\r\n", path).unwrap();
+  let mut resp = String::new();
+  stream.read_to_string(&mut resp).unwrap();
+  assert!(resp.starts_with("HTTP/1.0 200"));
+  let body = &resp[resp.find("\r\n\r\n").unwrap() + 4..];
+  serde_json::from_str(body).unwrap()
+}
+
+fn until<F: Fn() -> bool>(f: F) -> Until<F> {
+  Until{cond: f}
+}
+
+/// Future resolving once the provided condition holds, polling it
+/// continuously. Only use for tests.
+struct Until<F> {
+  cond: F,
+}
+
+impl<F: Fn() -> bool> Future for Until<F> {
+  type Item = ();
+  type Error = ();
+
+  fn poll(&mut self) -> Poll<(), ()> {
+    if (self.cond)() {
+      Ok(Async::Ready(()))
+    } else {
+      park().unpark();
+      Ok(Async::NotReady)
+    }
+  }
+}
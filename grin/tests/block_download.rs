@@ -0,0 +1,171 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_grin as grin;
+extern crate grin_p2p as p2p;
+
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use core::core::hash::{Hash, Hashed};
+use grin::{BlockPeer, BlockScheduler};
+
+// Peer remembering the blocks it's been asked for, and in how many bundles,
+// the test decides whether they ever arrive.
+struct MockPeer {
+  addr: SocketAddr,
+  max_bundle: usize,
+  requested: Mutex<Vec<Hash>>,
+  bundles: Mutex<usize>,
+}
+
+impl MockPeer {
+  fn new(port: u16) -> MockPeer {
+    MockPeer::bundling(port, 1)
+  }
+
+  fn bundling(port: u16, max_bundle: usize) -> MockPeer {
+    MockPeer {
+      addr: format!("10.0.0.1:{}", port).parse().unwrap(),
+      max_bundle: max_bundle,
+      requested: Mutex::new(vec![]),
+      bundles: Mutex::new(0),
+    }
+  }
+
+  fn take_requested(&self) -> Vec<Hash> {
+    self.requested.lock().unwrap().drain(..).collect()
+  }
+}
+
+impl<'a> BlockPeer for &'a MockPeer {
+  fn addr(&self) -> SocketAddr {
+    self.addr
+  }
+  fn request_block(&self, h: Hash) -> Result<(), p2p::Error> {
+    self.requested.lock().unwrap().push(h);
+    Ok(())
+  }
+  fn max_bundle(&self) -> usize {
+    self.max_bundle
+  }
+  fn request_blocks(&self, hs: &[Hash]) -> Result<(), p2p::Error> {
+    if hs.len() > 1 {
+      *self.bundles.lock().unwrap() += 1;
+    }
+    self.requested.lock().unwrap().extend_from_slice(hs);
+    Ok(())
+  }
+}
+
+fn hashes(n: u64) -> Vec<Hash> {
+  (0..n).map(|i| [i as u8][..].hash()).collect()
+}
+
+#[test]
+fn round_robin_distribution() {
+  let peers = vec![MockPeer::new(1), MockPeer::new(2), MockPeer::new(3)];
+  let refs = peers.iter().collect::<Vec<_>>();
+  let mut sched = BlockScheduler::new(4, Duration::from_secs(10));
+  for h in hashes(30) {
+    sched.push(h);
+  }
+
+  // no peer ever gets more than 4 requests at once
+  let now = Instant::now();
+  assert_eq!(sched.schedule(&refs, now), 12);
+  assert_eq!(sched.schedule(&refs, now), 0);
+  for p in &peers {
+    assert_eq!(p.requested.lock().unwrap().len(), 4);
+  }
+
+  while !sched.is_done() {
+    for p in &peers {
+      for h in p.take_requested() {
+        assert_eq!(sched.received(h), Some(p.addr));
+      }
+    }
+    sched.schedule(&refs, now);
+  }
+  for p in &peers {
+    let served = sched.served_count(p.addr);
+    assert!(served >= 9 && served <= 11, "peer served {} blocks", served);
+  }
+}
+
+#[test]
+fn stalled_peer_reassigned() {
+  let peers = vec![MockPeer::new(1), MockPeer::new(2), MockPeer::new(3)];
+  let refs = peers.iter().collect::<Vec<_>>();
+  let mut sched = BlockScheduler::new(4, Duration::from_secs(10));
+  for h in hashes(30) {
+    sched.push(h);
+  }
+
+  // the third peer never answers, the others get the rest of the blocks
+  let start = Instant::now();
+  sched.schedule(&refs, start);
+  let stalled = peers[2].take_requested();
+  assert_eq!(stalled.len(), 4);
+  loop {
+    for p in &peers[..2] {
+      for h in p.take_requested() {
+        sched.received(h);
+      }
+    }
+    if sched.pending() == 0 {
+      break;
+    }
+    sched.schedule(&refs, start);
+  }
+  assert_eq!(sched.inflight(), 4);
+  assert!(!sched.is_done());
+
+  // until its requests time out and go to someone else
+  sched.schedule(&refs, start + Duration::from_secs(11));
+  assert!(peers[2].take_requested().is_empty());
+  for p in &peers[..2] {
+    for h in p.take_requested() {
+      sched.received(h);
+    }
+  }
+  assert!(sched.is_done());
+  for h in &stalled {
+    let by = sched.served_by(h).unwrap();
+    assert!(by == peers[0].addr || by == peers[1].addr);
+  }
+  assert_eq!(sched.served_count(peers[2].addr), 0);
+  assert_eq!(sched.served_count(peers[0].addr) + sched.served_count(peers[1].addr), 30);
+}
+
+#[test]
+fn bundles_to_capable_peers() {
+  let peers = vec![MockPeer::bundling(1, 16), MockPeer::new(2)];
+  let refs = peers.iter().collect::<Vec<_>>();
+  let mut sched = BlockScheduler::new(4, Duration::from_secs(10));
+  let hs = hashes(40);
+  for h in &hs {
+    sched.push(*h);
+  }
+
+  // the first peer gets a whole bundle as a single request, the other one
+  // block at a time up to its limit
+  assert_eq!(sched.schedule(&refs, Instant::now()), 20);
+  assert_eq!(*peers[0].requested.lock().unwrap(), hs[..16].to_vec());
+  assert_eq!(*peers[0].bundles.lock().unwrap(), 1);
+  assert_eq!(*peers[1].requested.lock().unwrap(), hs[16..20].to_vec());
+  assert_eq!(*peers[1].bundles.lock().unwrap(), 0);
+}
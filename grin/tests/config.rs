@@ -0,0 +1,134 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_grin as grin;
+extern crate grin_p2p as p2p;
+extern crate log;
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use grin::{ConfigError, ServerConfig};
+use log::LogLevelFilter;
+
+#[test]
+fn full_config_file() {
+  let path = write_config("grin-config-full.toml",
+                          r#"
+data_dir = "target/grin-config-full"
+
+[chain]
+max_reorg_depth = 100
+max_future_time = 300
+verify_chain_depth = 50
+compact_idle_time = 600
+
+[p2p]
+host = "0.0.0.0"
+port = 13500
+external_addr = "1.2.3.4:13500"
+seeds = ["seed.grin.test", "seed2.grin.test:13414"]
+
+[api]
+port = 13501
+
+[mining]
+enabled = true
+threads = 3
+
+[log]
+level = "warn"
+
+[log.targets]
+"grin::p2p" = "debug"
+"grin::chain" = "error"
+"#);
+  let config = ServerConfig::from_file(&path).unwrap();
+  assert_eq!(config.data_dir, "target/grin-config-full");
+  assert_eq!(config.max_reorg_depth, 100);
+  assert_eq!(config.max_future_time, 300);
+  assert_eq!(config.verify_chain_depth, Some(50));
+  assert_eq!(config.compact_idle_time, Some(600));
+  assert_eq!(config.p2p_config.host, "0.0.0.0".parse::<std::net::IpAddr>().unwrap());
+  assert_eq!(config.p2p_config.port, 13500);
+  assert_eq!(config.p2p_config.p2p_listen_addr, None);
+  assert_eq!(config.p2p_config.external_addr(), "1.2.3.4:13500".parse().unwrap());
+  assert_eq!(config.p2p_config.dns_seeds,
+             vec!["seed.grin.test".to_string(), "seed2.grin.test:13414".to_string()]);
+  assert_eq!(config.api_addr, Some("127.0.0.1:13501".parse().unwrap()));
+  assert!(config.enable_mining);
+  assert_eq!(config.miner_threads, 3);
+  assert_eq!(config.log_level, LogLevelFilter::Warn);
+  assert_eq!(config.log_targets,
+             vec![("grin::chain".to_string(), LogLevelFilter::Error),
+                  ("grin::p2p".to_string(), LogLevelFilter::Debug)]);
+  assert!(Path::new("target/grin-config-full").is_dir());
+}
+
+// Only sets the p2p port, along with a key we don't know about, everything
+// else should be the defaults.
+#[test]
+fn partial_config_file() {
+  let path = write_config("grin-config-partial.toml",
+                          r#"
+data_dir = "target/grin-config-partial"
+cuckoo = 16
+
+[p2p]
+port = 13502
+"#);
+  let config = ServerConfig::from_file(&path).unwrap();
+  let default = ServerConfig::default();
+  let default_p2p = p2p::P2PConfig::default();
+  assert_eq!(config.p2p_config.port, 13502);
+  assert_eq!(config.p2p_config.host, default_p2p.host);
+  assert!(config.p2p_config.dns_seeds.is_empty());
+  assert_eq!(config.api_addr, None);
+  assert_eq!(config.enable_mining, default.enable_mining);
+  assert_eq!(config.miner_threads, default.miner_threads);
+  assert_eq!(config.cuckoo_size, default.cuckoo_size);
+  assert_eq!(config.max_reorg_depth, default.max_reorg_depth);
+  assert_eq!(config.max_future_time, default.max_future_time);
+  assert_eq!(config.verify_chain_depth, None);
+  assert_eq!(config.compact_idle_time, None);
+  assert_eq!(config.log_level, default.log_level);
+  assert!(config.log_targets.is_empty());
+  assert_eq!(config.unknown_keys, vec!["cuckoo".to_string()]);
+}
+
+#[test]
+fn invalid_config_file() {
+  match ServerConfig::from_toml("[p2p]\nport = \"13414\"\n") {
+    Err(ConfigError::InvalidValue(key, _)) => assert_eq!(key, "p2p.port"),
+    res => panic!("Expected an invalid value, got {:?}", res),
+  }
+
+  // a file where the data directory should be
+  let _ = fs::create_dir_all("target");
+  File::create("target/grin-config-file").unwrap();
+  let path = write_config("grin-config-file.toml",
+                          "data_dir = \"target/grin-config-file/data\"");
+  match ServerConfig::from_file(&path) {
+    Err(ConfigError::DataDirErr(dir, _)) => assert_eq!(dir, "target/grin-config-file/data"),
+    res => panic!("Expected a data directory error, got {:?}", res),
+  }
+}
+
+fn write_config(name: &str, content: &str) -> String {
+  let _ = fs::create_dir_all("target");
+  let path = format!("target/{}", name);
+  File::create(&path).unwrap().write_all(content.as_bytes()).unwrap();
+  path
+}
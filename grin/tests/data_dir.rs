@@ -0,0 +1,69 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_grin as grin;
+#[macro_use]
+extern crate log;
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::Read;
+use std::path::Path;
+
+use log::LogLevelFilter;
+
+use grin::DataDir;
+
+#[test]
+fn data_dir_layout() {
+  let root = "target/grin-data-dir";
+  let _ = fs::remove_dir_all(root);
+  let data_dir = DataDir::new(root);
+  assert_eq!(data_dir.root(), Path::new(root));
+  // nothing until asked for
+  assert!(!Path::new(root).exists());
+
+  let chain = data_dir.chain_db_path().unwrap();
+  let peers = data_dir.peer_db_path().unwrap();
+  let log = data_dir.log_path().unwrap();
+  assert_eq!(chain, Path::new(root).join("chain"));
+  assert_eq!(peers, Path::new(root).join("peers"));
+  assert_eq!(log, Path::new(root).join("logs").join("grin.log"));
+  assert!(chain.is_dir());
+  assert!(peers.is_dir());
+  assert!(log.parent().unwrap().is_dir());
+  assert!(!log.exists());
+
+  // asking again is fine
+  assert_eq!(data_dir.chain_db_path().unwrap(), chain);
+}
+
+// Logs go to the log file of the data directory as well.
+#[test]
+fn logs_in_log_file() {
+  let root = "target/grin-data-dir-logs";
+  let _ = fs::remove_dir_all(root);
+  let log_path = DataDir::new(root).log_path().unwrap();
+  let log_file = OpenOptions::new().create(true).append(true).open(&log_path).unwrap();
+
+  env::remove_var("RUST_LOG");
+  grin::init_logging(LogLevelFilter::Info, &[], Some(log_file)).unwrap();
+  info!(target: grin::SERVER_TARGET, "logged to file");
+  debug!(target: grin::SERVER_TARGET, "not logged");
+
+  let mut logs = String::new();
+  File::open(&log_path).unwrap().read_to_string(&mut logs).unwrap();
+  assert_eq!(logs.lines().count(), 1);
+  assert!(logs.ends_with("INFO:grin::server: logged to file\n"));
+}
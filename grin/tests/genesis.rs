@@ -0,0 +1,103 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_grin as grin;
+extern crate grin_chain as chain;
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+
+extern crate tokio_core;
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+
+use tokio_core::reactor;
+
+use chain::ChainStore;
+use core::core::Block;
+use core::ser;
+
+// Writes a testnet genesis block, differing from the mainnet one by its
+// nonce, returning its path.
+fn write_genesis(name: &str, nonce: u64, height: u64) -> (PathBuf, Block) {
+  let mut gen = core::genesis::genesis();
+  gen.header.nonce = nonce;
+  gen.header.height = height;
+  gen.header.cuckoo_len = 12;
+  let path = PathBuf::from(format!("target/{}.bin", name));
+  let mut f = File::create(&path).unwrap();
+  f.write_all(&ser::ser_vec(&gen).unwrap()).unwrap();
+  (path, gen)
+}
+
+fn server(n: u16, genesis: &PathBuf, h: &reactor::Handle) -> Result<grin::Server, grin::Error> {
+  let _ = fs::remove_dir_all(format!("target/grin-genesis-{}", n));
+  reopen(n, genesis, h)
+}
+
+// Same as server, keeping whatever chain was already stored.
+fn reopen(n: u16, genesis: &PathBuf, h: &reactor::Handle) -> Result<grin::Server, grin::Error> {
+  grin::Server::future(grin::ServerConfig {
+                         data_dir: format!("target/grin-genesis-{}", n),
+                         cuckoo_size: 12,
+                         p2p_config: p2p::P2PConfig { port: 12200 + n, ..p2p::P2PConfig::default() },
+                         genesis_file: Some(genesis.clone()),
+                         ..grin::ServerConfig::default()
+                       },
+                       h)
+}
+
+#[test]
+fn custom_genesis() {
+  let evtlp = reactor::Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let (path, gen) = write_genesis("genesis-testnet", 1, 0);
+  let (other_path, other) = write_genesis("genesis-othernet", 2, 0);
+  assert!(gen.hash() != other.hash());
+
+  // servers on the same testnet start from the same head
+  let s0 = server(0, &path, &handle).unwrap();
+  let s1 = server(1, &path, &handle).unwrap();
+  assert_eq!(s0.head().last_block_h, gen.hash());
+  assert_eq!(s1.head().last_block_h, gen.hash());
+  assert!(s0.head().last_block_h != core::genesis::genesis().hash());
+
+  let s2 = server(2, &other_path, &handle).unwrap();
+  assert_eq!(s2.head().last_block_h, other.hash());
+  assert!(s2.head().last_block_h != s0.head().last_block_h);
+
+  // a chain stored from another genesis block isn't ours
+  let data_dir = "target/grin-genesis-4";
+  let _ = fs::remove_dir_all(data_dir);
+  {
+    let db_path = grin::DataDir::new(data_dir).chain_db_path().unwrap();
+    let store = chain::store::ChainKVStore::new(db_path.to_string_lossy().into_owned()).unwrap();
+    store.save_block(&other).unwrap();
+    store.setup_height(&other.header).unwrap();
+    store.save_head(&chain::Tip::new(other.hash())).unwrap();
+  }
+  match reopen(4, &path, &handle) {
+    Err(grin::Error::GenesisErr(_)) => {}
+    _ => panic!("Chain from another genesis block reopened"),
+  }
+
+  // only genesis blocks are accepted as such
+  let (bad_path, _) = write_genesis("genesis-bad", 1, 3);
+  match server(3, &bad_path, &handle) {
+    Err(grin::Error::GenesisErr(_)) => {}
+    _ => panic!("Block at height 3 shouldn't make it as a genesis block"),
+  }
+}
@@ -0,0 +1,99 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate env_logger;
+extern crate grin_grin as grin;
+extern crate grin_p2p as p2p;
+#[macro_use]
+extern crate log;
+
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use log::{Log, LogLevelFilter, LogMetadata, LogRecord};
+
+use grin::ServerConfig;
+
+// Keeps the logs the configured env_logger lets through, with their target.
+struct CaptureLogger {
+  filter: env_logger::Logger,
+  logs: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl Log for CaptureLogger {
+  fn enabled(&self, metadata: &LogMetadata) -> bool {
+    self.filter.enabled(metadata)
+  }
+  fn log(&self, record: &LogRecord) {
+    if self.enabled(record.metadata()) {
+      let log = (record.target().to_string(), format!("{}", record.args()));
+      self.logs.lock().unwrap().push(log);
+    }
+  }
+}
+
+// Resolves every seed to nothing.
+struct NoResolver;
+
+impl p2p::Resolver for NoResolver {
+  fn resolve(&self, _: &str, _: u16) -> io::Result<Vec<SocketAddr>> {
+    Ok(vec![])
+  }
+}
+
+// The server logs are configured down to errors only and the p2p ones up to
+// debug, warnings of the former get dropped while debug logs of the latter
+// get through.
+#[test]
+fn per_target_levels() {
+  let config = ServerConfig::from_toml(r#"
+data_dir = "target/grin-log-targets"
+
+[log]
+level = "info"
+
+[log.targets]
+"grin::server" = "error"
+"grin::p2p" = "debug"
+"#)
+    .unwrap();
+
+  env::remove_var("RUST_LOG");
+  let logs = Arc::new(Mutex::new(vec![]));
+  let capture = CaptureLogger {
+    filter: grin::log_builder(config.log_level, &config.log_targets).build(),
+    logs: logs.clone(),
+  };
+  log::set_logger(|max_level| {
+      max_level.set(LogLevelFilter::Trace);
+      Box::new(capture)
+    })
+    .unwrap();
+
+  // server warning, below its error level
+  warn!(target: grin::SERVER_TARGET, "server warning");
+  // debug log of the seed resolving
+  p2p::resolve_seeds(&NoResolver, &["seed.grin.test".to_string()], 13414);
+  // the chain has no level of its own, the default applies
+  info!(target: grin::CHAIN_TARGET, "chain info");
+  debug!(target: grin::CHAIN_TARGET, "chain debug");
+
+  let logs = logs.lock().unwrap();
+  assert_eq!(*logs,
+             vec![(grin::P2P_TARGET.to_string(),
+                   "DNS seed seed.grin.test resolved to 0 peers.".to_string()),
+                  (grin::CHAIN_TARGET.to_string(), "chain info".to_string())]);
+}
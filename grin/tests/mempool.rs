@@ -0,0 +1,128 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_grin as grin;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+
+use rand::os::OsRng;
+
+use core::core::{Block, Input, Output, Transaction};
+use core::core::hash::{Hash, Hashed};
+use grin::{Mempool, MempoolError};
+use secp::key::SecretKey;
+
+// A blinded transaction spending the provided output, balanced so it can
+// make it in a block.
+fn spend(output: Hash, outputs: u64, fee: u64) -> Transaction {
+  let mut rng = OsRng::new().unwrap();
+  let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+  let outs = (0..outputs)
+    .map(|_| {
+      Output::OvertOutput {
+        value: 1,
+        blindkey: SecretKey::new(&secp, &mut rng),
+      }
+    })
+    .collect();
+  Transaction::new(vec![Input::OvertInput {
+                          output: output,
+                          value: outputs + fee,
+                          blindkey: SecretKey::new(&secp, &mut rng),
+                        }],
+                   outs,
+                   fee)
+    .blind(&secp)
+    .unwrap()
+}
+
+fn output(n: u8) -> Hash {
+  [n][..].hash()
+}
+
+#[test]
+fn fee_ordered_selection() {
+  let mut pool = Mempool::new();
+  let low = spend(output(1), 1, 10);
+  let high = spend(output(2), 1, 30);
+  let mid = spend(output(3), 1, 20);
+  let heavy = spend(output(4), 2, 40);
+  for tx in vec![low.clone(), high.clone(), mid.clone(), heavy.clone()] {
+    pool.add(tx).unwrap();
+  }
+  assert_eq!(grin::tx_weight(&low), 6);
+  assert_eq!(grin::tx_weight(&heavy), 10);
+
+  let hashes = |txs: Vec<Transaction>| txs.iter().map(|tx| tx.hash()).collect::<Vec<_>>();
+  assert_eq!(hashes(pool.select_for_block(12)), vec![high.hash(), mid.hash()]);
+  assert_eq!(hashes(pool.select_for_block(16)), vec![high.hash(), heavy.hash()]);
+  assert_eq!(hashes(pool.select_for_block(5)), vec![]);
+  assert_eq!(pool.select_for_block(grin::MAX_BLOCK_WEIGHT).len(), 4);
+
+  assert!(pool.remove(&high.hash()).is_some());
+  assert_eq!(hashes(pool.select_for_block(12)), vec![mid.hash(), low.hash()]);
+}
+
+#[test]
+fn evict_spent_by_block() {
+  let mut pool = Mempool::new();
+  let tx = spend(output(1), 1, 1);
+  let other = spend(output(2), 1, 1);
+  pool.add(tx.clone()).unwrap();
+  pool.add(other.clone()).unwrap();
+
+  // the pool never holds 2 transactions spending the same output
+  let mut conflict = spend(output(1), 2, 1);
+  assert_eq!(pool.add(conflict.clone()), Err(MempoolError::DoubleSpend(tx.hash())));
+  assert_eq!(pool.add(other.clone()), Err(MempoolError::AlreadyInPool));
+
+  // a block with the conflicting transaction gets accepted instead
+  let mut rng = OsRng::new().unwrap();
+  let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+  let prev = core::genesis::genesis().header;
+  let b = Block::new(&prev, vec![&mut conflict], SecretKey::new(&secp, &mut rng)).unwrap();
+  assert_eq!(pool.block_accepted(&b), 1);
+  assert!(!pool.contains(&tx.hash()));
+  assert!(pool.contains(&other.hash()));
+  assert_eq!(pool.len(), 1);
+}
+
+#[test]
+fn readd_on_reorg() {
+  let mut pool = Mempool::new();
+  let tx = spend(output(1), 1, 1);
+  let other = spend(output(2), 1, 1);
+  pool.add(tx.clone()).unwrap();
+  pool.add(other.clone()).unwrap();
+
+  let mut rng = OsRng::new().unwrap();
+  let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+  let prev = core::genesis::genesis().header;
+  let mut mined = tx.clone();
+  let b = Block::new(&prev, vec![&mut mined], SecretKey::new(&secp, &mut rng)).unwrap();
+  assert_eq!(pool.block_accepted(&b), 1);
+  assert_eq!(pool.len(), 1);
+
+  // the block gets undone, its transaction is back, until a block of the
+  // new fork conflicts with it
+  assert_eq!(pool.block_disconnected(&b), 1);
+  assert!(pool.contains(&tx.hash()));
+  assert_eq!(pool.block_disconnected(&b), 0);
+  let mut conflict = spend(output(1), 2, 1);
+  let fork = Block::new(&prev, vec![&mut conflict], SecretKey::new(&secp, &mut rng)).unwrap();
+  assert_eq!(pool.block_accepted(&fork), 1);
+  assert!(!pool.contains(&tx.hash()));
+  assert!(pool.contains(&other.hash()));
+}
@@ -41,9 +41,10 @@ fn simulate_block_propagation() {
   for n in 0..5 {
       let s = grin::Server::future(
           grin::ServerConfig{
-            db_root: format!("target/grin-prop-{}", n),
+            data_dir: format!("target/grin-prop-{}", n),
             cuckoo_size: 12,
-            p2p_config: p2p::P2PConfig{port: 10000+n, ..p2p::P2PConfig::default()}
+            p2p_config: p2p::P2PConfig{port: 10000+n, ..p2p::P2PConfig::default()},
+            ..grin::ServerConfig::default()
           }, &handle).unwrap();
       servers.push(s);
   }
@@ -81,9 +82,10 @@ fn simulate_full_sync() {
   for n in 0..2 {
       let s = grin::Server::future(
           grin::ServerConfig{
-            db_root: format!("target/grin-sync-{}", n),
+            data_dir: format!("target/grin-sync-{}", n),
             cuckoo_size: 12,
-            p2p_config: p2p::P2PConfig{port: 11000+n, ..p2p::P2PConfig::default()}
+            p2p_config: p2p::P2PConfig{port: 11000+n, ..p2p::P2PConfig::default()},
+            ..grin::ServerConfig::default()
           }, &handle).unwrap();
       servers.push(s);
   }
@@ -0,0 +1,153 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_grin as grin;
+extern crate grin_core as core;
+extern crate grin_chain as chain;
+extern crate grin_store as store;
+
+extern crate serde_json;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use chain::ChainStore;
+use core::consensus::EASINESS;
+use core::core::hash::Hashed;
+use core::pow::cuckoo;
+use serde_json::Value;
+
+struct Worker {
+  stream: TcpStream,
+  reader: BufReader<TcpStream>,
+}
+
+impl Worker {
+  fn connect(port: u16) -> Worker {
+    let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let reader = BufReader::new(stream.try_clone().unwrap());
+    Worker{stream: stream, reader: reader}
+  }
+
+  fn read(&mut self) -> Value {
+    let mut line = String::new();
+    self.reader.read_line(&mut line).unwrap();
+    serde_json::from_str(&line).unwrap()
+  }
+
+  // sends a request and waits for its response, skipping job notifications
+  fn call(&mut self, id: u64, req: &str) -> Value {
+    writeln!(self.stream, "{}", req).unwrap();
+    loop {
+      let resp = self.read();
+      if resp.find("id").and_then(|i| i.as_u64()) == Some(id) {
+        return resp;
+      }
+    }
+  }
+
+  fn next_job(&mut self) -> Value {
+    loop {
+      let msg = self.read();
+      if msg.find("method").and_then(|m| m.as_string()) == Some("job") {
+        return msg.find("params").unwrap().clone();
+      }
+    }
+  }
+}
+
+fn from_hex(hex: &str) -> Vec<u8> {
+  (0..hex.len() / 2).map(|n| u8::from_str_radix(&hex[2*n..2*n+2], 16).unwrap()).collect()
+}
+
+// Mines the job the way an external miner would, only knowing the pre-pow
+// header, where its nonce is and the target.
+fn solve(job: &Value) -> (u64, Vec<u32>) {
+  let mut pre_pow = from_hex(job.find("pre_pow").unwrap().as_string().unwrap());
+  let offset = job.find("nonce_offset").unwrap().as_u64().unwrap() as usize;
+  let cuckoo_len = job.find("cuckoo_len").unwrap().as_u64().unwrap() as u32;
+  let difficulty = job.find("difficulty").unwrap().as_string().unwrap().parse::<u32>().unwrap();
+
+  let mut nonce: u64 = 0;
+  loop {
+    for n in 0..8 {
+      pre_pow[offset + n] = (nonce >> (56 - 8 * n)) as u8;
+    }
+    let hash = pre_pow[..].hash();
+    if let Ok(proof) = cuckoo::Miner::new(hash.to_slice(), EASINESS, cuckoo_len).mine() {
+      if proof.to_difficulty() >= core::core::target::Difficulty::from_num(difficulty) {
+        return (nonce, proof.0.to_vec());
+      }
+    }
+    nonce += 1;
+  }
+}
+
+fn submit(job_id: u64, nonce: u64, pow: &[u32]) -> String {
+  let pow = pow.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(",");
+  format!("{{\"id\":2,\"method\":\"submit\",\"params\":{{\"job_id\":{},\"nonce\":{},\"pow\":[{}]}}}}",
+          job_id, nonce, pow)
+}
+
+#[test]
+fn submit_solutions() {
+  let chain_store = chain::store::ChainKVStore::with_store(Box::new(store::MemStore::new()));
+  let mut gen = core::genesis::genesis();
+  gen.header.cuckoo_len = 12;
+  let diff = gen.header.difficulty.clone();
+  core::pow::pow(&mut gen.header, diff).unwrap();
+  chain_store.save_block(&gen).unwrap();
+  chain_store.setup_height(&gen.header).unwrap();
+  let tip = chain::Tip::new(gen.hash());
+  chain_store.save_head(&tip).unwrap();
+
+  let head = Arc::new(Mutex::new(tip));
+  let stratum = Arc::new(grin::StratumServer::new(head.clone(),
+                                                  Arc::new(chain_store),
+                                                  Arc::new(chain::types::NoopAdapter{})));
+  grin::StratumServer::start(stratum.clone(), "127.0.0.1:13440".parse().unwrap()).unwrap();
+  thread::sleep(time::Duration::from_millis(100));
+
+  let mut worker = Worker::connect(13440);
+  let resp = worker.call(1, r#"{"id":1,"method":"login","params":{"login":"rig1"}}"#);
+  assert_eq!(resp.find("result").and_then(|r| r.as_string()), Some("ok"));
+  let job = worker.next_job();
+  let job_id = job.find("job_id").unwrap().as_u64().unwrap();
+  assert_eq!(job.find("height").unwrap().as_u64(), Some(1));
+
+  // garbage proof gets rejected
+  let resp = worker.call(2, &submit(job_id, 0, &[1; 42]));
+  assert!(resp.find("error").is_some());
+  let stats = stratum.worker_stats();
+  assert_eq!(stats[0].login, "rig1");
+  assert_eq!((stats[0].accepted, stats[0].rejected), (0, 1));
+
+  // actual solution gets accepted and extends the chain
+  let (nonce, pow) = solve(&job);
+  let resp = worker.call(2, &submit(job_id, nonce, &pow));
+  assert_eq!(resp.find("result").and_then(|r| r.as_string()), Some("ok"));
+  let stats = stratum.worker_stats();
+  assert_eq!((stats[0].accepted, stats[0].rejected), (1, 1));
+  assert_eq!(stats[0].blocks_found, 1);
+  assert_eq!(head.lock().unwrap().height, 1);
+
+  // the new head comes with a new job, making the solution stale
+  let next = worker.next_job();
+  assert!(next.find("job_id").unwrap().as_u64() != Some(job_id));
+  let resp = worker.call(2, &submit(job_id, nonce, &pow));
+  assert!(resp.find("error").is_some());
+}
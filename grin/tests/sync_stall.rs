@@ -0,0 +1,104 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_grin as grin;
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use grin::StallDetector;
+
+// Peer sending headers until it goes silent after a given number of
+// requests.
+struct MockPeer {
+  addr: SocketAddr,
+  answers: usize,
+}
+
+impl MockPeer {
+  fn new(port: u16, answers: usize) -> MockPeer {
+    MockPeer {
+      addr: format!("10.0.0.1:{}", port).parse().unwrap(),
+      answers: answers,
+    }
+  }
+
+  // Whether the peer answers a header request
+  fn request_headers(&mut self) -> bool {
+    if self.answers == 0 {
+      return false;
+    }
+    self.answers -= 1;
+    true
+  }
+}
+
+// The first peer stops answering mid-sync, a stall gets noticed after the
+// timeout and the next peer takes over until progress resumes.
+#[test]
+fn silent_peer_replaced() {
+  let mut peers = vec![MockPeer::new(1, 3), MockPeer::new(2, 100)];
+  let start = Instant::now();
+  let mut stall = StallDetector::new(Duration::from_secs(30), start);
+  let mut dropped = vec![];
+  let mut switched_at = None;
+
+  // a request every 10 seconds to the first peer not dropped
+  for n in 1..20 {
+    let now = start + Duration::from_secs(n * 10);
+    let sync_peer = stall.sync_peer();
+    if stall.check(now) {
+      assert!(stall.is_stalled());
+      dropped.push(sync_peer.unwrap());
+      switched_at = Some(n);
+    }
+    let peer = peers.iter_mut().find(|p| !dropped.contains(&p.addr)).unwrap();
+    stall.syncing_from(peer.addr, now);
+    if peer.request_headers() {
+      stall.progress(now);
+    }
+    if switched_at.is_some() {
+      assert!(!stall.is_stalled());
+      assert_eq!(stall.sync_peer(), Some(peers[1].addr));
+      break;
+    }
+  }
+
+  // silent after its 3rd answer, at 30s, dropped 30s later
+  assert_eq!(switched_at, Some(6));
+  assert_eq!(dropped, vec![peers[0].addr]);
+}
+
+#[test]
+fn new_peer_gets_full_timeout() {
+  let addr1 = "10.0.0.1:1".parse().unwrap();
+  let addr2 = "10.0.0.1:2".parse().unwrap();
+  let start = Instant::now();
+  let mut stall = StallDetector::new(Duration::from_secs(30), start);
+
+  stall.syncing_from(addr1, start);
+  // asking the same peer again doesn't restart the clock
+  stall.syncing_from(addr1, start + Duration::from_secs(20));
+  assert!(stall.check(start + Duration::from_secs(30)));
+  assert_eq!(stall.sync_peer(), None);
+
+  stall.syncing_from(addr2, start + Duration::from_secs(40));
+  assert!(!stall.check(start + Duration::from_secs(65)));
+  assert!(stall.is_stalled());
+  // stays stalled, noticed again after another timeout
+  assert!(stall.check(start + Duration::from_secs(70)));
+  stall.progress(start + Duration::from_secs(71));
+  assert!(!stall.is_stalled());
+  assert!(!stall.check(start + Duration::from_secs(100)));
+}
@@ -0,0 +1,82 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_grin as grin;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use grin::{SyncProgress, SyncState, SyncStatus};
+
+// Walks the status through a full sync, from waiting for peers to being
+// caught up with the network.
+#[test]
+fn sync_phases() {
+  let mut status = SyncStatus::new();
+  assert_eq!(status.state, SyncState::AwaitingPeers);
+
+  status.update(SyncState::HeaderSync, 0, 0);
+  assert_eq!(status.state, SyncState::HeaderSync);
+  status.headers_received(3);
+  status.update(SyncState::HeaderSync, 0, 3);
+  assert_eq!(status.state, SyncState::HeaderSync);
+  assert_eq!(status.headers_downloaded, 3);
+  assert_eq!(status.target_height, 3);
+
+  status.update(SyncState::BlockSync, 0, 3);
+  assert_eq!(status.state, SyncState::BlockSync);
+  status.block_received(1);
+  status.block_received(2);
+  assert_eq!(status.state, SyncState::BlockSync);
+  assert_eq!(status.current_height, 2);
+
+  // reaching the target height means we're done
+  status.block_received(3);
+  assert_eq!(status.state, SyncState::Synced);
+  assert_eq!(status.blocks_downloaded, 3);
+  assert_eq!(status.current_height, 3);
+}
+
+// Progress gets reported every few blocks and for the last one, with
+// heights only going up and the target we're syncing to.
+#[test]
+fn progress_callback() {
+  let progress = SyncProgress::new();
+  // nothing to report to yet
+  progress.block_connected(1, 10);
+
+  let reported = Arc::new(Mutex::new(vec![]));
+  let r = reported.clone();
+  progress.set_callback(Box::new(move |current, target| {
+                          r.lock().unwrap().push((current, target));
+                        }),
+                        3);
+  for height in vec![1, 2, 3, 4, 3, 5, 6, 7, 8, 9, 10] {
+    progress.block_connected(height, 10);
+  }
+
+  let mut waited = 0;
+  while reported.lock().unwrap().last() != Some(&(10, 10)) {
+    assert!(waited < 100, "last block never reported");
+    thread::sleep(Duration::from_millis(20));
+    waited += 1;
+  }
+  let reported = reported.lock().unwrap().clone();
+  assert!(reported.len() <= 4);
+  assert!(reported.iter().all(|&(_, target)| target == 10));
+  assert!(reported.windows(2).all(|w| w[0].0 < w[1].0));
+  // reports every 3 blocks at most, except to reach the target
+  assert!(reported.windows(2).all(|w| w[1].0 - w[0].0 >= 3 || w[1].0 == 10));
+}
@@ -0,0 +1,136 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bounded, time-windowed record of the blocks and transactions our peers
+//! recently announced to us. Keeps us from processing the same block or
+//! transaction over and over during propagation spikes and from relaying it
+//! back to whoever sent it. Also keeps track of what we relayed ourselves so
+//! the same block or transaction doesn't go out again while gossip loops
+//! back to us.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::RwLock;
+use std::time::{Instant, Duration};
+
+use core::core::hash::Hash;
+
+/// Window of recent block announcements, each entry being the announced
+/// hash, the peer that announced it and when it did so. Entries older than
+/// the configured TTL are dropped and the oldest ones are evicted once the
+/// window is full.
+pub struct AnnounceWindow {
+	capacity: usize,
+	ttl: Duration,
+	entries: RwLock<VecDeque<(Hash, SocketAddr, Instant)>>,
+}
+
+impl AnnounceWindow {
+	/// Creates a new window holding at most capacity announcements for ttl
+	/// seconds each.
+	pub fn new(capacity: usize, ttl: u64) -> AnnounceWindow {
+		AnnounceWindow {
+			capacity: capacity,
+			ttl: Duration::from_secs(ttl),
+			entries: RwLock::new(VecDeque::with_capacity(capacity)),
+		}
+	}
+
+	/// Records the announcement of the provided hash by a peer. Returns true
+	/// if that's the first time we hear about it within the window, false if
+	/// it's a duplicate that can be ignored.
+	pub fn announced(&self, h: Hash, from: SocketAddr) -> bool {
+		let now = Instant::now();
+		let mut entries = self.entries.write().unwrap();
+		while entries.front().map(|e| now - e.2 > self.ttl).unwrap_or(false) {
+			entries.pop_front();
+		}
+
+		let first = !entries.iter().any(|e| e.0 == h);
+		if !entries.iter().any(|e| e.0 == h && e.1 == from) {
+			entries.push_back((h, from, now));
+			if entries.len() > self.capacity {
+				entries.pop_front();
+			}
+		}
+		first
+	}
+
+	/// Whether the provided peer announced the hash to us within the window.
+	pub fn announced_by(&self, h: &Hash, addr: &SocketAddr) -> bool {
+		let now = Instant::now();
+		let entries = self.entries.read().unwrap();
+		entries.iter().any(|e| e.0 == *h && e.1 == *addr && now - e.2 <= self.ttl)
+	}
+}
+
+/// Hashes of the blocks and transactions we recently relayed to our peers,
+/// with when we did. Entries expire after the configured TTL and the oldest
+/// ones are evicted once the cache is full.
+pub struct RelayCache {
+	capacity: usize,
+	ttl: Duration,
+	seen: RwLock<(HashMap<Hash, Instant>, VecDeque<(Hash, Instant)>)>,
+}
+
+impl RelayCache {
+	/// Creates a new cache holding at most capacity hashes for ttl seconds
+	/// each.
+	pub fn new(capacity: usize, ttl: u64) -> RelayCache {
+		RelayCache {
+			capacity: capacity,
+			ttl: Duration::from_secs(ttl),
+			seen: RwLock::new((HashMap::with_capacity(capacity),
+			                   VecDeque::with_capacity(capacity))),
+		}
+	}
+
+	/// Records that we're about to relay the provided hash. Returns false if
+	/// it was already relayed within the TTL, in which case it shouldn't go
+	/// out again.
+	pub fn relaying(&self, h: Hash) -> bool {
+		let now = Instant::now();
+		let mut seen = self.seen.write().unwrap();
+		let (ref mut times, ref mut order) = *seen;
+		while order.front().map(|e| now - e.1 > self.ttl).unwrap_or(false) {
+			let (oh, ot) = order.pop_front().unwrap();
+			if times.get(&oh) == Some(&ot) {
+				times.remove(&oh);
+			}
+		}
+
+		if times.contains_key(&h) {
+			return false;
+		}
+		times.insert(h, now);
+		order.push_back((h, now));
+		if order.len() > self.capacity {
+			let (oh, _) = order.pop_front().unwrap();
+			times.remove(&oh);
+		}
+		true
+	}
+
+	/// Whether the provided hash was relayed within the TTL.
+	pub fn relayed(&self, h: &Hash) -> bool {
+		let seen = self.seen.read().unwrap();
+		seen.0.get(h).map(|t| Instant::now() - *t <= self.ttl).unwrap_or(false)
+	}
+
+	/// Number of hashes currently remembered, expired ones included until
+	/// the next relay prunes them.
+	pub fn len(&self) -> usize {
+		self.seen.read().unwrap().0.len()
+	}
+}
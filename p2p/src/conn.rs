@@ -16,22 +16,132 @@
 //! or
 //! receiving data from the TCP socket, as well as dealing with timeouts.
 
+use std::cmp;
+use std::io::{self, Read, Write};
 use std::iter;
 use std::ops::Deref;
 use std::sync::{Mutex, Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Instant, Duration};
 
 use futures;
 use futures::{Stream, Future};
+use futures::future;
 use futures::stream;
-use futures::sync::mpsc::{Sender, UnboundedSender, UnboundedReceiver};
+use futures::sync::mpsc::{UnboundedSender, UnboundedReceiver};
 use tokio_core::io::{Io, WriteHalf, ReadHalf, write_all, read_exact};
 use tokio_core::net::TcpStream;
 use tokio_timer::{Timer, TimerError};
 
 use core::core::hash::{Hash, ZERO_HASH};
 use core::ser;
+use crypt::{Cipher, Ciphers, TAG_LEN};
 use msg::*;
+use rate::RateLimiter;
+use types::{PeerStats, INVALID_MSG_SCORE, MAX_BAN_SCORE};
+use LOG_TARGET;
+
+/// How long, in milliseconds, a closing connection waits for the data
+/// already queued to be written out before dropping the socket.
+pub const CLOSE_FLUSH_DELAY: u64 = 200;
+
+/// How long, in seconds, a peer has to answer our requests with a small
+/// response
+pub const RESPONSE_TIMEOUT: u64 = 2;
+
+/// Slowest rate, in bytes per second, we expect large responses to come in
+/// at. Gives peers more time to answer requests for responses that can get
+/// large, like bundles of blocks.
+pub const MIN_RESPONSE_RATE: u64 = 100_000;
+
+/// How long, in seconds, a peer can stall in the middle of a message by
+/// default
+pub const DEFAULT_IO_TIMEOUT: u64 = 30;
+
+/// How long the message being read from or written to the peer can go
+/// without any progress before we give up on the peer. Only applies once a
+/// message started, idle connections are kept alive by pings instead.
+#[derive(Debug, Clone, Copy)]
+pub struct IoTimeouts {
+	pub read: Duration,
+	pub write: Duration,
+}
+
+impl Default for IoTimeouts {
+	fn default() -> IoTimeouts {
+		IoTimeouts {
+			read: Duration::from_secs(DEFAULT_IO_TIMEOUT),
+			write: Duration::from_secs(DEFAULT_IO_TIMEOUT),
+		}
+	}
+}
+
+/// Last time the message being read and the one being written made
+/// progress, none when between messages.
+struct Progress {
+	read: Mutex<Option<Instant>>,
+	write: Mutex<Option<Instant>>,
+}
+
+impl Progress {
+	fn new() -> Progress {
+		Progress {
+			read: Mutex::new(None),
+			write: Mutex::new(None),
+		}
+	}
+
+	fn stalled(&self, timeouts: &IoTimeouts) -> Option<&'static str> {
+		let now = Instant::now();
+		if let Some(t) = *self.read.lock().unwrap() {
+			if now - t > timeouts.read {
+				return Some("read");
+			}
+		}
+		if let Some(t) = *self.write.lock().unwrap() {
+			if now - t > timeouts.write {
+				return Some("write");
+			}
+		}
+		None
+	}
+}
+
+/// Read half of the connection, recording each chunk of data that comes in.
+struct TimedReader {
+	inner: ReadHalf<TcpStream>,
+	progress: Arc<Progress>,
+}
+
+impl Read for TimedReader {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let n = try!(self.inner.read(buf));
+		if n > 0 {
+			*self.progress.read.lock().unwrap() = Some(Instant::now());
+		}
+		Ok(n)
+	}
+}
+
+/// Write half of the connection, recording each chunk of data that goes out.
+struct TimedWriter {
+	inner: WriteHalf<TcpStream>,
+	progress: Arc<Progress>,
+}
+
+impl Write for TimedWriter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let n = try!(self.inner.write(buf));
+		if n > 0 {
+			*self.progress.write.lock().unwrap() = Some(Instant::now());
+		}
+		Ok(n)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.inner.flush()
+	}
+}
 
 /// Handler to provide to the connection, will be called back anytime a message
 /// is received. The provided sender can be use to immediately send back
@@ -45,6 +155,12 @@ pub trait Handler: Sync + Send {
 	          header: MsgHeader,
 	          body: Vec<u8>)
 	          -> Result<Option<Hash>, ser::Error>;
+
+	/// Whether a message of the provided type would answer one of our
+	/// requests. Those are never dropped for going over the peer rate.
+	fn expects(&self, _: Type) -> bool {
+		false
+	}
 }
 
 impl<F> Handler for F
@@ -60,6 +176,41 @@ impl<F> Handler for F
 	}
 }
 
+/// Number of message types we keep counts for, up to the last one.
+const MSG_TYPES: usize = Type::BlockBundle as usize + 1;
+
+/// Traffic counters for a connection. They're only read for reporting so
+/// relaxed ordering is all we need, keeping the read and write paths cheap.
+struct Counters {
+	sent_bytes: AtomicUsize,
+	received_bytes: AtomicUsize,
+	sent_msgs: Vec<AtomicUsize>,
+	received_msgs: Vec<AtomicUsize>,
+}
+
+impl Counters {
+	fn new() -> Counters {
+		Counters {
+			sent_bytes: AtomicUsize::new(0),
+			received_bytes: AtomicUsize::new(0),
+			sent_msgs: (0..MSG_TYPES).map(|_| AtomicUsize::new(0)).collect(),
+			received_msgs: (0..MSG_TYPES).map(|_| AtomicUsize::new(0)).collect(),
+		}
+	}
+
+	fn stats(&self) -> PeerStats {
+		PeerStats {
+			sent_bytes: self.sent_bytes.load(Ordering::Relaxed) as u64,
+			received_bytes: self.received_bytes.load(Ordering::Relaxed) as u64,
+			sent_msgs: self.sent_msgs.iter().map(|n| n.load(Ordering::Relaxed) as u64).collect(),
+			received_msgs: self.received_msgs
+				.iter()
+				.map(|n| n.load(Ordering::Relaxed) as u64)
+				.collect(),
+		}
+	}
+}
+
 /// A higher level connection wrapping the TcpStream. Maintains the amount of
 /// data transmitted and deals with the low-level task of sending and
 /// receiving data, parsing message headers and timeouts.
@@ -68,23 +219,38 @@ pub struct Connection {
 	outbound_chan: UnboundedSender<Vec<u8>>,
 
 	// Close the connection with the remote peer
-	close_chan: Sender<()>,
+	close_chan: UnboundedSender<()>,
 
-	// Bytes we've sent.
-	sent_bytes: Arc<Mutex<u64>>,
+	// Bytes and messages we've sent and received.
+	counters: Arc<Counters>,
 
-	// Bytes we've received.
-	received_bytes: Arc<Mutex<u64>>,
+	// Limits the rate of messages we accept from the remote peer.
+	limiter: Arc<RateLimiter>,
 
-	// Counter for read errors.
-	error_count: Mutex<u64>,
+	// Progress of the messages being read and written.
+	progress: Arc<Progress>,
+
+	// Size above which the messages we send get compressed, none if the peer
+	// doesn't support compression.
+	compress_threshold: Option<u64>,
 }
 
 impl Connection {
 	/// Start listening on the provided connection and wraps it. Does not hang
 	/// the current thread, instead just returns a future and the Connection
-	/// itself.
+	/// itself. Inbound messages over the rate of the provided limiter get the
+	/// reading paused, those we didn't ask for are also ignored, and
+	/// the connection is dropped if the peer tries to send a message larger
+	/// than max_msg_len or stalls in the middle of a message for longer than
+	/// the provided timeouts. With a compression threshold, larger messages
+	/// get compressed and compressed messages from the peer are accepted.
+	/// With ciphers, every message header and body is encrypted both ways.
 	pub fn listen<F>(conn: TcpStream,
+	                 limiter: RateLimiter,
+	                 max_msg_len: u64,
+	                 timeouts: IoTimeouts,
+	                 compress_threshold: Option<u64>,
+	                 ciphers: Option<Ciphers>,
 	                 handler: F)
 	                 -> (Connection, Box<Future<Item = (), Error = ser::Error>>)
 		where F: Handler + 'static
@@ -95,62 +261,139 @@ impl Connection {
 		// prepare the channel that will transmit data to the connection writer
 		let (tx, rx) = futures::sync::mpsc::unbounded();
 
-		// same for closing the connection
-		let (close_tx, close_rx) = futures::sync::mpsc::channel(1);
-		let close_conn = close_rx.for_each(|_| Ok(())).map_err(|_| ser::Error::CorruptedData);
+		// same for closing the connection, leaving a little time for pending
+		// writes to complete
+		let (close_tx, close_rx) = futures::sync::mpsc::unbounded();
+		let close_conn = close_rx.into_future()
+			.map_err(|_| ser::Error::CorruptedData)
+			.and_then(|_| {
+				Timer::default()
+					.sleep(Duration::from_millis(CLOSE_FLUSH_DELAY))
+					.map_err(|_| ser::Error::CorruptedData)
+			});
 
 		let me = Connection {
 			outbound_chan: tx.clone(),
 			close_chan: close_tx,
-			sent_bytes: Arc::new(Mutex::new(0)),
-			received_bytes: Arc::new(Mutex::new(0)),
-			error_count: Mutex::new(0),
+			counters: Arc::new(Counters::new()),
+			limiter: Arc::new(limiter),
+			progress: Arc::new(Progress::new()),
+			compress_threshold: compress_threshold,
+		};
+
+		let (send_cipher, recv_cipher) = match ciphers {
+			Some(c) => (Some(c.send), Some(c.recv)),
+			None => (None, None),
 		};
 
 		// setup the reading future, getting messages from the peer and processing them
-		let read_msg = me.read_msg(tx, reader, handler).map(|_| ());
+		let reader = TimedReader {
+			inner: reader,
+			progress: me.progress.clone(),
+		};
+		let read_msg = me.read_msg(tx, reader, max_msg_len, recv_cipher, handler).map(|_| ());
 
 		// setting the writing future, getting messages from our system and sending
 		// them out
-		let write_msg = me.write_msg(rx, writer).map(|_| ());
+		let writer = TimedWriter {
+			inner: writer,
+			progress: me.progress.clone(),
+		};
+		let write_msg = me.write_msg(rx, writer, send_cipher).map(|_| ());
+
+		// regularly check the peer isn't holding us in the middle of a message
+		let progress = me.progress.clone();
+		let check = cmp::min(timeouts.read, timeouts.write) / 4;
+		let watchdog = Timer::default()
+			.interval(cmp::max(check, Duration::from_millis(100)))
+			.map_err(|_| ser::Error::CorruptedData)
+			.for_each(move |_| match progress.stalled(&timeouts) {
+				Some(dir) => {
+					debug!(target: LOG_TARGET,
+					       "Peer stalled during a message {}, disconnecting.",
+					       dir);
+					Err(ser::Error::IOErr(io::Error::new(io::ErrorKind::TimedOut,
+					                                     format!("{} stalled", dir))))
+				}
+				None => Ok(()),
+			});
 
 		// select between our different futures and return them
-		let fut =
-			Box::new(close_conn.select(read_msg.select(write_msg).map(|_| ()).map_err(|(e, _)| e))
-				.map(|_| ())
-				.map_err(|(e, _)| e));
+		let io = read_msg.select(write_msg)
+			.map(|_| ())
+			.map_err(|(e, _)| e)
+			.select(watchdog)
+			.map(|_| ())
+			.map_err(|(e, _)| e);
+		let fut = Box::new(close_conn.select(io)
+			.map(|_| ())
+			.map_err(|(e, _)| e));
 
 		(me, fut)
 	}
 
 	/// Prepares the future that gets message data produced by our system and
-	/// sends it to the peer connection
+	/// sends it to the peer connection, encrypting it with the provided cipher
 	fn write_msg(&self,
 	             rx: UnboundedReceiver<Vec<u8>>,
-	             writer: WriteHalf<TcpStream>)
-	             -> Box<Future<Item = WriteHalf<TcpStream>, Error = ser::Error>> {
-
-		let sent_bytes = self.sent_bytes.clone();
+	             writer: TimedWriter,
+	             cipher: Option<Cipher>)
+	             -> Box<Future<Item = TimedWriter, Error = ser::Error>> {
+
+		let counters = self.counters.clone();
+		let progress = self.progress.clone();
+		let compress_threshold = self.compress_threshold;
+		let mut cipher = cipher;
 		let send_data = rx.map(move |data| {
+				// every message goes through here, whoever queued it, so that's
+				// where large ones get compressed
+				let data = match compress_threshold {
+					Some(threshold) => compress_frame(data, threshold),
+					None => data,
+				};
         // add the count of bytes sent
-				let mut sent_bytes = sent_bytes.lock().unwrap();
-				*sent_bytes += data.len() as u64;
-				data
+				counters.sent_bytes.fetch_add(data.len(), Ordering::Relaxed);
+				// and of messages by type, read back from the frame so replies
+				// queued straight on the channel by handlers are counted as well
+				if let Ok(header) = ser::deserialize::<MsgHeader>(&mut &data[..]) {
+					counters.sent_msgs[header.msg_type as usize].fetch_add(1, Ordering::Relaxed);
+				}
+				// messages are written out in the order they're queued, so are their
+				// nonces
+				match cipher {
+					Some(ref mut cipher) => {
+						let (header, body) = data.split_at(HEADER_LEN as usize);
+						let mut sealed = cipher.seal(header);
+						sealed.append(&mut cipher.seal(body));
+						sealed
+					}
+					None => data,
+				}
+			})
+      // write the data and make sure the future returns the right types, the
+      // write timeout running from the moment we start each message
+			.fold(writer, move |writer, data| {
+				*progress.write.lock().unwrap() = Some(Instant::now());
+				let progress = progress.clone();
+				write_all(writer, data).map_err(|_| ()).map(move |(writer, buf)| {
+					*progress.write.lock().unwrap() = None;
+					writer
+				})
 			})
-      // write the data and make sure the future returns the right types
-			.fold(writer,
-			      |writer, data| write_all(writer, data).map_err(|_| ()).map(|(writer, buf)| writer))
 			.map_err(|_| ser::Error::CorruptedData);
 		Box::new(send_data)
 	}
 
-	/// Prepares the future reading from the peer connection, parsing each
-	/// message and forwarding them appropriately based on their type
+	/// Prepares the future reading from the peer connection, decrypting it with
+	/// the provided cipher, parsing each message and forwarding them
+	/// appropriately based on their type
 	fn read_msg<F>(&self,
 	               sender: UnboundedSender<Vec<u8>>,
-	               reader: ReadHalf<TcpStream>,
+	               reader: TimedReader,
+	               max_msg_len: u64,
+	               cipher: Option<Cipher>,
 	               handler: F)
-	               -> Box<Future<Item = ReadHalf<TcpStream>, Error = ser::Error>>
+	               -> Box<Future<Item = TimedReader, Error = ser::Error>>
 		where F: Handler + 'static
 	{
 
@@ -159,46 +402,114 @@ impl Connection {
 		let iter = stream::iter(iter::repeat(()).map(Ok::<(), ser::Error>));
 
 		// setup the reading future, getting messages from the peer and processing them
-		let recv_bytes = self.received_bytes.clone();
+		let counters = self.counters.clone();
 		let handler = Arc::new(handler);
+		let limiter = self.limiter.clone();
+		let progress = self.progress.clone();
+		let compression = self.compress_threshold.is_some();
+		let cipher = Arc::new(cipher.map(Mutex::new));
+		let tag_len = if cipher.is_some() { TAG_LEN } else { 0 };
+		let timer = Timer::default();
 
 		let read_msg = iter.fold(reader, move |reader, _| {
-			let recv_bytes = recv_bytes.clone();
+			let counters = counters.clone();
+			let progress = progress.clone();
+			let limiter_inner = limiter.clone();
 			let handler = handler.clone();
 			let sender_inner = sender.clone();
+			let (header_cipher, body_cipher) = (cipher.clone(), cipher.clone());
+			let timer = timer.clone();
 
 			// first read the message header
-			read_exact(reader, vec![0u8; HEADER_LEN as usize])
+			read_exact(reader, vec![0u8; (HEADER_LEN + tag_len) as usize])
 				.map_err(|e| ser::Error::IOErr(e))
 				.and_then(move |(reader, buf)| {
+					let buf = try!(open(&header_cipher, buf));
 					let header = try!(ser::deserialize::<MsgHeader>(&mut &buf[..]));
+					if header.compressed && !compression {
+						debug!(target: LOG_TARGET,
+						       "Peer sent a compressed message without negotiating it, disconnecting.");
+						return Err(ser::Error::CorruptedData);
+					}
+					if let Err(e) = header.check_len(max_msg_len) {
+						debug!(target: LOG_TARGET,
+						       "Peer sent a {:?} message of {} bytes, disconnecting.",
+						       header.msg_type,
+						       header.msg_len);
+						return Err(e);
+					}
 					Ok((reader, header))
 				})
 				.and_then(move |(reader, header)| {
 					// now that we have a size, proceed with the body
-					read_exact(reader, vec![0u8; header.msg_len as usize])
+					read_exact(reader, vec![0u8; (header.msg_len + tag_len) as usize])
 						.map(|(reader, buf)| (reader, header, buf))
 						.map_err(|e| ser::Error::IOErr(e))
 				})
-				.map(move |(reader, header, buf)| {
-					// add the count of bytes received
-					let mut recv_bytes = recv_bytes.lock().unwrap();
-					*recv_bytes += header.serialized_len() + header.msg_len;
+				.and_then(move |(reader, header, buf)| {
+					let buf = try!(open(&body_cipher, buf));
+					// done with this message, the read timeout only starts again with
+					// the first bytes of the next one
+					*progress.read.lock().unwrap() = None;
 
-					// and handle the different message types
+					// add the count of bytes and messages received
 					let msg_type = header.msg_type;
+					counters.received_bytes
+						.fetch_add((header.serialized_len() + header.msg_len) as usize,
+						           Ordering::Relaxed);
+					counters.received_msgs[msg_type as usize].fetch_add(1, Ordering::Relaxed);
+
+					// messages over their category rate are read but ignored, unless
+					// they answer our own requests, it's up to the peer to slow down
+					let pause = limiter_inner.take(msg_type.category());
+					if pause.is_some() && !handler.expects(msg_type) {
+						debug!(target: LOG_TARGET,
+						       "Peer over its {:?} message rate, dropping {:?} message.",
+						       msg_type.category(),
+						       msg_type);
+						limiter_inner.dropped();
+						return check_ban_score(&limiter_inner).map(|_| (reader, pause));
+					}
+
+					// inflate compressed bodies, handlers only ever see the original
+					let (header, buf) = if header.compressed {
+						let buf = try!(decompress(&buf, header.max_len(max_msg_len)));
+						(MsgHeader::new(msg_type, buf.len() as u64), buf)
+					} else {
+						(header, buf)
+					};
+
+					// and handle the different message types
 					if let Err(e) = handler.handle(sender_inner.clone(), header, buf) {
-						debug!("Invalid {:?} message: {}", msg_type, e);
+						debug!(target: LOG_TARGET, "Invalid {:?} message: {}", msg_type, e);
+						limiter_inner.penalize(INVALID_MSG_SCORE);
 					}
 
-					reader
+					// enough is enough, drop misbehaving peers
+					check_ban_score(&limiter_inner).map(|_| (reader, pause))
+				})
+				.and_then(move |(reader, pause)| {
+					// if the peer is going over its message rate, stop reading from the
+					// socket for a while, which pushes back on the sender
+					let next: Box<Future<Item = TimedReader, Error = ser::Error>> = match pause {
+						None => Box::new(future::ok(reader)),
+						Some(wait) => {
+							debug!(target: LOG_TARGET,
+							       "Peer over message rate, throttling for {:?}.",
+							       wait);
+							Box::new(timer.sleep(wait)
+								.map(|_| reader)
+								.map_err(|_| ser::Error::CorruptedData))
+						}
+					};
+					next
 				})
 		});
 		Box::new(read_msg)
 	}
 
 	/// Utility function to send any Writeable. Handles adding the header and
-	/// serialization.
+	/// serialization, compression happens when the message gets written.
 	pub fn send_msg(&self, t: Type, body: &ser::Writeable) -> Result<(), ser::Error> {
 
 		let mut body_data = vec![];
@@ -210,11 +521,121 @@ impl Connection {
 		self.outbound_chan.send(data).map_err(|_| ser::Error::CorruptedData)
 	}
 
+	/// Closes the connection once the messages already sent had a chance to
+	/// be written out.
+	pub fn close(&self) {
+		let _ = self.close_chan.send(());
+	}
+
 	/// Bytes sent and received by this peer to the remote peer.
 	pub fn transmitted_bytes(&self) -> (u64, u64) {
-		let sent = *self.sent_bytes.lock().unwrap();
-		let recv = *self.received_bytes.lock().unwrap();
-		(sent, recv)
+		let stats = self.counters.stats();
+		(stats.sent_bytes, stats.received_bytes)
+	}
+
+	/// Bytes and messages sent and received by this peer.
+	pub fn stats(&self) -> PeerStats {
+		self.counters.stats()
+	}
+
+	/// Ban score accumulated by the remote peer flooding us or sending
+	/// messages we can't handle, decaying over time.
+	pub fn ban_score(&self) -> u32 {
+		self.limiter.ban_score()
+	}
+}
+
+// Decrypts data read from the peer when the connection is encrypted, a
+// message that doesn't authenticate gets the peer dropped
+fn open(cipher: &Option<Mutex<Cipher>>, buf: Vec<u8>) -> Result<Vec<u8>, ser::Error> {
+	match *cipher {
+		Some(ref cipher) => {
+			cipher.lock().unwrap().open(&buf).map_err(|e| {
+				debug!(target: LOG_TARGET, "Peer sent a message we can't decrypt, disconnecting.");
+				e
+			})
+		}
+		None => Ok(buf),
+	}
+}
+
+// Compresses the body of a message queued for the peer if it's over the
+// threshold and it actually helps, rewriting its header. Anything else goes
+// out as it was queued.
+fn compress_frame(data: Vec<u8>, threshold: u64) -> Vec<u8> {
+	let header = match ser::deserialize::<MsgHeader>(&mut &data[..]) {
+		Ok(header) => header,
+		Err(_) => return data,
+	};
+	if header.compressed || header.msg_len <= threshold {
+		return data;
+	}
+	let mut compressed = match compress(&data[HEADER_LEN as usize..]) {
+		Ok(compressed) => compressed,
+		Err(_) => return data,
+	};
+	if compressed.len() as u64 >= header.msg_len {
+		return data;
+	}
+	let mut frame = vec![];
+	let header = MsgHeader::compressed(header.msg_type, compressed.len() as u64);
+	if ser::serialize(&mut frame, &header).is_err() {
+		return data;
+	}
+	frame.append(&mut compressed);
+	frame
+}
+
+// Fails once the peer reached the maximum ban score, flooding us or sending
+// messages we can't handle, so it gets dropped
+fn check_ban_score(limiter: &RateLimiter) -> Result<(), ser::Error> {
+	if limiter.ban_score() >= MAX_BAN_SCORE {
+		debug!(target: LOG_TARGET, "Peer reached the maximum ban score, disconnecting.");
+		return Err(ser::Error::CorruptedData);
+	}
+	Ok(())
+}
+
+/// Time a peer has to send us a response of the provided type, longer for
+/// responses that can be large enough to take a while to transfer.
+pub fn response_timeout(t: Type) -> Duration {
+	let len = t.max_len().unwrap_or(0);
+	Duration::from_secs(RESPONSE_TIMEOUT + len / MIN_RESPONSE_RATE)
+}
+
+// Decorates the handler to remove the "subscription" from the expected
+// responses. We got our replies, so no timeout should occur.
+struct ExpectHandler<F> {
+	handler: F,
+	expects: Arc<Mutex<Vec<(Type, Hash, Option<bool>, Instant)>>>,
+}
+
+impl<F> Handler for ExpectHandler<F>
+	where F: Handler
+{
+	fn handle(&self,
+	          sender: UnboundedSender<Vec<u8>>,
+	          header: MsgHeader,
+	          data: Vec<u8>)
+	          -> Result<Option<Hash>, ser::Error> {
+		let msg_type = header.msg_type;
+		let recv_h = try!(self.handler.handle(sender, header, data));
+
+		let mut expects = self.expects.lock().unwrap();
+		println!("EXP1 {}", expects.len());
+		let filtered = expects.iter()
+			.filter(|&&(typ, h, _, _)| msg_type != typ || recv_h.is_some() && recv_h.unwrap() != h)
+			.map(|&x| x)
+			.collect::<Vec<_>>();
+		*expects = filtered;
+		println!("EXP2 {}", expects.len());
+
+		Ok(recv_h)
+	}
+
+	fn expects(&self, t: Type) -> bool {
+		self.handler.expects(t) ||
+		self.expects.lock().unwrap().iter().any(|&(typ, _, _, _)| typ == t)
 	}
 }
 
@@ -223,12 +644,18 @@ impl Connection {
 pub struct TimeoutConnection {
 	underlying: Connection,
 
+	// responses we're waiting for, with the time they have to arrive by
 	expected_responses: Arc<Mutex<Vec<(Type, Hash, Option<bool>, Instant)>>>,
 }
 
 impl TimeoutConnection {
 	/// Same as Connection
 	pub fn listen<F>(conn: TcpStream,
+	                 limiter: RateLimiter,
+	                 max_msg_len: u64,
+	                 timeouts: IoTimeouts,
+	                 compress_threshold: Option<u64>,
+	                 ciphers: Option<Ciphers>,
 	                 handler: F)
 	                 -> (TimeoutConnection, Box<Future<Item = (), Error = ser::Error>>)
 		where F: Handler + 'static
@@ -236,26 +663,17 @@ impl TimeoutConnection {
 
 		let expects = Arc::new(Mutex::new(vec![]));
 
-		// Decorates the handler to remove the "subscription" from the expected
-		// responses. We got our replies, so no timeout should occur.
-		let exp = expects.clone();
-		let (conn, fut) = Connection::listen(conn, move |sender, header: MsgHeader, data| {
-			let msg_type = header.msg_type;
-			let recv_h = try!(handler.handle(sender, header, data));
-
-			let mut expects = exp.lock().unwrap();
-			println!("EXP1 {}", expects.len());
-			let filtered = expects.iter()
-				.filter(|&&(typ, h, _, _)| {
-					msg_type != typ || recv_h.is_some() && recv_h.unwrap() != h
-				})
-				.map(|&x| x)
-				.collect::<Vec<_>>();
-			*expects = filtered;
-			println!("EXP2 {}", expects.len());
-
-			Ok(recv_h)
-		});
+		let handler = ExpectHandler {
+			handler: handler,
+			expects: expects.clone(),
+		};
+		let (conn, fut) = Connection::listen(conn,
+		                                     limiter,
+		                                     max_msg_len,
+		                                     timeouts,
+		                                     compress_threshold,
+		                                     ciphers,
+		                                     handler);
 
 		// Registers a timer with the event loop to regularly check for timeouts.
 		let exp = expects.clone();
@@ -263,8 +681,8 @@ impl TimeoutConnection {
 			.interval(Duration::new(2, 0))
 			.fold((), move |_, _| {
 				let exp = exp.lock().unwrap();
-				for &(_, _, _, t) in exp.deref() {
-					if Instant::now() - t > Duration::new(2, 0) {
+				for &(_, _, _, deadline) in exp.deref() {
+					if Instant::now() > deadline {
 						return Err(TimerError::TooLong);
 					}
 				}
@@ -290,9 +708,9 @@ impl TimeoutConnection {
 
 		let mut expects = self.expected_responses.lock().unwrap();
 		if let Some((rt, h)) = expect_h {
-			expects.push((rt, h, None, Instant::now()));
+			expects.push((rt, h, None, Instant::now() + response_timeout(rt)));
 		} else {
-			expects.push((t, ZERO_HASH, None, Instant::now()));
+			expects.push((t, ZERO_HASH, None, Instant::now() + response_timeout(t)));
 		}
 		Ok(())
 	}
@@ -306,4 +724,19 @@ impl TimeoutConnection {
 	pub fn transmitted_bytes(&self) -> (u64, u64) {
 		self.underlying.transmitted_bytes()
 	}
+
+	/// Same as Connection
+	pub fn stats(&self) -> PeerStats {
+		self.underlying.stats()
+	}
+
+	/// Same as Connection
+	pub fn ban_score(&self) -> u32 {
+		self.underlying.ban_score()
+	}
+
+	/// Same as Connection
+	pub fn close(&self) {
+		self.underlying.close()
+	}
 }
@@ -0,0 +1,144 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Encryption of the traffic with peers that support it. Once the version
+//! handshake is done, both sides send an ephemeral public key and derive a
+//! shared secret from the other one with Diffie-Hellman. Each direction of
+//! the connection gets its own key and every message header and body is then
+//! sealed with ChaCha20-Poly1305, using a counter as nonce.
+//!
+//! The keys exchanged aren't authenticated, this keeps passive observers
+//! out and detects tampering but doesn't stop a man in the middle.
+
+use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use crypto::blake2b::Blake2b;
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use rand::os::OsRng;
+use secp::{self, Secp256k1, ContextFlag};
+use secp::ecdh::SharedSecret;
+use secp::key::{PublicKey, SecretKey};
+
+use core::ser;
+
+/// Size in bytes of the authentication tag following each sealed header and
+/// body.
+pub const TAG_LEN: u64 = 16;
+
+/// Size in bytes of the keys derived for each direction.
+const KEY_LEN: usize = 32;
+
+/// Seals or opens the messages going one way, each with the next nonce.
+pub struct Cipher {
+	key: [u8; KEY_LEN],
+	nonce: u64,
+}
+
+impl Cipher {
+	fn new(secret: &SharedSecret, label: &[u8]) -> Cipher {
+		let mut key = [0; KEY_LEN];
+		Blake2b::blake2b(&mut key, label, &secret[..]);
+		Cipher {
+			key: key,
+			nonce: 0,
+		}
+	}
+
+	/// Encrypts the provided data, appending the authentication tag.
+	pub fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+		let mut sealed = vec![0; data.len() + TAG_LEN as usize];
+		{
+			let (out, tag) = sealed.split_at_mut(data.len());
+			self.aead().encrypt(data, out, tag);
+		}
+		sealed
+	}
+
+	/// Decrypts data sealed by the remote peer, failing if it doesn't
+	/// authenticate.
+	pub fn open(&mut self, sealed: &[u8]) -> Result<Vec<u8>, ser::Error> {
+		if sealed.len() < TAG_LEN as usize {
+			return Err(ser::Error::CorruptedData);
+		}
+		let (data, tag) = sealed.split_at(sealed.len() - TAG_LEN as usize);
+		let mut out = vec![0; data.len()];
+		if !self.aead().decrypt(data, &mut out, tag) {
+			return Err(ser::Error::CorruptedData);
+		}
+		Ok(out)
+	}
+
+	// Cipher for the next nonce, nonces never get reused with the same key
+	fn aead(&mut self) -> ChaCha20Poly1305 {
+		let mut nonce = [0; 8];
+		for n in 0..8 {
+			nonce[n] = (self.nonce >> (56 - n * 8)) as u8;
+		}
+		self.nonce += 1;
+		ChaCha20Poly1305::new(&self.key, &nonce, &[])
+	}
+}
+
+/// Ciphers for both directions of a connection.
+pub struct Ciphers {
+	/// For the messages we send
+	pub send: Cipher,
+	/// For the messages the peer sends us
+	pub recv: Cipher,
+}
+
+/// Key pair only used for a single connection.
+pub struct EphemeralKey {
+	secp: Secp256k1,
+	secret: SecretKey,
+	public: PublicKey,
+}
+
+impl EphemeralKey {
+	/// Generates a new random key pair.
+	pub fn new() -> EphemeralKey {
+		let secp = Secp256k1::with_caps(ContextFlag::SignOnly);
+		let (secret, public) = secp.generate_keypair(&mut OsRng::new().unwrap()).unwrap();
+		EphemeralKey {
+			secp: secp,
+			secret: secret,
+			public: public,
+		}
+	}
+
+	/// Our public key, to send to the peer.
+	pub fn public_key(&self) -> Vec<u8> {
+		self.public.serialize_vec(&self.secp, true).to_vec()
+	}
+
+	/// Derives the ciphers of the connection from the public key the peer
+	/// sent us. Both sides get the same keys, the initiator of the connection
+	/// sending with the one the other side receives with.
+	pub fn agree(&self, peer_key: &[u8], initiator: bool) -> Result<Ciphers, secp::Error> {
+		let peer_key = try!(PublicKey::from_slice(&self.secp, peer_key));
+		let secret = SharedSecret::new(&self.secp, &peer_key, &self.secret);
+		let (initiator_key, responder_key) = (Cipher::new(&secret, b"grin initiator"),
+		                                      Cipher::new(&secret, b"grin responder"));
+		if initiator {
+			Ok(Ciphers {
+				send: initiator_key,
+				recv: responder_key,
+			})
+		} else {
+			Ok(Ciphers {
+				send: responder_key,
+				recv: initiator_key,
+			})
+		}
+	}
+}
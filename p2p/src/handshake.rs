@@ -12,128 +12,292 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp;
 use std::collections::VecDeque;
+use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
-use futures::Future;
+use futures::{future, Future};
 use rand::Rng;
 use rand::os::OsRng;
 use tokio_core::net::TcpStream;
+use tokio_timer::Timer;
 
-use core::ser::Error;
+use core::consensus::MAX_MSG_LEN;
+use core::core::hash::Hash;
 use core::core::target::Difficulty;
+use core::ser;
 use msg::*;
 use types::*;
+use conn::IoTimeouts;
+use crypt::{Ciphers, EphemeralKey};
 use protocol::ProtocolV1;
+use LOG_TARGET;
 
 const NONCES_CAP: usize = 100;
 
+/// Default time, in seconds, a peer has to complete the handshake.
+const HANDSHAKE_TIMEOUT: u64 = 5;
+
 /// Handles the handshake negotiation when two peers connect and decides on
 /// protocol.
 pub struct Handshake {
 	/// Ring buffer of nonces sent to detect self connections without requiring
 	/// a node id.
 	nonces: Arc<RwLock<VecDeque<u64>>>,
+	/// Hash of our genesis block, peers need to have the same one to be on
+	/// the same chain.
+	genesis: Hash,
+	/// Protocol version we advertise.
+	version: u32,
+	/// Capabilities we advertise.
+	capabilities: Capabilities,
+	/// How long we wait for the handshake to complete before dropping the
+	/// connection.
+	timeout: Duration,
+	/// Largest message the peers we shake hands with can send us.
+	max_msg_len: u64,
+	/// How long the peers we shake hands with can stall in the middle of a
+	/// message.
+	io_timeouts: IoTimeouts,
+	/// Size above which message bodies get compressed, when the peer
+	/// supports it.
+	compress_threshold: u64,
+	/// Address we tell peers they can reach us at, the local address of the
+	/// connection if not set.
+	advertised_addr: Option<SocketAddr>,
+	/// How long ago, in seconds, the addresses the peers we shake hands with
+	/// relay can have last been seen.
+	addr_max_age: i64,
+	/// How many relayed addresses we keep from each of the peers we shake
+	/// hands with every minute.
+	max_addrs_per_minute: u32,
 }
 
 unsafe impl Sync for Handshake {}
 unsafe impl Send for Handshake {}
 
 impl Handshake {
-	/// Creates a new handshake handler
-	pub fn new() -> Handshake {
-		Handshake { nonces: Arc::new(RwLock::new(VecDeque::with_capacity(NONCES_CAP))) }
+	/// Creates a new handshake handler for the chain starting at the provided
+	/// genesis block hash.
+	pub fn new(genesis: Hash) -> Handshake {
+		Handshake::with_version(genesis,
+		                        PROTOCOL_VERSION,
+		                        FULL_SYNC | COMPRESSION | ENCRYPTION | BLOCK_BUNDLES |
+		                        COMPACT_BLOCKS)
+	}
+
+	/// Creates a new handshake handler advertising the provided protocol
+	/// version and capabilities instead of our current ones, mostly useful to
+	/// check how different versions get along.
+	pub fn with_version(genesis: Hash, version: u32, capabilities: Capabilities) -> Handshake {
+		Handshake {
+			nonces: Arc::new(RwLock::new(VecDeque::with_capacity(NONCES_CAP))),
+			genesis: genesis,
+			version: version,
+			capabilities: capabilities,
+			timeout: Duration::from_secs(HANDSHAKE_TIMEOUT),
+			max_msg_len: MAX_MSG_LEN,
+			io_timeouts: IoTimeouts::default(),
+			compress_threshold: COMPRESS_THRESHOLD,
+			advertised_addr: None,
+			addr_max_age: ADDR_MAX_AGE,
+			max_addrs_per_minute: MAX_ADDRS_PER_MINUTE,
+		}
+	}
+
+	/// Sets how long a peer has to complete the handshake.
+	pub fn set_timeout(&mut self, timeout: Duration) {
+		self.timeout = timeout;
+	}
+
+	/// Sets the largest message the peers we connect with can send us once
+	/// the handshake is done.
+	pub fn set_max_msg_len(&mut self, max_msg_len: u64) {
+		self.max_msg_len = max_msg_len;
+	}
+
+	/// Sets how long the peers we connect with can take without making any
+	/// progress reading or writing a message, once the handshake is done.
+	pub fn set_io_timeouts(&mut self, read: Duration, write: Duration) {
+		self.io_timeouts = IoTimeouts {
+			read: read,
+			write: write,
+		};
+	}
+
+	/// Sets the size above which the bodies of the messages we send get
+	/// compressed, for peers that support compression.
+	pub fn set_compress_threshold(&mut self, threshold: u64) {
+		self.compress_threshold = threshold;
+	}
+
+	/// Sets how long ago the addresses relayed by the peers we connect with
+	/// can have last been seen, and how many of them we keep from each peer
+	/// every minute.
+	pub fn set_addr_relay(&mut self, max_age: i64, per_minute: u32) {
+		self.addr_max_age = max_age;
+		self.max_addrs_per_minute = per_minute;
+	}
+
+	/// Sets the address we advertise to the peers we connect to.
+	pub fn set_advertised_addr(&mut self, addr: SocketAddr) {
+		self.advertised_addr = Some(addr);
 	}
 
 	/// Handles connecting to a new remote peer, starting the version handshake.
 	pub fn connect(&self,
 	               total_difficulty: Difficulty,
+	               height: u64,
 	               conn: TcpStream)
 	               -> Box<Future<Item = (TcpStream, ProtocolV1, PeerInfo), Error = Error>> {
 		// prepare the first part of the hanshake
 		let nonce = self.next_nonce();
+		let genesis = self.genesis;
+		let (version, capabilities) = (self.version, self.capabilities);
+		let max_msg_len = self.max_msg_len;
+		let io_timeouts = self.io_timeouts;
+		let compress_threshold = self.compress_threshold;
+		let (addr_max_age, max_addrs_per_minute) = (self.addr_max_age, self.max_addrs_per_minute);
 		let hand = Hand {
-			version: PROTOCOL_VERSION,
-			capabilities: FULL_SYNC,
+			version: version,
+			capabilities: capabilities,
 			nonce: nonce,
+			genesis: genesis,
 			total_difficulty: total_difficulty,
-			sender_addr: SockAddr(conn.local_addr().unwrap()),
+			height: height,
+			sender_addr: SockAddr(self.advertised_addr.unwrap_or(conn.local_addr().unwrap())),
 			receiver_addr: SockAddr(conn.peer_addr().unwrap()),
 			user_agent: USER_AGENT.to_string(),
 		};
 
 		// write and read the handshake response
-		Box::new(write_msg(conn, hand, Type::Hand)
+		let hs = write_msg(conn, hand, Type::Hand)
 			.and_then(|conn| read_msg::<Shake>(conn))
-			.and_then(|(conn, shake)| {
-				if shake.version != 1 {
-					Err(Error::UnexpectedData {
-						expected: vec![PROTOCOL_VERSION as u8],
-						received: vec![shake.version as u8],
+			.map_err(Error::SerErr)
+			.and_then(move |(conn, shake)| {
+				let negotiated = try!(negotiate_version(version, shake.version));
+				if shake.genesis != genesis {
+					Err(Error::GenesisMismatch {
+						us: genesis,
+						peer: shake.genesis,
 					})
 				} else {
 					let peer_info = PeerInfo {
-						capabilities: shake.capabilities,
+						capabilities: capabilities & shake.capabilities,
 						user_agent: shake.user_agent,
 						addr: conn.peer_addr().unwrap(),
-						version: shake.version,
+						version: negotiated,
+						genesis: shake.genesis,
 						total_difficulty: shake.total_difficulty,
+						height: shake.height,
+						direction: Direction::Outbound,
+						advertised_addr: None,
 					};
 
-					info!("Connected to peer {:?}", peer_info);
-					// when more than one protocol version is supported, choosing should go here
-					Ok((conn, ProtocolV1::new(), peer_info))
+					info!(target: LOG_TARGET, "Connected to peer {:?}", peer_info);
+					Ok((conn, peer_info))
 				}
-			}))
+			})
+			.and_then(|(conn, peer_info)| {
+				exchange_keys(conn, &peer_info)
+					.map(move |(conn, ciphers)| (conn, ciphers, peer_info))
+			})
+			.map(move |(conn, ciphers, peer_info)| {
+				// when more than one protocol version is supported, choosing should go here
+				let compress = compression(&peer_info, compress_threshold);
+				let proto = ProtocolV1::new(max_msg_len,
+				                            io_timeouts,
+				                            compress,
+				                            ciphers,
+				                            peer_info.capabilities.contains(COMPACT_BLOCKS),
+				                            peer_info.total_difficulty.clone(),
+				                            peer_info.height,
+				                            addr_max_age,
+				                            max_addrs_per_minute);
+				(conn, proto, peer_info)
+			});
+		with_timeout(Box::new(hs), self.timeout)
 	}
 
 	/// Handles receiving a connection from a new remote peer that started the
 	/// version handshake.
 	pub fn handshake(&self,
 	                 total_difficulty: Difficulty,
+	                 height: u64,
 	                 conn: TcpStream)
 	                 -> Box<Future<Item = (TcpStream, ProtocolV1, PeerInfo), Error = Error>> {
 		let nonces = self.nonces.clone();
-		Box::new(read_msg::<Hand>(conn)
+		let genesis = self.genesis;
+		let (version, capabilities) = (self.version, self.capabilities);
+		let max_msg_len = self.max_msg_len;
+		let io_timeouts = self.io_timeouts;
+		let compress_threshold = self.compress_threshold;
+		let (addr_max_age, max_addrs_per_minute) = (self.addr_max_age, self.max_addrs_per_minute);
+		let hs = read_msg::<Hand>(conn)
+			.map_err(Error::SerErr)
 			.and_then(move |(conn, hand)| {
-				if hand.version != 1 {
-					return Err(Error::UnexpectedData {
-						expected: vec![PROTOCOL_VERSION as u8],
-						received: vec![hand.version as u8],
+				let negotiated = try!(negotiate_version(version, hand.version));
+				if hand.genesis != genesis {
+					return Err(Error::GenesisMismatch {
+						us: genesis,
+						peer: hand.genesis,
 					});
 				}
 				{
 					// check the nonce to see if we could be trying to connect to ourselves
 					let nonces = nonces.read().unwrap();
 					if nonces.contains(&hand.nonce) {
-						return Err(Error::UnexpectedData {
-							expected: vec![],
-							received: vec![],
-						});
+						return Err(Error::SelfConnection);
 					}
 				}
 				// all good, keep peer info
 				let peer_info = PeerInfo {
-					capabilities: hand.capabilities,
+					capabilities: capabilities & hand.capabilities,
 					user_agent: hand.user_agent,
 					addr: conn.peer_addr().unwrap(),
-					version: hand.version,
+					version: negotiated,
+					genesis: hand.genesis,
 					total_difficulty: hand.total_difficulty,
+					height: hand.height,
+					direction: Direction::Inbound,
+					advertised_addr: Some(hand.sender_addr.0),
 				};
 				// send our reply with our info
 				let shake = Shake {
-					version: PROTOCOL_VERSION,
-					capabilities: FULL_SYNC,
+					version: version,
+					capabilities: capabilities,
+					genesis: genesis,
 					total_difficulty: total_difficulty,
+					height: height,
 					user_agent: USER_AGENT.to_string(),
 				};
 				Ok((conn, shake, peer_info))
 			})
-			.and_then(|(conn, shake, peer_info)| {
+			.and_then(move |(conn, shake, peer_info)| {
 				write_msg(conn, shake, Type::Shake)
-				  // when more than one protocol version is supported, choosing should go here
-					.map(|conn| (conn, ProtocolV1::new(), peer_info))
-			}))
+					.map_err(Error::SerErr)
+					.and_then(move |conn| {
+						exchange_keys(conn, &peer_info)
+							.map(move |(conn, ciphers)| (conn, ciphers, peer_info))
+					})
+			})
+			.map(move |(conn, ciphers, peer_info)| {
+				// when more than one protocol version is supported, choosing should go here
+				let compress = compression(&peer_info, compress_threshold);
+				let proto = ProtocolV1::new(max_msg_len,
+				                            io_timeouts,
+				                            compress,
+				                            ciphers,
+				                            peer_info.capabilities.contains(COMPACT_BLOCKS),
+				                            peer_info.total_difficulty.clone(),
+				                            peer_info.height,
+				                            addr_max_age,
+				                            max_addrs_per_minute);
+				(conn, proto, peer_info)
+			});
+		with_timeout(Box::new(hs), self.timeout)
 	}
 
 	/// Generate a new random nonce and store it in our ring buffer
@@ -149,3 +313,62 @@ impl Handshake {
 		nonce
 	}
 }
+
+// Compression threshold to use with a peer, only when both of us support it
+fn compression(peer_info: &PeerInfo, threshold: u64) -> Option<u64> {
+	if peer_info.capabilities.contains(COMPRESSION) {
+		Some(threshold)
+	} else {
+		None
+	}
+}
+
+// Agrees on the keys encrypting the connection when both of us support it,
+// the side that initiated the connection sending its key first
+fn exchange_keys(conn: TcpStream,
+                 peer_info: &PeerInfo)
+                 -> Box<Future<Item = (TcpStream, Option<Ciphers>), Error = Error>> {
+	if !peer_info.capabilities.contains(ENCRYPTION) {
+		return Box::new(future::ok((conn, None)));
+	}
+	let key = EphemeralKey::new();
+	let ours = KeyExchange { public_key: key.public_key() };
+	let initiator = peer_info.direction == Direction::Outbound;
+	let exchange: Box<Future<Item = (TcpStream, KeyExchange), Error = ser::Error>> = if initiator {
+		Box::new(write_msg(conn, ours, Type::KeyExchange)
+			.and_then(|conn| read_msg::<KeyExchange>(conn)))
+	} else {
+		Box::new(read_msg::<KeyExchange>(conn).and_then(move |(conn, theirs)| {
+			write_msg(conn, ours, Type::KeyExchange).map(move |conn| (conn, theirs))
+		}))
+	};
+	Box::new(exchange.map_err(Error::SerErr).and_then(move |(conn, theirs)| {
+		let ciphers = try!(key.agree(&theirs.public_key, initiator).map_err(|_| {
+			Error::SerErr(ser::Error::CorruptedData)
+		}));
+		Ok((conn, Some(ciphers)))
+	}))
+}
+
+// Fails the handshake with a timeout error if it doesn't complete in time,
+// the connection gets dropped along with it
+fn with_timeout<T: 'static>(fut: Box<Future<Item = T, Error = Error>>,
+                            timeout: Duration)
+                            -> Box<Future<Item = T, Error = Error>> {
+	let expiry = Timer::default()
+		.sleep(timeout)
+		.then(|_| -> Result<T, Error> { Err(Error::Timeout) });
+	Box::new(fut.select(expiry).map(|(t, _)| t).map_err(|(e, _)| e))
+}
+
+// Both peers speak the lowest of their versions, as long as the remote one
+// isn't too old to talk to
+fn negotiate_version(ours: u32, theirs: u32) -> Result<u32, Error> {
+	if theirs < MIN_PROTOCOL_VERSION {
+		return Err(Error::VersionTooOld {
+			min: MIN_PROTOCOL_VERSION,
+			peer: theirs,
+		});
+	}
+	Ok(cmp::min(ours, theirs))
+}
@@ -0,0 +1,69 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Round-trip time to a peer, measured by matching the pongs it sends back
+//! with the nonce of our pings.
+
+use std::time::{Duration, Instant};
+
+/// Each new round-trip sample moves the smoothed one 1/RTT_SMOOTHING of the
+/// way towards it, as TCP does.
+pub const RTT_SMOOTHING: u32 = 8;
+
+/// Smoothed round-trip time to a peer, along with the ping it still has to
+/// answer.
+pub struct Latency {
+	// nonce of the ping waiting for its pong and when it was sent
+	pending: Option<(u64, Instant)>,
+	rtt: Option<Duration>,
+}
+
+impl Latency {
+	/// No round-trip measured yet.
+	pub fn new() -> Latency {
+		Latency {
+			pending: None,
+			rtt: None,
+		}
+	}
+
+	/// A ping with the provided nonce was sent at the provided time. A pong
+	/// for a previous ping can't be matched anymore.
+	pub fn ping_sent(&mut self, nonce: u64, at: Instant) {
+		self.pending = Some((nonce, at));
+	}
+
+	/// A pong echoing the provided nonce was received at the provided time.
+	/// Returns the round-trip time of the matching ping, if it's the one
+	/// we're waiting for. Unmatched pongs are ignored.
+	pub fn pong_received(&mut self, nonce: u64, at: Instant) -> Option<Duration> {
+		match self.pending {
+			Some((n, sent)) if n == nonce && at >= sent => {
+				self.pending = None;
+				let sample = at - sent;
+				self.rtt = Some(match self.rtt {
+					Some(rtt) => (rtt * (RTT_SMOOTHING - 1) + sample) / RTT_SMOOTHING,
+					None => sample,
+				});
+				Some(sample)
+			}
+			_ => None,
+		}
+	}
+
+	/// Smoothed round-trip time, once a ping got answered.
+	pub fn rtt(&self) -> Option<Duration> {
+		self.rtt
+	}
+}
@@ -26,25 +26,56 @@ extern crate bitflags;
 extern crate enum_primitive;
 #[macro_use]
 extern crate grin_core as core;
+extern crate grin_store;
 extern crate grin_util as util;
 #[macro_use]
 extern crate log;
+extern crate flate2;
 extern crate futures;
+extern crate futures_cpupool;
 #[macro_use]
 extern crate tokio_core;
 extern crate tokio_timer;
 extern crate rand;
 extern crate time;
+extern crate net2;
 extern crate num;
+extern crate crypto;
+extern crate secp256k1zkp as secp;
 
+mod announce;
 mod conn;
+mod crypt;
 pub mod handshake;
+mod latency;
 mod msg;
 mod peer;
 mod protocol;
+mod rate;
+mod reconnect;
+mod seed;
 mod server;
+mod store;
 mod types;
 
-pub use server::{Server, DummyAdapter};
+/// Target of all the logs of the peer-to-peer layer, so their level can be
+/// set apart from the other subsystems'.
+pub const LOG_TARGET: &'static str = "grin::p2p";
+
+pub use announce::{AnnounceWindow, RelayCache};
+pub use latency::{Latency, RTT_SMOOTHING};
+pub use msg::{Type, MsgCategory, BlockBundle, CompactBlock, Headers, PeerAddr, PeerAddrs, SockAddr,
+              short_id, HEADER_LEN, COMPRESS_THRESHOLD, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION};
+pub use rate::RateLimiter;
+pub use reconnect::{Reconnector, Sleep, RECONNECT_BASE_DELAY};
+pub use seed::{DnsResolver, Resolver, resolve_seeds};
+pub use server::{Server, DummyAdapter, addr_group, select_outbound};
 pub use peer::Peer;
-pub use types::{P2PConfig, NetAdapter, MAX_LOCATORS, MAX_BLOCK_HEADERS};
+pub use store::{PeerStore, PeerData, State};
+pub use types::{P2PConfig, MsgRateLimit, NetAdapter, Error, BanReason, ByeReason, TxStatus,
+                DisconnectReason, Capabilities, PeerInfo, PeerStats, Direction, FULL_SYNC,
+                COMPRESSION, ENCRYPTION, BLOCK_BUNDLES, COMPACT_BLOCKS, UNKNOWN, MAX_LOCATORS,
+                MAX_BLOCK_HEADERS, MAX_BUNDLE_BLOCKS, MAX_BUNDLE_SIZE, MAX_PEER_ADDRS,
+                MAX_BAN_SCORE, BAN_SCORE_DECAY, MIN_REPUTATION, MAX_REPUTATION, BLOCK_REPUTATION,
+                INVALID_MSG_REPUTATION, PONG_REPUTATION, ADDR_MAX_AGE, MAX_ADDRS_PER_MINUTE,
+                is_routable};
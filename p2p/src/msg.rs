@@ -14,23 +14,32 @@
 
 //! Message types that transit over the network and related serialization code.
 
+use std::collections::HashSet;
+use std::io::{Read, Write};
 use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
 use num::FromPrimitive;
 
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
 use futures::future::{Future, ok};
 use tokio_core::net::TcpStream;
 use tokio_core::io::{write_all, read_exact};
 
 use core::consensus::MAX_MSG_LEN;
-use core::core::BlockHeader;
-use core::core::hash::Hash;
+use core::core::{Block, BlockHeader, Input, Output, Transaction, TxProof};
+use core::core::hash::{Hash, Hashed};
 use core::core::target::Difficulty;
 use core::ser::{self, Writeable, Readable, Writer, Reader};
 
 use types::*;
 
-/// Current latest version of the protocol
-pub const PROTOCOL_VERSION: u32 = 1;
+/// Current latest version of the protocol. Version 2 added the genesis block
+/// hash to the Hand and Shake messages.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest version of the protocol we still accept to talk to, the handshake
+/// messages of version 1 peers can't be read anymore.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
 /// Grin's user agent with current version (TODO externalize)
 pub const USER_AGENT: &'static str = "MW/Grin 0.1";
 
@@ -40,12 +49,20 @@ const MAGIC: [u8; 2] = [0x1e, 0xc5];
 /// Size in bytes of a message header
 pub const HEADER_LEN: u64 = 11;
 
+/// Bit set on the message type in the header when the body is compressed
+const COMPRESSED_FLAG: u8 = 0x80;
+
+/// Default size in bytes above which message bodies get compressed, when
+/// the peer supports it
+pub const COMPRESS_THRESHOLD: u64 = 1024;
+
 /// Codes for each error that can be produced reading a message.
 pub enum ErrCodes {
 	UnsupportedVersion = 100,
 }
 
-/// Types of messages
+/// Types of messages. New types go last, their wire value being their
+/// position, and the message counters of conn.rs go up to the last one.
 enum_from_primitive! {
   #[derive(Debug, Clone, Copy, PartialEq)]
   pub enum Type {
@@ -61,9 +78,78 @@ enum_from_primitive! {
     GetBlock,
     Block,
     Transaction,
+    CompactBlock,
+    GetBlockTxn,
+    BlockTxn,
+    Bye,
+    Inv,
+    GetData,
+    KeyExchange,
+    GetBlockBundle,
+    BlockBundle,
   }
 }
 
+/// Categories of messages, each limited to its own rate so a peer flooding
+/// us with messages of one kind doesn't starve the others.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MsgCategory {
+	/// Pings, errors and the like
+	Control,
+	/// Peer addresses requests and responses
+	PeerAddrs,
+	/// Block headers requests and responses
+	Headers,
+	/// Blocks and missing transactions of compact blocks
+	Blocks,
+	/// Transaction relays
+	Transactions,
+}
+
+/// Largest serialized size of a peer address
+const SOCK_ADDR_LEN: u64 = 19;
+
+/// Largest serialized size of a relayed peer address, along with when it
+/// was last seen
+const PEER_ADDR_LEN: u64 = SOCK_ADDR_LEN + 8;
+
+impl Type {
+	/// Maximum length of the body of a message of this type, when we know
+	/// it has to be smaller than any configured maximum. Messages like pings
+	/// can't legitimately be megabytes large.
+	pub fn max_len(&self) -> Option<u64> {
+		match *self {
+			Type::Error => Some(1024),
+			Type::Hand | Type::Shake => Some(1024),
+			Type::Ping | Type::Pong => Some(64),
+			Type::GetPeerAddrs => Some(4),
+			Type::PeerAddrs => Some(4 + MAX_PEER_ADDRS as u64 * PEER_ADDR_LEN),
+			Type::GetHeaders => Some(1 + MAX_LOCATORS as u64 * 32),
+			Type::GetBlock | Type::Inv | Type::GetData => Some(32),
+			Type::Bye => Some(1),
+			Type::KeyExchange => Some(8 + 33),
+			Type::GetBlockBundle => Some(32 + 4),
+			Type::BlockBundle => Some(MAX_BUNDLE_SIZE),
+			Type::Headers | Type::Block | Type::Transaction | Type::CompactBlock |
+			Type::GetBlockTxn | Type::BlockTxn => None,
+		}
+	}
+
+	/// Category of the messages of this type, for rate limiting.
+	pub fn category(&self) -> MsgCategory {
+		match *self {
+			Type::Error | Type::Hand | Type::Shake | Type::Ping | Type::Pong | Type::Bye |
+			Type::KeyExchange => MsgCategory::Control,
+			Type::GetPeerAddrs | Type::PeerAddrs => MsgCategory::PeerAddrs,
+			Type::GetHeaders | Type::Headers => MsgCategory::Headers,
+			Type::GetBlock | Type::Block | Type::CompactBlock | Type::GetBlockTxn |
+			Type::BlockTxn | Type::Inv | Type::GetData | Type::GetBlockBundle |
+			Type::BlockBundle => MsgCategory::Blocks,
+			Type::Transaction => MsgCategory::Transactions,
+		}
+	}
+}
+
 /// Future combinator to read any message where the body is a Readable. Reads
 /// the  header first, handles its validation and then reads the Readable body,
 /// allocating buffers of the right size.
@@ -74,11 +160,7 @@ pub fn read_msg<T>(conn: TcpStream) -> Box<Future<Item = (TcpStream, T), Error =
 		.map_err(|e| ser::Error::IOErr(e))
 		.and_then(|(reader, buf)| {
 			let header = try!(ser::deserialize::<MsgHeader>(&mut &buf[..]));
-			if header.msg_len > MAX_MSG_LEN {
-				// TODO add additional restrictions on a per-message-type basis to avoid 20MB
-				// pings
-				return Err(ser::Error::TooLargeReadErr);
-			}
+			try!(header.check_len(MAX_MSG_LEN));
 			Ok((reader, header))
 		});
 
@@ -102,15 +184,16 @@ pub fn write_msg<T>(conn: TcpStream,
 	where T: Writeable + 'static
 {
 	let write_msg = ok((conn)).and_then(move |conn| {
-		// prepare the body first so we know its serialized length
-		let mut body_buf = vec![];
-		ser::serialize(&mut body_buf, &msg);
+		// prepare the body first so we know its serialized length, nothing
+		// gets sent if it can't be serialized
+		let body_buf = try!(ser::ser_vec(&msg));
 
 		// build and serialize the header using the body size
-		let mut header_buf = vec![];
 		let blen = body_buf.len() as u64;
-		ser::serialize(&mut header_buf, &MsgHeader::new(msg_type, blen));
-
+		let header_buf = try!(ser::ser_vec(&MsgHeader::new(msg_type, blen)));
+		Ok((conn, header_buf, body_buf))
+	});
+	let write_msg = write_msg.and_then(|(conn, header_buf, body_buf)| {
 		// send the whole thing
 		write_all(conn, header_buf)
 			.and_then(|(conn, _)| write_all(conn, body_buf))
@@ -120,11 +203,35 @@ pub fn write_msg<T>(conn: TcpStream,
 	Box::new(write_msg)
 }
 
+/// Compresses a message body.
+pub fn compress(data: &[u8]) -> Result<Vec<u8>, ser::Error> {
+	let mut encoder = DeflateEncoder::new(Vec::with_capacity(data.len() / 2),
+	                                      Compression::Default);
+	try!(encoder.write_all(data).map_err(ser::Error::IOErr));
+	encoder.finish().map_err(ser::Error::IOErr)
+}
+
+/// Decompresses a message body, refusing to inflate it beyond max_len
+/// bytes so a small message can't turn into a huge allocation.
+pub fn decompress(data: &[u8], max_len: u64) -> Result<Vec<u8>, ser::Error> {
+	let mut buf = vec![];
+	try!(DeflateDecoder::new(data)
+		.take(max_len + 1)
+		.read_to_end(&mut buf)
+		.map_err(ser::Error::IOErr));
+	if buf.len() as u64 > max_len {
+		return Err(ser::Error::TooLargeReadErr);
+	}
+	Ok(buf)
+}
+
 /// Header of any protocol message, used to identify incoming messages.
 pub struct MsgHeader {
 	magic: [u8; 2],
 	/// Type of the message.
 	pub msg_type: Type,
+	/// Whether the body is compressed.
+	pub compressed: bool,
 	/// Tota length of the message in bytes.
 	pub msg_len: u64,
 }
@@ -135,22 +242,49 @@ impl MsgHeader {
 		MsgHeader {
 			magic: MAGIC,
 			msg_type: msg_type,
+			compressed: false,
 			msg_len: len,
 		}
 	}
 
+	/// Creates a new header for a message with a compressed body of the
+	/// provided length.
+	pub fn compressed(msg_type: Type, len: u64) -> MsgHeader {
+		MsgHeader { compressed: true, ..MsgHeader::new(msg_type, len) }
+	}
+
 	/// Serialized length of the header in bytes
 	pub fn serialized_len(&self) -> u64 {
 		HEADER_LEN
 	}
+
+	/// Checks the message length the header declares against the provided
+	/// maximum and the one for its type, so we can refuse it before
+	/// allocating anything for the body.
+	pub fn check_len(&self, max_len: u64) -> Result<(), ser::Error> {
+		if self.msg_len > self.max_len(max_len) {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		Ok(())
+	}
+
+	/// Largest body a message of this type can have, given the provided
+	/// maximum for any message.
+	pub fn max_len(&self, max_len: u64) -> u64 {
+		match self.msg_type.max_len() {
+			Some(type_max) if type_max < max_len => type_max,
+			_ => max_len,
+		}
+	}
 }
 
 impl Writeable for MsgHeader {
 	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		let flag = if self.compressed { COMPRESSED_FLAG } else { 0 };
 		ser_multiwrite!(writer,
 		                [write_u8, self.magic[0]],
 		                [write_u8, self.magic[1]],
-		                [write_u8, self.msg_type as u8],
+		                [write_u8, self.msg_type as u8 | flag],
 		                [write_u64, self.msg_len]);
 		Ok(())
 	}
@@ -161,11 +295,12 @@ impl Readable<MsgHeader> for MsgHeader {
 		try!(reader.expect_u8(MAGIC[0]));
 		try!(reader.expect_u8(MAGIC[1]));
 		let (t, len) = ser_multiread!(reader, read_u8, read_u64);
-		match Type::from_u8(t) {
+		match Type::from_u8(t & !COMPRESSED_FLAG) {
 			Some(ty) => {
 				Ok(MsgHeader {
 					magic: MAGIC,
 					msg_type: ty,
+					compressed: t & COMPRESSED_FLAG != 0,
 					msg_len: len,
 				})
 			}
@@ -183,10 +318,14 @@ pub struct Hand {
 	pub capabilities: Capabilities,
 	/// randomly generated for each handshake, helps detect self
 	pub nonce: u64,
+	/// genesis block of our chain, only peers on the same chain will connect
+	pub genesis: Hash,
 	/// total difficulty accumulated by the sender, used to check whether sync
 	/// may
 	/// be needed
 	pub total_difficulty: Difficulty,
+	/// height of the sender chain
+	pub height: u64,
 	/// network address of the sender
 	pub sender_addr: SockAddr,
 	/// network address of the receiver
@@ -201,9 +340,11 @@ impl Writeable for Hand {
 		                [write_u32, self.version],
 		                [write_u32, self.capabilities.bits()],
 		                [write_u64, self.nonce]);
-		self.total_difficulty.write(writer);
-		self.sender_addr.write(writer);
-		self.receiver_addr.write(writer);
+		try!(self.genesis.write(writer));
+		try!(self.total_difficulty.write(writer));
+		try!(writer.write_u64(self.height));
+		try!(self.sender_addr.write(writer));
+		try!(self.receiver_addr.write(writer));
 		writer.write_bytes(&self.user_agent)
 	}
 }
@@ -211,17 +352,22 @@ impl Writeable for Hand {
 impl Readable<Hand> for Hand {
 	fn read(reader: &mut Reader) -> Result<Hand, ser::Error> {
 		let (version, capab, nonce) = ser_multiread!(reader, read_u32, read_u32, read_u64);
+		let genesis = try!(Hash::read(reader));
 		let total_diff = try!(Difficulty::read(reader));
+		let height = try!(reader.read_u64());
 		let sender_addr = try!(SockAddr::read(reader));
 		let receiver_addr = try!(SockAddr::read(reader));
 		let ua = try!(reader.read_vec());
 		let user_agent = try!(String::from_utf8(ua).map_err(|_| ser::Error::CorruptedData));
-		let capabilities = try!(Capabilities::from_bits(capab).ok_or(ser::Error::CorruptedData));
+		// capabilities we don't know of yet can't be negotiated, just ignore them
+		let capabilities = Capabilities::from_bits_truncate(capab);
 		Ok(Hand {
 			version: version,
 			capabilities: capabilities,
 			nonce: nonce,
+			genesis: genesis,
 			total_difficulty: total_diff,
+			height: height,
 			sender_addr: sender_addr,
 			receiver_addr: receiver_addr,
 			user_agent: user_agent,
@@ -236,10 +382,14 @@ pub struct Shake {
 	pub version: u32,
 	/// sender capabilities
 	pub capabilities: Capabilities,
+	/// genesis block of our chain, only peers on the same chain will connect
+	pub genesis: Hash,
 	/// total difficulty accumulated by the sender, used to check whether sync
 	/// may
 	/// be needed
 	pub total_difficulty: Difficulty,
+	/// height of the sender chain
+	pub height: u64,
 	/// name of version of the software
 	pub user_agent: String,
 }
@@ -249,23 +399,28 @@ impl Writeable for Shake {
 		ser_multiwrite!(writer,
 		                [write_u32, self.version],
 		                [write_u32, self.capabilities.bits()]);
-		self.total_difficulty.write(writer);
-		writer.write_bytes(&self.user_agent);
-		Ok(())
+		try!(self.genesis.write(writer));
+		try!(self.total_difficulty.write(writer));
+		try!(writer.write_u64(self.height));
+		writer.write_bytes(&self.user_agent)
 	}
 }
 
 impl Readable<Shake> for Shake {
 	fn read(reader: &mut Reader) -> Result<Shake, ser::Error> {
 		let (version, capab) = ser_multiread!(reader, read_u32, read_u32);
+		let genesis = try!(Hash::read(reader));
 		let total_diff = try!(Difficulty::read(reader));
+		let height = try!(reader.read_u64());
 		let ua = try!(reader.read_vec());
 		let user_agent = try!(String::from_utf8(ua).map_err(|_| ser::Error::CorruptedData));
-		let capabilities = try!(Capabilities::from_bits(capab).ok_or(ser::Error::CorruptedData));
+		let capabilities = Capabilities::from_bits_truncate(capab);
 		Ok(Shake {
 			version: version,
 			capabilities: capabilities,
+			genesis: genesis,
 			total_difficulty: total_diff,
+			height: height,
 			user_agent: user_agent,
 		})
 	}
@@ -292,16 +447,20 @@ impl Readable<GetPeerAddrs> for GetPeerAddrs {
 }
 
 /// Peer addresses we know of that are fresh enough, in response to
-/// GetPeerAddrs.
+/// GetPeerAddrs. Never more than MAX_PEER_ADDRS of them.
 pub struct PeerAddrs {
-	pub peers: Vec<SockAddr>,
+	pub peers: Vec<PeerAddr>,
 }
 
 impl Writeable for PeerAddrs {
 	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		// checked upfront so nothing gets written, our peers would drop us
+		if self.peers.len() > MAX_PEER_ADDRS as usize {
+			return Err(ser::Error::TooLargeWriteErr);
+		}
 		try!(writer.write_u32(self.peers.len() as u32));
 		for p in &self.peers {
-			p.write(writer);
+			try!(p.write(writer));
 		}
 		Ok(())
 	}
@@ -310,14 +469,22 @@ impl Writeable for PeerAddrs {
 impl Readable<PeerAddrs> for PeerAddrs {
 	fn read(reader: &mut Reader) -> Result<PeerAddrs, ser::Error> {
 		let peer_count = try!(reader.read_u32());
-		if peer_count > 1000 {
+		if peer_count > MAX_PEER_ADDRS {
 			return Err(ser::Error::TooLargeReadErr);
 		}
-		let peers = try_map_vec!([0..peer_count], |_| SockAddr::read(reader));
+		let peers = try_map_vec!([0..peer_count], |_| PeerAddr::read(reader));
 		Ok(PeerAddrs { peers: peers })
 	}
 }
 
+/// Error code sent to a peer we disconnect from because we already have
+/// enough peers.
+pub const ERR_TOO_MANY_PEERS: u32 = 1;
+
+/// Error code sent to our peers when we're shutting down, by nodes that
+/// don't say bye yet.
+pub const ERR_SHUTDOWN: u32 = 2;
+
 /// We found some issue in the communication, sending an error back, usually
 /// followed by closing the connection.
 pub struct PeerError {
@@ -347,7 +514,9 @@ impl Readable<PeerError> for PeerError {
 
 /// Only necessary so we can implement Readable and Writeable. Rust disallows
 /// implementing traits when both types are outside of this crate (which is the
-/// case for SocketAddr and Readable/Writeable).
+/// case for SocketAddr and Readable/Writeable). Serialized as an address
+/// family byte, 0 for IPv4 and 1 for IPv6, followed by the 4 or 16 bytes of
+/// the IP and the port.
 pub struct SockAddr(pub SocketAddr);
 
 impl Writeable for SockAddr {
@@ -374,6 +543,9 @@ impl Writeable for SockAddr {
 impl Readable<SockAddr> for SockAddr {
 	fn read(reader: &mut Reader) -> Result<SockAddr, ser::Error> {
 		let v4_or_v6 = try!(reader.read_u8());
+		if v4_or_v6 > 1 {
+			return Err(ser::Error::CorruptedData);
+		}
 		if v4_or_v6 == 0 {
 			let ip = try!(reader.read_fixed_bytes(4));
 			let port = try!(reader.read_u16());
@@ -400,6 +572,32 @@ impl Readable<SockAddr> for SockAddr {
 	}
 }
 
+/// Address of a peer relayed to another one, with the last time we
+/// successfully connected to it as a UTC timestamp in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerAddr {
+	pub addr: SocketAddr,
+	pub last_seen: i64,
+}
+
+impl Writeable for PeerAddr {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		try!(SockAddr(self.addr).write(writer));
+		writer.write_i64(self.last_seen)
+	}
+}
+
+impl Readable<PeerAddr> for PeerAddr {
+	fn read(reader: &mut Reader) -> Result<PeerAddr, ser::Error> {
+		let addr = try!(SockAddr::read(reader));
+		let last_seen = try!(reader.read_i64());
+		Ok(PeerAddr {
+			addr: addr.0,
+			last_seen: last_seen,
+		})
+	}
+}
+
 /// Serializable wrapper for the block locator.
 pub struct Locator {
 	pub hashes: Vec<Hash>,
@@ -426,13 +624,18 @@ impl Readable<Locator> for Locator {
 	}
 }
 
-/// Serializable wrapper for a list of block headers.
+/// Serializable wrapper for a list of block headers, never more than
+/// MAX_BLOCK_HEADERS of them.
 pub struct Headers {
 	pub headers: Vec<BlockHeader>,
 }
 
 impl Writeable for Headers {
 	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		// also keeps the count from overflowing its u16
+		if self.headers.len() > MAX_BLOCK_HEADERS as usize {
+			return Err(ser::Error::TooLargeWriteErr);
+		}
 		writer.write_u16(self.headers.len() as u16)?;
 		for h in &self.headers {
 			h.write(writer)?
@@ -444,6 +647,9 @@ impl Writeable for Headers {
 impl Readable<Headers> for Headers {
 	fn read(reader: &mut Reader) -> Result<Headers, ser::Error> {
 		let len = reader.read_u16()?;
+		if len as u32 > MAX_BLOCK_HEADERS {
+			return Err(ser::Error::TooLargeReadErr);
+		}
 		let mut headers = Vec::with_capacity(len as usize);
 		for _ in 0..len {
 			headers.push(BlockHeader::read(reader)?);
@@ -452,8 +658,327 @@ impl Readable<Headers> for Headers {
 	}
 }
 
-/// Placeholder for messages like Ping and Pong that don't send anything but
-/// the header.
+/// Short identifier of a transaction in a compact block, the first 8 bytes
+/// of its hash.
+pub fn short_id(tx: &Transaction) -> u64 {
+	tx.hash().0[..8].iter().fold(0, |acc, &b| (acc << 8) | b as u64)
+}
+
+/// A block relayed without the transactions our peers most likely already
+/// have. Carries the block header and all its proofs, the short ids of the
+/// transactions it was built from and whatever inputs and outputs can't be
+/// found in those transactions, like the reward.
+pub struct CompactBlock {
+	/// header of the full block
+	pub header: BlockHeader,
+	/// inputs not coming from any of the transactions
+	pub inputs: Vec<Input>,
+	/// outputs not coming from any of the transactions
+	pub outputs: Vec<Output>,
+	/// all the proofs of the full block
+	pub proofs: Vec<TxProof>,
+	/// short ids of the transactions the block was built from
+	pub tx_ids: Vec<u64>,
+}
+
+impl CompactBlock {
+	/// Builds the compact version of a block out of the transactions we know
+	/// of. Only the transactions that have a proof in the block are kept.
+	pub fn new(b: &Block, txs: &[Transaction]) -> CompactBlock {
+		let sigs = b.proofs.iter().map(|p| p.sig.clone()).collect::<HashSet<_>>();
+		let txs = txs.iter().filter(|tx| sigs.contains(&tx.zerosig)).collect::<Vec<_>>();
+
+		let tx_inputs = txs.iter()
+			.flat_map(|tx| tx.inputs.iter().map(|inp| inp.hash()))
+			.collect::<HashSet<_>>();
+		let tx_outputs = txs.iter()
+			.flat_map(|tx| tx.outputs.iter().map(|out| out.hash()))
+			.collect::<HashSet<_>>();
+
+		CompactBlock {
+			header: b.header.clone(),
+			inputs: b.inputs.iter().filter(|inp| !tx_inputs.contains(&inp.hash())).cloned().collect(),
+			outputs: b.outputs.iter().filter(|out| !tx_outputs.contains(&out.hash())).cloned().collect(),
+			proofs: b.proofs.clone(),
+			tx_ids: txs.iter().map(|tx| short_id(tx)).collect(),
+		}
+	}
+
+	/// Hash of the full block.
+	pub fn hash(&self) -> Hash {
+		self.header.hash()
+	}
+
+	/// Rebuilds the full block from the transactions our short ids refer to,
+	/// in the same order. None if the result doesn't match the header, which
+	/// can happen on a short id collision.
+	pub fn reconstruct(&self, txs: &[Transaction]) -> Option<Block> {
+		if txs.len() != self.tx_ids.len() {
+			return None;
+		}
+		let mut inputs = self.inputs.clone();
+		let mut outputs = self.outputs.clone();
+		for tx in txs {
+			inputs.append(&mut tx.inputs.clone());
+			outputs.append(&mut tx.outputs.clone());
+		}
+		inputs.sort_by_key(|inp| inp.hash());
+		outputs.sort_by_key(|out| out.hash());
+
+		// compacting recalculates the Merkle root, which has to be the one we got
+		let b = Block {
+				header: self.header.clone(),
+				inputs: inputs,
+				outputs: outputs,
+				proofs: self.proofs.clone(),
+			}
+			.compact();
+		if b.header.tx_merkle != self.header.tx_merkle {
+			return None;
+		}
+		Some(b)
+	}
+}
+
+impl Writeable for CompactBlock {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		try!(self.header.write(writer));
+		ser_multiwrite!(writer,
+		                [write_u64, self.inputs.len() as u64],
+		                [write_u64, self.outputs.len() as u64],
+		                [write_u64, self.proofs.len() as u64],
+		                [write_u64, self.tx_ids.len() as u64]);
+		for inp in &self.inputs {
+			try!(inp.write(writer));
+		}
+		for out in &self.outputs {
+			try!(out.write(writer));
+		}
+		for proof in &self.proofs {
+			try!(proof.write(writer));
+		}
+		for id in &self.tx_ids {
+			try!(writer.write_u64(*id));
+		}
+		Ok(())
+	}
+}
+
+impl Readable<CompactBlock> for CompactBlock {
+	fn read(reader: &mut Reader) -> Result<CompactBlock, ser::Error> {
+		let header = try!(BlockHeader::read(reader));
+		let (input_len, output_len, proof_len, id_len) =
+			ser_multiread!(reader, read_u64, read_u64, read_u64, read_u64);
+
+		let inputs = try!((0..input_len).map(|_| Input::read(reader)).collect());
+		let outputs = try!((0..output_len).map(|_| Output::read(reader)).collect());
+		let proofs = try!((0..proof_len).map(|_| TxProof::read(reader)).collect());
+		let tx_ids = try!((0..id_len).map(|_| reader.read_u64()).collect());
+
+		Ok(CompactBlock {
+			header: header,
+			inputs: inputs,
+			outputs: outputs,
+			proofs: proofs,
+			tx_ids: tx_ids,
+		})
+	}
+}
+
+/// Request for the transactions of a compact block we couldn't find.
+pub struct GetBlockTxn {
+	/// hash of the block
+	pub hash: Hash,
+	/// short ids of the transactions we're missing
+	pub tx_ids: Vec<u64>,
+}
+
+impl Writeable for GetBlockTxn {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		try!(self.hash.write(writer));
+		try!(writer.write_u64(self.tx_ids.len() as u64));
+		for id in &self.tx_ids {
+			try!(writer.write_u64(*id));
+		}
+		Ok(())
+	}
+}
+
+impl Readable<GetBlockTxn> for GetBlockTxn {
+	fn read(reader: &mut Reader) -> Result<GetBlockTxn, ser::Error> {
+		let hash = try!(Hash::read(reader));
+		let len = try!(reader.read_u64());
+		let tx_ids = try!((0..len).map(|_| reader.read_u64()).collect());
+		Ok(GetBlockTxn {
+			hash: hash,
+			tx_ids: tx_ids,
+		})
+	}
+}
+
+/// Request for a contiguous range of blocks of the peer's chain, starting
+/// with the block with the provided hash.
+pub struct GetBlockBundle {
+	/// hash of the first block of the range
+	pub start: Hash,
+	/// number of blocks wanted, at most MAX_BUNDLE_BLOCKS
+	pub count: u32,
+}
+
+impl Writeable for GetBlockBundle {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		try!(self.start.write(writer));
+		writer.write_u32(self.count)
+	}
+}
+
+impl Readable<GetBlockBundle> for GetBlockBundle {
+	fn read(reader: &mut Reader) -> Result<GetBlockBundle, ser::Error> {
+		let start = try!(Hash::read(reader));
+		let count = try!(reader.read_u32());
+		if count > MAX_BUNDLE_BLOCKS {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		Ok(GetBlockBundle {
+			start: start,
+			count: count,
+		})
+	}
+}
+
+/// Blocks sent in a single message, in the order of the chain they're
+/// part of. At most MAX_BUNDLE_BLOCKS of them, the serialized whole staying
+/// within MAX_BUNDLE_SIZE.
+pub struct BlockBundle {
+	/// hash of the first block that was asked for, even if it's missing
+	pub start: Hash,
+	pub blocks: Vec<Block>,
+}
+
+impl Writeable for BlockBundle {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		if self.blocks.len() > MAX_BUNDLE_BLOCKS as usize {
+			return Err(ser::Error::TooLargeWriteErr);
+		}
+		try!(self.start.write(writer));
+		try!(writer.write_u32(self.blocks.len() as u32));
+		for b in &self.blocks {
+			try!(b.write(writer));
+		}
+		Ok(())
+	}
+}
+
+impl Readable<BlockBundle> for BlockBundle {
+	fn read(reader: &mut Reader) -> Result<BlockBundle, ser::Error> {
+		let start = try!(Hash::read(reader));
+		let len = try!(reader.read_u32());
+		if len > MAX_BUNDLE_BLOCKS {
+			return Err(ser::Error::TooLargeReadErr);
+		}
+		let mut blocks = Vec::with_capacity(len as usize);
+		for _ in 0..len {
+			blocks.push(try!(Block::read(reader)));
+		}
+		Ok(BlockBundle {
+			start: start,
+			blocks: blocks,
+		})
+	}
+}
+
+/// Transactions of a compact block a peer asked us for.
+pub struct BlockTxn {
+	/// hash of the block
+	pub hash: Hash,
+	/// the transactions that were requested, as far as we have them
+	pub txs: Vec<Transaction>,
+}
+
+impl Writeable for BlockTxn {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		try!(self.hash.write(writer));
+		try!(writer.write_u64(self.txs.len() as u64));
+		for tx in &self.txs {
+			try!(tx.write(writer));
+		}
+		Ok(())
+	}
+}
+
+impl Readable<BlockTxn> for BlockTxn {
+	fn read(reader: &mut Reader) -> Result<BlockTxn, ser::Error> {
+		let hash = try!(Hash::read(reader));
+		let len = try!(reader.read_u64());
+		let txs = try!((0..len).map(|_| Transaction::read(reader)).collect());
+		Ok(BlockTxn {
+			hash: hash,
+			txs: txs,
+		})
+	}
+}
+
+/// Keepalive sent regularly to our peers, also gossips our total difficulty.
+pub struct Ping {
+	/// total difficulty accumulated by the sender
+	pub total_difficulty: Difficulty,
+	/// height of the sender chain
+	pub height: u64,
+	/// random nonce the pong echoes back, to measure the round-trip time
+	pub nonce: u64,
+}
+
+impl Writeable for Ping {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		try!(self.total_difficulty.write(writer));
+		ser_multiwrite!(writer, [write_u64, self.height], [write_u64, self.nonce]);
+		Ok(())
+	}
+}
+
+impl Readable<Ping> for Ping {
+	fn read(reader: &mut Reader) -> Result<Ping, ser::Error> {
+		let total_difficulty = try!(Difficulty::read(reader));
+		let (height, nonce) = ser_multiread!(reader, read_u64, read_u64);
+		Ok(Ping {
+			total_difficulty: total_difficulty,
+			height: height,
+			nonce: nonce,
+		})
+	}
+}
+
+/// Reply to a Ping, with the total difficulty of the replying peer.
+pub struct Pong {
+	/// total difficulty accumulated by the sender
+	pub total_difficulty: Difficulty,
+	/// height of the sender chain
+	pub height: u64,
+	/// nonce of the ping this answers
+	pub nonce: u64,
+}
+
+impl Writeable for Pong {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		try!(self.total_difficulty.write(writer));
+		ser_multiwrite!(writer, [write_u64, self.height], [write_u64, self.nonce]);
+		Ok(())
+	}
+}
+
+impl Readable<Pong> for Pong {
+	fn read(reader: &mut Reader) -> Result<Pong, ser::Error> {
+		let total_difficulty = try!(Difficulty::read(reader));
+		let (height, nonce) = ser_multiread!(reader, read_u64, read_u64);
+		Ok(Pong {
+			total_difficulty: total_difficulty,
+			height: height,
+			nonce: nonce,
+		})
+	}
+}
+
+/// Placeholder for messages that don't send anything but the header.
 pub struct Empty {}
 
 impl Writeable for Empty {
@@ -467,3 +992,45 @@ impl Readable<Empty> for Empty {
 		Ok(Empty {})
 	}
 }
+
+/// Sent right before intentionally closing the connection, so the remote
+/// peer can tell it apart from a crash or a network failure.
+pub struct Bye {
+	/// why we're disconnecting
+	pub reason: ByeReason,
+}
+
+impl Writeable for Bye {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		writer.write_u8(self.reason as u8)
+	}
+}
+
+impl Readable<Bye> for Bye {
+	fn read(reader: &mut Reader) -> Result<Bye, ser::Error> {
+		let code = try!(reader.read_u8());
+		// an unknown reason still means the peer is leaving on purpose
+		let reason = ByeReason::from_u8(code).unwrap_or(ByeReason::Unspecified);
+		Ok(Bye { reason: reason })
+	}
+}
+
+/// Ephemeral public key sent right after the version handshake by peers
+/// that both support encryption, see the `crypt` module.
+pub struct KeyExchange {
+	/// compressed public key of the sender
+	pub public_key: Vec<u8>,
+}
+
+impl Writeable for KeyExchange {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		writer.write_bytes(&self.public_key)
+	}
+}
+
+impl Readable<KeyExchange> for KeyExchange {
+	fn read(reader: &mut Reader) -> Result<KeyExchange, ser::Error> {
+		let public_key = try!(reader.read_vec());
+		Ok(KeyExchange { public_key: public_key })
+	}
+}
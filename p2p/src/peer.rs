@@ -13,16 +13,21 @@
 // limitations under the License.
 
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use futures::Future;
+use futures::{Future, Stream};
 use tokio_core::net::TcpStream;
+use tokio_timer::Timer;
 
+use announce::AnnounceWindow;
 use core::core;
 use core::core::hash::Hash;
 use core::core::target::Difficulty;
-use core::ser::Error;
 use handshake::Handshake;
+use msg::CompactBlock;
+use rate::RateLimiter;
 use types::*;
+use LOG_TARGET;
 
 pub struct Peer {
 	pub info: PeerInfo,
@@ -36,30 +41,34 @@ impl Peer {
 	/// Initiates the handshake with another peer.
 	pub fn connect(conn: TcpStream,
 	               total_difficulty: Difficulty,
+	               height: u64,
 	               hs: &Handshake)
 	               -> Box<Future<Item = (TcpStream, Peer), Error = Error>> {
-		let connect_peer = hs.connect(total_difficulty, conn).and_then(|(conn, proto, info)| {
-			Ok((conn,
-			    Peer {
-				info: info,
-				proto: Box::new(proto),
-			}))
-		});
+		let connect_peer = hs.connect(total_difficulty, height, conn)
+			.and_then(|(conn, proto, info)| {
+				Ok((conn,
+				    Peer {
+					info: info,
+					proto: Box::new(proto),
+				}))
+			});
 		Box::new(connect_peer)
 	}
 
 	/// Accept a handshake initiated by another peer.
 	pub fn accept(conn: TcpStream,
 	              total_difficulty: Difficulty,
+	              height: u64,
 	              hs: &Handshake)
 	              -> Box<Future<Item = (TcpStream, Peer), Error = Error>> {
-		let hs_peer = hs.handshake(total_difficulty, conn).and_then(|(conn, proto, info)| {
-			Ok((conn,
-			    Peer {
-				info: info,
-				proto: Box::new(proto),
-			}))
-		});
+		let hs_peer = hs.handshake(total_difficulty, height, conn)
+			.and_then(|(conn, proto, info)| {
+				Ok((conn,
+				    Peer {
+					info: info,
+					proto: Box::new(proto),
+				}))
+			});
 		Box::new(hs_peer)
 	}
 
@@ -67,14 +76,12 @@ impl Peer {
 	/// system.
 	pub fn run(&self,
 	           conn: TcpStream,
-	           na: Arc<NetAdapter>)
+	           na: Arc<NetAdapter>,
+	           announces: Arc<AnnounceWindow>,
+	           limiter: RateLimiter)
 	           -> Box<Future<Item = (), Error = Error>> {
 
-		let addr = self.info.addr;
-		Box::new(self.proto.handle(conn, na).and_then(move |_| {
-			info!("Client {} disconnected.", addr);
-			Ok(())
-		}))
+		self.proto.handle(conn, na, announces, limiter)
 	}
 
 	/// Bytes sent and received by this peer to the remote peer.
@@ -82,8 +89,97 @@ impl Peer {
 		self.proto.transmitted_bytes()
 	}
 
-	pub fn send_ping(&self) -> Result<(), Error> {
-		self.proto.send_ping()
+	/// Bytes and messages, by type, exchanged with the remote peer.
+	pub fn stats(&self) -> PeerStats {
+		self.proto.stats()
+	}
+
+	/// Ban score accumulated by the remote peer, for example by flooding us
+	/// with messages.
+	pub fn ban_score(&self) -> u32 {
+		self.proto.ban_score()
+	}
+
+	/// Total difficulty of the remote peer's chain, as last advertised in the
+	/// handshake or its pings.
+	pub fn total_difficulty(&self) -> Difficulty {
+		self.proto.total_difficulty()
+	}
+
+	/// Height of the remote peer's chain, as last advertised in the
+	/// handshake or its pings or seen in the blocks and headers it sent us.
+	/// Heights its total difficulty can't account for are ignored.
+	pub fn height(&self) -> u64 {
+		self.proto.height()
+	}
+
+	/// Whether the remote peer advertised a height higher than what its total
+	/// difficulty allows for, every block adding at least one to it. Its
+	/// height shouldn't be trusted.
+	pub fn is_suspicious(&self) -> bool {
+		self.proto.is_suspicious()
+	}
+
+	/// The reason the remote peer gave for disconnecting, if it said bye.
+	pub fn remote_bye(&self) -> Option<ByeReason> {
+		self.proto.remote_bye()
+	}
+
+	/// How well the remote peer behaved, relaying blocks and answering our
+	/// pings in time or sending us messages we couldn't handle. Used to pick
+	/// which peer to drop when we're full.
+	pub fn reputation(&self) -> i32 {
+		self.proto.reputation()
+	}
+
+	/// Smoothed round-trip time of our pings to the peer, None until it
+	/// answered one.
+	pub fn latency(&self) -> Option<Duration> {
+		self.proto.latency()
+	}
+
+	/// Raises or lowers the reputation of the remote peer, for example to
+	/// pick up what we remembered of it.
+	pub fn adjust_reputation(&self, delta: i32) {
+		self.proto.adjust_reputation(delta)
+	}
+
+	/// Keeps the connection to the peer alive, pinging it every interval.
+	/// Fails with a timeout if we haven't heard anything from the peer for
+	/// longer than the provided timeout, to be used alongside `run`.
+	pub fn keepalive(peer: Arc<Peer>,
+	                 na: Arc<NetAdapter>,
+	                 interval: Duration,
+	                 timeout: Duration)
+	                 -> Box<Future<Item = (), Error = Error>> {
+		let mut last_recv = (peer.transmitted_bytes().1, Instant::now());
+		let pings = Timer::default()
+			.interval(interval)
+			.map_err(|_| Error::Timeout)
+			.for_each(move |_| {
+				let recv = peer.transmitted_bytes().1;
+				let now = Instant::now();
+				if recv != last_recv.0 {
+					last_recv = (recv, now);
+				} else if now - last_recv.1 > timeout {
+					debug!(target: LOG_TARGET,
+					       "No news from peer {} in {:?}, dropping it.",
+					       peer.info.addr,
+					       timeout);
+					return Err(Error::Timeout);
+				}
+				peer.send_ping(na.total_difficulty(), na.height())
+			});
+		Box::new(pings)
+	}
+
+	pub fn send_ping(&self, total_difficulty: Difficulty, height: u64) -> Result<(), Error> {
+		self.proto.send_ping(total_difficulty, height)
+	}
+
+	/// Asks the remote peer for the addresses of other peers it knows of.
+	pub fn send_peer_request(&self, capab: Capabilities) -> Result<(), Error> {
+		self.proto.send_peer_request(capab)
 	}
 
 	/// Sends the provided block to the remote peer. The request may be dropped
@@ -93,15 +189,41 @@ impl Peer {
 		self.proto.send_block(b)
 	}
 
+	/// Announces a block to the remote peer, which will ask for the full
+	/// block if it doesn't have it. Nothing gets sent if the peer is known
+	/// to have the block already.
+	pub fn send_inv(&self, h: Hash) -> Result<(), Error> {
+		self.proto.send_inv(h)
+	}
+
+	/// Sends the compact version of a block to the remote peer.
+	pub fn send_compact_block(&self, cb: &CompactBlock) -> Result<(), Error> {
+		self.proto.send_compact_block(cb)
+	}
+
+	/// Relays the provided transaction to the remote peer.
+	pub fn send_transaction(&self, tx: &core::Transaction) -> Result<(), Error> {
+		self.proto.send_transaction(tx)
+	}
+
 	pub fn send_header_request(&self, locator: Vec<Hash>) -> Result<(), Error> {
 		self.proto.send_header_request(locator)
 	}
 
 	pub fn send_block_request(&self, h: Hash) -> Result<(), Error> {
-		debug!("Requesting block {} from peer {}.", h, self.info.addr);
+		debug!(target: LOG_TARGET, "Requesting block {} from peer {}.", h, self.info.addr);
 		self.proto.send_block_request(h)
 	}
 
+	pub fn send_block_bundle_request(&self, start: Hash, count: u32) -> Result<(), Error> {
+		debug!(target: LOG_TARGET,
+		       "Requesting {} blocks from {} to peer {}.",
+		       count,
+		       start,
+		       self.info.addr);
+		self.proto.send_block_bundle_request(start, count)
+	}
+
 	pub fn stop(&self) {
 		self.proto.close();
 	}
@@ -12,32 +12,97 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::sync::{Mutex, Arc};
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Mutex, Arc, RwLock};
+use std::time::{Duration, Instant};
 
 use futures;
 use futures::Future;
 use futures::stream;
 use futures::sync::mpsc::UnboundedSender;
+use rand::{self, Rng};
+use time;
 use tokio_core::net::TcpStream;
 
+use announce::AnnounceWindow;
 use core::core;
-use core::core::hash::Hash;
+use core::core::hash::{Hash, Hashed, ZERO_HASH};
+use core::core::target::Difficulty;
 use core::ser;
-use conn::TimeoutConnection;
+use conn::{Handler, IoTimeouts, TimeoutConnection, response_timeout};
+use crypt::Ciphers;
+use latency::Latency;
 use msg::*;
+use rate::RateLimiter;
 use types::*;
 use util::OneTime;
+use LOG_TARGET;
+
+/// Maximum number of compact blocks we keep around per peer while waiting
+/// for their missing transactions
+const MAX_PENDING_BLOCKS: usize = 16;
+
+/// Compact blocks we're getting the rest of from a peer, with the
+/// transactions we found so far. A block with no transactions left to find
+/// is waiting for its full version.
+type PendingBlocks = Mutex<HashMap<Hash, (CompactBlock, Vec<Option<core::Transaction>>)>>;
+
+/// Maximum number of block hashes we remember a peer knows of, as well as of
+/// blocks we asked it for
+const MAX_KNOWN_HASHES: usize = 1024;
 
 pub struct ProtocolV1 {
 	conn: OneTime<TimeoutConnection>,
 
+	max_msg_len: u64,
+
+	io_timeouts: IoTimeouts,
+
+	compress_threshold: Option<u64>,
+
+	// keys agreed on with the peer, until the connection takes them over
+	ciphers: Mutex<Option<Ciphers>>,
+
+	// what we learned about the peer so far, shared with the message handler
+	remote: Arc<RwLock<RemoteState>>,
+
 	expected_responses: Mutex<Vec<(Type, Hash)>>,
 }
 
 impl ProtocolV1 {
-	pub fn new() -> ProtocolV1 {
+	pub fn new(max_msg_len: u64,
+	           io_timeouts: IoTimeouts,
+	           compress_threshold: Option<u64>,
+	           ciphers: Option<Ciphers>,
+	           compact_blocks: bool,
+	           total_difficulty: Difficulty,
+	           height: u64,
+	           addr_max_age: i64,
+	           max_addrs_per_minute: u32)
+	           -> ProtocolV1 {
+		let mut remote = RemoteState {
+			total_difficulty: Difficulty::one(),
+			height: 0,
+			suspicious: false,
+			compact_blocks: compact_blocks,
+			bye: None,
+			reputation: 0,
+			latency: Latency::new(),
+			known: KnownHashes::new(),
+			requested: HashSet::new(),
+			bundles: HashMap::new(),
+			addrs: AddrThrottle::new(addr_max_age, max_addrs_per_minute),
+		};
+		remote.advertised(total_difficulty, height);
 		ProtocolV1 {
 			conn: OneTime::new(),
+			max_msg_len: max_msg_len,
+			io_timeouts: io_timeouts,
+			compress_threshold: compress_threshold,
+			ciphers: Mutex::new(ciphers),
+			remote: Arc::new(RwLock::new(remote)),
 			expected_responses: Mutex::new(vec![]),
 		}
 	}
@@ -47,17 +112,40 @@ impl Protocol for ProtocolV1 {
 	/// Sets up the protocol reading, writing and closing logic.
 	fn handle(&self,
 	          conn: TcpStream,
-	          adapter: Arc<NetAdapter>)
-	          -> Box<Future<Item = (), Error = ser::Error>> {
+	          adapter: Arc<NetAdapter>,
+	          announces: Arc<AnnounceWindow>,
+	          limiter: RateLimiter)
+	          -> Box<Future<Item = (), Error = Error>> {
 
-		let (conn, listener) = TimeoutConnection::listen(conn, move |sender, header, data| {
-			let adapt = adapter.as_ref();
-			handle_payload(adapt, sender, header, data)
-		});
+		// the connection can already be gone, nothing to talk to then
+		let addr = match conn.peer_addr() {
+			Ok(addr) => addr,
+			Err(e) => return Box::new(futures::future::err(Error::IOErr(e))),
+		};
+		let handler = PayloadHandler {
+			adapter: adapter,
+			announces: announces,
+			pending: Mutex::new(HashMap::new()),
+			remote: self.remote.clone(),
+			addr: addr,
+		};
+		let (conn, listener) = TimeoutConnection::listen(conn,
+		                                                 limiter,
+		                                                 self.max_msg_len,
+		                                                 self.io_timeouts,
+		                                                 self.compress_threshold,
+		                                                 self.ciphers.lock().unwrap().take(),
+		                                                 handler);
 
 		self.conn.init(conn);
 
-		listener
+		// bundles still on their way won't come anymore once we're disconnected
+		let remote = self.remote.clone();
+		Box::new(listener.then(move |res| {
+				remote.write().unwrap().bundles.clear();
+				res
+			})
+			.map_err(Error::SerErr))
 	}
 
 	/// Bytes sent and received.
@@ -65,112 +153,759 @@ impl Protocol for ProtocolV1 {
 		self.conn.borrow().transmitted_bytes()
 	}
 
+	/// Bytes and messages sent and received.
+	fn stats(&self) -> PeerStats {
+		self.conn.borrow().stats()
+	}
+
+	/// Ban score accumulated by the remote peer flooding us.
+	fn ban_score(&self) -> u32 {
+		self.conn.borrow().ban_score()
+	}
+
+	/// Total difficulty of the remote peer, as of its last ping or pong.
+	fn total_difficulty(&self) -> Difficulty {
+		self.remote.read().unwrap().total_difficulty.clone()
+	}
+
+	/// Height of the remote peer, as of its last ping, pong or block.
+	fn height(&self) -> u64 {
+		self.remote.read().unwrap().height
+	}
+
+	/// Whether the remote peer claimed a height it can't have.
+	fn is_suspicious(&self) -> bool {
+		self.remote.read().unwrap().suspicious
+	}
+
+	/// The reason the remote peer gave when saying bye, if it did.
+	fn remote_bye(&self) -> Option<ByeReason> {
+		self.remote.read().unwrap().bye
+	}
+
+	/// Smoothed round-trip time of our pings to the remote peer.
+	fn latency(&self) -> Option<Duration> {
+		self.remote.read().unwrap().latency.rtt()
+	}
+
+	/// How well the remote peer behaved so far.
+	fn reputation(&self) -> i32 {
+		self.remote.read().unwrap().reputation
+	}
+
+	/// Raises or lowers the reputation of the remote peer, within bounds.
+	fn adjust_reputation(&self, delta: i32) {
+		self.remote.write().unwrap().adjust_reputation(delta);
+	}
+
 	/// Sends a ping message to the remote peer. Will panic if handle has never
 	/// been called on this protocol.
-	fn send_ping(&self) -> Result<(), ser::Error> {
-		self.send_request(Type::Ping, &Empty {}, None)
+	fn send_ping(&self, total_difficulty: Difficulty, height: u64) -> Result<(), Error> {
+		let nonce = rand::thread_rng().gen();
+		{
+			// pings are regular enough to have the reputation fade along
+			let mut remote = self.remote.write().unwrap();
+			remote.reputation -= remote.reputation / REPUTATION_DECAY;
+			remote.latency.ping_sent(nonce, Instant::now());
+		}
+		self.send_msg(Type::Ping,
+		              &Ping {
+			              total_difficulty: total_difficulty,
+			              height: height,
+			              nonce: nonce,
+		              })
 	}
 
 	/// Serializes and sends a block to our remote peer
-	fn send_block(&self, b: &core::Block) -> Result<(), ser::Error> {
+	fn send_block(&self, b: &core::Block) -> Result<(), Error> {
+		self.remote.write().unwrap().known.insert(b.hash());
 		self.send_msg(Type::Block, b)
 	}
 
+	/// Announces a block to our remote peer, unless it already knows of it
+	fn send_inv(&self, h: Hash) -> Result<(), Error> {
+		if !self.remote.write().unwrap().known.insert(h) {
+			return Ok(());
+		}
+		self.send_msg(Type::Inv, &h)
+	}
+
+	/// Serializes and sends a compact block to our remote peer
+	fn send_compact_block(&self, cb: &CompactBlock) -> Result<(), Error> {
+		self.remote.write().unwrap().known.insert(cb.hash());
+		self.send_msg(Type::CompactBlock, cb)
+	}
+
 	/// Serializes and sends a transaction to our remote peer
-	fn send_transaction(&self, tx: &core::Transaction) -> Result<(), ser::Error> {
+	fn send_transaction(&self, tx: &core::Transaction) -> Result<(), Error> {
 		self.send_msg(Type::Transaction, tx)
 	}
 
-	fn send_header_request(&self, locator: Vec<Hash>) -> Result<(), ser::Error> {
-		self.send_request(Type::GetHeaders, &Locator { hashes: locator }, None)
+	fn send_header_request(&self, locator: Vec<Hash>) -> Result<(), Error> {
+		self.send_request(Type::GetHeaders,
+		                  &Locator { hashes: locator },
+		                  Some((Type::Headers, ZERO_HASH)))
 	}
 
-	fn send_block_request(&self, h: Hash) -> Result<(), ser::Error> {
+	fn send_block_request(&self, h: Hash) -> Result<(), Error> {
+		self.remote.write().unwrap().block_requested(h);
 		self.send_request(Type::GetBlock, &h, Some((Type::Block, h)))
 	}
 
-	/// Close the connection to the remote peer
+	fn send_block_bundle_request(&self, start: Hash, count: u32) -> Result<(), Error> {
+		self.remote.write().unwrap().bundle_requested(start, Instant::now());
+		self.send_request(Type::GetBlockBundle,
+		                  &GetBlockBundle {
+			                  start: start,
+			                  count: min(count, MAX_BUNDLE_BLOCKS),
+		                  },
+		                  Some((Type::BlockBundle, start)))
+	}
+
+	fn send_peer_request(&self, capab: Capabilities) -> Result<(), Error> {
+		self.send_request(Type::GetPeerAddrs,
+		                  &GetPeerAddrs { capabilities: capab },
+		                  Some((Type::PeerAddrs, ZERO_HASH)))
+	}
+
+	/// Close the connection to the remote peer, saying bye first so it knows
+	/// we're shutting down rather than crashing.
 	fn close(&self) {
-		// TODO some kind of shutdown signal
+		if let Err(e) = self.send_msg(Type::Bye, &Bye { reason: ByeReason::Shutdown }) {
+			debug!(target: LOG_TARGET, "Could not say bye to peer: {}", e);
+		}
+		self.conn.borrow().close();
 	}
 }
 
 impl ProtocolV1 {
-	fn send_msg(&self, t: Type, body: &ser::Writeable) -> Result<(), ser::Error> {
-		self.conn.borrow().send_msg(t, body)
+	fn send_msg(&self, t: Type, body: &ser::Writeable) -> Result<(), Error> {
+		self.conn.borrow().send_msg(t, body).map_err(Error::SerErr)
 	}
 
 	fn send_request(&self,
 	                t: Type,
 	                body: &ser::Writeable,
 	                expect_resp: Option<(Type, Hash)>)
-	                -> Result<(), ser::Error> {
-		self.conn.borrow().send_request(t, body, expect_resp)
+	                -> Result<(), Error> {
+		self.conn.borrow().send_request(t, body, expect_resp).map_err(Error::SerErr)
+	}
+}
+
+// What we learn about the remote peer while talking to it
+struct RemoteState {
+	// total difficulty the peer last told us about
+	total_difficulty: Difficulty,
+	// highest plausible height the peer told us about
+	height: u64,
+	// whether the peer claimed a height its total difficulty can't explain
+	suspicious: bool,
+	// whether the peer can rebuild the compact blocks we send when it asks
+	compact_blocks: bool,
+	// reason the peer gave for leaving, once it said bye
+	bye: Option<ByeReason>,
+	// how well the peer behaved, between MIN_REPUTATION and MAX_REPUTATION
+	reputation: i32,
+	// round-trip time of our pings, and the one the peer still has to answer
+	latency: Latency,
+	// blocks the peer has, or was told about, no need to announce them
+	known: KnownHashes,
+	// blocks we asked the peer for, after it announced them or to sync
+	requested: HashSet<Hash>,
+	// first blocks of the bundles we asked the peer for, with the time the
+	// bundles have to arrive by
+	bundles: HashMap<Hash, Instant>,
+	// peer addresses the peer relayed that we kept over the last minute
+	addrs: AddrThrottle,
+}
+
+impl RemoteState {
+	// Remembers we asked the peer for a block, up to MAX_KNOWN_HASHES of them.
+	fn block_requested(&mut self, h: Hash) {
+		if self.requested.len() < MAX_KNOWN_HASHES {
+			self.requested.insert(h);
+		}
+	}
+
+	// Remembers we asked for the bundle starting with the provided block,
+	// forgetting the bundles that didn't arrive in time.
+	fn bundle_requested(&mut self, start: Hash, now: Instant) {
+		self.bundles.retain(|_, deadline| *deadline > now);
+		self.bundles.insert(start, now + response_timeout(Type::BlockBundle));
+	}
+
+	// Whether the bundle starting with the provided block is one we asked
+	// for and are still waiting for.
+	fn bundle_received(&mut self, start: &Hash, now: Instant) -> bool {
+		match self.bundles.remove(start) {
+			Some(deadline) => deadline >= now,
+			None => false,
+		}
+	}
+
+	fn adjust_reputation(&mut self, delta: i32) {
+		self.reputation = min(max(self.reputation + delta, MIN_REPUTATION), MAX_REPUTATION);
+	}
+
+	// Records the total difficulty and height the peer advertised, the
+	// height only if the total difficulty can account for it.
+	fn advertised(&mut self, total_difficulty: Difficulty, height: u64) {
+		if plausible_height(height, &total_difficulty) {
+			self.height = height;
+		} else {
+			self.suspicious = true;
+		}
+		self.total_difficulty = total_difficulty;
+	}
+
+	// Raises the height of the peer to the one of a block it sent us
+	fn saw_header(&mut self, bh: &core::BlockHeader) {
+		if bh.height <= self.height {
+			return;
+		}
+		if plausible_height(bh.height, &bh.total_difficulty) {
+			self.height = bh.height;
+		} else {
+			self.suspicious = true;
+		}
+	}
+}
+
+// Whether a chain of the provided height can have the provided total
+// difficulty, every block including genesis adding at least one to it.
+fn plausible_height(height: u64, total_difficulty: &Difficulty) -> bool {
+	Difficulty::from_u64(height) < *total_difficulty
+}
+
+// Bounded set of block hashes, forgetting the oldest ones first
+struct KnownHashes {
+	hashes: HashSet<Hash>,
+	order: VecDeque<Hash>,
+}
+
+impl KnownHashes {
+	fn new() -> KnownHashes {
+		KnownHashes {
+			hashes: HashSet::new(),
+			order: VecDeque::new(),
+		}
+	}
+
+	// Adds the hash, false if it was already known
+	fn insert(&mut self, h: Hash) -> bool {
+		if !self.hashes.insert(h) {
+			return false;
+		}
+		self.order.push_back(h);
+		if self.order.len() > MAX_KNOWN_HASHES {
+			let oldest = self.order.pop_front().unwrap();
+			self.hashes.remove(&oldest);
+		}
+		true
+	}
+}
+
+// Keeps the fresh and routable addresses out of the ones a peer relays, no
+// more than a number of them each minute
+struct AddrThrottle {
+	max_age: i64,
+	per_minute: u32,
+	window_start: Instant,
+	kept: u32,
+}
+
+impl AddrThrottle {
+	fn new(max_age: i64, per_minute: u32) -> AddrThrottle {
+		AddrThrottle {
+			max_age: max_age,
+			per_minute: per_minute,
+			window_start: Instant::now(),
+			kept: 0,
+		}
+	}
+
+	// The relayed addresses worth keeping, now being a UTC timestamp in
+	// seconds. Addresses claiming to be seen in the future are taken as seen
+	// now.
+	fn filter(&mut self, addrs: Vec<PeerAddr>, now: i64) -> Vec<PeerAddr> {
+		if self.window_start.elapsed() >= Duration::from_secs(60) {
+			self.window_start = Instant::now();
+			self.kept = 0;
+		}
+		let room = self.per_minute.saturating_sub(self.kept) as usize;
+		let max_age = self.max_age;
+		let kept = addrs.into_iter()
+			.filter(|a| is_routable(&a.addr) && a.last_seen >= now.saturating_sub(max_age))
+			.take(room)
+			.map(|a| {
+				PeerAddr {
+					addr: a.addr,
+					last_seen: min(a.last_seen, now),
+				}
+			})
+			.collect::<Vec<_>>();
+		self.kept += kept.len() as u32;
+		kept
+	}
+}
+
+// Handles the messages of the remote peer, lowering its reputation for each
+// one that's invalid.
+struct PayloadHandler {
+	adapter: Arc<NetAdapter>,
+	announces: Arc<AnnounceWindow>,
+	pending: PendingBlocks,
+	remote: Arc<RwLock<RemoteState>>,
+	addr: SocketAddr,
+}
+
+impl Handler for PayloadHandler {
+	fn handle(&self,
+	          sender: UnboundedSender<Vec<u8>>,
+	          header: MsgHeader,
+	          data: Vec<u8>)
+	          -> Result<Option<Hash>, ser::Error> {
+		let res = handle_payload(self.adapter.as_ref(),
+		                         &self.announces,
+		                         &self.pending,
+		                         &self.remote,
+		                         self.addr,
+		                         sender,
+		                         header,
+		                         data);
+		if res.is_err() {
+			self.remote.write().unwrap().adjust_reputation(INVALID_MSG_REPUTATION);
+		}
+		res
+	}
+
+	// Blocks and bundles we asked for, after an announcement or to sync, and
+	// the transactions missing from the compact blocks we're rebuilding are
+	// replies to our requests as well.
+	fn expects(&self, t: Type) -> bool {
+		let remote = self.remote.read().unwrap();
+		match t {
+			Type::Block | Type::CompactBlock => !remote.requested.is_empty(),
+			Type::BlockBundle => !remote.bundles.is_empty(),
+			Type::BlockTxn => !self.pending.lock().unwrap().is_empty(),
+			_ => false,
+		}
 	}
 }
 
 fn handle_payload(adapter: &NetAdapter,
+                  announces: &AnnounceWindow,
+                  pending: &PendingBlocks,
+                  remote: &RwLock<RemoteState>,
+                  addr: SocketAddr,
                   sender: UnboundedSender<Vec<u8>>,
                   header: MsgHeader,
                   buf: Vec<u8>)
                   -> Result<Option<Hash>, ser::Error> {
 	match header.msg_type {
 		Type::Ping => {
-			let data = ser::ser_vec(&MsgHeader::new(Type::Pong, 0))?;
-			sender.send(data);
+			let ping = ser::deserialize::<Ping>(&mut &buf[..])?;
+			debug!(target: LOG_TARGET, "Ping from {} at total difficulty {} and height {}.",
+			       addr,
+			       ping.total_difficulty,
+			       ping.height);
+			remote.write().unwrap().advertised(ping.total_difficulty, ping.height);
+
+			try!(reply(&sender,
+			           Type::Pong,
+			           &Pong {
+				           total_difficulty: adapter.total_difficulty(),
+				           height: adapter.height(),
+				           nonce: ping.nonce,
+			           }));
+			Ok(None)
+		}
+		Type::Pong => {
+			let pong = ser::deserialize::<Pong>(&mut &buf[..])?;
+			debug!(target: LOG_TARGET, "Pong from {} at total difficulty {} and height {}.",
+			       addr,
+			       pong.total_difficulty,
+			       pong.height);
+			let mut remote = remote.write().unwrap();
+			remote.advertised(pong.total_difficulty, pong.height);
+			// a pong that doesn't answer our last ping says nothing of the peer
+			let timely = remote.latency
+				.pong_received(pong.nonce, Instant::now())
+				.map(|rtt| rtt < Duration::from_secs(TIMELY_PONG_SECS))
+				.unwrap_or(false);
+			if timely {
+				remote.adjust_reputation(PONG_REPUTATION);
+			}
 			Ok(None)
 		}
-		Type::Pong => Ok(None),
 		Type::Transaction => {
 			let tx = ser::deserialize::<core::Transaction>(&mut &buf[..])?;
-			adapter.transaction_received(tx);
+			let txh = tx.hash();
+			// same as blocks, only the first peer relaying a transaction to us
+			// is worth listening to
+			if announces.announced(txh, addr) {
+				if adapter.transaction_received(tx) == TxStatus::Invalid {
+					return Err(ser::Error::CorruptedData);
+				}
+			} else {
+				debug!(target: LOG_TARGET,
+				       "Ignoring duplicate relay of transaction {} from {}.",
+				       txh,
+				       addr);
+			}
+			Ok(None)
+		}
+		Type::Inv => {
+			let h = ser::deserialize::<Hash>(&mut &buf[..])?;
+			remote.write().unwrap().known.insert(h);
+			// only worth asking the first peer announcing a block we don't have
+			if !announces.announced(h, addr) || adapter.is_bad_block(&h) ||
+			   adapter.get_block(h).is_some() {
+				return Ok(None);
+			}
+			debug!(target: LOG_TARGET, "Peer {} announced block {}, asking for it.", addr, h);
+			remote.write().unwrap().block_requested(h);
+			try!(reply(&sender, Type::GetData, &h));
 			Ok(None)
 		}
-		Type::GetBlock => {
+		Type::GetBlock | Type::GetData => {
 			let h = ser::deserialize::<Hash>(&mut &buf[..])?;
+			let compact = {
+				let mut remote = remote.write().unwrap();
+				remote.known.insert(h);
+				remote.compact_blocks
+			};
+			// a block announced by inventory goes compactly to peers that can
+			// rebuild it, explicit requests are for the full block, typically
+			// after failing to rebuild
+			if compact && header.msg_type == Type::GetData {
+				if let Some(cb) = adapter.get_compact_block(h) {
+					try!(reply(&sender, Type::CompactBlock, &cb));
+					return Ok(None);
+				}
+			}
 			let bo = adapter.get_block(h);
 			if let Some(b) = bo {
 				// serialize and send the block over
-				let mut body_data = vec![];
-				try!(ser::serialize(&mut body_data, &b));
-				let mut data = vec![];
-				try!(ser::serialize(&mut data,
-				                    &MsgHeader::new(Type::Block, body_data.len() as u64)));
-				data.append(&mut body_data);
-				sender.send(data);
+				try!(reply(&sender, Type::Block, &b));
+			} else if let Some(bh) = adapter.get_pruned_header(h) {
+				// we pruned that block, the header is all we can offer
+				try!(reply(&sender, Type::Headers, &Headers { headers: vec![bh] }));
 			}
 			Ok(None)
 		}
 		Type::Block => {
 			let b = ser::deserialize::<core::Block>(&mut &buf[..])?;
 			let bh = b.hash();
-			adapter.block_received(b);
+			if adapter.is_bad_block(&bh) {
+				debug!(target: LOG_TARGET,
+				       "Peer {} sent block {} we already found invalid.",
+				       addr,
+				       bh);
+				return Err(ser::Error::CorruptedData);
+			}
+			// blocks we asked for, after an inventory, to sync or after failing
+			// to rebuild their compact version, went through the announce window
+			// already when we decided to ask. Of the blocks pushed to us, only the
+			// first within the window is worth processing, the others are just our
+			// peers relaying the same thing.
+			let requested = {
+				let mut remote = remote.write().unwrap();
+				remote.known.insert(bh);
+				remote.saw_header(&b.header);
+				remote.requested.remove(&bh)
+			};
+			if requested || pending.lock().unwrap().remove(&bh).is_some() ||
+			   announces.announced(bh, addr) {
+				remote.write().unwrap().adjust_reputation(BLOCK_REPUTATION);
+				if !adapter.block_received(b) {
+					return Err(ser::Error::CorruptedData);
+				}
+			} else {
+				debug!(target: LOG_TARGET,
+				       "Ignoring duplicate announcement of block {} from {}.",
+				       bh,
+				       addr);
+			}
 			Ok(Some(bh))
 		}
+		Type::GetBlockBundle => {
+			let req = ser::deserialize::<GetBlockBundle>(&mut &buf[..])?;
+			let blocks = try!(fit_bundle(adapter.get_blocks(req.start, req.count)));
+			try!(reply(&sender,
+			           Type::BlockBundle,
+			           &BlockBundle {
+				           start: req.start,
+				           blocks: blocks,
+			           }));
+			Ok(None)
+		}
+		Type::BlockBundle => {
+			let bundle = ser::deserialize::<BlockBundle>(&mut &buf[..])?;
+			let start = bundle.start;
+			if !remote.write().unwrap().bundle_received(&start, Instant::now()) {
+				debug!(target: LOG_TARGET,
+				       "Ignoring bundle of blocks from {} we didn't ask for.",
+				       addr);
+				return Ok(Some(start));
+			}
+			// blocks have to follow each other from the one we asked for, for us
+			// to connect them in order
+			if bundle.blocks.first().map(|b| b.hash() != start).unwrap_or(false) {
+				debug!(target: LOG_TARGET,
+				       "Peer {} sent a bundle not starting with {}.",
+				       addr,
+				       start);
+				return Err(ser::Error::CorruptedData);
+			}
+			for pair in bundle.blocks.windows(2) {
+				if pair[1].header.previous != pair[0].hash() ||
+				   pair[1].header.height != pair[0].header.height + 1 {
+					debug!(target: LOG_TARGET,
+					       "Peer {} sent a bundle of blocks not following each other.",
+					       addr);
+					return Err(ser::Error::CorruptedData);
+				}
+			}
+			if bundle.blocks.iter().any(|b| adapter.is_bad_block(&b.hash())) {
+				debug!(target: LOG_TARGET,
+				       "Peer {} sent a bundle with a block we already found invalid.",
+				       addr);
+				return Err(ser::Error::CorruptedData);
+			}
+			if let Some(last) = bundle.blocks.last() {
+				let mut remote = remote.write().unwrap();
+				for b in &bundle.blocks {
+					remote.known.insert(b.hash());
+				}
+				remote.saw_header(&last.header);
+				remote.adjust_reputation(BLOCK_REPUTATION);
+			}
+			for b in bundle.blocks {
+				if !adapter.block_received(b) {
+					return Err(ser::Error::CorruptedData);
+				}
+			}
+			Ok(Some(start))
+		}
+		Type::CompactBlock => {
+			let cb = ser::deserialize::<CompactBlock>(&mut &buf[..])?;
+			let bh = cb.hash();
+			// compact blocks come in when we ask for a block the peer announced
+			let requested = {
+				let mut remote = remote.write().unwrap();
+				remote.known.insert(bh);
+				remote.saw_header(&cb.header);
+				remote.requested.remove(&bh)
+			};
+			if !requested && !announces.announced(bh, addr) {
+				debug!(target: LOG_TARGET,
+				       "Ignoring duplicate announcement of compact block {} from {}.",
+				       bh,
+				       addr);
+				return Ok(None);
+			}
+			remote.write().unwrap().adjust_reputation(BLOCK_REPUTATION);
+
+			// look for the transactions on our side first and only ask for the
+			// ones we don't have
+			let txs = cb.tx_ids.iter().map(|id| adapter.get_transaction(*id)).collect::<Vec<_>>();
+			let missing = cb.tx_ids
+				.iter()
+				.zip(txs.iter())
+				.filter(|&(_, tx)| tx.is_none())
+				.map(|(id, _)| *id)
+				.collect::<Vec<_>>();
+			if missing.is_empty() {
+				let txs = txs.into_iter().map(|tx| tx.unwrap()).collect::<Vec<_>>();
+				try!(rebuild_block(adapter, pending, addr, &sender, cb, txs));
+			} else {
+				debug!(target: LOG_TARGET, "Missing {} transactions of compact block {} from {}.",
+				       missing.len(),
+				       bh,
+				       addr);
+				add_pending(pending, cb, txs);
+				try!(reply(&sender,
+				           Type::GetBlockTxn,
+				           &GetBlockTxn {
+					           hash: bh,
+					           tx_ids: missing,
+				           }));
+			}
+			Ok(None)
+		}
+		Type::GetBlockTxn => {
+			let req = ser::deserialize::<GetBlockTxn>(&mut &buf[..])?;
+			let txs = req.tx_ids
+				.iter()
+				.filter_map(|id| adapter.get_transaction(*id))
+				.collect::<Vec<_>>();
+			try!(reply(&sender,
+			           Type::BlockTxn,
+			           &BlockTxn {
+				           hash: req.hash,
+				           txs: txs,
+			           }));
+			Ok(None)
+		}
+		Type::BlockTxn => {
+			let block_txn = ser::deserialize::<BlockTxn>(&mut &buf[..])?;
+			let entry = pending.lock().unwrap().remove(&block_txn.hash);
+			match entry {
+				Some((cb, txs)) => {
+					// fill the gaps in the order we asked for the transactions, if
+					// the peer didn't send them all the block just won't rebuild
+					let mut received = block_txn.txs.into_iter();
+					let txs = txs.into_iter()
+						.filter_map(|tx| tx.or_else(|| received.next()))
+						.collect::<Vec<_>>();
+					try!(rebuild_block(adapter, pending, addr, &sender, cb, txs));
+				}
+				None => {
+					debug!(target: LOG_TARGET, "Unexpected transactions for block {} from {}.",
+					       block_txn.hash,
+					       addr);
+				}
+			}
+			Ok(None)
+		}
 		Type::GetHeaders => {
 			// load headers from the locator
 			let loc = ser::deserialize::<Locator>(&mut &buf[..])?;
-			let headers = adapter.locate_headers(loc.hashes);
+			let mut headers = adapter.locate_headers(loc.hashes);
+			headers.truncate(MAX_BLOCK_HEADERS as usize);
 
 			// serialize and send all the headers over
-			let mut body_data = vec![];
-			try!(ser::serialize(&mut body_data, &Headers { headers: headers }));
-			let mut data = vec![];
-			try!(ser::serialize(&mut data,
-			                    &MsgHeader::new(Type::Headers, body_data.len() as u64)));
-			data.append(&mut body_data);
-			sender.send(data);
+			try!(reply(&sender, Type::Headers, &Headers { headers: headers }));
 
 			Ok(None)
 		}
 		Type::Headers => {
 			let headers = ser::deserialize::<Headers>(&mut &buf[..])?;
-			adapter.headers_received(headers.headers);
+			if let Some(last) = headers.headers.last() {
+				remote.write().unwrap().saw_header(last);
+			}
+			if !adapter.headers_received(headers.headers) {
+				return Err(ser::Error::CorruptedData);
+			}
+			Ok(None)
+		}
+		Type::GetPeerAddrs => {
+			let get_peers = ser::deserialize::<GetPeerAddrs>(&mut &buf[..])?;
+
+			// never tell a peer about itself or about addresses that only make
+			// sense on our own host
+			let peer_addrs = adapter.find_peer_addrs(get_peers.capabilities)
+				.into_iter()
+				.filter(|a| a.addr.ip() != addr.ip() && !is_local(&a.addr))
+				.take(MAX_PEER_ADDRS as usize)
+				.collect::<Vec<_>>();
+
+			try!(reply(&sender, Type::PeerAddrs, &PeerAddrs { peers: peer_addrs }));
+
+			Ok(None)
+		}
+		Type::PeerAddrs => {
+			let peer_addrs = ser::deserialize::<PeerAddrs>(&mut &buf[..])?;
+			let now = time::now_utc().to_timespec().sec;
+			let addrs = remote.write().unwrap().addrs.filter(peer_addrs.peers, now);
+			if !addrs.is_empty() {
+				adapter.peer_addrs_received(addrs);
+			}
+			Ok(None)
+		}
+		Type::Error => {
+			let err = ser::deserialize::<PeerError>(&mut &buf[..])?;
+			info!(target: LOG_TARGET, "Peer {} sent error {}: {}", addr, err.code, err.message);
+			Ok(None)
+		}
+		Type::Bye => {
+			// the peer closes the connection right after, which ends our side
+			// of it without holding it against the peer
+			let msg = ser::deserialize::<Bye>(&mut &buf[..])?;
+			debug!(target: LOG_TARGET, "Peer {} said bye: {:?}", addr, msg.reason);
+			remote.write().unwrap().bye = Some(msg.reason);
 			Ok(None)
 		}
 		_ => {
-			debug!("unknown message type {:?}", header.msg_type);
+			debug!(target: LOG_TARGET, "unknown message type {:?}", header.msg_type);
 			Ok(None)
 		}
 	}
 }
+
+/// Rebuilds the full block out of a compact block and its transactions and
+/// hands it over to the adapter. If that fails, the full block gets
+/// requested instead.
+fn rebuild_block(adapter: &NetAdapter,
+                 pending: &PendingBlocks,
+                 addr: SocketAddr,
+                 sender: &UnboundedSender<Vec<u8>>,
+                 cb: CompactBlock,
+                 txs: Vec<core::Transaction>)
+                 -> Result<(), ser::Error> {
+	match cb.reconstruct(&txs) {
+		Some(b) => {
+			if !adapter.block_received(b) {
+				return Err(ser::Error::CorruptedData);
+			}
+			Ok(())
+		}
+		None => {
+			let bh = cb.hash();
+			debug!(target: LOG_TARGET,
+			       "Could not rebuild compact block {} from {}, requesting it in full.",
+			       bh,
+			       addr);
+			add_pending(pending, cb, vec![]);
+			reply(sender, Type::GetBlock, &bh)
+		}
+	}
+}
+
+/// Keeps the blocks that fit in a bundle, in order, stopping at the first
+/// one that would take the bundle over MAX_BUNDLE_SIZE.
+fn fit_bundle(blocks: Vec<core::Block>) -> Result<Vec<core::Block>, ser::Error> {
+	// count of the blocks the bundle starts with
+	let mut size = 4;
+	let mut fit = vec![];
+	for b in blocks.into_iter().take(MAX_BUNDLE_BLOCKS as usize) {
+		size += try!(ser::ser_vec(&b)).len() as u64;
+		if size > MAX_BUNDLE_SIZE {
+			break;
+		}
+		fit.push(b);
+	}
+	Ok(fit)
+}
+
+/// Keeps track of a compact block until we get the rest of it. Peers are
+/// expected to answer quickly so if too many blocks are pending, we just
+/// give up on the older ones.
+fn add_pending(pending: &PendingBlocks, cb: CompactBlock, txs: Vec<Option<core::Transaction>>) {
+	let mut pending = pending.lock().unwrap();
+	if pending.len() >= MAX_PENDING_BLOCKS {
+		pending.clear();
+	}
+	pending.insert(cb.hash(), (cb, txs));
+}
+
+/// Serializes a message and queues it to be sent back to the peer.
+fn reply(sender: &UnboundedSender<Vec<u8>>,
+         t: Type,
+         body: &ser::Writeable)
+         -> Result<(), ser::Error> {
+	let mut body_data = vec![];
+	try!(ser::serialize(&mut body_data, body));
+	let mut data = vec![];
+	try!(ser::serialize(&mut data, &MsgHeader::new(t, body_data.len() as u64)));
+	data.append(&mut body_data);
+	sender.send(data);
+	Ok(())
+}
+
+/// Whether the address can only be reached from our own host, in which case
+/// relaying it to other peers is pointless.
+fn is_local(addr: &SocketAddr) -> bool {
+	addr.ip().is_loopback() || addr.ip().is_unspecified()
+}
@@ -0,0 +1,173 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Token buckets limiting the rate at which we accept each category of
+//! messages from a peer, protects us from peers flooding us with cheap
+//! messages. Also keeps the peer ban score, which decays over time.
+
+use std::sync::Mutex;
+use std::time::{Instant, Duration};
+
+use msg::MsgCategory;
+use types::BAN_SCORE_DECAY;
+
+/// Number of message categories, each with its own bucket
+const MSG_CATEGORIES: usize = MsgCategory::Transactions as usize + 1;
+
+/// Messages dropped for going over their category rate that cost a peer a
+/// point of ban score
+const DROPS_PER_BAN_SCORE: u32 = 10;
+
+struct Bucket {
+	tokens: f64,
+	last: Instant,
+}
+
+impl Bucket {
+	fn new(burst: f64) -> Bucket {
+		Bucket {
+			tokens: burst,
+			last: Instant::now(),
+		}
+	}
+
+	// Adds the tokens earned since the last message, never more than a burst
+	fn refill(&mut self, rate: f64, burst: f64) {
+		let now = Instant::now();
+		let elapsed = now - self.last;
+		let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+		self.tokens = (self.tokens + elapsed * rate).min(burst);
+		self.last = now;
+	}
+}
+
+struct BanScore {
+	points: u32,
+	// messages dropped since the last point they cost
+	drops: u32,
+	// start of the current decay period
+	last: Instant,
+}
+
+impl BanScore {
+	// Forgives a point for each decay period elapsed since the last one
+	fn decay(&mut self, decay: Duration) {
+		let now = Instant::now();
+		if self.points == 0 {
+			self.last = now;
+			return;
+		}
+		let elapsed = nanos(now - self.last);
+		let periods = elapsed / nanos(decay).max(1);
+		if periods >= self.points as u64 {
+			self.points = 0;
+			self.last = now;
+		} else {
+			self.points -= periods as u32;
+			self.last += decay * periods as u32;
+		}
+	}
+}
+
+// Whole nanoseconds in a duration
+fn nanos(d: Duration) -> u64 {
+	d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64
+}
+
+/// Per-peer token buckets, one for each category of messages. Each inbound
+/// message takes a token from its category bucket, tokens are refilled at
+/// the configured rate up to the burst size. Past its burst, a peer has to
+/// wait for the bucket to refill before we read from it again, which pushes
+/// back on the sender. Messages it sent us over the rate unasked get
+/// dropped, and every few dropped messages add to the peer ban score.
+///
+/// The limiter also keeps the ban score of the peer for other misbehaviors.
+/// A point of ban score is forgiven every time the decay period elapses, so
+/// only a peer persistently misbehaving ends up banned.
+pub struct RateLimiter {
+	rate: f64,
+	burst: f64,
+	decay: Duration,
+	buckets: Mutex<Vec<Bucket>>,
+	score: Mutex<BanScore>,
+}
+
+impl RateLimiter {
+	/// Creates a new limiter allowing rate messages of each category per
+	/// second on average and bursts of up to burst messages. A point of ban
+	/// score is forgiven every BAN_SCORE_DECAY seconds.
+	pub fn new(rate: u32, burst: u32) -> RateLimiter {
+		RateLimiter::with_decay(rate, burst, Duration::from_secs(BAN_SCORE_DECAY))
+	}
+
+	/// Same as `new` but forgives a point of ban score every time the
+	/// provided decay period elapses.
+	pub fn with_decay(rate: u32, burst: u32, decay: Duration) -> RateLimiter {
+		RateLimiter {
+			rate: rate as f64,
+			burst: burst as f64,
+			decay: decay,
+			buckets: Mutex::new((0..MSG_CATEGORIES).map(|_| Bucket::new(burst as f64)).collect()),
+			score: Mutex::new(BanScore {
+				points: 0,
+				drops: 0,
+				last: Instant::now(),
+			}),
+		}
+	}
+
+	/// Takes a token for a message of the provided category. Returns None if
+	/// one was available, otherwise how long to wait before reading the next
+	/// message, for the bucket to refill.
+	pub fn take(&self, category: MsgCategory) -> Option<Duration> {
+		let mut buckets = self.buckets.lock().unwrap();
+		let bucket = &mut buckets[category as usize];
+		bucket.refill(self.rate, self.burst);
+
+		bucket.tokens -= 1.0;
+		if bucket.tokens >= 0.0 {
+			return None;
+		}
+		let wait = -bucket.tokens / self.rate;
+		Some(Duration::new(wait as u64, (wait.fract() * 1e9) as u32))
+	}
+
+	/// Counts a message dropped for going over its category rate, every few
+	/// of them add a point to the peer ban score.
+	pub fn dropped(&self) {
+		let mut score = self.score.lock().unwrap();
+		score.drops += 1;
+		if score.drops == DROPS_PER_BAN_SCORE {
+			score.drops = 0;
+			score.decay(self.decay);
+			score.points += 1;
+		}
+	}
+
+	/// Adds to the ban score of the peer, for a message we couldn't handle
+	/// for example.
+	pub fn penalize(&self, points: u32) {
+		let mut score = self.score.lock().unwrap();
+		score.decay(self.decay);
+		score.points += points;
+	}
+
+	/// Ban score accumulated by the peer going over the rate or otherwise
+	/// penalized, minus what was forgiven since.
+	pub fn ban_score(&self) -> u32 {
+		let mut score = self.score.lock().unwrap();
+		score.decay(self.decay);
+		score.points
+	}
+}
@@ -0,0 +1,111 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Schedules reconnections to the peers we lost, backing off exponentially
+//! on consecutive failures so a peer that went away for good doesn't keep
+//! us busy forever.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::Future;
+use tokio_timer::Timer;
+
+use types::Error;
+
+/// Delay before the first reconnection attempt, doubled on each consecutive
+/// failure.
+pub const RECONNECT_BASE_DELAY: u64 = 5;
+
+/// Something able to wait for a given duration. Abstracts the timer away so
+/// reconnection schedules can be checked without actually waiting.
+pub trait Sleep: Sync + Send {
+	/// Future resolving once the provided duration elapsed.
+	fn sleep(&self, d: Duration) -> Box<Future<Item = (), Error = Error>>;
+}
+
+impl Sleep for Timer {
+	fn sleep(&self, d: Duration) -> Box<Future<Item = (), Error = Error>> {
+		Box::new(Timer::sleep(self, d).map_err(|_| Error::Timeout))
+	}
+}
+
+/// Keeps track of the consecutive failed connection attempts to each peer
+/// and tells how long to wait before trying again.
+pub struct Reconnector {
+	base_delay: Duration,
+	max_delay: Duration,
+	max_failures: u32,
+	sleeper: Box<Sleep>,
+	failures: Mutex<HashMap<SocketAddr, u32>>,
+}
+
+impl Reconnector {
+	/// Creates a new reconnector doubling base_delay on each failure up to
+	/// max_delay and giving up after max_failures consecutive failures.
+	pub fn new(base_delay: Duration,
+	           max_delay: Duration,
+	           max_failures: u32,
+	           sleeper: Box<Sleep>)
+	           -> Reconnector {
+		Reconnector {
+			base_delay: base_delay,
+			max_delay: max_delay,
+			max_failures: max_failures,
+			sleeper: sleeper,
+			failures: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/// We successfully completed a handshake with the peer, resets its
+	/// backoff.
+	pub fn connected(&self, addr: SocketAddr) {
+		self.failures.lock().unwrap().remove(&addr);
+	}
+
+	/// A connection attempt to the peer failed.
+	pub fn failed(&self, addr: SocketAddr) {
+		let mut failures = self.failures.lock().unwrap();
+		*failures.entry(addr).or_insert(0) += 1;
+	}
+
+	/// How long to wait before trying to reconnect to the peer, None if we
+	/// should give up on it.
+	pub fn delay(&self, addr: SocketAddr) -> Option<Duration> {
+		let failures = *self.failures.lock().unwrap().get(&addr).unwrap_or(&0);
+		if failures >= self.max_failures {
+			return None;
+		}
+		let factor = 1u32.checked_shl(failures).unwrap_or(u32::max_value());
+		let delay = self.base_delay.checked_mul(factor).unwrap_or(self.max_delay);
+		Some(if delay > self.max_delay {
+			self.max_delay
+		} else {
+			delay
+		})
+	}
+
+	/// Future resolving once it's time to reconnect to the peer, None if we
+	/// should give up on it.
+	pub fn wait(&self, addr: SocketAddr) -> Option<Box<Future<Item = (), Error = Error>>> {
+		self.delay(addr).map(|d| self.sleeper.sleep(d))
+	}
+
+	/// Forgets everything about the peer, to be called once we gave up on it.
+	pub fn forget(&self, addr: SocketAddr) {
+		self.failures.lock().unwrap().remove(&addr);
+	}
+}
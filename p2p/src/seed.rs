@@ -0,0 +1,76 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! DNS seeds, hostnames resolving to the addresses of peers we can bootstrap
+//! from when we don't know enough peers yet.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use LOG_TARGET;
+
+/// Resolves hostnames to the addresses they point to. Abstracted so tests
+/// don't have to rely on actual DNS.
+pub trait Resolver: Sync + Send {
+	/// All the addresses the host resolves to, with the provided port.
+	fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolver going through the system DNS resolution, returning both the A
+/// and AAAA records of a host.
+pub struct DnsResolver;
+
+impl Resolver for DnsResolver {
+	fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+		Ok(try!((host, port).to_socket_addrs()).collect())
+	}
+}
+
+/// Resolves all the provided seeds, skipping the seeds that fail to resolve.
+/// Seeds are hostnames, optionally followed by the port their peers listen
+/// on, the provided default port being used otherwise.
+pub fn resolve_seeds(resolver: &Resolver, seeds: &[String], default_port: u16) -> Vec<SocketAddr> {
+	let mut addrs = vec![];
+	for seed in seeds {
+		let (host, port) = match seed.rfind(':') {
+			Some(n) => {
+				match seed[n + 1..].parse() {
+					Ok(port) => (&seed[..n], port),
+					Err(_) => {
+						warn!(target: LOG_TARGET,
+						      "Invalid port for DNS seed {}, skipping it.",
+						      seed);
+						continue;
+					}
+				}
+			}
+			None => (&seed[..], default_port),
+		};
+		match resolver.resolve(host, port) {
+			Ok(resolved) => {
+				debug!(target: LOG_TARGET,
+				       "DNS seed {} resolved to {} peers.",
+				       seed,
+				       resolved.len());
+				for addr in resolved {
+					if !addrs.contains(&addr) {
+						addrs.push(addr);
+					}
+				}
+			}
+			Err(e) => warn!(target: LOG_TARGET, "Could not resolve DNS seed {}: {}", seed, e),
+		}
+	}
+	addrs
+}
@@ -16,25 +16,41 @@
 //! other peers in the network.
 
 use std::cell::RefCell;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::ops::Deref;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::Duration;
 
 use futures;
 use futures::{Future, Stream};
-use futures::future::IntoFuture;
+use futures::future::{self, IntoFuture, Loop};
+use futures_cpupool::CpuPool;
+use net2::TcpBuilder;
 use rand::{self, Rng};
+use time;
 use tokio_core::net::{TcpListener, TcpStream};
 use tokio_core::reactor;
+use tokio_timer::Timer;
 
+use announce::{AnnounceWindow, RelayCache};
+use conn::CLOSE_FLUSH_DELAY;
 use core::core;
-use core::core::hash::Hash;
+use core::core::hash::{Hash, Hashed};
 use core::core::target::Difficulty;
-use core::ser::Error;
+use core::ser;
+use grin_store;
 use handshake::Handshake;
+use msg::{write_msg, CompactBlock, PeerAddr, PeerError, Type, ERR_TOO_MANY_PEERS};
 use peer::Peer;
+use rate::RateLimiter;
+use reconnect::{Reconnector, RECONNECT_BASE_DELAY};
+use seed::{DnsResolver, Resolver, resolve_seeds};
+use store::{PeerStore, PeerData, State};
 use types::*;
+use LOG_TARGET;
 
 /// A no-op network adapter used for testing.
 pub struct DummyAdapter {}
@@ -42,15 +58,214 @@ impl NetAdapter for DummyAdapter {
 	fn total_difficulty(&self) -> Difficulty {
 		Difficulty::one()
 	}
-	fn transaction_received(&self, tx: core::Transaction) {}
-	fn block_received(&self, b: core::Block) {}
-	fn headers_received(&self, bh: Vec<core::BlockHeader>) {}
+	fn transaction_received(&self, tx: core::Transaction) -> TxStatus {
+		TxStatus::Accepted
+	}
+	fn block_received(&self, b: core::Block) -> bool {
+		true
+	}
+	fn headers_received(&self, bh: Vec<core::BlockHeader>) -> bool {
+		true
+	}
 	fn locate_headers(&self, locator: Vec<Hash>) -> Vec<core::BlockHeader> {
 		vec![]
 	}
 	fn get_block(&self, h: Hash) -> Option<core::Block> {
 		None
 	}
+	fn get_transaction(&self, id: u64) -> Option<core::Transaction> {
+		None
+	}
+	fn find_peer_addrs(&self, capab: Capabilities) -> Vec<PeerAddr> {
+		vec![]
+	}
+	fn peer_addrs_received(&self, addrs: Vec<PeerAddr>) {}
+}
+
+/// Number of peers we'd like to be connected to. We try to connect to that
+/// many known peers on startup and ask for more addresses while below it.
+const PREFERRED_PEERS: usize = 8;
+
+/// Interval, in seconds, at which we ask our peers for more peers when we
+/// don't have enough of them
+const PEER_DISCOVERY_INTERVAL: u64 = 30;
+
+/// Number of handshake timeouts in a row after which a peer is only tried
+/// once we're out of better ones
+const MAX_HANDSHAKE_TIMEOUTS: u32 = 3;
+
+/// Number of recent disconnects we remember, for diagnostics
+const MAX_RECENT_DISCONNECTS: usize = 32;
+
+/// Number of peers we're connected to. Slots are reserved before a peer gets
+/// added so concurrent accepts and dials can't go over the configured limits.
+struct PeerCounts {
+	total: AtomicUsize,
+	inbound: AtomicUsize,
+}
+
+impl PeerCounts {
+	fn new() -> PeerCounts {
+		PeerCounts {
+			total: AtomicUsize::new(0),
+			inbound: AtomicUsize::new(0),
+		}
+	}
+
+	/// Reserves a slot for a peer that connected to us, false if we're full.
+	fn reserve_inbound(&self, config: &P2PConfig) -> bool {
+		if !reserve(&self.inbound, config.max_inbound as usize) {
+			return false;
+		}
+		if !reserve(&self.total, config.max_peers as usize) {
+			self.inbound.fetch_sub(1, Ordering::SeqCst);
+			return false;
+		}
+		true
+	}
+
+	/// Takes over the slot of an inbound peer we're dropping. Its own slot
+	/// only gets released once its connection is done, so we may briefly be
+	/// over the limit.
+	fn take_inbound(&self) {
+		self.inbound.fetch_add(1, Ordering::SeqCst);
+		self.total.fetch_add(1, Ordering::SeqCst);
+	}
+
+	/// Reserves a slot for a peer we connect to, false if we're full.
+	fn reserve_outbound(&self, config: &P2PConfig) -> bool {
+		reserve(&self.total, config.max_peers as usize)
+	}
+
+	/// Frees the slot of a peer we're done with.
+	fn release(&self, inbound: bool) {
+		if inbound {
+			self.inbound.fetch_sub(1, Ordering::SeqCst);
+		}
+		self.total.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+// Increments the count unless it already reached max
+fn reserve(count: &AtomicUsize, max: usize) -> bool {
+	let mut current = count.load(Ordering::SeqCst);
+	loop {
+		if current >= max {
+			return false;
+		}
+		let prev = count.compare_and_swap(current, current + 1, Ordering::SeqCst);
+		if prev == current {
+			return true;
+		}
+		current = prev;
+	}
+}
+
+/// The last disconnects from our peers and why they happened, oldest first.
+struct Disconnects {
+	recent: Mutex<VecDeque<(SocketAddr, DisconnectReason)>>,
+}
+
+impl Disconnects {
+	fn new() -> Disconnects {
+		Disconnects { recent: Mutex::new(VecDeque::with_capacity(MAX_RECENT_DISCONNECTS)) }
+	}
+
+	/// Logs the disconnect, louder when the peer is at fault, and remembers
+	/// it, forgetting the oldest one if needed.
+	fn record(&self, addr: SocketAddr, reason: DisconnectReason) {
+		match reason {
+			DisconnectReason::ProtocolViolation |
+			DisconnectReason::Banned => {
+				warn!(target: LOG_TARGET, "Disconnected from peer {}: {}", addr, reason)
+			}
+			DisconnectReason::Timeout |
+			DisconnectReason::IoError |
+			DisconnectReason::WrongNetwork => {
+				info!(target: LOG_TARGET, "Disconnected from peer {}: {}", addr, reason)
+			}
+			DisconnectReason::TooManyPeers |
+			DisconnectReason::SelfConnection |
+			DisconnectReason::RemoteClosed(_) => {
+				debug!(target: LOG_TARGET, "Disconnected from peer {}: {}", addr, reason)
+			}
+		}
+		let mut recent = self.recent.lock().unwrap();
+		if recent.len() >= MAX_RECENT_DISCONNECTS {
+			recent.pop_front();
+		}
+		recent.push_back((addr, reason));
+	}
+}
+
+/// Connects to the peers our DNS seeds point to when we're short on peers.
+#[derive(Clone)]
+struct Seeder {
+	seeds: Vec<String>,
+	port: u16,
+	max_group_peers: usize,
+	resolver: Arc<Resolver>,
+	peers: Arc<RwLock<Vec<Arc<Peer>>>>,
+	peer_store: Arc<PeerStore>,
+	connecting: Arc<Mutex<HashSet<SocketAddr>>>,
+	dial: Arc<Fn(SocketAddr) -> Box<Future<Item = (), Error = Error>>>,
+	pool: CpuPool,
+	h: reactor::Handle,
+}
+
+impl Seeder {
+	/// Resolves all seeds and connects to as many of the peers they point to
+	/// as needed to get to our preferred number of peers, spread across
+	/// seeds and network groups. Peers we're already connected or connecting
+	/// to are skipped, as well as banned ones and the pending ones provided.
+	///
+	/// DNS lookups block, so they're done on our own thread pool and the
+	/// connections made once they all have completed.
+	fn seed(&self, pending: Vec<SocketAddr>) {
+		if self.seeds.is_empty() {
+			return;
+		}
+		let seeds = self.seeds.clone();
+		let resolver = self.resolver.clone();
+		let port = self.port;
+		let resolved = self.pool.spawn_fn(move || -> Result<Vec<Vec<SocketAddr>>, ()> {
+			Ok(seeds.chunks(1).map(|seed| resolve_seeds(resolver.as_ref(), seed, port)).collect())
+		});
+		let seeder = self.clone();
+		self.h.spawn(resolved.map(move |by_seed| seeder.connect(by_seed, &pending)));
+	}
+
+	fn connect(&self, by_seed: Vec<Vec<SocketAddr>>, pending: &[SocketAddr]) {
+		let connected = self.peers.read().unwrap().iter().map(|p| p.info.addr).collect::<Vec<_>>();
+		let wanted = PREFERRED_PEERS.saturating_sub(connected.len() + pending.len());
+		let mut connecting = self.connecting.lock().unwrap();
+		let mut taken = connected.clone();
+		taken.extend(connecting.iter().cloned());
+		taken.extend_from_slice(pending);
+		let by_seed = by_seed.into_iter()
+			.map(|addrs| {
+				addrs.into_iter()
+					.filter(|addr| !banned(&self.peer_store, *addr))
+					.collect::<Vec<_>>()
+			})
+			.collect::<Vec<_>>();
+		let addrs = select_outbound(by_seed, &taken, self.max_group_peers, wanted);
+
+		for addr in addrs {
+			debug!(target: LOG_TARGET, "Connecting to seed peer {}", addr);
+			if let Err(grin_store::Error::NotFoundErr) = self.peer_store.get_peer(addr) {
+				if let Err(e) = self.peer_store.save_peer(&PeerData::new(addr)) {
+					warn!(target: LOG_TARGET, "Could not save seed peer {}: {:?}", addr, e);
+				}
+			}
+			connecting.insert(addr);
+			let done = self.connecting.clone();
+			self.h.spawn((self.dial)(addr).then(move |_| {
+				done.lock().unwrap().remove(&addr);
+				Ok(())
+			}));
+		}
+	}
 }
 
 /// P2P server implementation, handling bootstrapping to find and connect to
@@ -59,7 +274,17 @@ pub struct Server {
 	config: P2PConfig,
 	peers: Arc<RwLock<Vec<Arc<Peer>>>>,
 	adapter: Arc<NetAdapter>,
+	handshake: Arc<Handshake>,
+	announces: Arc<AnnounceWindow>,
+	relayed: Arc<RelayCache>,
+	peer_store: Arc<PeerStore>,
+	counts: Arc<PeerCounts>,
+	reconnects: Arc<Reconnector>,
+	resolver: Arc<Resolver>,
+	seed_connecting: Arc<Mutex<HashSet<SocketAddr>>>,
+	disconnects: Arc<Disconnects>,
 	stop: RefCell<Option<futures::sync::oneshot::Sender<()>>>,
+	stopped: Arc<AtomicBool>,
 }
 
 unsafe impl Sync for Server {}
@@ -67,50 +292,241 @@ unsafe impl Send for Server {}
 
 // TODO TLS
 impl Server {
-	/// Creates a new idle p2p server with no peers
-	pub fn new(config: P2PConfig, adapter: Arc<NetAdapter>) -> Server {
-		Server {
-			config: config,
+	/// Creates a new idle p2p server with no peers, for the chain starting at
+	/// the provided genesis block hash. Known peers are stored in the
+	/// provided directory.
+	pub fn new(store_path: String,
+	           config: P2PConfig,
+	           adapter: Arc<NetAdapter>,
+	           genesis: Hash)
+	           -> Result<Server, Error> {
+		if let Some(addr) = config.p2p_external_addr {
+			if !is_routable(&addr) {
+				return Err(Error::UnroutableAddr(addr));
+			}
+		}
+		let peer_store = try!(PeerStore::new(store_path).map_err(Error::StoreErr));
+		let mut handshake = Handshake::new(genesis);
+		handshake.set_advertised_addr(config.external_addr());
+		handshake.set_timeout(Duration::from_secs(config.handshake_timeout));
+		handshake.set_max_msg_len(config.max_msg_len);
+		handshake.set_io_timeouts(Duration::from_secs(config.read_timeout),
+		                          Duration::from_secs(config.write_timeout));
+		handshake.set_compress_threshold(config.compress_threshold);
+		handshake.set_addr_relay(config.addr_max_age, config.max_addrs_per_minute);
+		Ok(Server {
 			peers: Arc::new(RwLock::new(Vec::new())),
 			adapter: adapter,
+			handshake: Arc::new(handshake),
+			announces: Arc::new(AnnounceWindow::new(config.announce_window, config.announce_ttl)),
+			relayed: Arc::new(RelayCache::new(config.announce_window, config.relay_ttl)),
+			peer_store: Arc::new(peer_store),
+			counts: Arc::new(PeerCounts::new()),
+			reconnects: Arc::new(Reconnector::new(Duration::from_secs(RECONNECT_BASE_DELAY),
+			                                      Duration::from_secs(config.max_reconnect_delay),
+			                                      config.max_reconnect_failures,
+			                                      Box::new(Timer::default()))),
+			resolver: Arc::new(DnsResolver),
+			seed_connecting: Arc::new(Mutex::new(HashSet::new())),
+			disconnects: Arc::new(Disconnects::new()),
 			stop: RefCell::new(None),
-		}
+			stopped: Arc::new(AtomicBool::new(false)),
+			config: config,
+		})
+	}
+
+	/// Sets the resolver our DNS seeds are resolved with, the system DNS
+	/// resolution being used by default.
+	pub fn set_resolver(&mut self, resolver: Arc<Resolver>) {
+		self.resolver = resolver;
+	}
+
+	/// Addresses from our DNS seeds we're currently connected or trying to
+	/// connect to.
+	pub fn seed_peers(&self) -> Vec<SocketAddr> {
+		self.seed_connecting.lock().unwrap().iter().cloned().collect()
+	}
+
+	/// The last disconnects from our peers, with the address of the peer and
+	/// the reason the connection ended, oldest first.
+	pub fn recent_disconnects(&self) -> Vec<(SocketAddr, DisconnectReason)> {
+		self.disconnects.recent.lock().unwrap().iter().cloned().collect()
 	}
 
 	/// Starts the p2p server. Opens a TCP port to allow incoming
 	/// connections and starts the bootstrapping process to find peers.
 	pub fn start(&self, h: reactor::Handle) -> Box<Future<Item = (), Error = Error>> {
-		let addr = SocketAddr::new(self.config.host, self.config.port);
-		let socket = TcpListener::bind(&addr, &h.clone()).unwrap();
-		warn!("P2P server started on {}", addr);
+		let addr = self.config.listen_addr();
+		let socket = bind(&addr, self.config.dual_stack, &h).unwrap();
+		warn!(target: LOG_TARGET,
+		      "P2P server started on {}, advertising {}",
+		      addr,
+		      self.config.external_addr());
 
-		let hs = Arc::new(Handshake::new());
+		// reconnect to the peers that worked for us most recently, without too
+		// many of them in the same network group, falling back on our DNS
+		// seeds if we don't know enough of them
+		let max_group_peers = self.config.max_group_peers as usize;
+		let known = self.known_peers().into_iter().map(|pd| pd.addr).collect::<Vec<_>>();
+		let known = select_outbound(vec![known], &[], max_group_peers, PREFERRED_PEERS);
+		for addr in &known {
+			debug!(target: LOG_TARGET, "Connecting to known peer {}", addr);
+			h.spawn(self.connect_peer(*addr, h.clone()).map_err(|_| ()));
+		}
+		let seeder = Seeder {
+			seeds: self.config.dns_seeds.clone(),
+			port: self.config.port,
+			max_group_peers: max_group_peers,
+			resolver: self.resolver.clone(),
+			peers: self.peers.clone(),
+			peer_store: self.peer_store.clone(),
+			connecting: self.seed_connecting.clone(),
+			dial: self.dialer(h.clone()),
+			pool: CpuPool::new(1),
+			h: h.clone(),
+		};
+		if known.len() < PREFERRED_PEERS {
+			seeder.seed(known);
+		}
+
+		// regularly ask our peers about other peers until we have enough,
+		// going back to the seeds as well
+		let discovery_peers = self.peers.clone();
+		let discovery_stopped = self.stopped.clone();
+		let discovery = Timer::default()
+			.interval(Duration::new(PEER_DISCOVERY_INTERVAL, 0))
+			.take_while(move |_| Ok(!discovery_stopped.load(Ordering::SeqCst)))
+			.for_each(move |_| {
+				let few_peers = {
+					let peers = discovery_peers.read().unwrap();
+					if peers.len() < PREFERRED_PEERS {
+						for p in peers.deref() {
+							if let Err(e) = p.send_peer_request(FULL_SYNC) {
+								debug!(target: LOG_TARGET,
+								       "Error asking peer {} for peers: {}",
+								       p.info.addr,
+								       e);
+							}
+						}
+					}
+					peers.len() < PREFERRED_PEERS
+				};
+				if few_peers {
+					seeder.seed(vec![]);
+				}
+				Ok(())
+			});
+		h.spawn(discovery.map_err(|e| warn!(target: LOG_TARGET,
+		                                    "Peer discovery timer failed: {:?}",
+		                                    e)));
+
+		let hs = self.handshake.clone();
 		let peers = self.peers.clone();
 		let adapter = self.adapter.clone();
+		let announces = self.announces.clone();
+		let config = self.config.clone();
+		let peer_store = self.peer_store.clone();
+		let filter_store = self.peer_store.clone();
+		let counts = self.counts.clone();
+		let disconnects = self.disconnects.clone();
 
 		// main peer acceptance future handling handshake
-		let hp = h.clone();
-		let peers = socket.incoming().map_err(|e| Error::IOErr(e)).map(move |(conn, addr)| {
-			let adapter = adapter.clone();
-			let total_diff = adapter.total_difficulty();
-			let peers = peers.clone();
+		let peers = socket.incoming()
+			.map_err(|e| Error::IOErr(e))
+			.filter(move |&(_, addr)| {
+				let is_banned = banned(&filter_store, addr);
+				if is_banned {
+					debug!(target: LOG_TARGET, "Refusing connection from banned peer {}.", addr);
+				}
+				!is_banned
+			})
+			.map(move |(conn, addr)| {
+				let adapter = adapter.clone();
+				let announces = announces.clone();
+				let total_diff = adapter.total_difficulty();
+				let height = adapter.height();
+				let peers = peers.clone();
+				let run_peers = peers.clone();
+				let evict_peers = peers.clone();
+				let peer_store = peer_store.clone();
+				let hs_store = peer_store.clone();
+				let rep_store = peer_store.clone();
+				let counts = counts.clone();
+				let accept_counts = counts.clone();
+				let disconnects = disconnects.clone();
+				let hs_disconnects = disconnects.clone();
 
-			// accept the peer and add it to the server map
-			let peer_accept = add_to_peers(peers, Peer::accept(conn, total_diff, &hs.clone()));
+				// accept the peer and add it to the server map, banning it if it
+				// doesn't follow the handshake protocol and politely sending it
+				// away if we already have enough peers
+				let accept = Peer::accept(conn, total_diff, height, &hs.clone())
+					.map_err(move |e| {
+						if handshake_violation(&e) {
+							ban(&hs_store, config.ban_window, addr, BanReason::BadHandshake);
+						}
+						e
+					})
+					.and_then(move |(conn, peer)| -> Box<Future<Item = (TcpStream, Peer), Error = Error>> {
+						restore_reputation(&rep_store, &peer);
+						if accept_counts.reserve_inbound(&config) {
+							return Box::new(futures::finished((conn, peer)));
+						}
+						if let Some(evicted) = make_room(&evict_peers, peer.reputation()) {
+							debug!(target: LOG_TARGET,
+							       "Dropping peer {} (reputation {}) to make room for {} (reputation {}).",
+							       evicted.info.addr,
+							       evicted.reputation(),
+							       addr,
+							       peer.reputation());
+							accept_counts.take_inbound();
+							evicted.stop();
+							return Box::new(futures::finished((conn, peer)));
+						}
+						debug!(target: LOG_TARGET, "Too many peers, disconnecting from {}.", addr);
+						let err = PeerError {
+							code: ERR_TOO_MANY_PEERS,
+							message: "too many peers".to_string(),
+						};
+						Box::new(write_msg(conn, err, Type::Error)
+							.map_err(Error::SerErr)
+							.and_then(|_| Err(Error::TooManyPeers)))
+					});
+				let peer_accept = add_to_peers(peers, accept).map_err(move |e| {
+					hs_disconnects.record(addr, DisconnectReason::from_error(&e));
+					e
+				});
 
-			// wire in a future to timeout the accept after 5 secs
-			let timed_peer = with_timeout(Box::new(peer_accept), &hp);
-
-			// run the main peer protocol
-			timed_peer.and_then(move |(conn, peer)| peer.clone().run(conn, adapter))
-		});
+				// run the main peer protocol
+				let limiter = RateLimiter::with_decay(config.msg_rate_limit.rate,
+				                                      config.msg_rate_limit.burst,
+				                                      Duration::from_secs(config.ban_score_decay));
+				peer_accept.and_then(move |(conn, peer)| {
+					let keepalive = Peer::keepalive(peer.clone(),
+					                                adapter.clone(),
+					                                Duration::from_secs(config.ping_interval),
+					                                Duration::from_secs(config.ping_timeout));
+					peer.clone()
+						.run(conn, adapter, announces, limiter)
+						.select(keepalive)
+						.map(|_| ())
+						.map_err(|(e, _)| e)
+						.then(move |res| {
+							counts.release(true);
+							remove_peer(&run_peers, &peer);
+							save_reputation(&peer_store, &peer);
+							let reason = disconnect_reason(&peer_store, config.ban_window, &peer, &res);
+							disconnects.record(addr, reason);
+							res
+						})
+				})
+			});
 
 		// spawn each peer future to its own task
 		let hs = h.clone();
 		let server = peers.for_each(move |peer| {
 			hs.spawn(peer.then(|res| {
 				match res {
-					Err(e) => info!("Client error: {}", e),
+					Err(e) => info!(target: LOG_TARGET, "Client error: {}", e),
 					_ => {}
 				}
 				futures::finished(())
@@ -118,13 +534,21 @@ impl Server {
 			Ok(())
 		});
 
-		// setup the stopping oneshot on the server and join it with the peer future
+		// setup the stopping oneshot on the server and join it with the peer future,
+		// the listener is only dropped once our peers had time to get our last
+		// messages
 		let (stop, stop_rx) = futures::sync::oneshot::channel();
 		{
 			let mut stop_mut = self.stop.borrow_mut();
 			*stop_mut = Some(stop);
 		}
-		Box::new(server.select(stop_rx.map_err(|_| Error::CorruptedData)).then(|res| {
+		let stop_rx = stop_rx.map_err(|_| Error::SerErr(ser::Error::CorruptedData))
+			.and_then(|_| {
+				Timer::default()
+					.sleep(Duration::from_millis(CLOSE_FLUSH_DELAY))
+					.map_err(|_| Error::SerErr(ser::Error::CorruptedData))
+			});
+		Box::new(server.select(stop_rx).then(|res| {
 			match res {
 				Ok((_, _)) => Ok(()),
 				Err((e, _)) => Err(e),
@@ -132,44 +556,288 @@ impl Server {
 		}))
 	}
 
-	/// Asks the server to connect to a new peer.
+	/// Asks the server to connect to a new peer. Whenever we lose the peer
+	/// we try to reconnect to it, backing off exponentially on consecutive
+	/// failures until we give up and mark it defunct. Also gives up right
+	/// away if we already have as many peers as we're allowed to or once the
+	/// server is stopped.
 	pub fn connect_peer(&self,
 	                    addr: SocketAddr,
 	                    h: reactor::Handle)
 	                    -> Box<Future<Item = (), Error = Error>> {
+		(self.dialer(h))(addr)
+	}
+
+	// Builds a function connecting to peers like connect_peer does, which can
+	// outlive the borrow of the server.
+	fn dialer(&self,
+	          h: reactor::Handle)
+	          -> Arc<Fn(SocketAddr) -> Box<Future<Item = (), Error = Error>>> {
+		let connect = self.connector(h);
+		let reconnects = self.reconnects.clone();
+		let peer_store = self.peer_store.clone();
+		let stopped = self.stopped.clone();
+
+		Arc::new(move |addr| -> Box<Future<Item = (), Error = Error>> {
+			let connect = connect.clone();
+			let reconnects = reconnects.clone();
+			let peer_store = peer_store.clone();
+			let stopped = stopped.clone();
+			let attempts = future::loop_fn((), move |_| -> Box<Future<Item = Loop<(), ()>, Error = Error>> {
+				if stopped.load(Ordering::SeqCst) {
+					return Box::new(future::ok(Loop::Break(())));
+				}
+				let reconnects = reconnects.clone();
+				let peer_store = peer_store.clone();
+				let stopped = stopped.clone();
+				Box::new(connect(addr).then(move |res| -> Box<Future<Item = Loop<(), ()>, Error = Error>> {
+					match res {
+						Err(Error::TooManyPeers) => return Box::new(future::err(Error::TooManyPeers)),
+						Err(e) => debug!(target: LOG_TARGET,
+						                 "Lost connection to peer {}: {}",
+						                 addr,
+						                 e),
+						Ok(_) => debug!(target: LOG_TARGET, "Peer {} disconnected.", addr),
+					}
+					if stopped.load(Ordering::SeqCst) {
+						reconnects.forget(addr);
+						return Box::new(future::ok(Loop::Break(())));
+					}
+					if banned(&peer_store, addr) {
+						reconnects.forget(addr);
+						return Box::new(future::ok(Loop::Break(())));
+					}
+					match reconnects.wait(addr) {
+						Some(wait) => Box::new(wait.map(|_| Loop::Continue(()))),
+						None => {
+							info!(target: LOG_TARGET,
+							      "Giving up on reconnecting to peer {}.",
+							      addr);
+							reconnects.forget(addr);
+							if let Err(e) = defunct(&peer_store, addr) {
+								warn!(target: LOG_TARGET, "Could not save peer {}: {:?}", addr, e);
+							}
+							Box::new(future::ok(Loop::Break(())))
+						}
+					}
+				}))
+			});
+			Box::new(attempts)
+		})
+	}
+
+	// Builds a function making a single connection attempt to a peer,
+	// resolving once the connection is lost.
+	fn connector(&self,
+	             h: reactor::Handle)
+	             -> Arc<Fn(SocketAddr) -> Box<Future<Item = (), Error = Error>>> {
+		let config = self.config.clone();
+		let counts = self.counts.clone();
 		let peers = self.peers.clone();
-		let adapter1 = self.adapter.clone();
-		let adapter2 = self.adapter.clone();
+		let hs = self.handshake.clone();
+		let adapter = self.adapter.clone();
+		let announces = self.announces.clone();
+		let peer_store = self.peer_store.clone();
+		let reconnects = self.reconnects.clone();
+		let disconnects = self.disconnects.clone();
 
-		let socket = TcpStream::connect(&addr, &h).map_err(|e| Error::IOErr(e));
-		let request = socket.and_then(move |socket| {
-				let peers = peers.clone();
-				let total_diff = adapter1.total_difficulty();
+		Arc::new(move |addr| -> Box<Future<Item = (), Error = Error>> {
+			if !counts.reserve_outbound(&config) {
+				debug!(target: LOG_TARGET, "Too many peers, not connecting to {}.", addr);
+				return Box::new(futures::failed(Error::TooManyPeers));
+			}
+			let counts = counts.clone();
+			let peers = peers.clone();
+			let run_peers = peers.clone();
+			let hs = hs.clone();
+			let adapter1 = adapter.clone();
+			let adapter2 = adapter.clone();
+			let announces = announces.clone();
+			let limiter = RateLimiter::with_decay(config.msg_rate_limit.rate,
+			                                      config.msg_rate_limit.burst,
+			                                      Duration::from_secs(config.ban_score_decay));
+			let peer_store = peer_store.clone();
+			let fail_store = peer_store.clone();
+			let ban_window = config.ban_window;
+			let fail_reconnects = reconnects.clone();
+			let reconnects = reconnects.clone();
+			let fail_disconnects = disconnects.clone();
+			let disconnects = disconnects.clone();
+			let ping_interval = Duration::from_secs(config.ping_interval);
+			let ping_timeout = Duration::from_secs(config.ping_timeout);
+
+			let socket = TcpStream::connect(&addr, &h).map_err(|e| Error::IOErr(e));
+			let request = socket.and_then(move |socket| {
+					let peers = peers.clone();
+					let total_diff = adapter1.total_difficulty();
+					let height = adapter1.height();
+
+					// connect to the peer and add it to the server map, the handshake
+					// times out on its own
+					add_to_peers(peers, Peer::connect(socket, total_diff, height, &hs))
+				})
+				.map_err(move |e| {
+					fail_disconnects.record(addr, DisconnectReason::from_error(&e));
+					fail_reconnects.failed(addr);
+					// same as inbound, a peer breaking the handshake protocol gets banned
+					if handshake_violation(&e) {
+						ban(&fail_store, ban_window, addr, BanReason::BadHandshake);
+					}
+					if let Error::Timeout = e {
+						if let Err(e) = handshake_timeout(&fail_store, addr) {
+							warn!(target: LOG_TARGET, "Could not save peer {}: {:?}", addr, e);
+						}
+					}
+					e
+				})
+				.and_then(move |(socket, peer)| {
+					reconnects.connected(addr);
+					if let Err(e) = peer_connected(&peer_store, addr) {
+						warn!(target: LOG_TARGET, "Could not save peer {}: {:?}", addr, e);
+					}
+					restore_reputation(&peer_store, &peer);
+					let keepalive =
+						Peer::keepalive(peer.clone(), adapter2.clone(), ping_interval, ping_timeout);
+					peer.clone()
+						.run(socket, adapter2, announces, limiter)
+						.select(keepalive)
+						.map(|_| ())
+						.map_err(|(e, _)| e)
+						.then(move |res| {
+							remove_peer(&run_peers, &peer);
+							save_reputation(&peer_store, &peer);
+							let reason = disconnect_reason(&peer_store, ban_window, &peer, &res);
+							disconnects.record(addr, reason);
+							res
+						})
+				})
+				.then(move |res| {
+					counts.release(false);
+					res
+				});
+			Box::new(request)
+		})
+	}
+
+	/// Bans the peer at the provided address for the configured ban window,
+	/// dropping it if we're connected to it.
+	pub fn ban_peer(&self, addr: SocketAddr, reason: BanReason) {
+		ban(&self.peer_store, self.config.ban_window, addr, reason);
+		let mut peers = self.peers.write().unwrap();
+		peers.retain(|p| if p.info.addr == addr {
+			p.stop();
+			false
+		} else {
+			true
+		});
+	}
+
+	/// Whether the peer at the provided address is currently banned, which
+	/// is the case of all peers at a banned IP address.
+	pub fn is_banned(&self, addr: SocketAddr) -> bool {
+		banned(&self.peer_store, addr)
+	}
+
+	/// Healthy peers we've successfully connected to in the past, most
+	/// recently seen first. Peers that repeatedly failed to complete the
+	/// handshake in time come last.
+	pub fn known_peers(&self) -> Vec<PeerData> {
+		let (mut peers, mut slow): (Vec<PeerData>, Vec<PeerData>) = self.peer_store
+			.all_peers()
+			.into_iter()
+			.filter(|p| p.flags == State::Healthy && p.success_count > 0)
+			.partition(|p| p.handshake_timeouts < MAX_HANDSHAKE_TIMEOUTS);
+		peers.append(&mut slow);
+		peers
+	}
 
-				// connect to the peer and add it to the server map, wiring it a timeout for
-				// the handhake
-				let peer_connect =
-					add_to_peers(peers, Peer::connect(socket, total_diff, &Handshake::new()));
-				with_timeout(Box::new(peer_connect), &h)
+	/// Addresses of the healthy peers we know of that are worth relaying to
+	/// a peer asking for them, with when we last saw them. Capabilities
+	/// aren't tracked in the peer store yet so no filtering is done on them.
+	pub fn find_peer_addrs(&self, capab: Capabilities) -> Vec<PeerAddr> {
+		self.known_peers()
+			.into_iter()
+			.map(|p| {
+				PeerAddr {
+					addr: p.addr,
+					last_seen: p.last_seen,
+				}
 			})
-			.and_then(move |(socket, peer)| peer.run(socket, adapter2));
-		Box::new(request)
+			.take(MAX_PEER_ADDRS as usize)
+			.collect()
+	}
+
+	/// Saves peer addresses relayed by one of our peers, so we can try to
+	/// connect to them later. Addresses we already know of get their last
+	/// seen time moved forward when the relayed one is more recent.
+	pub fn peer_addrs_received(&self, addrs: Vec<PeerAddr>) {
+		for pa in addrs.into_iter().take(MAX_PEER_ADDRS as usize) {
+			let pd = match self.peer_store.get_peer(pa.addr) {
+				Ok(ref pd) if pd.last_seen >= pa.last_seen => continue,
+				Ok(pd) => PeerData { last_seen: pa.last_seen, ..pd },
+				Err(grin_store::Error::NotFoundErr) => {
+					PeerData { last_seen: pa.last_seen, ..PeerData::new(pa.addr) }
+				}
+				Err(e) => {
+					warn!(target: LOG_TARGET,
+					      "Could not read received peer address {}: {:?}",
+					      pa.addr,
+					      e);
+					continue;
+				}
+			};
+			if let Err(e) = self.peer_store.save_peer(&pd) {
+				warn!(target: LOG_TARGET,
+				      "Could not save received peer address {}: {:?}",
+				      pa.addr,
+				      e);
+			}
+		}
 	}
 
 	/// Returns the peer with the most worked branch, showing the highest total
-	/// difficulty.
+	/// difficulty, if that's more than ours. Among peers with the same total
+	/// difficulty, the one with the lowest latency wins, then the one we've
+	/// been connected to the longest.
 	pub fn most_work_peer(&self) -> Option<Arc<Peer>> {
+		let ours = self.adapter.total_difficulty();
 		let peers = self.peers.read().unwrap();
-		if peers.len() == 0 {
-			return None;
-		}
-		let mut res = peers[0].clone();
+		let mut res: Option<(Arc<Peer>, Difficulty)> = None;
 		for p in peers.deref() {
-			if res.info.total_difficulty < p.info.total_difficulty {
-				res = (*p).clone();
+			let diff = p.total_difficulty();
+			if diff <= ours {
+				continue;
+			}
+			let better = match res {
+				Some((ref best_peer, ref best)) => {
+					diff > *best || (diff == *best && faster(p.latency(), best_peer.latency()))
+				}
+				None => true,
+			};
+			if better {
+				res = Some((p.clone(), diff));
 			}
 		}
-		Some(res)
+		res.map(|(p, _)| p)
+	}
+
+	/// Highest chain height advertised by our peers, leaving out the ones
+	/// flagged as suspicious for claiming more than their total difficulty
+	/// allows for. Zero without any such peer.
+	pub fn max_peer_height(&self) -> u64 {
+		self.peers
+			.read()
+			.unwrap()
+			.iter()
+			.filter(|p| !p.is_suspicious())
+			.map(|p| p.height())
+			.max()
+			.unwrap_or(0)
+	}
+
+	/// All the peers we're currently connected to.
+	pub fn connected_peers(&self) -> Vec<Arc<Peer>> {
+		self.peers.read().unwrap().clone()
 	}
 
 	/// Returns a random peer we're connected to.
@@ -185,58 +853,341 @@ impl Server {
 
 	/// Broadcasts the provided block to all our peers. A peer implementation
 	/// may drop the broadcast request if it knows the remote peer already has
-	/// the block.
+	/// the block. Peers that recently announced the block to us are skipped
+	/// and nothing is sent if we already relayed it within the relay TTL.
 	pub fn broadcast_block(&self, b: &core::Block) {
+		let bh = b.hash();
+		if !self.relayed.relaying(bh) {
+			return;
+		}
 		let peers = self.peers.write().unwrap();
 		for p in peers.deref() {
+			if self.announces.announced_by(&bh, &p.info.addr) {
+				continue;
+			}
 			if let Err(e) = p.send_block(b) {
-				debug!("Error sending block to peer: {}", e);
+				debug!(target: LOG_TARGET, "Error sending block to peer: {}", e);
+			}
+		}
+	}
+
+	/// Announces a block to all our peers, except the ones that recently
+	/// announced it to us or are otherwise known to have it. Peers ask for
+	/// the full block if they need it. Nothing is sent if we already relayed
+	/// the block within the relay TTL.
+	pub fn announce_block(&self, h: Hash) {
+		if !self.relayed.relaying(h) {
+			return;
+		}
+		let peers = self.peers.read().unwrap();
+		for p in peers.deref() {
+			if self.announces.announced_by(&h, &p.info.addr) {
+				continue;
+			}
+			if let Err(e) = p.send_inv(h) {
+				debug!(target: LOG_TARGET, "Error announcing block to peer: {}", e);
 			}
 		}
 	}
 
+	/// Relays the compact version of a block to all our peers, except the
+	/// ones that recently announced the block to us. Nothing is sent if we
+	/// already relayed the block within the relay TTL.
+	pub fn broadcast_compact_block(&self, cb: &CompactBlock) {
+		let bh = cb.hash();
+		if !self.relayed.relaying(bh) {
+			return;
+		}
+		let peers = self.peers.read().unwrap();
+		for p in peers.deref() {
+			if self.announces.announced_by(&bh, &p.info.addr) {
+				continue;
+			}
+			if let Err(e) = p.send_compact_block(cb) {
+				debug!(target: LOG_TARGET, "Error sending compact block to peer: {}", e);
+			}
+		}
+	}
+
+	/// Relays the provided transaction to all our peers, except the ones
+	/// that recently relayed it to us. Nothing is sent if we already relayed
+	/// it within the relay TTL.
+	pub fn broadcast_transaction(&self, tx: &core::Transaction) {
+		let txh = tx.hash();
+		if !self.relayed.relaying(txh) {
+			return;
+		}
+		let peers = self.peers.read().unwrap();
+		for p in peers.deref() {
+			if self.announces.announced_by(&txh, &p.info.addr) {
+				continue;
+			}
+			if let Err(e) = p.send_transaction(tx) {
+				debug!(target: LOG_TARGET, "Error sending transaction to peer: {}", e);
+			}
+		}
+	}
+
+	/// Total bytes sent to and received from all the peers we're currently
+	/// connected to.
+	pub fn total_bandwidth(&self) -> (u64, u64) {
+		let peers = self.peers.read().unwrap();
+		peers.iter().fold((0, 0), |(sent, recv), p| {
+			let stats = p.stats();
+			(sent + stats.sent_bytes, recv + stats.received_bytes)
+		})
+	}
+
 	/// Number of peers we're currently connected to.
 	pub fn peer_count(&self) -> u32 {
 		self.peers.read().unwrap().len() as u32
 	}
 
-	/// Stops the server. Disconnect from all peers at the same time.
-	pub fn stop(self) {
-		let peers = self.peers.write().unwrap();
-		for p in peers.deref() {
+	/// Stops the server. Lets all our peers know we're going away and
+	/// disconnects from them, stops reconnecting and looking for new peers
+	/// and finally releases the listening socket, resolving the future
+	/// returned by start. The server is usually shared, so it can be stopped
+	/// through any of its references.
+	pub fn stop(&self) {
+		self.stopped.store(true, Ordering::SeqCst);
+		let mut peers = self.peers.write().unwrap();
+		for p in peers.drain(..) {
 			p.stop();
 		}
-		self.stop.into_inner().unwrap().complete(());
+		if let Some(stop) = self.stop.borrow_mut().take() {
+			stop.complete(());
+		}
+	}
+}
+
+/// Network group of an address, peers in the same group likely being run
+/// by the same operator. That's the /16 for IPv4 and the /32 for IPv6,
+/// IPv4-mapped IPv6 addresses going with their IPv4 group.
+pub fn addr_group(addr: &SocketAddr) -> Vec<u8> {
+	match addr.ip() {
+		IpAddr::V4(ip) => {
+			let o = ip.octets();
+			vec![4, o[0], o[1]]
+		}
+		IpAddr::V6(ip) => {
+			let o = ip.octets();
+			if o[..10].iter().all(|b| *b == 0) && o[10] == 0xff && o[11] == 0xff {
+				vec![4, o[12], o[13]]
+			} else {
+				vec![6, o[0], o[1], o[2], o[3]]
+			}
+		}
 	}
 }
 
+/// Picks up to count addresses to connect to among candidates coming from
+/// several sources, each in order of preference. Sources are taken from in
+/// turn so no single one provides all our peers, and no more than
+/// per_group addresses of the same network group get picked, counting the
+/// peers we're already connected to. Those are never picked again.
+pub fn select_outbound(sources: Vec<Vec<SocketAddr>>,
+                       connected: &[SocketAddr],
+                       per_group: usize,
+                       count: usize)
+                       -> Vec<SocketAddr> {
+	let mut groups: HashMap<Vec<u8>, usize> = HashMap::new();
+	for addr in connected {
+		*groups.entry(addr_group(addr)).or_insert(0) += 1;
+	}
+	let mut sources = sources.into_iter().map(|s| s.into_iter()).collect::<Vec<_>>();
+	let mut selected = vec![];
+	loop {
+		let mut picked = false;
+		for source in sources.iter_mut() {
+			if selected.len() >= count {
+				return selected;
+			}
+			// the next candidate of that source we don't have and whose group
+			// isn't full yet
+			while let Some(addr) = source.next() {
+				if selected.contains(&addr) || connected.contains(&addr) {
+					continue;
+				}
+				let in_group = groups.entry(addr_group(&addr)).or_insert(0);
+				if *in_group < per_group {
+					*in_group += 1;
+					selected.push(addr);
+					picked = true;
+					break;
+				}
+			}
+		}
+		if !picked {
+			return selected;
+		}
+	}
+}
+
+// Binds our listening socket. IPv6 sockets only accept IPv6 connections
+// unless asked to go dual-stack, regardless of the system default.
+fn bind(addr: &SocketAddr, dual_stack: bool, h: &reactor::Handle) -> io::Result<TcpListener> {
+	let builder = match *addr {
+		SocketAddr::V4(_) => try!(TcpBuilder::new_v4()),
+		SocketAddr::V6(_) => {
+			let builder = try!(TcpBuilder::new_v6());
+			try!(builder.only_v6(!dual_stack));
+			builder
+		}
+	};
+	try!(builder.reuse_address(true));
+	try!(builder.bind(addr));
+	let listener = try!(builder.listen(1024));
+	TcpListener::from_listener(listener, addr, h)
+}
+
 // Adds the peer built by the provided future in the peers map
 fn add_to_peers<A>(peers: Arc<RwLock<Vec<Arc<Peer>>>>,
                    peer_fut: A)
-                   -> Box<Future<Item = Result<(TcpStream, Arc<Peer>), ()>, Error = Error>>
+                   -> Box<Future<Item = (TcpStream, Arc<Peer>), Error = Error>>
 	where A: IntoFuture<Item = (TcpStream, Peer), Error = Error> + 'static
 {
 	let peer_add = peer_fut.into_future().map(move |(conn, peer)| {
 		let apeer = Arc::new(peer);
 		let mut peers = peers.write().unwrap();
 		peers.push(apeer.clone());
-		Ok((conn, apeer))
+		(conn, apeer)
 	});
 	Box::new(peer_add)
 }
 
-// Adds a timeout to a future
-fn with_timeout<T: 'static>(fut: Box<Future<Item = Result<T, ()>, Error = Error>>,
-                            h: &reactor::Handle)
-                            -> Box<Future<Item = T, Error = Error>> {
-	let timeout = reactor::Timeout::new(Duration::new(5, 0), h).unwrap();
-	let timed = fut.select(timeout.map(Err).map_err(|e| Error::IOErr(e)))
-		.then(|res| {
-			match res {
-				Ok((Ok(inner), _timeout)) => Ok(inner),
-				Ok((_, _accept)) => Err(Error::TooLargeReadErr),
-				Err((e, _other)) => Err(e),
-			}
-		});
-	Box::new(timed)
+// Removes a peer we got disconnected from out of the peers map
+fn remove_peer(peers: &RwLock<Vec<Arc<Peer>>>, peer: &Peer) {
+	peers.write().unwrap().retain(|p| p.info.addr != peer.info.addr);
+}
+
+// Whether a peer with the first latency answers faster than one with the
+// second, peers that never answered a ping being the slowest
+fn faster(latency: Option<Duration>, than: Option<Duration>) -> bool {
+	match (latency, than) {
+		(Some(l), Some(t)) => l < t,
+		(Some(_), None) => true,
+		_ => false,
+	}
+}
+
+// Removes the inbound peer with the lowest reputation out of the peers map,
+// as long as it's lower than the provided one, returning it so it can be
+// disconnected
+fn make_room(peers: &RwLock<Vec<Arc<Peer>>>, reputation: i32) -> Option<Arc<Peer>> {
+	let mut peers = peers.write().unwrap();
+	let lowest = peers.iter()
+		.enumerate()
+		.filter(|&(_, p)| p.info.direction == Direction::Inbound)
+		.min_by_key(|&(_, p)| p.reputation())
+		.map(|(idx, p)| (idx, p.reputation()));
+	match lowest {
+		Some((idx, lowest)) if lowest < reputation => Some(peers.remove(idx)),
+		_ => None,
+	}
+}
+
+// Picks up the reputation the peer had when we last got disconnected from it,
+// or from another peer at the same IP address
+fn restore_reputation(peer_store: &PeerStore, peer: &Peer) {
+	if let Ok(reputation) = peer_store.get_reputation(peer.info.addr.ip()) {
+		peer.adjust_reputation(reputation);
+	}
+}
+
+// Remembers the reputation of a peer we got disconnected from, by IP address
+// as inbound peers connect from a different port every time
+fn save_reputation(peer_store: &PeerStore, peer: &Peer) {
+	let addr = peer.info.addr;
+	if let Err(e) = peer_store.save_reputation(addr.ip(), peer.reputation()) {
+		warn!(target: LOG_TARGET, "Could not save reputation of peer {}: {:?}", addr, e);
+	}
+}
+
+// Records a successful connection to the peer at the provided address
+fn peer_connected(peer_store: &PeerStore, addr: SocketAddr) -> Result<(), Error> {
+	let mut pd = peer_store.get_peer(addr).unwrap_or(PeerData::new(addr));
+	pd.last_seen = time::now_utc().to_timespec().sec;
+	pd.success_count += 1;
+	pd.handshake_timeouts = 0;
+	if pd.flags == State::Banned && pd.ban_expiry <= pd.last_seen ||
+	   pd.flags == State::Defunct {
+		pd.flags = State::Healthy;
+	}
+	peer_store.save_peer(&pd).map_err(Error::StoreErr)
+}
+
+// Records the peer at the provided address didn't complete the handshake in
+// time
+fn handshake_timeout(peer_store: &PeerStore, addr: SocketAddr) -> Result<(), Error> {
+	let mut pd = peer_store.get_peer(addr).unwrap_or(PeerData::new(addr));
+	pd.handshake_timeouts += 1;
+	debug!(target: LOG_TARGET, "Handshake with peer {} timed out ({} in a row).",
+	       addr,
+	       pd.handshake_timeouts);
+	peer_store.save_peer(&pd).map_err(Error::StoreErr)
+}
+
+// Marks the peer at the provided address as defunct, we won't try to connect
+// to it on our own anymore
+fn defunct(peer_store: &PeerStore, addr: SocketAddr) -> Result<(), Error> {
+	let mut pd = peer_store.get_peer(addr).unwrap_or(PeerData::new(addr));
+	if pd.flags == State::Healthy {
+		pd.flags = State::Defunct;
+	}
+	peer_store.save_peer(&pd).map_err(Error::StoreErr)
+}
+
+// Whether the IP address of the peer at the provided address is banned and
+// its ban hasn't expired yet, whatever the port
+fn banned(peer_store: &PeerStore, addr: SocketAddr) -> bool {
+	match peer_store.ban_expiry(addr.ip()) {
+		Ok(expiry) => expiry > time::now_utc().to_timespec().sec,
+		Err(_) => false,
+	}
+}
+
+// Bans the peer at the provided address, along with any other peer at the
+// same IP address, for ban_window seconds
+fn ban(peer_store: &PeerStore, ban_window: i64, addr: SocketAddr, reason: BanReason) {
+	warn!(target: LOG_TARGET, "Banning peer {} for {} secs: {:?}", addr, ban_window, reason);
+	let mut pd = peer_store.get_peer(addr).unwrap_or(PeerData::new(addr));
+	pd.flags = State::Banned;
+	pd.ban_expiry = time::now_utc().to_timespec().sec + ban_window;
+	let saved = peer_store.save_peer(&pd)
+		.and_then(|_| peer_store.save_ban(addr.ip(), pd.ban_expiry));
+	if let Err(e) = saved {
+		warn!(target: LOG_TARGET, "Could not save ban for peer {}: {:?}", addr, e);
+	}
+}
+
+// Bans a peer once it's done running if it misbehaved too much, returning
+// why the connection to it ended. Peers banned while running, whatever the
+// cause, count as banned.
+fn disconnect_reason(peer_store: &PeerStore,
+                     ban_window: i64,
+                     peer: &Peer,
+                     res: &Result<(), Error>)
+                     -> DisconnectReason {
+	if peer.ban_score() >= MAX_BAN_SCORE {
+		ban(peer_store, ban_window, peer.info.addr, BanReason::BadMessages);
+	}
+	if banned(peer_store, peer.info.addr) {
+		return DisconnectReason::Banned;
+	}
+	// however the connection ended, a peer that said bye left on purpose
+	if let Some(bye) = peer.remote_bye() {
+		return DisconnectReason::RemoteClosed(Some(bye));
+	}
+	DisconnectReason::from_result(res)
+}
+
+// Whether the handshake failed because the remote didn't follow the protocol
+// rather than because of network trouble. Peers on another network and
+// connections to ourselves are just dropped, they did nothing wrong.
+fn handshake_violation(e: &Error) -> bool {
+	match *e {
+		Error::SerErr(ser::Error::UnexpectedData { .. }) |
+		Error::SerErr(ser::Error::CorruptedData) => true,
+		_ => false,
+	}
 }
@@ -0,0 +1,193 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage implementation for peer data, so we remember the peers we've
+//! known across restarts.
+
+use std::net::{IpAddr, SocketAddr};
+use num::FromPrimitive;
+
+use core::ser::{self, Readable, Writeable, Reader, Writer};
+use grin_store::{self, Error, Key, option_to_not_found};
+use msg::SockAddr;
+use LOG_TARGET;
+
+const PEER_PREFIX: u8 = 'p' as u8;
+const BAN_PREFIX: u8 = 'b' as u8;
+const REPUTATION_PREFIX: u8 = 'r' as u8;
+
+/// State of a peer as far as we're concerned
+enum_from_primitive! {
+	#[derive(Debug, Clone, Copy, PartialEq)]
+	pub enum State {
+		Healthy,
+		Banned,
+		Defunct,
+	}
+}
+
+/// Data stored for any given peer we've encountered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerData {
+	/// Network address of the peer.
+	pub addr: SocketAddr,
+	/// Last time we successfully connected to the peer, as a UTC timestamp
+	/// in seconds.
+	pub last_seen: i64,
+	/// Number of times we successfully connected to the peer.
+	pub success_count: u32,
+	/// The current state of the peer.
+	pub flags: State,
+	/// When a ban on the peer lifts, as a UTC timestamp in seconds.
+	pub ban_expiry: i64,
+	/// Number of times in a row the peer didn't complete the handshake in
+	/// time when we connected to it.
+	pub handshake_timeouts: u32,
+}
+
+impl PeerData {
+	/// Data for a peer we know nothing about yet.
+	pub fn new(addr: SocketAddr) -> PeerData {
+		PeerData {
+			addr: addr,
+			last_seen: 0,
+			success_count: 0,
+			flags: State::Healthy,
+			ban_expiry: 0,
+			handshake_timeouts: 0,
+		}
+	}
+}
+
+impl Writeable for PeerData {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		try!(SockAddr(self.addr).write(writer));
+		ser_multiwrite!(writer,
+		                [write_i64, self.last_seen],
+		                [write_u32, self.success_count],
+		                [write_u8, self.flags as u8],
+		                [write_i64, self.ban_expiry],
+		                [write_u32, self.handshake_timeouts]);
+		Ok(())
+	}
+}
+
+impl Readable<PeerData> for PeerData {
+	fn read(reader: &mut Reader) -> Result<PeerData, ser::Error> {
+		let addr = try!(SockAddr::read(reader));
+		let (last_seen, success_count, fl) = ser_multiread!(reader, read_i64, read_u32, read_u8);
+		let flags = try!(State::from_u8(fl).ok_or(ser::Error::CorruptedData));
+		let (ban_expiry, handshake_timeouts) = ser_multiread!(reader, read_i64, read_u32);
+		Ok(PeerData {
+			addr: addr.0,
+			last_seen: last_seen,
+			success_count: success_count,
+			flags: flags,
+			ban_expiry: ban_expiry,
+			handshake_timeouts: handshake_timeouts,
+		})
+	}
+}
+
+// A number we keep for all the peers behind an IP address, whatever port
+// they connect from, like when their ban lifts or their reputation.
+struct IpValue(i64);
+
+impl Writeable for IpValue {
+	fn write(&self, writer: &mut Writer) -> Result<(), ser::Error> {
+		writer.write_i64(self.0)
+	}
+}
+
+impl Readable<IpValue> for IpValue {
+	fn read(reader: &mut Reader) -> Result<IpValue, ser::Error> {
+		Ok(IpValue(try!(reader.read_i64())))
+	}
+}
+
+/// Storage facility for peer data.
+pub struct PeerStore {
+	db: grin_store::Store,
+}
+
+impl PeerStore {
+	/// Instantiates a new peer store at the provided path.
+	pub fn new(path: String) -> Result<PeerStore, Error> {
+		let db = try!(grin_store::Store::open(path.as_str()));
+		Ok(PeerStore { db: db })
+	}
+
+	/// Saves (or updates) the data we have about a peer.
+	pub fn save_peer(&self, p: &PeerData) -> Result<(), Error> {
+		self.db.put_ser(&peer_key(p.addr)[..], p)
+	}
+
+	/// Gets the data we have about the peer at the provided address.
+	pub fn get_peer(&self, addr: SocketAddr) -> Result<PeerData, Error> {
+		option_to_not_found(self.db.get_ser(&peer_key(addr)[..]))
+	}
+
+	/// Bans all the peers at the provided IP address until the provided UTC
+	/// timestamp in seconds. Inbound peers connect from ports that change
+	/// every time, so bans can't be tied to a port.
+	pub fn save_ban(&self, ip: IpAddr, expiry: i64) -> Result<(), Error> {
+		self.db.put_ser(&ip_key(BAN_PREFIX, ip)[..], &IpValue(expiry))
+	}
+
+	/// When the last ban on the provided IP address lifts, or lifted, as a
+	/// UTC timestamp in seconds.
+	pub fn ban_expiry(&self, ip: IpAddr) -> Result<i64, Error> {
+		option_to_not_found(self.db.get_ser::<IpValue>(&ip_key(BAN_PREFIX, ip)[..])).map(|v| v.0)
+	}
+
+	/// Remembers the reputation of the peers at the provided IP address, so
+	/// a peer reconnecting from a new port picks it up again.
+	pub fn save_reputation(&self, ip: IpAddr, reputation: i32) -> Result<(), Error> {
+		self.db.put_ser(&ip_key(REPUTATION_PREFIX, ip)[..], &IpValue(reputation as i64))
+	}
+
+	/// The reputation the peers at the provided IP address had when we last
+	/// got disconnected from one of them.
+	pub fn get_reputation(&self, ip: IpAddr) -> Result<i32, Error> {
+		let key = ip_key(REPUTATION_PREFIX, ip);
+		option_to_not_found(self.db.get_ser::<IpValue>(&key[..])).map(|v| v.0 as i32)
+	}
+
+	/// All the peers we know of, most recently seen first. Peers whose data
+	/// can't be read are skipped.
+	pub fn all_peers(&self) -> Vec<PeerData> {
+		let iter = match self.db.iter::<PeerData>(&Key::prefix(PEER_PREFIX).build()) {
+			Ok(iter) => iter,
+			Err(_) => return vec![],
+		};
+		let mut peers = iter.filter_map(|res| match res {
+				Ok(pd) => Some(pd),
+				Err(e) => {
+					warn!(target: LOG_TARGET, "Skipping unreadable peer data: {}", e);
+					None
+				}
+			})
+			.collect::<Vec<_>>();
+		peers.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+		peers
+	}
+}
+
+fn peer_key(addr: SocketAddr) -> Vec<u8> {
+	Key::prefix(PEER_PREFIX).append_bytes(format!("{}", addr).as_bytes()).build()
+}
+
+fn ip_key(prefix: u8, ip: IpAddr) -> Vec<u8> {
+	Key::prefix(prefix).append_bytes(format!("{}", ip).as_bytes()).build()
+}
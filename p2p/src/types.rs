@@ -12,16 +12,24 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::fmt;
+use std::io;
 use std::net::{SocketAddr, IpAddr};
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::Future;
 use tokio_core::net::TcpStream;
 
+use announce::AnnounceWindow;
+use msg::{CompactBlock, PeerAddr, Type};
+use rate::RateLimiter;
 use core::core;
 use core::core::hash::Hash;
+use core::consensus::MAX_MSG_LEN;
 use core::core::target::Difficulty;
-use core::ser::Error;
+use core::ser;
+use grin_store;
 
 /// Maximum number of hashes in a block header locator request
 pub const MAX_LOCATORS: u32 = 10;
@@ -32,11 +40,348 @@ pub const MAX_BLOCK_HEADERS: u32 = 512;
 /// Maximum number of block bodies a peer should ever ask for and send
 pub const MAX_BLOCK_BODIES: u32 = 16;
 
+/// Maximum number of blocks a peer should ever ask for and send in a single
+/// bundle
+pub const MAX_BUNDLE_BLOCKS: u32 = 128;
+
+/// Maximum serialized size of a bundle of blocks, bundles stop short of the
+/// blocks that would make them larger
+pub const MAX_BUNDLE_SIZE: u64 = 8_000_000;
+
+/// Maximum number of peer addresses a peer should ever send
+pub const MAX_PEER_ADDRS: u32 = 256;
+
+/// How long ago, in seconds, a peer address relayed to us can have last been
+/// seen for us to keep it
+pub const ADDR_MAX_AGE: i64 = 3 * 3600;
+
+/// Maximum number of relayed peer addresses we keep from a single peer each
+/// minute
+pub const MAX_ADDRS_PER_MINUTE: u32 = 2 * MAX_PEER_ADDRS;
+
+/// Ban score a peer gets for each message we couldn't handle
+pub const INVALID_MSG_SCORE: u32 = 10;
+
+/// Ban score at which a peer gets disconnected and banned
+pub const MAX_BAN_SCORE: u32 = 100;
+
+/// How long, in seconds, it takes for a point of a peer's ban score to be
+/// forgiven
+pub const BAN_SCORE_DECAY: u64 = 60;
+
+/// Bounds of a peer's reputation, so neither a long-lived peer's goodwill
+/// nor a bad peer's record grow forever
+pub const MIN_REPUTATION: i32 = -100;
+pub const MAX_REPUTATION: i32 = 100;
+
+/// Reputation a peer gains for being the first to relay us a block
+pub const BLOCK_REPUTATION: i32 = 5;
+
+/// Reputation a peer loses for each message we couldn't handle, including
+/// blocks we already know are invalid
+pub const INVALID_MSG_REPUTATION: i32 = -10;
+
+/// Reputation a peer gains answering our ping within TIMELY_PONG_SECS
+pub const PONG_REPUTATION: i32 = 1;
+pub const TIMELY_PONG_SECS: u64 = 5;
+
+/// Each ping we send takes a peer's reputation 1/REPUTATION_DECAY closer to
+/// zero, only recent behavior really counts
+pub const REPUTATION_DECAY: i32 = 10;
+
+/// Why a peer got banned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BanReason {
+	/// The peer didn't follow the handshake protocol
+	BadHandshake,
+	/// The peer sent too many messages we couldn't handle or flooded us
+	BadMessages,
+	/// Banned on request of the rest of the system
+	Manual,
+}
+
+enum_from_primitive! {
+  /// Reason a peer gives for intentionally disconnecting from us.
+  #[derive(Debug, Clone, Copy, PartialEq)]
+  pub enum ByeReason {
+    /// No reason given, or one we don't know about
+    Unspecified = 0,
+    /// The peer is shutting down
+    Shutdown = 1,
+    /// The peer already has as many peers as it's allowed to
+    TooManyPeers = 2,
+  }
+}
+
+/// What became of a transaction relayed to us by a peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TxStatus {
+	/// The transaction is valid and has been accepted
+	Accepted,
+	/// The transaction doesn't fit our chain, spending an output we don't
+	/// know of or that's already spent. The peer may just see another chain
+	/// state than ours so it's dropped without holding it against the peer.
+	Dropped,
+	/// The transaction is invalid in itself, like a bad signature or range
+	/// proof, whatever the chain state
+	Invalid,
+}
+
+/// Why the connection to a peer ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisconnectReason {
+	/// The peer didn't answer in time, during the handshake or afterward
+	Timeout,
+	/// The peer sent us something that doesn't follow the protocol
+	ProtocolViolation,
+	/// The peer misbehaved enough to get banned
+	Banned,
+	/// We already had as many peers as we're allowed to
+	TooManyPeers,
+	/// The peer, or us, closed the connection. Holds the reason the peer
+	/// gave if it said bye first.
+	RemoteClosed(Option<ByeReason>),
+	/// The connection or our peer storage failed
+	IoError,
+	/// The peer is on another network, its genesis block isn't ours
+	WrongNetwork,
+	/// The peer turned out to be ourselves
+	SelfConnection,
+}
+
+impl DisconnectReason {
+	/// The reason a connection ended with the provided result, as returned
+	/// by the peer's futures.
+	pub fn from_result(res: &Result<(), Error>) -> DisconnectReason {
+		match *res {
+			Ok(()) => DisconnectReason::RemoteClosed(None),
+			Err(ref e) => DisconnectReason::from_error(e),
+		}
+	}
+
+	/// The reason a connection ended with the provided error.
+	pub fn from_error(e: &Error) -> DisconnectReason {
+		match *e {
+			Error::Timeout => DisconnectReason::Timeout,
+			Error::TooManyPeers => DisconnectReason::TooManyPeers,
+			Error::IOErr(ref e) |
+			Error::SerErr(ser::Error::IOErr(ref e)) => {
+				match e.kind() {
+					io::ErrorKind::UnexpectedEof |
+					io::ErrorKind::ConnectionReset |
+					io::ErrorKind::BrokenPipe => DisconnectReason::RemoteClosed(None),
+					io::ErrorKind::TimedOut => DisconnectReason::Timeout,
+					_ => DisconnectReason::IoError,
+				}
+			}
+			Error::StoreErr(_) |
+			Error::UnroutableAddr(_) => DisconnectReason::IoError,
+			Error::GenesisMismatch { .. } => DisconnectReason::WrongNetwork,
+			Error::SelfConnection => DisconnectReason::SelfConnection,
+			Error::SerErr(_) |
+			Error::VersionTooOld { .. } => DisconnectReason::ProtocolViolation,
+		}
+	}
+}
+
+impl fmt::Display for DisconnectReason {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let reason = match *self {
+			DisconnectReason::Timeout => "timeout",
+			DisconnectReason::ProtocolViolation => "protocol violation",
+			DisconnectReason::Banned => "banned",
+			DisconnectReason::TooManyPeers => "too many peers",
+			DisconnectReason::RemoteClosed(None) => "connection closed",
+			DisconnectReason::RemoteClosed(Some(bye)) => {
+				return write!(f, "peer said bye ({:?})", bye);
+			}
+			DisconnectReason::IoError => "i/o error",
+			DisconnectReason::WrongNetwork => "wrong network",
+			DisconnectReason::SelfConnection => "connected to ourselves",
+		};
+		write!(f, "{}", reason)
+	}
+}
+
+/// Errors that can be produced by the peer-to-peer layer.
+#[derive(Debug)]
+pub enum Error {
+	/// Wraps a serialization error when reading or writing messages
+	SerErr(ser::Error),
+	/// Wraps an io error produced by the underlying connection
+	IOErr(io::Error),
+	/// Error reading or writing our peer data storage
+	StoreErr(grin_store::Error),
+	/// The remote peer didn't answer in time
+	Timeout,
+	/// We're already connected to as many peers as we're allowed to
+	TooManyPeers,
+	/// The remote peer speaks a protocol version older than the oldest one
+	/// we support
+	VersionTooOld {
+		/// oldest version we support
+		min: u32,
+		/// the version the remote peer sent
+		peer: u32,
+	},
+	/// The remote peer has a different genesis block than ours, it's on
+	/// another chain
+	GenesisMismatch {
+		/// our genesis block hash
+		us: Hash,
+		/// the genesis block hash the remote peer sent
+		peer: Hash,
+	},
+	/// The remote peer sent us a handshake nonce of our own, we're connected
+	/// to ourselves
+	SelfConnection,
+	/// The external address we're configured to advertise can't be reached
+	/// from the internet
+	UnroutableAddr(SocketAddr),
+}
+
+impl From<ser::Error> for Error {
+	fn from(e: ser::Error) -> Error {
+		Error::SerErr(e)
+	}
+}
+
+impl From<io::Error> for Error {
+	fn from(e: io::Error) -> Error {
+		Error::IOErr(e)
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Error::SerErr(ref e) => write!(f, "serialization error: {}", e),
+			Error::IOErr(ref e) => write!(f, "connection error: {}", e),
+			Error::StoreErr(ref e) => write!(f, "peer store error: {}", e),
+			Error::Timeout => write!(f, "timeout"),
+			Error::TooManyPeers => write!(f, "too many peers"),
+			Error::VersionTooOld { min, peer } => {
+				write!(f, "peer protocol version {} is older than our minimum {}", peer, min)
+			}
+			Error::GenesisMismatch { us: ref us, peer: ref peer } => {
+				write!(f, "genesis mismatch, ours is {} but peer has {}", us, peer)
+			}
+			Error::SelfConnection => write!(f, "connected to ourselves"),
+			Error::UnroutableAddr(addr) => write!(f, "address {} isn't routable", addr),
+		}
+	}
+}
+
+/// Average number of messages per second, and burst over that average, a
+/// peer can send us for each category of messages.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MsgRateLimit {
+	pub rate: u32,
+	pub burst: u32,
+}
+
 /// Configuration for the peer-to-peer server.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct P2PConfig {
 	pub host: IpAddr,
 	pub port: u16,
+	/// Maximum number of recent block and transaction announcements we keep
+	/// track of
+	pub announce_window: usize,
+	/// How long, in seconds, an announcement is remembered
+	pub announce_ttl: u64,
+	/// How long, in seconds, we refrain from relaying a block or transaction
+	/// again after relaying it once
+	pub relay_ttl: u64,
+	/// Rate of messages of each category we accept from a peer, the ones
+	/// over it get dropped
+	pub msg_rate_limit: MsgRateLimit,
+	/// How long, in seconds, it takes for a point of a peer's ban score to
+	/// be forgiven
+	pub ban_score_decay: u64,
+	/// How long, in seconds, a misbehaving peer stays banned
+	pub ban_window: i64,
+	/// How long ago, in seconds, a peer address relayed to us can have last
+	/// been seen for us to keep it
+	pub addr_max_age: i64,
+	/// Maximum number of relayed peer addresses we keep from a single peer
+	/// each minute, the ones over it get dropped
+	pub max_addrs_per_minute: u32,
+	/// Interval, in seconds, at which we ping our peers
+	pub ping_interval: u64,
+	/// How long, in seconds, a peer can stay silent before we drop it
+	pub ping_timeout: u64,
+	/// Maximum number of peers we stay connected to, inbound and outbound
+	pub max_peers: u32,
+	/// Maximum number of peers that connected to us we accept, should be
+	/// lower than max_peers to always leave room for outbound connections
+	pub max_inbound: u32,
+	/// Maximum number of peers in the same network group we connect to, a
+	/// group being a /16 for IPv4 and a /32 for IPv6
+	pub max_group_peers: u32,
+	/// Longest we wait, in seconds, before trying to reconnect to a peer
+	pub max_reconnect_delay: u64,
+	/// Consecutive failed reconnections after which we give up on a peer
+	pub max_reconnect_failures: u32,
+	/// How long, in seconds, a peer has to complete the handshake
+	pub handshake_timeout: u64,
+	/// Largest message we accept from a peer, in bytes. Peers declaring a
+	/// larger message get disconnected.
+	pub max_msg_len: u64,
+	/// How long, in seconds, a peer can stall in the middle of sending us a
+	/// message before we drop it
+	pub read_timeout: u64,
+	/// How long, in seconds, a peer can stall in the middle of receiving a
+	/// message from us before we drop it
+	pub write_timeout: u64,
+	/// Size in bytes above which message bodies get compressed, for peers
+	/// that support it
+	pub compress_threshold: u64,
+	/// Whether to also accept IPv4 connections when listening on an IPv6
+	/// host
+	pub dual_stack: bool,
+	/// Hostnames resolving to the addresses of peers to bootstrap from,
+	/// optionally with a port
+	pub dns_seeds: Vec<String>,
+	/// Address to listen on, instead of host and port
+	pub p2p_listen_addr: Option<SocketAddr>,
+	/// Address our peers can reach us at, advertised in handshakes instead
+	/// of the one we listen on. Needs to be routable, mostly useful behind
+	/// NAT.
+	pub p2p_external_addr: Option<SocketAddr>,
+}
+
+impl P2PConfig {
+	/// Address the server listens on.
+	pub fn listen_addr(&self) -> SocketAddr {
+		self.p2p_listen_addr.unwrap_or(SocketAddr::new(self.host, self.port))
+	}
+
+	/// Address advertised to our peers, our listening one unless an external
+	/// address is set.
+	pub fn external_addr(&self) -> SocketAddr {
+		self.p2p_external_addr.unwrap_or(self.listen_addr())
+	}
+}
+
+/// Whether the address can be reached from the internet, excluding local,
+/// private and otherwise reserved addresses, as well as port 0.
+pub fn is_routable(addr: &SocketAddr) -> bool {
+	if addr.port() == 0 {
+		return false;
+	}
+	match addr.ip() {
+		IpAddr::V4(ip) => {
+			!(ip.is_unspecified() || ip.is_loopback() || ip.is_private() || ip.is_link_local() ||
+			  ip.is_broadcast() || ip.is_documentation() || ip.is_multicast())
+		}
+		IpAddr::V6(ip) => {
+			// unique local fc00::/7 and link local fe80::/10
+			let first = ip.segments()[0];
+			!(ip.is_unspecified() || ip.is_loopback() || ip.is_multicast() ||
+			  first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80)
+		}
+	}
 }
 
 /// Default address for peer-to-peer connections.
@@ -46,6 +391,33 @@ impl Default for P2PConfig {
 		P2PConfig {
 			host: ipaddr,
 			port: 13414,
+			announce_window: 512,
+			announce_ttl: 60,
+			relay_ttl: 60,
+			msg_rate_limit: MsgRateLimit {
+				rate: 100,
+				burst: 200,
+			},
+			ban_score_decay: BAN_SCORE_DECAY,
+			ban_window: 10800,
+			addr_max_age: ADDR_MAX_AGE,
+			max_addrs_per_minute: MAX_ADDRS_PER_MINUTE,
+			ping_interval: 10,
+			ping_timeout: 30,
+			max_peers: 32,
+			max_inbound: 24,
+			max_group_peers: 2,
+			max_reconnect_delay: 300,
+			max_reconnect_failures: 8,
+			handshake_timeout: 5,
+			max_msg_len: MAX_MSG_LEN,
+			read_timeout: 30,
+			write_timeout: 30,
+			compress_threshold: 1024,
+			dual_stack: false,
+			dns_seeds: vec![],
+			p2p_listen_addr: None,
+			p2p_external_addr: None,
 		}
 	}
 }
@@ -57,17 +429,71 @@ bitflags! {
     const UNKNOWN = 0b00000000,
     /// Runs with the easier version of the Proof of Work, mostly to make testing easier.
     const FULL_SYNC = 0b00000001,
+    /// Can compress and decompress large message bodies.
+    const COMPRESSION = 0b00000010,
+    /// Can encrypt the connection once the handshake is done.
+    const ENCRYPTION = 0b00000100,
+    /// Can send contiguous ranges of blocks in a single message.
+    const BLOCK_BUNDLES = 0b00001000,
+    /// Can rebuild blocks sent in their compact form when asking for them.
+    const COMPACT_BLOCKS = 0b00010000,
   }
 }
 
+/// Who initiated the connection with a peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+	/// The peer connected to us
+	Inbound,
+	/// We connected to the peer
+	Outbound,
+}
+
 /// General information about a connected peer that's useful to other modules.
 #[derive(Debug)]
 pub struct PeerInfo {
+	/// capabilities both us and the peer support
 	pub capabilities: Capabilities,
 	pub user_agent: String,
+	/// protocol version negotiated with the peer
 	pub version: u32,
 	pub addr: SocketAddr,
+	pub genesis: Hash,
+	/// total difficulty of the peer at handshake time, see
+	/// `Peer::total_difficulty` for its latest one
 	pub total_difficulty: Difficulty,
+	/// height of the peer chain at handshake time, see `Peer::height` for
+	/// its latest one
+	pub height: u64,
+	pub direction: Direction,
+	/// address the peer says it can be reached at, only sent by the peers
+	/// connecting to us
+	pub advertised_addr: Option<SocketAddr>,
+}
+
+/// Traffic exchanged with a peer since we connected to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStats {
+	/// Bytes sent to the peer
+	pub sent_bytes: u64,
+	/// Bytes received from the peer
+	pub received_bytes: u64,
+	/// Number of messages sent to the peer, indexed by message type
+	pub sent_msgs: Vec<u64>,
+	/// Number of messages received from the peer, indexed by message type
+	pub received_msgs: Vec<u64>,
+}
+
+impl PeerStats {
+	/// Number of messages of the provided type sent to the peer.
+	pub fn sent(&self, t: Type) -> u64 {
+		self.sent_msgs[t as usize]
+	}
+
+	/// Number of messages of the provided type received from the peer.
+	pub fn received(&self, t: Type) -> u64 {
+		self.received_msgs[t as usize]
+	}
 }
 
 /// A given communication protocol agreed upon between 2 peers (usually
@@ -80,15 +506,27 @@ pub trait Protocol {
 	/// only once.
 	fn handle(&self,
 	          conn: TcpStream,
-	          na: Arc<NetAdapter>)
+	          na: Arc<NetAdapter>,
+	          announces: Arc<AnnounceWindow>,
+	          limiter: RateLimiter)
 	          -> Box<Future<Item = (), Error = Error>>;
 
-	/// Sends a ping message to the remote peer.
-	fn send_ping(&self) -> Result<(), Error>;
+	/// Sends a ping message to the remote peer, along with our total
+	/// difficulty and height.
+	fn send_ping(&self, total_difficulty: Difficulty, height: u64) -> Result<(), Error>;
 
 	/// Relays a block to the remote peer.
 	fn send_block(&self, b: &core::Block) -> Result<(), Error>;
 
+	/// Announces the hash of a block to the remote peer, unless it's known
+	/// to have the block already. The peer asks for the block if it needs
+	/// it.
+	fn send_inv(&self, h: Hash) -> Result<(), Error>;
+
+	/// Relays a block to the remote peer in its compact form, leaving it to
+	/// rebuild it from the transactions it already has.
+	fn send_compact_block(&self, cb: &CompactBlock) -> Result<(), Error>;
+
 	/// Relays a transaction to the remote peer.
 	fn send_transaction(&self, tx: &core::Transaction) -> Result<(), Error>;
 
@@ -98,10 +536,50 @@ pub trait Protocol {
 	/// Sends a request for a block from its hash.
 	fn send_block_request(&self, h: Hash) -> Result<(), Error>;
 
+	/// Sends a request for up to count blocks of the remote peer's chain,
+	/// starting with the block with the provided hash. Only peers with the
+	/// BLOCK_BUNDLES capability understand it.
+	fn send_block_bundle_request(&self, start: Hash, count: u32) -> Result<(), Error>;
+
+	/// Asks the remote peer for addresses of other peers it knows of that
+	/// have the provided capabilities.
+	fn send_peer_request(&self, capab: Capabilities) -> Result<(), Error>;
+
 	/// How many bytes have been sent/received to/from the remote peer.
 	fn transmitted_bytes(&self) -> (u64, u64);
 
-	/// Close the connection to the remote peer.
+	/// Bytes and messages exchanged with the remote peer.
+	fn stats(&self) -> PeerStats;
+
+	/// Ban score the remote peer accumulated by misbehaving.
+	fn ban_score(&self) -> u32;
+
+	/// Total difficulty the remote peer last advertised.
+	fn total_difficulty(&self) -> Difficulty;
+
+	/// Highest plausible height the remote peer advertised.
+	fn height(&self) -> u64;
+
+	/// Whether the remote peer advertised a height its total difficulty
+	/// can't account for.
+	fn is_suspicious(&self) -> bool;
+
+	/// The reason the remote peer gave when saying bye, if it did.
+	fn remote_bye(&self) -> Option<ByeReason>;
+
+	/// Smoothed round-trip time of our pings to the remote peer, once it
+	/// answered one.
+	fn latency(&self) -> Option<Duration>;
+
+	/// How well the remote peer behaved, between MIN_REPUTATION and
+	/// MAX_REPUTATION.
+	fn reputation(&self) -> i32;
+
+	/// Raises or lowers the reputation of the remote peer, staying within
+	/// bounds.
+	fn adjust_reputation(&self, delta: i32);
+
+	/// Close the connection to the remote peer, saying bye first.
 	fn close(&self);
 }
 
@@ -112,16 +590,25 @@ pub trait NetAdapter: Sync + Send {
 	/// Current height of our chain.
 	fn total_difficulty(&self) -> Difficulty;
 
-	/// A valid transaction has been received from one of our peers
-	fn transaction_received(&self, tx: core::Transaction);
+	/// Height of our chain, advertised to our peers along with our total
+	/// difficulty.
+	fn height(&self) -> u64 {
+		0
+	}
+
+	/// A transaction has been received from one of our peers. Returns what
+	/// became of it, only invalid ones counting against the peer's ban score.
+	fn transaction_received(&self, tx: core::Transaction) -> TxStatus;
 
-	/// A block has been received from one of our peers
-	fn block_received(&self, b: core::Block);
+	/// A block has been received from one of our peers. Returns whether the
+	/// peer was right sending it, invalid blocks and forks too deep for us to
+	/// follow counting against the peer's ban score.
+	fn block_received(&self, b: core::Block) -> bool;
 
 	/// A set of block header has been received, typically in response to a
-	/// block
-	/// header request.
-	fn headers_received(&self, bh: Vec<core::BlockHeader>);
+	/// block header request. Returns whether they're valid, invalid ones
+	/// counting against the peer's ban score.
+	fn headers_received(&self, bh: Vec<core::BlockHeader>) -> bool;
 
 	/// Finds a list of block headers based on the provided locator. Tries to
 	/// identify the common chain and gets the headers that follow it
@@ -130,4 +617,42 @@ pub trait NetAdapter: Sync + Send {
 
 	/// Gets a full block by its hash.
 	fn get_block(&self, h: Hash) -> Option<core::Block>;
+
+	/// Gets the compact version of a block by its hash, only the ids of the
+	/// transactions our peers most likely already have going along with it.
+	fn get_compact_block(&self, h: Hash) -> Option<CompactBlock> {
+		None
+	}
+
+	/// Gets up to count blocks of our chain, in order, starting with the
+	/// block with the provided hash. Stops at the first block we don't have
+	/// the full data of.
+	fn get_blocks(&self, start: Hash, count: u32) -> Vec<core::Block> {
+		self.get_block(start).into_iter().collect()
+	}
+
+	/// Gets the header of a block we don't have the full data of anymore,
+	/// sent instead of the block when asked for it.
+	fn get_pruned_header(&self, h: Hash) -> Option<core::BlockHeader> {
+		None
+	}
+
+	/// Whether the block with the provided hash is one we recently found
+	/// invalid. Peers sending it again get penalized without the block going
+	/// through validation.
+	fn is_bad_block(&self, h: &Hash) -> bool {
+		false
+	}
+
+	/// Gets a transaction we've recently seen by its short id, to rebuild
+	/// compact blocks.
+	fn get_transaction(&self, id: u64) -> Option<core::Transaction>;
+
+	/// Finds the addresses of healthy peers we know of with the provided
+	/// capabilities, along with when we last saw them.
+	fn find_peer_addrs(&self, capab: Capabilities) -> Vec<PeerAddr>;
+
+	/// A list of peer addresses has been received from one of our peers,
+	/// only the fresh and routable ones within the peer's allowance.
+	fn peer_addrs_received(&self, addrs: Vec<PeerAddr>);
 }
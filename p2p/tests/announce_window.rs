@@ -0,0 +1,65 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+
+use std::net::SocketAddr;
+use std::thread;
+use std::time;
+
+use core::core::hash::Hash;
+use p2p::AnnounceWindow;
+
+// Replays the same block announcement from several peers, only the first one
+// should be worth processing and none of the announcers should get it back.
+#[test]
+fn duplicate_announcements() {
+  let window = AnnounceWindow::new(10, 60);
+  let h = Hash([1; 32]);
+  let peer1: SocketAddr = "127.0.0.1:10001".parse().unwrap();
+  let peer2: SocketAddr = "127.0.0.1:10002".parse().unwrap();
+  let peer3: SocketAddr = "127.0.0.1:10003".parse().unwrap();
+
+  assert!(window.announced(h, peer1));
+  assert!(!window.announced(h, peer2));
+  assert!(!window.announced(h, peer1));
+
+  assert!(window.announced_by(&h, &peer1));
+  assert!(window.announced_by(&h, &peer2));
+  assert!(!window.announced_by(&h, &peer3));
+
+  // a different block is a fresh announcement
+  assert!(window.announced(Hash([2; 32]), peer2));
+}
+
+// Announcements are forgotten once they go past the TTL or get evicted by
+// newer ones when the window is full.
+#[test]
+fn announcements_expire() {
+  let peer: SocketAddr = "127.0.0.1:10001".parse().unwrap();
+
+  let window = AnnounceWindow::new(10, 1);
+  assert!(window.announced(Hash([1; 32]), peer));
+  thread::sleep(time::Duration::from_millis(1100));
+  assert!(!window.announced_by(&Hash([1; 32]), &peer));
+  assert!(window.announced(Hash([1; 32]), peer));
+
+  let window = AnnounceWindow::new(2, 60);
+  for n in 1..4 {
+    assert!(window.announced(Hash([n; 32]), peer));
+  }
+  assert!(!window.announced_by(&Hash([1; 32]), &peer));
+  assert!(window.announced_by(&Hash([3; 32]), &peer));
+}
@@ -0,0 +1,170 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use futures::future::Future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::{Block, BlockHeader, Transaction};
+use core::core::hash::{Hash, Hashed, ZERO_HASH};
+use core::core::target::Difficulty;
+use p2p::{NetAdapter, Peer, Type};
+
+// Adapter serving a chain of blocks, keeps track of the blocks it gets.
+struct ChainAdapter {
+  chain: Vec<Block>,
+  received: Mutex<Vec<Hash>>,
+}
+
+impl ChainAdapter {
+  fn new(chain: Vec<Block>) -> Arc<ChainAdapter> {
+    Arc::new(ChainAdapter {
+      chain: chain,
+      received: Mutex::new(vec![]),
+    })
+  }
+}
+
+impl NetAdapter for ChainAdapter {
+  fn total_difficulty(&self) -> Difficulty {
+    Difficulty::one()
+  }
+  fn transaction_received(&self, _: Transaction) -> p2p::TxStatus {
+    p2p::TxStatus::Accepted
+  }
+  fn block_received(&self, b: Block) -> bool {
+    self.received.lock().unwrap().push(b.hash());
+    true
+  }
+  fn headers_received(&self, _: Vec<BlockHeader>) -> bool {
+    true
+  }
+  fn locate_headers(&self, _: Vec<Hash>) -> Vec<BlockHeader> {
+    vec![]
+  }
+  fn get_block(&self, h: Hash) -> Option<Block> {
+    self.get_blocks(h, 1).pop()
+  }
+  fn get_blocks(&self, start: Hash, count: u32) -> Vec<Block> {
+    self.chain
+      .iter()
+      .skip_while(|b| b.hash() != start)
+      .take(count as usize)
+      .map(|b| Block { header: b.header.clone(), ..Block::default() })
+      .collect()
+  }
+  fn get_transaction(&self, _: u64) -> Option<Transaction> {
+    None
+  }
+  fn find_peer_addrs(&self, _: p2p::Capabilities) -> Vec<p2p::PeerAddr> {
+    vec![]
+  }
+  fn peer_addrs_received(&self, _: Vec<p2p::PeerAddr>) {}
+}
+
+// Blocks following each other, from height 1.
+fn chain(len: u64) -> Vec<Block> {
+  let mut chain: Vec<Block> = vec![];
+  for height in 1..(len + 1) {
+    let mut b = Block::default();
+    b.header.height = height;
+    b.header.previous = chain.last().map(|prev| prev.hash()).unwrap_or(ZERO_HASH);
+    chain.push(b);
+  }
+  chain
+}
+
+// A peer asks the server for 50 blocks in the middle of its chain and gets
+// them all in a single message, in the order of the chain.
+#[test]
+fn block_bundle_in_order() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let blocks = chain(60);
+  let expected = blocks[5..55].iter().map(|b| b.hash()).collect::<Vec<_>>();
+  let start = expected[0];
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13454;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server = Arc::new(p2p::Server::new("target/p2p-block-bundle".to_string(),
+                                         p2p_conf,
+                                         ChainAdapter::new(blocks),
+                                         ZERO_HASH)
+    .unwrap());
+  let run_server = server.start(handle.clone());
+
+  let adapter = ChainAdapter::new(vec![]);
+  let a = adapter.clone();
+
+  let h = handle.clone();
+  let s = server.clone();
+  let client = wait(&handle, 500)
+    .and_then(move |_| connect(addr, h.clone(), a).map(move |peer| (peer, h)))
+    .and_then(move |(peer, h)| {
+      assert!(peer.info.capabilities.contains(p2p::BLOCK_BUNDLES));
+      peer.send_block_bundle_request(start, 50).unwrap();
+      wait(&h, 500).map(move |_| (peer, s))
+    })
+    .and_then(|(peer, s)| {
+      let stats = peer.stats();
+      assert_eq!(stats.sent(Type::GetBlockBundle), 1);
+      assert_eq!(stats.received(Type::BlockBundle), 1);
+      assert_eq!(stats.received(Type::Block), 0);
+      s.stop();
+      Ok(())
+    });
+  handle.spawn(client.map_err(|e| panic!("Client failed: {}", e)));
+
+  evtlp.run(run_server).unwrap();
+  assert_eq!(*adapter.received.lock().unwrap(), expected);
+}
+
+fn wait(h: &reactor::Handle, ms: u64) -> Box<Future<Item = (), Error = p2p::Error>> {
+  Box::new(reactor::Timeout::new(time::Duration::from_millis(ms), h)
+    .unwrap()
+    .map_err(|e| p2p::Error::IOErr(e)))
+}
+
+// Handshakes with the server and keeps the client peer running with the
+// provided adapter.
+fn connect(addr: SocketAddr,
+           h: reactor::Handle,
+           adapter: Arc<ChainAdapter>)
+           -> Box<Future<Item = Arc<Peer>, Error = p2p::Error>> {
+  let socket = TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e));
+  Box::new(socket.and_then(move |socket| {
+      Peer::connect(socket,
+                    Difficulty::one(),
+                    0,
+                    &p2p::handshake::Handshake::new(ZERO_HASH))
+    })
+    .and_then(move |(socket, peer)| {
+      let peer = Arc::new(peer);
+      let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+      let limiter = p2p::RateLimiter::new(100, 100);
+      h.spawn(peer.run(socket, adapter, announces, limiter).map_err(|_| ()));
+      wait(&h, 200).map(move |_| peer)
+    }))
+}
@@ -0,0 +1,269 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use futures::future::Future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::{Block, BlockHeader, Transaction};
+use core::core::hash::{Hash, Hashed, ZERO_HASH};
+use core::core::target::Difficulty;
+use core::ser;
+use p2p::{NetAdapter, Peer, Type};
+
+// Adapter optionally serving a block, keeps track of the blocks it gets.
+struct BlockAdapter {
+  block: Option<Vec<u8>>,
+  received: Mutex<Vec<Hash>>,
+  refuses: bool,
+}
+
+impl BlockAdapter {
+  fn new(block: Option<&Block>) -> Arc<BlockAdapter> {
+    Arc::new(BlockAdapter {
+      block: block.map(|b| ser::ser_vec(b).unwrap()),
+      received: Mutex::new(vec![]),
+      refuses: false,
+    })
+  }
+
+  // Serves nothing and refuses all the blocks it gets, like a chain they
+  // don't fit.
+  fn refusing() -> Arc<BlockAdapter> {
+    Arc::new(BlockAdapter {
+      block: None,
+      received: Mutex::new(vec![]),
+      refuses: true,
+    })
+  }
+}
+
+impl NetAdapter for BlockAdapter {
+  fn total_difficulty(&self) -> Difficulty {
+    Difficulty::one()
+  }
+  fn transaction_received(&self, _: Transaction) -> p2p::TxStatus {
+    p2p::TxStatus::Accepted
+  }
+  fn block_received(&self, b: Block) -> bool {
+    self.received.lock().unwrap().push(b.hash());
+    !self.refuses
+  }
+  fn headers_received(&self, _: Vec<BlockHeader>) -> bool {
+    true
+  }
+  fn locate_headers(&self, _: Vec<Hash>) -> Vec<BlockHeader> {
+    vec![]
+  }
+  fn get_block(&self, _: Hash) -> Option<Block> {
+    self.block.as_ref().map(|data| ser::deserialize::<Block>(&mut &data[..]).unwrap())
+  }
+  fn get_transaction(&self, _: u64) -> Option<Transaction> {
+    None
+  }
+  fn find_peer_addrs(&self, _: p2p::Capabilities) -> Vec<p2p::PeerAddr> {
+    vec![]
+  }
+  fn peer_addrs_received(&self, _: Vec<p2p::PeerAddr>) {}
+}
+
+// The server announces a block to two peers, only the one that doesn't
+// already know of it should get the inventory and ask for the full block.
+// Announcing again doesn't send anything.
+#[test]
+fn inv_skips_peers_knowing_block() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut b = Block::default();
+  b.header.height = 1;
+  let bh = b.hash();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13444;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server = Arc::new(p2p::Server::new("target/p2p-block-inv".to_string(),
+                                         p2p_conf,
+                                         BlockAdapter::new(Some(&b)),
+                                         ZERO_HASH)
+    .unwrap());
+  let run_server = server.start(handle.clone());
+
+  let knowing_adapter = BlockAdapter::new(None);
+  let missing_adapter = BlockAdapter::new(None);
+  let (ka, ma) = (knowing_adapter.clone(), missing_adapter.clone());
+
+  let h = handle.clone();
+  let s = server.clone();
+  let client = wait(&handle, 500)
+    .and_then(move |_| connect(addr, h.clone(), ka).map(move |knowing| (knowing, h)))
+    .and_then(move |(knowing, h)| {
+      connect(addr, h.clone(), ma).map(move |missing| (knowing, missing, h))
+    })
+    .and_then(move |(knowing, missing, h)| {
+      // the first peer lets the server know it has the block
+      knowing.send_inv(bh).unwrap();
+      wait(&h, 300).map(move |_| (knowing, missing, h, s))
+    })
+    .and_then(move |(knowing, missing, h, s)| {
+      s.announce_block(bh);
+      s.announce_block(bh);
+      wait(&h, 500).map(move |_| (knowing, missing, s))
+    })
+    .and_then(|(knowing, missing, s)| {
+      let (knowing, missing) = (knowing.stats(), missing.stats());
+      assert_eq!(knowing.received(Type::Inv), 0);
+      assert_eq!(knowing.received(Type::Block), 0);
+      assert_eq!(missing.received(Type::Inv), 1);
+      assert_eq!(missing.sent(Type::GetData), 1);
+      assert_eq!(missing.received(Type::Block), 1);
+      s.stop();
+      Ok(())
+    });
+  handle.spawn(client.map_err(|e| panic!("Client failed: {}", e)));
+
+  evtlp.run(run_server).unwrap();
+  assert!(knowing_adapter.received.lock().unwrap().is_empty());
+  assert_eq!(*missing_adapter.received.lock().unwrap(), vec![bh]);
+}
+
+// A block we ask for comes back and gets processed, even when another peer
+// already pushed it to us within the announce window.
+#[test]
+fn requested_block_not_deduplicated() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut b = Block::default();
+  b.header.height = 1;
+  let bh = b.hash();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13459;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server = Arc::new(p2p::Server::new("target/p2p-block-requested".to_string(),
+                                         p2p_conf,
+                                         BlockAdapter::new(Some(&b)),
+                                         ZERO_HASH)
+    .unwrap());
+  let run_server = server.start(handle.clone());
+
+  let adapter = BlockAdapter::new(None);
+  let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+  assert!(announces.announced(bh, "127.0.0.1:10001".parse().unwrap()));
+
+  let (a, h, s) = (adapter.clone(), handle.clone(), server.clone());
+  let client = wait(&handle, 500)
+    .and_then(move |_| connect_with(addr, h.clone(), a, announces).map(move |peer| (peer, h)))
+    .and_then(move |(peer, h)| {
+      peer.send_block_request(bh).unwrap();
+      wait(&h, 500).map(move |_| peer)
+    })
+    .and_then(move |peer| {
+      assert_eq!(peer.stats().received(Type::Block), 1);
+      s.stop();
+      Ok(())
+    });
+  handle.spawn(client.map_err(|e| panic!("Client failed: {}", e)));
+
+  evtlp.run(run_server).unwrap();
+  assert_eq!(*adapter.received.lock().unwrap(), vec![bh]);
+}
+
+// A block our chain refuses, invalid or on a fork too deep to follow, counts
+// against the peer that sent it.
+#[test]
+fn refused_block_penalized() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut b = Block::default();
+  b.header.height = 1;
+  let bh = b.hash();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13461;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server = Arc::new(p2p::Server::new("target/p2p-block-refused".to_string(),
+                                         p2p_conf,
+                                         BlockAdapter::new(Some(&b)),
+                                         ZERO_HASH)
+    .unwrap());
+  let run_server = server.start(handle.clone());
+
+  let adapter = BlockAdapter::refusing();
+  let (a, h, s) = (adapter.clone(), handle.clone(), server.clone());
+  let client = wait(&handle, 500)
+    .and_then(move |_| connect(addr, h.clone(), a).map(move |peer| (peer, h)))
+    .and_then(move |(peer, h)| {
+      assert_eq!(peer.ban_score(), 0);
+      peer.send_block_request(bh).unwrap();
+      wait(&h, 500).map(move |_| peer)
+    })
+    .and_then(move |peer| {
+      assert_eq!(peer.stats().received(Type::Block), 1);
+      assert!(peer.ban_score() > 0);
+      s.stop();
+      Ok(())
+    });
+  handle.spawn(client.map_err(|e| panic!("Client failed: {}", e)));
+
+  evtlp.run(run_server).unwrap();
+  assert_eq!(*adapter.received.lock().unwrap(), vec![bh]);
+}
+
+fn wait(h: &reactor::Handle, ms: u64) -> Box<Future<Item = (), Error = p2p::Error>> {
+  Box::new(reactor::Timeout::new(time::Duration::from_millis(ms), h)
+    .unwrap()
+    .map_err(|e| p2p::Error::IOErr(e)))
+}
+
+// Handshakes with the server and keeps the client peer running with the
+// provided adapter.
+fn connect(addr: SocketAddr,
+           h: reactor::Handle,
+           adapter: Arc<BlockAdapter>)
+           -> Box<Future<Item = Arc<Peer>, Error = p2p::Error>> {
+  connect_with(addr, h, adapter, Arc::new(p2p::AnnounceWindow::new(10, 10)))
+}
+
+// Same as connect, with the provided announce window.
+fn connect_with(addr: SocketAddr,
+                h: reactor::Handle,
+                adapter: Arc<BlockAdapter>,
+                announces: Arc<p2p::AnnounceWindow>)
+                -> Box<Future<Item = Arc<Peer>, Error = p2p::Error>> {
+  let socket = TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e));
+  Box::new(socket.and_then(move |socket| {
+      Peer::connect(socket,
+                    Difficulty::one(),
+                    0,
+                    &p2p::handshake::Handshake::new(ZERO_HASH))
+    })
+    .and_then(move |(socket, peer)| {
+      let peer = Arc::new(peer);
+      let limiter = p2p::RateLimiter::new(100, 100);
+      h.spawn(peer.run(socket, adapter, announces, limiter).map_err(|_| ()));
+      wait(&h, 200).map(move |_| peer)
+    }))
+}
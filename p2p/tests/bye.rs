@@ -0,0 +1,110 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time;
+
+use futures::future::Future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::hash::ZERO_HASH;
+use core::core::target::Difficulty;
+use p2p::{ByeReason, DisconnectReason, Peer};
+
+// A client connects and leaves saying bye, the server should drop it without
+// holding it against the peer. Stopping the server afterward says bye to the
+// remaining client.
+#[test]
+fn peer_says_bye() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13441;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server = Arc::new(p2p::Server::new("target/p2p-bye".to_string(),
+                                         p2p_conf,
+                                         Arc::new(p2p::DummyAdapter {}),
+                                         ZERO_HASH)
+    .unwrap());
+  let run_server = server.start(handle.clone());
+
+  let h = handle.clone();
+  let s = server.clone();
+  let client = wait(&handle, 500)
+    .and_then(move |_| connect(addr, h.clone()).map(move |leaving| (leaving, h)))
+    .and_then(move |((leaving, local), h)| {
+      assert_eq!(s.peer_count(), 1);
+      let remote = s.connected_peers()[0].clone();
+
+      leaving.stop();
+      wait(&h, 500).map(move |_| (remote, local, s, h))
+    })
+    .and_then(|(remote, local, s, h)| {
+      assert_eq!(s.peer_count(), 0);
+      assert_eq!(remote.remote_bye(), Some(ByeReason::Shutdown));
+      assert_eq!(remote.ban_score(), 0);
+      assert!(!s.is_banned(local));
+      assert_eq!(s.recent_disconnects(),
+                 vec![(local, DisconnectReason::RemoteClosed(Some(ByeReason::Shutdown)))]);
+
+      connect(addr, h.clone()).map(move |(staying, _)| (staying, s))
+    })
+    .and_then(|(staying, s)| {
+      s.stop();
+      Ok(staying)
+    });
+
+  let staying = evtlp.run(run_server.join(client)).unwrap().1;
+
+  // give the remaining client the time to hear from the server
+  evtlp.run(wait(&handle, 500)).unwrap();
+  assert_eq!(staying.remote_bye(), Some(ByeReason::Shutdown));
+}
+
+fn wait(h: &reactor::Handle, ms: u64) -> Box<Future<Item = (), Error = p2p::Error>> {
+  Box::new(reactor::Timeout::new(time::Duration::from_millis(ms), h)
+    .unwrap()
+    .map_err(|e| p2p::Error::IOErr(e)))
+}
+
+// Handshakes with the server and gives it a moment to register the peer.
+// Returns the client peer, kept running, and its local address.
+fn connect(addr: SocketAddr,
+           h: reactor::Handle)
+           -> Box<Future<Item = (Arc<Peer>, SocketAddr), Error = p2p::Error>> {
+  let socket = TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e));
+  Box::new(socket.and_then(move |socket| {
+      Peer::connect(socket,
+                    Difficulty::one(),
+                    0,
+                    &p2p::handshake::Handshake::new(ZERO_HASH))
+    })
+    .and_then(move |(socket, peer)| {
+      let local = socket.local_addr().unwrap();
+      let peer = Arc::new(peer);
+      let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+      let limiter = p2p::RateLimiter::new(100, 100);
+      h.spawn(peer.run(socket, Arc::new(p2p::DummyAdapter {}), announces, limiter)
+        .map_err(|_| ()));
+      wait(&h, 200).map(move |_| (peer, local))
+    }))
+}
@@ -0,0 +1,307 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate rand;
+extern crate secp256k1zkp as secp;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use futures::future::Future;
+use rand::os::OsRng;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::{Block, BlockHeader, Input, Output, Transaction};
+use core::core::hash::{Hash, ZERO_HASH};
+use core::core::target::Difficulty;
+use core::ser;
+use p2p::{CompactBlock, NetAdapter, Peer, PeerStats, Type, FULL_SYNC, PROTOCOL_VERSION};
+use secp::key::SecretKey;
+
+// Adapter knowing of a fixed set of transactions and optionally serving a
+// full block, keeps track of the blocks it gets.
+struct BlockAdapter {
+  txs: Vec<Transaction>,
+  block: Option<Vec<u8>>,
+  received: Mutex<Vec<Hash>>,
+}
+
+impl NetAdapter for BlockAdapter {
+  fn total_difficulty(&self) -> Difficulty {
+    Difficulty::one()
+  }
+  fn transaction_received(&self, _: Transaction) -> p2p::TxStatus {
+    p2p::TxStatus::Accepted
+  }
+  fn block_received(&self, b: Block) -> bool {
+    self.received.lock().unwrap().push(b.hash());
+    true
+  }
+  fn headers_received(&self, _: Vec<BlockHeader>) -> bool {
+    true
+  }
+  fn locate_headers(&self, _: Vec<Hash>) -> Vec<BlockHeader> {
+    vec![]
+  }
+  fn get_block(&self, _: Hash) -> Option<Block> {
+    self.block.as_ref().map(|data| ser::deserialize::<Block>(&mut &data[..]).unwrap())
+  }
+  fn get_compact_block(&self, h: Hash) -> Option<CompactBlock> {
+    self.get_block(h).map(|b| CompactBlock::new(&b, &self.txs))
+  }
+  fn get_transaction(&self, id: u64) -> Option<Transaction> {
+    self.txs.iter().find(|tx| p2p::short_id(tx) == id).cloned()
+  }
+  fn find_peer_addrs(&self, _: p2p::Capabilities) -> Vec<p2p::PeerAddr> {
+    vec![]
+  }
+  fn peer_addrs_received(&self, _: Vec<p2p::PeerAddr>) {}
+}
+
+// A block with 2 transactions, along with the transactions.
+fn block_with_txs() -> (Block, Vec<Transaction>) {
+  let mut rng = OsRng::new().unwrap();
+  let secp = secp::Secp256k1::with_caps(secp::ContextFlag::Commit);
+  let mut txs = (0..2)
+    .map(|n| {
+      Transaction::new(vec![Input::OvertInput {
+                              output: ZERO_HASH,
+                              value: 5 + n,
+                              blindkey: SecretKey::new(&secp, &mut rng),
+                            }],
+                       vec![Output::OvertOutput {
+                              value: 4 + n,
+                              blindkey: SecretKey::new(&secp, &mut rng),
+                            }],
+                       1)
+        .blind(&secp)
+        .unwrap()
+    })
+    .collect::<Vec<_>>();
+  let reward_key = SecretKey::new(&secp, &mut rng);
+  let prev = core::genesis::genesis().header;
+  let b = Block::new(&prev, txs.iter_mut().collect(), reward_key).unwrap();
+  (b, txs)
+}
+
+#[test]
+fn reconstruct_from_local_txs() {
+  let (b, txs) = block_with_txs();
+  let cb = CompactBlock::new(&b, &txs);
+  assert_eq!(cb.tx_ids.len(), 2);
+  assert_eq!(cb.inputs.len(), 0);
+  // only the reward output isn't in a transaction
+  assert_eq!(cb.outputs.len(), 1);
+
+  // goes over the wire without losing anything
+  let data = ser::ser_vec(&cb).unwrap();
+  let cb = ser::deserialize::<CompactBlock>(&mut &data[..]).unwrap();
+
+  let rebuilt = cb.reconstruct(&txs).unwrap();
+  assert_eq!(rebuilt.hash(), b.hash());
+  assert_eq!(rebuilt.inputs.len(), b.inputs.len());
+  assert_eq!(rebuilt.outputs.len(), b.outputs.len());
+  assert_eq!(rebuilt.proofs.len(), b.proofs.len());
+}
+
+#[test]
+fn reconstruct_missing_txs() {
+  let (b, txs) = block_with_txs();
+  let (_, other_txs) = block_with_txs();
+  let cb = CompactBlock::new(&b, &txs);
+
+  assert!(cb.reconstruct(&txs[..1]).is_none());
+  assert!(cb.reconstruct(&[txs[0].clone(), other_txs[0].clone()]).is_none());
+}
+
+// The receiving end only has one of the transactions and gets the other one
+// from the sender.
+#[test]
+fn relay_missing_tx() {
+  let (b, txs) = block_with_txs();
+  let cb = CompactBlock::new(&b, &txs);
+  let (received, stats) = relay(13431, &b, cb, vec![txs[0].clone()], txs.clone());
+  assert_eq!(received, vec![b.hash()]);
+  assert_eq!(stats.received(Type::GetBlockTxn), 1);
+  assert_eq!(stats.received(Type::GetBlock), 0);
+}
+
+// Neither end has the transactions anymore, the receiving end has to ask for
+// the full block instead.
+#[test]
+fn relay_full_block_fallback() {
+  let (b, txs) = block_with_txs();
+  let cb = CompactBlock::new(&b, &txs);
+  let (received, stats) = relay(13432, &b, cb, vec![], vec![]);
+  assert_eq!(received, vec![b.hash()]);
+  assert_eq!(stats.received(Type::GetBlockTxn), 1);
+  assert_eq!(stats.received(Type::GetBlock), 1);
+}
+
+// The server announces a block, the peer that can rebuild compact blocks
+// gets the compact version when asking for it while the other one gets the
+// full block.
+#[test]
+fn inv_answered_compactly() {
+  let (b, txs) = block_with_txs();
+  let bh = b.hash();
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13456;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server = Arc::new(p2p::Server::new("target/p2p-compact-inv".to_string(),
+                                         p2p_conf,
+                                         adapter(txs.clone(), Some(&b)),
+                                         ZERO_HASH)
+    .unwrap());
+  let run_server = server.start(handle.clone());
+
+  let compact_adapter = adapter(txs.clone(), None);
+  let full_adapter = adapter(vec![], None);
+  let (ca, fa) = (compact_adapter.clone(), full_adapter.clone());
+  let full_hs = p2p::handshake::Handshake::with_version(ZERO_HASH, PROTOCOL_VERSION, FULL_SYNC);
+
+  let h = handle.clone();
+  let s = server.clone();
+  let client = wait(&handle, 500)
+    .and_then(move |_| {
+      let compact_hs = p2p::handshake::Handshake::new(ZERO_HASH);
+      connect(addr, h.clone(), ca, compact_hs).map(move |compact| (compact, h))
+    })
+    .and_then(move |(compact, h)| {
+      connect(addr, h.clone(), fa, full_hs).map(move |full| (compact, full, h))
+    })
+    .and_then(move |(compact, full, h)| {
+      s.announce_block(bh);
+      wait(&h, 500).map(move |_| (compact, full, s))
+    })
+    .and_then(|(compact, full, s)| {
+      let (compact, full) = (compact.stats(), full.stats());
+      assert_eq!(compact.sent(Type::GetData), 1);
+      assert_eq!(compact.received(Type::CompactBlock), 1);
+      assert_eq!(compact.received(Type::Block), 0);
+      assert_eq!(full.sent(Type::GetData), 1);
+      assert_eq!(full.received(Type::CompactBlock), 0);
+      assert_eq!(full.received(Type::Block), 1);
+      s.stop();
+      Ok(())
+    });
+  handle.spawn(client.map_err(|e| panic!("Client failed: {}", e)));
+
+  evtlp.run(run_server).unwrap();
+  assert_eq!(*compact_adapter.received.lock().unwrap(), vec![bh]);
+  assert_eq!(*full_adapter.received.lock().unwrap(), vec![bh]);
+}
+
+fn adapter(txs: Vec<Transaction>, b: Option<&Block>) -> Arc<BlockAdapter> {
+  Arc::new(BlockAdapter {
+    txs: txs,
+    block: b.map(|b| ser::ser_vec(b).unwrap()),
+    received: Mutex::new(vec![]),
+  })
+}
+
+fn wait(h: &reactor::Handle, ms: u64) -> Box<Future<Item = (), Error = p2p::Error>> {
+  Box::new(reactor::Timeout::new(time::Duration::from_millis(ms), h)
+    .unwrap()
+    .map_err(|e| p2p::Error::IOErr(e)))
+}
+
+// Handshakes with the server as the provided handshake says and keeps the
+// client peer running with the provided adapter.
+fn connect(addr: SocketAddr,
+           h: reactor::Handle,
+           adapter: Arc<BlockAdapter>,
+           hs: p2p::handshake::Handshake)
+           -> Box<Future<Item = Arc<Peer>, Error = p2p::Error>> {
+  let socket = TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e));
+  Box::new(socket.and_then(move |socket| Peer::connect(socket, Difficulty::one(), 0, &hs))
+    .and_then(move |(socket, peer)| {
+      let peer = Arc::new(peer);
+      let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+      let limiter = p2p::RateLimiter::new(100, 100);
+      h.spawn(peer.run(socket, adapter, announces, limiter).map_err(|_| ()));
+      wait(&h, 200).map(move |_| peer)
+    }))
+}
+
+// Sends the compact version of a block from a client to a server, each
+// knowing of the provided transactions. Returns the blocks the server got
+// and the stats of the client.
+fn relay(port: u16,
+         b: &Block,
+         cb: CompactBlock,
+         server_txs: Vec<Transaction>,
+         client_txs: Vec<Transaction>)
+         -> (Vec<Hash>, PeerStats) {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = port;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server_adapter = Arc::new(BlockAdapter {
+    txs: server_txs,
+    block: None,
+    received: Mutex::new(vec![]),
+  });
+  let server = p2p::Server::new(format!("target/p2p-compact-{}", port),
+                                p2p_conf,
+                                server_adapter.clone(),
+                                ZERO_HASH)
+    .unwrap();
+  let run_server = server.start(handle.clone());
+
+  let client_adapter = Arc::new(BlockAdapter {
+    txs: client_txs,
+    block: Some(ser::ser_vec(b).unwrap()),
+    received: Mutex::new(vec![]),
+  });
+
+  let stats = Arc::new(Mutex::new(None));
+  let client_stats = stats.clone();
+  let h = handle.clone();
+  let rhandle = handle.clone();
+  let start = reactor::Timeout::new(time::Duration::new(1, 0), &handle).unwrap();
+  handle.spawn(start.map_err(|e| p2p::Error::IOErr(e)).and_then(move |_| {
+    TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e))
+  }).and_then(|socket| {
+    Peer::connect(socket, Difficulty::one(), 0, &p2p::handshake::Handshake::new(ZERO_HASH))
+  }).and_then(move |(socket, peer)| {
+    let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+    let limiter = p2p::RateLimiter::new(100, 100);
+    rhandle.spawn(peer.run(socket, client_adapter, announces, limiter).map_err(|_| ()));
+    peer.send_compact_block(&cb).unwrap();
+    let wait = reactor::Timeout::new(time::Duration::new(1, 0), &rhandle).unwrap();
+    wait.map_err(|e| p2p::Error::IOErr(e)).map(move |_| peer)
+  }).and_then(move |peer| {
+    *client_stats.lock().unwrap() = Some(peer.stats());
+    server.stop();
+    Ok(())
+  }).map_err(|e| panic!("Client connection failed: {}", e)));
+
+  evtlp.run(run_server).unwrap();
+
+  let received = server_adapter.received.lock().unwrap().clone();
+  let stats = stats.lock().unwrap().take().unwrap();
+  (received, stats)
+}
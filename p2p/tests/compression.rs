@@ -0,0 +1,193 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use futures::future::Future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::{Block, BlockHeader, Input, Transaction};
+use core::core::hash::{Hash, ZERO_HASH};
+use core::core::target::Difficulty;
+use core::ser;
+use p2p::{NetAdapter, Peer, PeerStats, Type};
+
+// Keeps track of the hash and number of inputs of the blocks it gets, serving
+// a large block to anyone asking if told so.
+struct BlockAdapter {
+  received: Mutex<Vec<(Hash, usize)>>,
+  serves: bool,
+}
+
+// A block with plenty of identical inputs, compressing well.
+fn large_block() -> Block {
+  Block {
+    inputs: vec![Input::BareInput { output: ZERO_HASH }; 2000],
+    ..Default::default()
+  }
+}
+
+impl NetAdapter for BlockAdapter {
+  fn total_difficulty(&self) -> Difficulty {
+    Difficulty::one()
+  }
+  fn transaction_received(&self, _: Transaction) -> p2p::TxStatus {
+    p2p::TxStatus::Accepted
+  }
+  fn block_received(&self, b: Block) -> bool {
+    self.received.lock().unwrap().push((b.hash(), b.inputs.len()));
+    true
+  }
+  fn headers_received(&self, _: Vec<BlockHeader>) -> bool {
+    true
+  }
+  fn locate_headers(&self, _: Vec<Hash>) -> Vec<BlockHeader> {
+    vec![]
+  }
+  fn get_block(&self, _: Hash) -> Option<Block> {
+    if self.serves { Some(large_block()) } else { None }
+  }
+  fn get_transaction(&self, _: u64) -> Option<Transaction> {
+    None
+  }
+  fn find_peer_addrs(&self, _: p2p::Capabilities) -> Vec<p2p::PeerAddr> {
+    vec![]
+  }
+  fn peer_addrs_received(&self, _: Vec<p2p::PeerAddr>) {}
+}
+
+// Sends a large block, compressing well, and then a ping to a server. The
+// block should go compressed and come out intact on the other side while
+// the ping goes as is.
+#[test]
+fn compress_large_messages() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13435;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server_adapter = Arc::new(BlockAdapter { received: Mutex::new(vec![]), serves: false });
+  let server = p2p::Server::new("target/p2p-compression".to_string(),
+                                p2p_conf,
+                                server_adapter.clone(),
+                                ZERO_HASH)
+    .unwrap();
+  let run_server = server.start(handle.clone());
+
+  let b = large_block();
+  let block_len = ser::ser_vec(&b).unwrap().len() as u64;
+  let block_h = b.hash();
+
+  let stats = Arc::new(Mutex::new(vec![]));
+  let client_stats = stats.clone();
+  let h = handle.clone();
+  let rhandle = handle.clone();
+  let start = reactor::Timeout::new(time::Duration::new(1, 0), &handle).unwrap();
+  handle.spawn(start.map_err(|e| p2p::Error::IOErr(e)).and_then(move |_| {
+    TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e))
+  }).and_then(|socket| {
+    Peer::connect(socket, Difficulty::one(), 0, &p2p::handshake::Handshake::new(ZERO_HASH))
+  }).and_then(move |(socket, peer)| {
+    assert!(peer.info.capabilities.contains(p2p::COMPRESSION));
+    let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+    let limiter = p2p::RateLimiter::new(100, 100);
+    let adapter = Arc::new(BlockAdapter { received: Mutex::new(vec![]), serves: false });
+    rhandle.spawn(peer.run(socket, adapter, announces, limiter).map_err(|_| ()));
+    peer.send_block(&b).unwrap();
+    let wait = reactor::Timeout::new(time::Duration::new(1, 0), &rhandle).unwrap();
+    wait.map_err(|e| p2p::Error::IOErr(e)).map(move |_| (peer, rhandle))
+  }).and_then(move |(peer, rhandle)| {
+    client_stats.lock().unwrap().push(peer.stats());
+    peer.send_ping(Difficulty::one(), 0).unwrap();
+    let wait = reactor::Timeout::new(time::Duration::new(1, 0), &rhandle).unwrap();
+    wait.map_err(|e| p2p::Error::IOErr(e)).map(move |_| (peer, client_stats))
+  }).and_then(move |(peer, client_stats)| {
+    client_stats.lock().unwrap().push(peer.stats());
+    server.stop();
+    Ok(())
+  }).map_err(|e| panic!("Client connection failed: {}", e)));
+
+  evtlp.run(run_server).unwrap();
+
+  assert_eq!(*server_adapter.received.lock().unwrap(), vec![(block_h, 2000)]);
+  let stats: Vec<PeerStats> = stats.lock().unwrap().clone();
+  let (after_block, after_ping) = (&stats[0], &stats[1]);
+  assert_eq!(after_block.sent(Type::Block), 1);
+  assert!(after_block.sent_bytes < block_len / 10);
+
+  // header and a difficulty, nothing saved compressing that
+  assert_eq!(after_ping.received(Type::Pong), 1);
+  assert_eq!(after_ping.sent_bytes - after_block.sent_bytes, p2p::HEADER_LEN + 8);
+}
+
+// Asks a server for a large block, its reply should come compressed as well.
+#[test]
+fn compress_replies() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13460;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server_adapter = Arc::new(BlockAdapter { received: Mutex::new(vec![]), serves: true });
+  let server = p2p::Server::new("target/p2p-compression-reply".to_string(),
+                                p2p_conf,
+                                server_adapter,
+                                ZERO_HASH)
+    .unwrap();
+  let run_server = server.start(handle.clone());
+
+  let block_len = ser::ser_vec(&large_block()).unwrap().len() as u64;
+  let block_h = large_block().hash();
+
+  let client_adapter = Arc::new(BlockAdapter { received: Mutex::new(vec![]), serves: false });
+  let adapter = client_adapter.clone();
+  let stats = Arc::new(Mutex::new(vec![]));
+  let client_stats = stats.clone();
+  let h = handle.clone();
+  let rhandle = handle.clone();
+  let start = reactor::Timeout::new(time::Duration::new(1, 0), &handle).unwrap();
+  handle.spawn(start.map_err(|e| p2p::Error::IOErr(e)).and_then(move |_| {
+    TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e))
+  }).and_then(|socket| {
+    Peer::connect(socket, Difficulty::one(), 0, &p2p::handshake::Handshake::new(ZERO_HASH))
+  }).and_then(move |(socket, peer)| {
+    let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+    let limiter = p2p::RateLimiter::new(100, 100);
+    rhandle.spawn(peer.run(socket, adapter, announces, limiter).map_err(|_| ()));
+    peer.send_block_request(block_h).unwrap();
+    let wait = reactor::Timeout::new(time::Duration::new(1, 0), &rhandle).unwrap();
+    wait.map_err(|e| p2p::Error::IOErr(e)).map(move |_| peer)
+  }).and_then(move |peer| {
+    client_stats.lock().unwrap().push(peer.stats());
+    server.stop();
+    Ok(())
+  }).map_err(|e| panic!("Client connection failed: {}", e)));
+
+  evtlp.run(run_server).unwrap();
+
+  assert_eq!(*client_adapter.received.lock().unwrap(), vec![(block_h, 2000)]);
+  let stats: Vec<PeerStats> = stats.lock().unwrap().clone();
+  assert_eq!(stats[0].received(Type::Block), 1);
+  assert!(stats[0].received_bytes < block_len / 10);
+}
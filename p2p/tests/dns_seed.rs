@@ -0,0 +1,147 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time;
+
+use futures::future::Future;
+use tokio_core::reactor::{self, Core};
+
+use core::core::hash::ZERO_HASH;
+use p2p::Resolver;
+
+// Resolves a single seed to fixed addresses, failing on any other.
+struct FakeResolver {
+  addrs: Vec<SocketAddr>,
+}
+
+impl Resolver for FakeResolver {
+  fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    assert_eq!(port, 13437);
+    if host == "seed.grin.test" {
+      Ok(self.addrs.clone())
+    } else {
+      Err(io::Error::new(io::ErrorKind::Other, "no such host"))
+    }
+  }
+}
+
+// Takes its time resolving anything, as a DNS server timing out would.
+struct SlowResolver;
+
+impl Resolver for SlowResolver {
+  fn resolve(&self, _: &str, _: u16) -> io::Result<Vec<SocketAddr>> {
+    thread::sleep(time::Duration::new(3, 0));
+    Ok(vec![])
+  }
+}
+
+#[test]
+fn resolve_seeds_skips_failures() {
+  let addrs = vec!["127.0.0.1:13437".parse().unwrap(), "[::1]:13437".parse().unwrap()];
+  let resolver = FakeResolver { addrs: addrs.clone() };
+  let seeds = vec!["broken.grin.test".to_string(),
+                   "seed.grin.test".to_string(),
+                   "seed.grin.test:13437".to_string()];
+  assert_eq!(p2p::resolve_seeds(&resolver, &seeds, 13437), addrs);
+}
+
+// A server without any known peer should go through its DNS seeds and try
+// to connect to all the peers they point to.
+#[test]
+fn connect_to_seed_peers() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  // only the first seed peer is actually up
+  let live: SocketAddr = "127.0.0.1:13437".parse().unwrap();
+  let down: SocketAddr = "127.0.0.2:13437".parse().unwrap();
+  let _ = fs::remove_dir_all("target/p2p-dns-seed-peer");
+  let mut seed_conf = p2p::P2PConfig::default();
+  seed_conf.port = 13437;
+  let seed_peer = p2p::Server::new("target/p2p-dns-seed-peer".to_string(),
+                                   seed_conf,
+                                   Arc::new(p2p::DummyAdapter {}),
+                                   ZERO_HASH)
+    .unwrap();
+  handle.spawn(seed_peer.start(handle.clone()).map_err(|e| panic!("seed peer failed: {}", e)));
+
+  let _ = fs::remove_dir_all("target/p2p-dns-seed");
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13438;
+  p2p_conf.dns_seeds = vec!["broken.grin.test".to_string(), "seed.grin.test:13437".to_string()];
+  let mut server = p2p::Server::new("target/p2p-dns-seed".to_string(),
+                                    p2p_conf,
+                                    Arc::new(p2p::DummyAdapter {}),
+                                    ZERO_HASH)
+    .unwrap();
+  server.set_resolver(Arc::new(FakeResolver { addrs: vec![live, down] }));
+  let server = Arc::new(server);
+  let run_server = server.start(handle.clone());
+
+  let s = server.clone();
+  let wait = reactor::Timeout::new(time::Duration::new(1, 0), &handle).unwrap();
+  handle.spawn(wait.then(move |_| {
+    let seeds = s.seed_peers();
+    assert_eq!(seeds.len(), 2);
+    assert!(seeds.contains(&live) && seeds.contains(&down));
+    assert_eq!(s.peer_count(), 1);
+    assert!(s.known_peers().iter().any(|p| p.addr == live));
+    s.stop();
+    Ok(())
+  }));
+
+  evtlp.run(run_server).unwrap();
+  seed_peer.stop();
+}
+
+// Slow DNS lookups shouldn't hold up the event loop the server runs on.
+#[test]
+fn slow_seeds_do_not_block() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let _ = fs::remove_dir_all("target/p2p-dns-seed-slow");
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13457;
+  p2p_conf.dns_seeds = vec!["slow.grin.test".to_string()];
+  let mut server = p2p::Server::new("target/p2p-dns-seed-slow".to_string(),
+                                    p2p_conf,
+                                    Arc::new(p2p::DummyAdapter {}),
+                                    ZERO_HASH)
+    .unwrap();
+  server.set_resolver(Arc::new(SlowResolver));
+  let server = Arc::new(server);
+  let started = time::Instant::now();
+  let run_server = server.start(handle.clone());
+
+  let s = server.clone();
+  let wait = reactor::Timeout::new(time::Duration::from_millis(100), &handle).unwrap();
+  handle.spawn(wait.then(move |_| {
+    assert!(started.elapsed() < time::Duration::new(1, 0));
+    s.stop();
+    Ok(())
+  }));
+
+  evtlp.run(run_server).unwrap();
+}
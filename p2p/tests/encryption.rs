@@ -0,0 +1,195 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::io::{self, Read, Write};
+use std::net::{self, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use futures::future::Future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::{Block, BlockHeader, Transaction};
+use core::core::hash::{Hash, ZERO_HASH};
+use core::core::target::Difficulty;
+use p2p::{NetAdapter, Peer};
+use p2p::handshake::Handshake;
+
+// Height of our blocks, easy to spot in the traffic
+const MARKER: u64 = 0x0123456789abcdef;
+
+// Adapter keeping track of the heights of the blocks it gets.
+struct BlockAdapter {
+  received: Mutex<Vec<u64>>,
+}
+
+impl NetAdapter for BlockAdapter {
+  fn total_difficulty(&self) -> Difficulty {
+    Difficulty::one()
+  }
+  fn transaction_received(&self, _: Transaction) -> p2p::TxStatus {
+    p2p::TxStatus::Accepted
+  }
+  fn block_received(&self, b: Block) -> bool {
+    self.received.lock().unwrap().push(b.header.height);
+    true
+  }
+  fn headers_received(&self, _: Vec<BlockHeader>) -> bool {
+    true
+  }
+  fn locate_headers(&self, _: Vec<Hash>) -> Vec<BlockHeader> {
+    vec![]
+  }
+  fn get_block(&self, _: Hash) -> Option<Block> {
+    None
+  }
+  fn get_transaction(&self, _: u64) -> Option<Transaction> {
+    None
+  }
+  fn find_peer_addrs(&self, _: p2p::Capabilities) -> Vec<p2p::PeerAddr> {
+    vec![]
+  }
+  fn peer_addrs_received(&self, _: Vec<p2p::PeerAddr>) {}
+}
+
+// Two peers supporting encryption send a block to the server through a relay
+// watching the traffic, as does a peer that doesn't support it. The server
+// should get both blocks but the first one should never show up on the wire.
+#[test]
+fn encrypt_when_both_support_it() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13445;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let adapter = Arc::new(BlockAdapter { received: Mutex::new(vec![]) });
+  let server = Arc::new(p2p::Server::new("target/p2p-encryption".to_string(),
+                                         p2p_conf,
+                                         adapter.clone(),
+                                         ZERO_HASH)
+    .unwrap());
+  let run_server = server.start(handle.clone());
+
+  let encrypted_wire = relay(13446, addr);
+  let plain_wire = relay(13447, addr);
+  let plain_hs =
+    Handshake::with_version(ZERO_HASH, p2p::PROTOCOL_VERSION, p2p::FULL_SYNC | p2p::COMPRESSION);
+
+  let h = handle.clone();
+  let s = server.clone();
+  let client = wait(&handle, 500)
+    .and_then(move |_| {
+      connect(relay_addr(13446), h.clone(), Handshake::new(ZERO_HASH))
+        .map(move |encrypted| (encrypted, h))
+    })
+    .and_then(move |(encrypted, h)| {
+      connect(relay_addr(13447), h.clone(), plain_hs).map(move |plain| (encrypted, plain, h))
+    })
+    .and_then(move |(encrypted, plain, h)| {
+      assert!(encrypted.info.capabilities.contains(p2p::ENCRYPTION));
+      assert!(!plain.info.capabilities.contains(p2p::ENCRYPTION));
+      encrypted.send_block(&block(MARKER)).unwrap();
+      plain.send_block(&block(MARKER + 1)).unwrap();
+      wait(&h, 500).map(move |_| (encrypted, plain, s))
+    })
+    .and_then(|(_, _, s)| {
+      s.stop();
+      Ok(())
+    });
+  handle.spawn(client.map_err(|e| panic!("Client failed: {}", e)));
+
+  evtlp.run(run_server).unwrap();
+
+  let mut received = adapter.received.lock().unwrap().clone();
+  received.sort();
+  assert_eq!(received, vec![MARKER, MARKER + 1]);
+  assert!(!contains(&encrypted_wire.lock().unwrap(), MARKER));
+  assert!(contains(&plain_wire.lock().unwrap(), MARKER + 1));
+}
+
+fn block(height: u64) -> Block {
+  let mut b = Block::default();
+  b.header.height = height;
+  b
+}
+
+// Whether the height shows up in the data, as it would serialized
+fn contains(data: &[u8], height: u64) -> bool {
+  let bytes = (0..8).map(|n| (height >> (56 - n * 8)) as u8).collect::<Vec<_>>();
+  data.windows(8).any(|w| w == &bytes[..])
+}
+
+fn relay_addr(port: u16) -> SocketAddr {
+  SocketAddr::new("127.0.0.1".parse().unwrap(), port)
+}
+
+// Forwards a single connection to the server, keeping a copy of all the data
+// the client sends.
+fn relay(port: u16, server: SocketAddr) -> Arc<Mutex<Vec<u8>>> {
+  let listener = net::TcpListener::bind(relay_addr(port)).unwrap();
+  let sent = Arc::new(Mutex::new(vec![]));
+  let copy = sent.clone();
+  thread::spawn(move || {
+    let mut client = listener.accept().unwrap().0;
+    let mut upstream = net::TcpStream::connect(server).unwrap();
+    let (mut client_write, mut upstream_read) =
+      (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+    thread::spawn(move || io::copy(&mut upstream_read, &mut client_write));
+
+    let mut buf = [0; 4096];
+    loop {
+      match client.read(&mut buf) {
+        Ok(0) | Err(_) => break,
+        Ok(n) => {
+          copy.lock().unwrap().extend_from_slice(&buf[..n]);
+          if upstream.write_all(&buf[..n]).is_err() {
+            break;
+          }
+        }
+      }
+    }
+  });
+  sent
+}
+
+fn wait(h: &reactor::Handle, ms: u64) -> Box<Future<Item = (), Error = p2p::Error>> {
+  Box::new(reactor::Timeout::new(time::Duration::from_millis(ms), h)
+    .unwrap()
+    .map_err(|e| p2p::Error::IOErr(e)))
+}
+
+// Handshakes through the provided address and keeps the client peer running.
+fn connect(addr: SocketAddr,
+           h: reactor::Handle,
+           hs: Handshake)
+           -> Box<Future<Item = Arc<Peer>, Error = p2p::Error>> {
+  let socket = TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e));
+  Box::new(socket.and_then(move |socket| Peer::connect(socket, Difficulty::one(), 0, &hs))
+    .and_then(move |(socket, peer)| {
+      let peer = Arc::new(peer);
+      let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+      let limiter = p2p::RateLimiter::new(100, 100);
+      h.spawn(peer.run(socket, Arc::new(p2p::DummyAdapter {}), announces, limiter)
+        .map_err(|_| ()));
+      wait(&h, 200).map(move |_| peer)
+    }))
+}
@@ -0,0 +1,124 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time;
+
+use futures::Stream;
+use futures::future::Future;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{self, Core};
+
+use core::core::hash::ZERO_HASH;
+use core::core::target::Difficulty;
+use p2p::Peer;
+use p2p::handshake::Handshake;
+
+// A server listening on one address and advertising another: peers connect
+// to the former and get told about the latter when the server connects to
+// them.
+#[test]
+fn advertise_external_addr() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let listen: SocketAddr = "127.0.0.1:13450".parse().unwrap();
+  let external: SocketAddr = "93.184.216.34:13414".parse().unwrap();
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.p2p_listen_addr = Some(listen);
+  p2p_conf.p2p_external_addr = Some(external);
+  assert_eq!(p2p_conf.listen_addr(), listen);
+  assert_eq!(p2p_conf.external_addr(), external);
+  let server = Arc::new(p2p::Server::new("target/p2p-external-addr".to_string(),
+                                         p2p_conf,
+                                         Arc::new(p2p::DummyAdapter {}),
+                                         ZERO_HASH)
+    .unwrap());
+  let run_server = server.start(handle.clone());
+
+  // a peer of our own the server connects to
+  let peer_addr: SocketAddr = "127.0.0.1:13451".parse().unwrap();
+  let listener = TcpListener::bind(&peer_addr, &handle).unwrap();
+  let accepted = listener.incoming()
+    .take(1)
+    .into_future()
+    .map_err(|(e, _)| p2p::Error::IOErr(e))
+    .and_then(|(conn, _)| {
+      let (conn, _) = conn.unwrap();
+      Peer::accept(conn, Difficulty::one(), 0, &Handshake::new(ZERO_HASH))
+    });
+
+  let h = handle.clone();
+  let s = server.clone();
+  let client = wait(&handle, 500)
+    .and_then(move |_| {
+      // the server only accepts connections on its listen address
+      TcpStream::connect(&listen, &h)
+        .map_err(|e| p2p::Error::IOErr(e))
+        .and_then(move |socket| {
+          Peer::connect(socket, Difficulty::one(), 0, &Handshake::new(ZERO_HASH))
+        })
+        .map(move |res| (res, h))
+    })
+    .and_then(move |(_, h)| {
+      h.spawn(s.connect_peer(peer_addr, h.clone()).map_err(|_| ()));
+      accepted.map(move |(_, peer)| (peer, s))
+    })
+    .and_then(|(peer, s)| {
+      assert_eq!(peer.info.advertised_addr, Some(external));
+      s.stop();
+      Ok(())
+    });
+  handle.spawn(client.map_err(|e| panic!("Client failed: {}", e)));
+
+  evtlp.run(run_server).unwrap();
+}
+
+#[test]
+fn unroutable_external_addr() {
+  for addr in &["10.0.0.1:13414",
+                "192.168.1.1:13414",
+                "127.0.0.1:13414",
+                "0.0.0.0:13414",
+                "93.184.216.34:0",
+                "[fd00::1]:13414",
+                "[fe80::1]:13414"] {
+    let addr: SocketAddr = addr.parse().unwrap();
+    assert!(!p2p::is_routable(&addr), "{} routable", addr);
+
+    let mut p2p_conf = p2p::P2PConfig::default();
+    p2p_conf.p2p_external_addr = Some(addr);
+    match p2p::Server::new("target/p2p-unroutable-addr".to_string(),
+                           p2p_conf,
+                           Arc::new(p2p::DummyAdapter {}),
+                           ZERO_HASH) {
+      Err(p2p::Error::UnroutableAddr(a)) => assert_eq!(a, addr),
+      _ => panic!("server advertising {} created", addr),
+    }
+  }
+  assert!(p2p::is_routable(&"93.184.216.34:13414".parse().unwrap()));
+  assert!(p2p::is_routable(&"[2001:4860::8888]:13414".parse().unwrap()));
+}
+
+fn wait(h: &reactor::Handle, ms: u64) -> Box<Future<Item = (), Error = p2p::Error>> {
+  Box::new(reactor::Timeout::new(time::Duration::from_millis(ms), h)
+    .unwrap()
+    .map_err(|e| p2p::Error::IOErr(e)))
+}
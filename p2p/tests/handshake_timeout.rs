@@ -0,0 +1,64 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{self, Instant};
+
+use futures::future::Future;
+use tokio_core::io::read_to_end;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::hash::ZERO_HASH;
+
+// Opens a connection to a server and never starts the handshake, the server
+// should hang up on us once the handshake timeout elapses, remembering why.
+#[test]
+fn stalled_handshake() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13429;
+  p2p_conf.handshake_timeout = 1;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server = p2p::Server::new("target/p2p-hs-timeout".to_string(),
+                                p2p_conf,
+                                Arc::new(p2p::DummyAdapter {}),
+                                ZERO_HASH)
+    .unwrap();
+  handle.spawn(server.start(handle.clone()).map_err(|e| panic!("Server failed: {}", e)));
+
+  let h = handle.clone();
+  let start = reactor::Timeout::new(time::Duration::from_millis(500), &handle).unwrap();
+  let client = start.and_then(move |_| TcpStream::connect(&addr, &h))
+    .and_then(|socket| {
+      let connected = Instant::now();
+      let local = socket.local_addr().unwrap();
+      read_to_end(socket, vec![]).map(move |(_, buf)| (connected.elapsed(), buf, local))
+    });
+  let (elapsed, buf, local) = evtlp.run(client).unwrap();
+
+  assert!(buf.is_empty());
+  assert!(elapsed >= time::Duration::from_secs(1));
+  assert!(elapsed < time::Duration::from_secs(3));
+  assert_eq!(server.peer_count(), 0);
+  assert_eq!(server.recent_disconnects(), vec![(local, p2p::DisconnectReason::Timeout)]);
+}
@@ -0,0 +1,80 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+
+use futures::future::Future;
+use futures::Stream;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::Core;
+
+use core::core::hash::ZERO_HASH;
+use core::core::target::Difficulty;
+use p2p::handshake::Handshake;
+use p2p::Peer;
+
+// Runs a handshake between a listening peer using the provided handshake and
+// a connecting peer using ours. Returns the results on both sides.
+fn handshake(port: u16,
+             remote: Handshake)
+             -> (Result<p2p::Peer, p2p::Error>, Result<p2p::Peer, p2p::Error>) {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+  let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+
+  let listener = TcpListener::bind(&addr, &handle).unwrap();
+  let accept = listener.incoming()
+    .into_future()
+    .map_err(|(e, _)| p2p::Error::IOErr(e))
+    .and_then(move |(conn, _)| Peer::accept(conn.unwrap().0, Difficulty::one(), 0, &remote))
+    .map(|(_, peer)| peer);
+  let connect = TcpStream::connect(&addr, &handle)
+    .map_err(|e| p2p::Error::IOErr(e))
+    .and_then(|socket| Peer::connect(socket, Difficulty::one(), 0, &Handshake::new(ZERO_HASH)))
+    .map(|(_, peer)| peer);
+
+  evtlp.run(accept.then(Ok::<_, ()>).join(connect.then(Ok::<_, ()>))).unwrap()
+}
+
+// A peer speaking a newer version than ours should agree on our version.
+#[test]
+fn negotiate_lowest_version() {
+  let newer = Handshake::with_version(ZERO_HASH, p2p::PROTOCOL_VERSION + 1, p2p::FULL_SYNC);
+  let (remote, local) = handshake(13424, newer);
+
+  assert_eq!(remote.unwrap().info.version, p2p::PROTOCOL_VERSION);
+  let local = local.unwrap();
+  assert_eq!(local.info.version, p2p::PROTOCOL_VERSION);
+  assert_eq!(local.info.capabilities, p2p::FULL_SYNC);
+}
+
+// A peer older than our minimum version gets refused.
+#[test]
+fn refuse_too_old_version() {
+  let older = Handshake::with_version(ZERO_HASH, p2p::MIN_PROTOCOL_VERSION - 1, p2p::FULL_SYNC);
+  let (_, local) = handshake(13425, older);
+
+  match local {
+    Err(p2p::Error::VersionTooOld { min, peer }) => {
+      assert_eq!(min, p2p::MIN_PROTOCOL_VERSION);
+      assert_eq!(peer, p2p::MIN_PROTOCOL_VERSION - 1);
+    }
+    _ => panic!("Expected the handshake to be refused as too old"),
+  }
+}
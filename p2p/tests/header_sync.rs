@@ -0,0 +1,154 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use futures::future::Future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::{Block, BlockHeader, Transaction};
+use core::core::hash::{Hash, Hashed, ZERO_HASH};
+use core::core::target::Difficulty;
+use p2p::{NetAdapter, Peer};
+
+// Adapter of a peer knowing of a fixed header chain, answers header
+// requests with all the headers following the first locator hash it knows,
+// however many there are. Requests more headers through its peer whenever
+// it gets a full batch.
+struct HeadersAdapter {
+  chain: Vec<BlockHeader>,
+  received: Mutex<Vec<Vec<Hash>>>,
+  peer: Mutex<Option<Arc<Peer>>>,
+}
+
+impl NetAdapter for HeadersAdapter {
+  fn total_difficulty(&self) -> Difficulty {
+    Difficulty::one()
+  }
+  fn transaction_received(&self, _: Transaction) -> p2p::TxStatus {
+    p2p::TxStatus::Accepted
+  }
+  fn block_received(&self, _: Block) -> bool {
+    true
+  }
+  fn headers_received(&self, bh: Vec<BlockHeader>) -> bool {
+    let full = bh.len() == p2p::MAX_BLOCK_HEADERS as usize;
+    let last = bh.last().map(|h| h.hash());
+    self.received.lock().unwrap().push(bh.iter().map(|h| h.hash()).collect());
+    if let (true, Some(last)) = (full, last) {
+      let peer = self.peer.lock().unwrap();
+      peer.as_ref().unwrap().send_header_request(vec![last]).unwrap();
+    }
+    true
+  }
+  fn locate_headers(&self, locator: Vec<Hash>) -> Vec<BlockHeader> {
+    for h in locator {
+      if let Some(n) = self.chain.iter().position(|bh| bh.hash() == h) {
+        return self.chain[n + 1..].to_vec();
+      }
+    }
+    vec![]
+  }
+  fn get_block(&self, _: Hash) -> Option<Block> {
+    None
+  }
+  fn get_transaction(&self, _: u64) -> Option<Transaction> {
+    None
+  }
+  fn find_peer_addrs(&self, _: p2p::Capabilities) -> Vec<p2p::PeerAddr> {
+    vec![]
+  }
+  fn peer_addrs_received(&self, _: Vec<p2p::PeerAddr>) {}
+}
+
+fn header_chain(len: u64) -> Vec<BlockHeader> {
+  let mut chain: Vec<BlockHeader> = vec![BlockHeader::default()];
+  for height in 1..len {
+    let mut bh = BlockHeader::default();
+    bh.height = height;
+    bh.previous = chain.last().unwrap().hash();
+    chain.push(bh);
+  }
+  chain
+}
+
+// A client starting from genesis asks a server knowing about a longer chain
+// for headers, following up with a locator on each full batch until it has
+// the whole chain. The server never sends more than the maximum at once.
+#[test]
+fn locator_driven_header_sync() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+  let chain = header_chain(1200);
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13439;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server_adapter = Arc::new(HeadersAdapter {
+    chain: chain.clone(),
+    received: Mutex::new(vec![]),
+    peer: Mutex::new(None),
+  });
+  let server = Arc::new(p2p::Server::new("target/p2p-header-sync".to_string(),
+                                         p2p_conf,
+                                         server_adapter,
+                                         ZERO_HASH)
+    .unwrap());
+  let run_server = server.start(handle.clone());
+
+  let client_adapter = Arc::new(HeadersAdapter {
+    chain: vec![],
+    received: Mutex::new(vec![]),
+    peer: Mutex::new(None),
+  });
+  let adapter = client_adapter.clone();
+  let genesis = chain[0].hash();
+  let h = handle.clone();
+  let rhandle = handle.clone();
+  let s = server.clone();
+  let start = reactor::Timeout::new(time::Duration::new(1, 0), &handle).unwrap();
+  handle.spawn(start.map_err(|e| p2p::Error::IOErr(e)).and_then(move |_| {
+    TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e))
+  }).and_then(|socket| {
+    Peer::connect(socket, Difficulty::one(), 0, &p2p::handshake::Handshake::new(ZERO_HASH))
+  }).and_then(move |(socket, peer)| {
+    let peer = Arc::new(peer);
+    *adapter.peer.lock().unwrap() = Some(peer.clone());
+    let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+    let limiter = p2p::RateLimiter::new(100, 100);
+    rhandle.spawn(peer.run(socket, adapter.clone(), announces, limiter).map_err(|_| ()));
+    peer.send_header_request(vec![genesis]).unwrap();
+    let wait = reactor::Timeout::new(time::Duration::new(2, 0), &rhandle).unwrap();
+    wait.map_err(|e| p2p::Error::IOErr(e))
+  }).then(move |_| {
+    s.stop();
+    Ok(())
+  }));
+
+  evtlp.run(run_server).unwrap();
+
+  let received = client_adapter.received.lock().unwrap();
+  assert_eq!(received.iter().map(|b| b.len()).collect::<Vec<_>>(), vec![512, 512, 175]);
+  let all = received.iter().flat_map(|b| b.iter().cloned()).collect::<Vec<_>>();
+  assert_eq!(all, chain[1..].iter().map(|bh| bh.hash()).collect::<Vec<_>>());
+  client_adapter.peer.lock().unwrap().take();
+}
@@ -0,0 +1,91 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{self, Instant};
+
+use futures::future::Future;
+use futures::stream::{self, Stream};
+use tokio_core::io::{read_to_end, write_all};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::hash::ZERO_HASH;
+use core::core::target::Difficulty;
+use p2p::Peer;
+
+// Once connected, trickles the first bytes of a message header to the
+// server, slowly but within the read timeout, and then stalls. The server
+// should keep the peer while data comes in and drop it once it stops.
+#[test]
+fn stalled_message() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13434;
+  p2p_conf.read_timeout = 1;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server = Arc::new(p2p::Server::new("target/p2p-io-timeout".to_string(),
+                                         p2p_conf,
+                                         Arc::new(p2p::DummyAdapter {}),
+                                         ZERO_HASH)
+    .unwrap());
+  handle.spawn(server.start(handle.clone()).map_err(|e| panic!("Server failed: {}", e)));
+
+  let h = handle.clone();
+  let s = server.clone();
+  let start = reactor::Timeout::new(time::Duration::from_millis(500), &handle).unwrap();
+  let client = start.and_then(move |_| TcpStream::connect(&addr, &h).map(move |socket| (socket, h)))
+    .map_err(|e| p2p::Error::IOErr(e))
+    .and_then(|(socket, h)| {
+      Peer::connect(socket, Difficulty::one(), 0, &p2p::handshake::Handshake::new(ZERO_HASH))
+        .map(move |(socket, _)| (socket, h))
+    })
+    .and_then(|(socket, h)| {
+      let local = socket.local_addr().unwrap();
+      // magic and a couple more bytes, a header is never complete
+      let bytes = vec![0x1e, 0xc5, 0, 0];
+      stream::iter(bytes.into_iter().map(Ok::<u8, io::Error>))
+        .fold(socket, move |socket, b| {
+          reactor::Timeout::new(time::Duration::from_millis(500), &h)
+            .unwrap()
+            .and_then(move |_| write_all(socket, vec![b]))
+            .map(|(socket, _)| socket)
+        })
+        .map(move |socket| (socket, local))
+        .map_err(|e| p2p::Error::IOErr(e))
+    })
+    .and_then(move |(socket, local)| {
+      // 2 seconds in the message, over the timeout but never idle for long
+      assert_eq!(s.peer_count(), 1);
+      let stalled = Instant::now();
+      read_to_end(socket, vec![])
+        .map(move |_| (stalled.elapsed(), local))
+        .map_err(|e| p2p::Error::IOErr(e))
+    });
+  let (elapsed, local) = evtlp.run(client).unwrap();
+
+  assert!(elapsed >= time::Duration::from_secs(1));
+  assert!(elapsed < time::Duration::from_secs(3));
+  assert_eq!(server.peer_count(), 0);
+  assert!(server.recent_disconnects().contains(&(local, p2p::DisconnectReason::Timeout)));
+}
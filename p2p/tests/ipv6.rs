@@ -0,0 +1,109 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time;
+
+use futures::future::Future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::hash::ZERO_HASH;
+use core::core::target::Difficulty;
+use core::ser;
+use p2p::{Direction, Peer, PeerData, PeerStore};
+
+#[test]
+fn ipv6_addr_roundtrip() {
+  for addr in &["[2001:db8::1:80]:13414", "[::1]:1", "10.0.0.1:13414"] {
+    let pd = PeerData::new(addr.parse().unwrap());
+    let bytes = ser::ser_vec(&pd).unwrap();
+    let read: PeerData = ser::deserialize(&mut &bytes[..]).unwrap();
+    assert_eq!(read, pd);
+  }
+
+  let _ = fs::remove_dir_all("target/p2p-ipv6-store");
+  let store = PeerStore::new("target/p2p-ipv6-store".to_string()).unwrap();
+  let v6 = PeerData::new("[2001:db8::1:80]:13414".parse().unwrap());
+  let v4 = PeerData::new("10.0.0.1:13414".parse().unwrap());
+  store.save_peer(&v6).unwrap();
+  store.save_peer(&v4).unwrap();
+  assert_eq!(store.get_peer(v6.addr).unwrap(), v6);
+  assert_eq!(store.get_peer(v4.addr).unwrap(), v4);
+}
+
+// Connects a client peer over IPv6 and another over IPv4 to a dual-stack
+// server, both should make it through the handshake.
+#[test]
+fn dual_stack_listener() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.host = "::".parse().unwrap();
+  p2p_conf.port = 13433;
+  p2p_conf.dual_stack = true;
+  let server = Arc::new(p2p::Server::new("target/p2p-ipv6".to_string(),
+                                         p2p_conf,
+                                         Arc::new(p2p::DummyAdapter {}),
+                                         ZERO_HASH)
+    .unwrap());
+  let run_server = server.start(handle.clone());
+
+  let h = handle.clone();
+  let s = server.clone();
+  let wait = reactor::Timeout::new(time::Duration::new(1, 0), &handle).unwrap();
+  handle.spawn(wait.map_err(|e| p2p::Error::IOErr(e)).and_then(move |_| {
+    connect("[::1]:13433".parse().unwrap(), h.clone())
+      .join(connect("127.0.0.1:13433".parse().unwrap(), h.clone()))
+      .and_then(move |(v6, v4)| {
+        let wait = reactor::Timeout::new(time::Duration::new(1, 0), &h).unwrap();
+        wait.map_err(|e| p2p::Error::IOErr(e)).map(move |_| (v6, v4))
+      })
+  }).then(move |res| {
+    let (v6, v4) = res.unwrap();
+    let peers = s.connected_peers();
+    assert_eq!(peers.len(), 2);
+    assert!(peers.iter().all(|p| p.info.direction == Direction::Inbound));
+    assert!(peers.iter().any(|p| p.info.addr.ip().is_loopback() && p.info.addr.is_ipv6()));
+    drop((v6, v4));
+    s.stop();
+    Ok(())
+  }));
+
+  evtlp.run(run_server).unwrap();
+}
+
+// Handshakes with the server at the provided address, keeping the client
+// peer running.
+fn connect(addr: SocketAddr, h: reactor::Handle) -> Box<Future<Item = Arc<Peer>, Error = p2p::Error>> {
+  let socket = TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e));
+  Box::new(socket.and_then(move |socket| {
+    Peer::connect(socket, Difficulty::one(), 0, &p2p::handshake::Handshake::new(ZERO_HASH))
+  }).map(move |(socket, peer)| {
+    let peer = Arc::new(peer);
+    let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+    let limiter = p2p::RateLimiter::new(100, 100);
+    h.spawn(peer.run(socket, Arc::new(p2p::DummyAdapter {}), announces, limiter)
+      .map_err(|_| ()));
+    peer
+  }))
+}
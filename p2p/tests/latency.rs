@@ -0,0 +1,61 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_p2p as p2p;
+
+use std::time::{Duration, Instant};
+
+use p2p::Latency;
+
+fn ms(n: u64) -> Duration {
+  Duration::from_millis(n)
+}
+
+#[test]
+fn ping_round_trip() {
+  let start = Instant::now();
+  let mut latency = Latency::new();
+  assert_eq!(latency.rtt(), None);
+
+  latency.ping_sent(1, start);
+  assert_eq!(latency.pong_received(1, start + ms(80)), Some(ms(80)));
+  assert_eq!(latency.rtt(), Some(ms(80)));
+
+  // smoothed, an 1/8th of the way towards the new sample
+  latency.ping_sent(2, start + ms(1000));
+  assert_eq!(latency.pong_received(2, start + ms(1160)), Some(ms(160)));
+  assert_eq!(latency.rtt(), Some(ms(90)));
+}
+
+#[test]
+fn unmatched_pong_ignored() {
+  let start = Instant::now();
+  let mut latency = Latency::new();
+
+  // a pong for nothing, or for the wrong nonce
+  assert_eq!(latency.pong_received(1, start), None);
+  latency.ping_sent(2, start);
+  assert_eq!(latency.pong_received(3, start + ms(10)), None);
+  assert_eq!(latency.rtt(), None);
+
+  // the ping is still waiting, answered once
+  assert_eq!(latency.pong_received(2, start + ms(50)), Some(ms(50)));
+  assert_eq!(latency.pong_received(2, start + ms(60)), None);
+
+  // late pong for a ping another one replaced
+  latency.ping_sent(4, start + ms(1000));
+  latency.ping_sent(5, start + ms(2000));
+  assert_eq!(latency.pong_received(4, start + ms(2010)), None);
+  assert_eq!(latency.rtt(), Some(ms(50)));
+}
@@ -0,0 +1,115 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time;
+
+use futures::future::Future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::hash::ZERO_HASH;
+use core::core::target::Difficulty;
+use p2p::Peer;
+
+// Connects peers advertising different total difficulties to a server at
+// difficulty 1. Only the peers ahead of it should be picked, the heaviest
+// first, the earliest connected on ties, following later pings.
+#[test]
+fn select_most_work_peer() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13436;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server = Arc::new(p2p::Server::new("target/p2p-most-work".to_string(),
+                                         p2p_conf,
+                                         Arc::new(p2p::DummyAdapter {}),
+                                         ZERO_HASH)
+    .unwrap());
+  let run_server = server.start(handle.clone());
+
+  let h = handle.clone();
+  let s = server.clone();
+  let client = wait(&handle, 500)
+    .and_then(move |_| connect(addr, h.clone(), 1).map(move |even| (even, h)))
+    .and_then(move |(even, h)| {
+      // same difficulty as the server, not worth syncing from
+      assert!(s.most_work_peer().is_none());
+      connect(addr, h.clone(), 5)
+        .and_then(move |lighter| {
+          connect(addr, h.clone(), 10).map(move |heavier| (lighter, heavier, h))
+        })
+        .and_then(|(lighter, heavier, h)| {
+          connect(addr, h.clone(), 10).map(move |tied| (vec![even, lighter, heavier, tied], h))
+        })
+        .map(move |res| (res, s))
+    })
+    .and_then(|((peers, h), s)| {
+      assert_eq!(s.peer_count(), 4);
+      assert_eq!(s.most_work_peer().map(|p| p.info.addr), Some(peers[2].1));
+
+      // the lighter peer catches up and more
+      peers[1].0.send_ping(Difficulty::from_num(20), 0).unwrap();
+      wait(&h, 500).map(move |_| (peers, s))
+    })
+    .and_then(|(peers, s)| {
+      let best = s.most_work_peer().unwrap();
+      assert_eq!(best.info.addr, peers[1].1);
+      assert_eq!(best.total_difficulty(), Difficulty::from_num(20));
+      s.stop();
+      Ok(())
+    });
+  handle.spawn(client.map_err(|e| panic!("Client failed: {}", e)));
+
+  evtlp.run(run_server).unwrap();
+}
+
+fn wait(h: &reactor::Handle, ms: u64) -> Box<Future<Item = (), Error = p2p::Error>> {
+  Box::new(reactor::Timeout::new(time::Duration::from_millis(ms), h)
+    .unwrap()
+    .map_err(|e| p2p::Error::IOErr(e)))
+}
+
+// Handshakes with the server advertising the provided total difficulty and
+// gives it a moment to register the peer. Returns the client peer, kept
+// running, and its local address.
+fn connect(addr: SocketAddr,
+           h: reactor::Handle,
+           diff: u32)
+           -> Box<Future<Item = (Arc<Peer>, SocketAddr), Error = p2p::Error>> {
+  let socket = TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e));
+  Box::new(socket.and_then(move |socket| {
+      Peer::connect(socket,
+                    Difficulty::from_num(diff),
+                    0,
+                    &p2p::handshake::Handshake::new(ZERO_HASH))
+    })
+    .and_then(move |(socket, peer)| {
+      let local = socket.local_addr().unwrap();
+      let peer = Arc::new(peer);
+      let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+      let limiter = p2p::RateLimiter::new(100, 100);
+      h.spawn(peer.run(socket, Arc::new(p2p::DummyAdapter {}), announces, limiter)
+        .map_err(|_| ()));
+      wait(&h, 200).map(move |_| (peer, local))
+    }))
+}
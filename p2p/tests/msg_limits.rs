@@ -0,0 +1,129 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{self, Instant};
+
+use futures::future::Future;
+use tokio_core::io::{read_to_end, write_all};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::BlockHeader;
+use core::core::hash::ZERO_HASH;
+use core::core::target::Difficulty;
+use core::ser;
+use p2p::{Headers, PeerAddr, PeerAddrs, Peer, Type};
+
+// Raw message header declaring a body of the provided length.
+fn header(msg_type: Type, len: u64) -> Vec<u8> {
+  let mut buf = vec![0x1e, 0xc5, msg_type as u8];
+  for n in 0..8 {
+    buf.push((len >> (56 - n * 8)) as u8);
+  }
+  buf
+}
+
+// Shakes hands with the server, sends a header declaring a message too large
+// and returns how long it took for the server to hang up on us.
+fn send_oversized(addr: SocketAddr,
+                  h: &reactor::Handle,
+                  msg_type: Type,
+                  len: u64)
+                  -> Box<Future<Item = time::Duration, Error = p2p::Error>> {
+  let connect = TcpStream::connect(&addr, h).map_err(|e| p2p::Error::IOErr(e));
+  Box::new(connect.and_then(|socket| {
+      Peer::connect(socket, Difficulty::one(), 0, &p2p::handshake::Handshake::new(ZERO_HASH))
+    })
+    .and_then(move |(socket, _)| {
+      let sent = Instant::now();
+      write_all(socket, header(msg_type, len))
+        .and_then(|(socket, _)| read_to_end(socket, vec![]))
+        .map(move |_| sent.elapsed())
+        .map_err(|e| p2p::Error::IOErr(e))
+    }))
+}
+
+// Peers declaring messages larger than the configured maximum, or larger
+// than what their message type allows, get disconnected right away, without
+// us waiting for a body we'd have to allocate for.
+#[test]
+fn oversized_messages() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13430;
+  p2p_conf.max_msg_len = 1_000_000;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server = p2p::Server::new("target/p2p-msg-limits".to_string(),
+                                p2p_conf,
+                                Arc::new(p2p::DummyAdapter {}),
+                                ZERO_HASH)
+    .unwrap();
+  handle.spawn(server.start(handle.clone()).map_err(|e| panic!("Server failed: {}", e)));
+
+  let h = handle.clone();
+  let start = reactor::Timeout::new(time::Duration::from_millis(500), &handle).unwrap();
+  let client = start.map_err(|e| p2p::Error::IOErr(e))
+    .and_then(move |_| {
+      let h2 = h.clone();
+      send_oversized(addr, &h, Type::Block, 4_000_000_000)
+        .and_then(move |block_wait| {
+          send_oversized(addr, &h2, Type::Ping, 100_000)
+            .map(move |ping_wait| (block_wait, ping_wait))
+        })
+    });
+  let (block_wait, ping_wait) = evtlp.run(client).unwrap();
+
+  assert!(block_wait < time::Duration::from_secs(1));
+  assert!(ping_wait < time::Duration::from_secs(1));
+}
+
+// Messages our peers would refuse for carrying too many items fail to
+// serialize, before anything gets written.
+#[test]
+fn oversized_writes() {
+  let headers = Headers { headers: vec![BlockHeader::default(); p2p::MAX_BLOCK_HEADERS as usize] };
+  assert!(ser::ser_vec(&headers).is_ok());
+
+  let mut headers = headers;
+  headers.headers.push(BlockHeader::default());
+  let mut buf = vec![];
+  match ser::serialize(&mut buf, &headers) {
+    Err(ser::Error::TooLargeWriteErr) => {}
+    res => panic!("over-limit headers serialized: {:?}", res),
+  }
+  assert!(buf.is_empty());
+
+  let addr: SocketAddr = "10.0.0.1:13414".parse().unwrap();
+  let peers = (0..p2p::MAX_PEER_ADDRS + 1)
+    .map(|_| {
+      PeerAddr {
+        addr: addr,
+        last_seen: 0,
+      }
+    })
+    .collect();
+  match ser::ser_vec(&PeerAddrs { peers: peers }) {
+    Err(ser::Error::TooLargeWriteErr) => {}
+    res => panic!("over-limit peer addresses serialized: {:?}", res),
+  }
+}
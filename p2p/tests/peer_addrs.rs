@@ -0,0 +1,179 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+extern crate time as timestamp;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use futures::future::Future;
+use futures::Stream;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{self, Core};
+
+use core::core::{Block, BlockHeader, Transaction};
+use core::core::hash::{Hash, ZERO_HASH};
+use core::core::target::Difficulty;
+use p2p::{NetAdapter, Peer, PeerAddr};
+
+// Adapter knowing a fixed set of peers and recording the ones it's told about.
+struct AddrsAdapter {
+  known: Vec<PeerAddr>,
+  received: Mutex<Vec<PeerAddr>>,
+}
+
+impl NetAdapter for AddrsAdapter {
+  fn total_difficulty(&self) -> Difficulty {
+    Difficulty::one()
+  }
+  fn transaction_received(&self, _: Transaction) -> p2p::TxStatus {
+    p2p::TxStatus::Accepted
+  }
+  fn block_received(&self, _: Block) -> bool {
+    true
+  }
+  fn headers_received(&self, _: Vec<BlockHeader>) -> bool {
+    true
+  }
+  fn locate_headers(&self, _: Vec<Hash>) -> Vec<BlockHeader> {
+    vec![]
+  }
+  fn get_block(&self, _: Hash) -> Option<Block> {
+    None
+  }
+  fn get_transaction(&self, _: u64) -> Option<Transaction> {
+    None
+  }
+  fn find_peer_addrs(&self, _: p2p::Capabilities) -> Vec<PeerAddr> {
+    self.known.clone()
+  }
+  fn peer_addrs_received(&self, addrs: Vec<PeerAddr>) {
+    self.received.lock().unwrap().extend(addrs);
+  }
+}
+
+// Serves the known addresses on the provided address and asks for them as
+// many times as provided, shaking hands with the provided handshake. Returns
+// the addresses the asking side kept.
+fn exchange(addr: SocketAddr,
+            known: Vec<PeerAddr>,
+            hs: p2p::handshake::Handshake,
+            requests: usize)
+            -> Vec<PeerAddr> {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+  let server_adapter = Arc::new(AddrsAdapter {
+    known: known,
+    received: Mutex::new(vec![]),
+  });
+
+  // serving peer, runs the protocol with its known addresses
+  let listener = TcpListener::bind(&addr, &handle).unwrap();
+  let lhandle = handle.clone();
+  handle.spawn(listener.incoming().take(1).for_each(move |(conn, _)| {
+    let adapter = server_adapter.clone();
+    let run_handle = lhandle.clone();
+    let hs = p2p::handshake::Handshake::new(ZERO_HASH);
+    let accept = Peer::accept(conn, Difficulty::one(), 0, &hs)
+      .and_then(move |(conn, peer)| {
+        let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+        let limiter = p2p::RateLimiter::new(100, 100);
+        let run = peer.run(conn, adapter, announces, limiter);
+        run_handle.spawn(run.then(move |_| {
+          drop(peer);
+          Ok(())
+        }));
+        Ok(())
+      })
+      .map_err(|e| panic!("Accept failed: {}", e));
+    lhandle.spawn(accept);
+    Ok(())
+  }).map_err(|e| panic!("Listener failed: {}", e)));
+
+  let client_adapter = Arc::new(AddrsAdapter {
+    known: vec![],
+    received: Mutex::new(vec![]),
+  });
+  let rhandle = handle.clone();
+  let wait = reactor::Timeout::new(time::Duration::new(1, 0), &handle).unwrap();
+  let received = client_adapter.clone();
+  let socket = TcpStream::connect(&addr, &handle).map_err(|e| p2p::Error::IOErr(e));
+  let client = socket.and_then(move |socket| {
+    Peer::connect(socket, Difficulty::one(), 0, &hs)
+  }).and_then(move |(socket, peer)| {
+    let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+    let limiter = p2p::RateLimiter::new(100, 100);
+    rhandle.spawn(peer.run(socket, client_adapter, announces, limiter).map_err(|e| {
+      panic!("Client run failed: {}", e);
+    }));
+    for _ in 0..requests {
+      peer.send_peer_request(p2p::FULL_SYNC).unwrap();
+    }
+    wait.map_err(|e| p2p::Error::IOErr(e)).map(move |_| drop(peer))
+  });
+  evtlp.run(client).unwrap();
+
+  let received = received.received.lock().unwrap().clone();
+  received
+}
+
+fn peer_addr(addr: &str, last_seen: i64) -> PeerAddr {
+  PeerAddr {
+    addr: addr.parse().unwrap(),
+    last_seen: last_seen,
+  }
+}
+
+// Asks a peer for the addresses it knows. Local ones should be filtered out
+// of the reply, and only the fresh and routable ones kept out of it.
+#[test]
+fn peer_addrs_exchange() {
+  let now = timestamp::now_utc().to_timespec().sec;
+  let fresh = vec![peer_addr("93.184.216.34:13414", now - 60),
+                   peer_addr("[2001:4860::8888]:13414", now - 3600)];
+
+  let mut known = fresh.clone();
+  known.push(peer_addr("127.0.0.1:13414", now));
+  known.push(peer_addr("0.0.0.0:13414", now));
+  known.push(peer_addr("10.0.0.1:13414", now));
+  known.push(peer_addr("192.168.1.1:13414", now));
+  known.push(peer_addr("93.184.216.35:13414", now - p2p::ADDR_MAX_AGE - 60));
+  known.push(peer_addr("93.184.216.36:13414", 0));
+
+  let received = exchange("127.0.0.1:13421".parse().unwrap(),
+                          known,
+                          p2p::handshake::Handshake::new(ZERO_HASH),
+                          1);
+  assert_eq!(received, fresh);
+}
+
+// Addresses relayed by a peer over the number we keep each minute get
+// dropped, even once asked for again.
+#[test]
+fn peer_addrs_throttled() {
+  let now = timestamp::now_utc().to_timespec().sec;
+  let known = vec![peer_addr("93.184.216.34:13414", now),
+                   peer_addr("93.184.216.35:13414", now),
+                   peer_addr("93.184.216.36:13414", now)];
+
+  let mut hs = p2p::handshake::Handshake::new(ZERO_HASH);
+  hs.set_addr_relay(p2p::ADDR_MAX_AGE, 2);
+  let received = exchange("127.0.0.1:13452".parse().unwrap(), known.clone(), hs, 2);
+  assert_eq!(received, known[..2].to_vec());
+}
@@ -0,0 +1,139 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{self, SocketAddr};
+use std::sync::Arc;
+use std::thread;
+use std::time;
+
+use futures::future::{self, Future};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::hash::ZERO_HASH;
+use core::core::target::Difficulty;
+use p2p::{BanReason, Peer};
+
+// Bans a peer and checks it gets refused until the ban window elapses.
+#[test]
+fn ban_lifts_after_window() {
+  let _ = fs::remove_dir_all("target/p2p-ban");
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.ban_window = 1;
+  let server = p2p::Server::new("target/p2p-ban".to_string(),
+                                p2p_conf,
+                                Arc::new(p2p::DummyAdapter {}),
+                                ZERO_HASH)
+    .unwrap();
+
+  let addr: SocketAddr = "10.0.0.1:13414".parse().unwrap();
+  let other: SocketAddr = "10.0.0.2:13414".parse().unwrap();
+  let other_port: SocketAddr = "10.0.0.1:41234".parse().unwrap();
+  assert!(!server.is_banned(addr));
+
+  server.ban_peer(addr, BanReason::Manual);
+  assert!(server.is_banned(addr));
+  assert!(server.is_banned(other_port));
+  assert!(!server.is_banned(other));
+
+  thread::sleep(time::Duration::from_millis(2100));
+  assert!(!server.is_banned(addr));
+  assert!(!server.is_banned(other_port));
+}
+
+// Bans the address an inbound peer connected from, the same peer
+// reconnecting from another ephemeral port must still be refused.
+#[test]
+fn inbound_reconnect_from_banned_ip() {
+  let _ = fs::remove_dir_all("target/p2p-ban-inbound");
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13455;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server = Arc::new(p2p::Server::new("target/p2p-ban-inbound".to_string(),
+                                         p2p_conf,
+                                         Arc::new(p2p::DummyAdapter {}),
+                                         ZERO_HASH)
+    .unwrap());
+  let run_server = server.start(handle.clone());
+
+  // some earlier connection from the same host
+  server.ban_peer(SocketAddr::new(addr.ip(), 40000), BanReason::Manual);
+
+  let h = handle.clone();
+  let s = server.clone();
+  let client = wait(&handle, 500)
+    .and_then(move |_| {
+      TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e))
+    })
+    .and_then(|socket| {
+      Peer::connect(socket,
+                    Difficulty::one(),
+                    0,
+                    &p2p::handshake::Handshake::new(ZERO_HASH))
+        .then(|res| future::ok(res.is_ok()))
+    })
+    .and_then(move |connected| {
+      assert!(!connected, "handshake with a banned IP succeeded");
+      assert_eq!(s.peer_count(), 0);
+      s.stop();
+      Ok(())
+    });
+  handle.spawn(client.map_err(|e| panic!("Client failed: {}", e)));
+
+  evtlp.run(run_server).unwrap();
+}
+
+// Connects to a peer answering our handshake with garbage, which should get
+// it banned and stop our attempts at reconnecting to it.
+#[test]
+fn outbound_bad_handshake_banned() {
+  let _ = fs::remove_dir_all("target/p2p-ban-outbound");
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let addr: SocketAddr = "127.0.0.1:13463".parse().unwrap();
+  let listener = net::TcpListener::bind(addr).unwrap();
+  thread::spawn(move || {
+    for stream in listener.incoming() {
+      let mut stream = stream.unwrap();
+      stream.write_all(&[0xff; 16]).unwrap();
+      let _ = stream.read_to_end(&mut vec![]);
+    }
+  });
+
+  let server = p2p::Server::new("target/p2p-ban-outbound".to_string(),
+                                p2p::P2PConfig::default(),
+                                Arc::new(p2p::DummyAdapter {}),
+                                ZERO_HASH)
+    .unwrap();
+  evtlp.run(server.connect_peer(addr, handle)).unwrap();
+  assert!(server.is_banned(addr));
+  assert_eq!(server.peer_count(), 0);
+}
+
+fn wait(h: &reactor::Handle, ms: u64) -> Box<Future<Item = (), Error = p2p::Error>> {
+  Box::new(reactor::Timeout::new(time::Duration::from_millis(ms), h)
+    .unwrap()
+    .map_err(|e| p2p::Error::IOErr(e)))
+}
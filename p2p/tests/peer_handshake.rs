@@ -26,7 +26,7 @@ use futures::future::Future;
 use tokio_core::net::TcpStream;
 use tokio_core::reactor::{self, Core};
 
-use core::ser;
+use core::core::hash::ZERO_HASH;
 use core::core::target::Difficulty;
 use p2p::Peer;
 
@@ -39,25 +39,31 @@ fn peer_handshake() {
   let handle = evtlp.handle();
   let p2p_conf = p2p::P2PConfig::default();
   let net_adapter = Arc::new(p2p::DummyAdapter{});
-  let server = p2p::Server::new(p2p_conf, net_adapter.clone());
+  let server = p2p::Server::new("target/p2p-handshake".to_string(),
+                                p2p_conf,
+                                net_adapter.clone(),
+                                ZERO_HASH)
+    .unwrap();
   let run_server = server.start(handle.clone());
 
   let phandle = handle.clone();
   let rhandle = handle.clone();
   let timeout = reactor::Timeout::new(time::Duration::new(1, 0), &handle).unwrap();
   let timeout_send = reactor::Timeout::new(time::Duration::new(2, 0), &handle).unwrap();
-  handle.spawn(timeout.map_err(|e| ser::Error::IOErr(e)).and_then(move |_| {
+  handle.spawn(timeout.map_err(|e| p2p::Error::IOErr(e)).and_then(move |_| {
     let p2p_conf = p2p::P2PConfig::default();
     let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
-    let socket = TcpStream::connect(&addr, &phandle).map_err(|e| ser::Error::IOErr(e));
+    let socket = TcpStream::connect(&addr, &phandle).map_err(|e| p2p::Error::IOErr(e));
     socket.and_then(move |socket| {
-      Peer::connect(socket, Difficulty::one(), &p2p::handshake::Handshake::new())
+      Peer::connect(socket, Difficulty::one(), 0, &p2p::handshake::Handshake::new(ZERO_HASH))
 		}).and_then(move |(socket, peer)| {
-      rhandle.spawn(peer.run(socket, net_adapter.clone()).map_err(|e| {
+      let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+      let limiter = p2p::RateLimiter::new(100, 100);
+      rhandle.spawn(peer.run(socket, net_adapter.clone(), announces, limiter).map_err(|e| {
         panic!("Client run failed: {}", e);
       }));
-      peer.send_ping().unwrap();
-      timeout_send.map_err(|e| ser::Error::IOErr(e)).map(|_| peer)
+      peer.send_ping(Difficulty::one(), 0).unwrap();
+      timeout_send.map_err(|e| p2p::Error::IOErr(e)).map(|_| peer)
 		}).and_then(|peer| {
       let (sent, recv) = peer.transmitted_bytes();
       assert!(sent > 0);
@@ -0,0 +1,120 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time;
+
+use futures::future::Future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::hash::ZERO_HASH;
+use core::core::target::Difficulty;
+use p2p::Peer;
+
+// Connects peers advertising different heights, one of them claiming more
+// blocks than its total difficulty allows for. The highest height should be
+// the one of the heaviest honest peer, following later pings.
+#[test]
+fn max_honest_peer_height() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13448;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server = Arc::new(p2p::Server::new("target/p2p-peer-height".to_string(),
+                                         p2p_conf,
+                                         Arc::new(p2p::DummyAdapter {}),
+                                         ZERO_HASH)
+    .unwrap());
+  let run_server = server.start(handle.clone());
+
+  let h = handle.clone();
+  let s = server.clone();
+  let client = wait(&handle, 500)
+    .and_then(move |_| connect(addr, h.clone(), 10, 5).map(move |low| (low, h)))
+    .and_then(move |(low, h)| {
+      connect(addr, h.clone(), 20, 12)
+        .and_then(move |high| {
+          connect(addr, h.clone(), 5, 100).map(move |liar| (low, high, liar, h))
+        })
+        .map(move |res| (res, s))
+    })
+    .and_then(|((low, high, liar, h), s)| {
+      assert_eq!(s.peer_count(), 3);
+      assert_eq!(s.max_peer_height(), 12);
+      let remote = |local: SocketAddr| {
+        s.connected_peers().into_iter().find(|p| p.info.addr == local).unwrap()
+      };
+      assert_eq!(remote(low.1).height(), 5);
+      assert!(!remote(high.1).is_suspicious());
+      assert!(remote(liar.1).is_suspicious());
+      assert_eq!(remote(liar.1).height(), 0);
+
+      // the lower peer catches up and more, the liar keeps lying
+      low.0.send_ping(Difficulty::from_num(30), 20).unwrap();
+      liar.0.send_ping(Difficulty::from_num(5), 1000).unwrap();
+      wait(&h, 500).map(move |_| (vec![low, high, liar], s))
+    })
+    .and_then(|(peers, s)| {
+      assert_eq!(s.max_peer_height(), 20);
+      // and the server told everyone about its own chain
+      assert_eq!(peers[1].0.height(), 0);
+      assert!(!peers[1].0.is_suspicious());
+      s.stop();
+      Ok(())
+    });
+  handle.spawn(client.map_err(|e| panic!("Client failed: {}", e)));
+
+  evtlp.run(run_server).unwrap();
+}
+
+fn wait(h: &reactor::Handle, ms: u64) -> Box<Future<Item = (), Error = p2p::Error>> {
+  Box::new(reactor::Timeout::new(time::Duration::from_millis(ms), h)
+    .unwrap()
+    .map_err(|e| p2p::Error::IOErr(e)))
+}
+
+// Handshakes with the server advertising the provided total difficulty and
+// height, and gives it a moment to register the peer. Returns the client
+// peer, kept running, and its local address.
+fn connect(addr: SocketAddr,
+           h: reactor::Handle,
+           diff: u32,
+           height: u64)
+           -> Box<Future<Item = (Arc<Peer>, SocketAddr), Error = p2p::Error>> {
+  let socket = TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e));
+  Box::new(socket.and_then(move |socket| {
+      Peer::connect(socket,
+                    Difficulty::from_num(diff),
+                    height,
+                    &p2p::handshake::Handshake::new(ZERO_HASH))
+    })
+    .and_then(move |(socket, peer)| {
+      let local = socket.local_addr().unwrap();
+      let peer = Arc::new(peer);
+      let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+      let limiter = p2p::RateLimiter::new(100, 100);
+      h.spawn(peer.run(socket, Arc::new(p2p::DummyAdapter {}), announces, limiter)
+        .map_err(|_| ()));
+      wait(&h, 200).map(move |_| (peer, local))
+    }))
+}
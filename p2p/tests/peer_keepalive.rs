@@ -0,0 +1,82 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{self, Instant};
+
+use futures::future::Future;
+use futures::Stream;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{self, Core};
+
+use core::core::hash::ZERO_HASH;
+use core::core::target::Difficulty;
+use p2p::Peer;
+
+// Connects to a peer that completes the handshake but never answers
+// anything afterward. Our keepalive should give up on it once the ping
+// timeout elapses.
+#[test]
+fn dead_peer_timeout() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+  let addr: SocketAddr = "127.0.0.1:13420".parse().unwrap();
+
+  // silent peer, accepts and handshakes but doesn't run the protocol
+  let listener = TcpListener::bind(&addr, &handle).unwrap();
+  let lhandle = handle.clone();
+  handle.spawn(listener.incoming().take(1).for_each(move |(conn, _)| {
+    let hold_handle = lhandle.clone();
+    let hs = p2p::handshake::Handshake::new(ZERO_HASH);
+    let accept = Peer::accept(conn, Difficulty::one(), 0, &hs)
+      .map_err(|e| panic!("Accept failed: {}", e))
+      .and_then(move |(conn, _)| {
+        let hold = reactor::Timeout::new(time::Duration::new(10, 0), &hold_handle).unwrap();
+        hold.map(move |_| drop(conn)).map_err(|_| ())
+      });
+    lhandle.spawn(accept);
+    Ok(())
+  }).map_err(|e| panic!("Listener failed: {}", e)));
+
+  let start = Instant::now();
+  let net_adapter = Arc::new(p2p::DummyAdapter{});
+  let socket = TcpStream::connect(&addr, &handle).map_err(|e| p2p::Error::IOErr(e));
+  let client = socket.and_then(move |socket| {
+    Peer::connect(socket, Difficulty::one(), 0, &p2p::handshake::Handshake::new(ZERO_HASH))
+  }).and_then(move |(socket, peer)| {
+    let peer = Arc::new(peer);
+    let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+    let limiter = p2p::RateLimiter::new(100, 100);
+    let keepalive = Peer::keepalive(peer.clone(),
+                                    net_adapter.clone(),
+                                    time::Duration::from_secs(1),
+                                    time::Duration::from_secs(2));
+    peer.run(socket, net_adapter, announces, limiter)
+      .select(keepalive)
+      .map(|_| ())
+      .map_err(|(e, _)| e)
+  });
+
+  match evtlp.run(client) {
+    Err(p2p::Error::Timeout) => {}
+    res => panic!("Expected a timeout, got {:?}", res.err()),
+  }
+  assert!(start.elapsed() < time::Duration::from_secs(8));
+}
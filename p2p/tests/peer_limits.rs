@@ -0,0 +1,113 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time;
+
+use futures::future::Future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::hash::ZERO_HASH;
+use core::core::target::Difficulty;
+use p2p::Peer;
+
+// Connects a client peer to the server and runs it, the returned future
+// resolves to true once the server hung up on it.
+fn run_client(addr: SocketAddr,
+              h: reactor::Handle)
+              -> Box<Future<Item = bool, Error = p2p::Error>> {
+  let socket = TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e));
+  Box::new(socket.and_then(move |socket| {
+    Peer::connect(socket, Difficulty::one(), 0, &p2p::handshake::Handshake::new(ZERO_HASH))
+  }).and_then(move |(socket, peer)| {
+    let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+    let limiter = p2p::RateLimiter::new(100, 100);
+    let adapter = Arc::new(p2p::DummyAdapter {});
+    let run = peer.run(socket, adapter, announces, limiter);
+    let wait = reactor::Timeout::new(time::Duration::new(3, 0), &h).unwrap();
+    run.then(|_| Ok(true))
+      .select(wait.then(|_| Ok(false)))
+      .map(move |(hung_up, _)| {
+        drop(peer);
+        hung_up
+      })
+      .map_err(|(e, _)| e)
+  }))
+}
+
+// Fills up the inbound slots of a server, the next inbound peer should get
+// disconnected right after its handshake while the server can still connect
+// to other peers.
+#[test]
+fn max_inbound_peers() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13422;
+  p2p_conf.max_inbound = 1;
+  p2p_conf.max_peers = 2;
+  let server = p2p::Server::new("target/p2p-limits".to_string(),
+                                p2p_conf.clone(),
+                                Arc::new(p2p::DummyAdapter {}),
+                                ZERO_HASH)
+    .unwrap();
+  let run_server = server.start(handle.clone());
+
+  let mut other_conf = p2p::P2PConfig::default();
+  other_conf.port = 13423;
+  let other = p2p::Server::new("target/p2p-limits-other".to_string(),
+                               other_conf.clone(),
+                               Arc::new(p2p::DummyAdapter {}),
+                               ZERO_HASH)
+    .unwrap();
+  handle.spawn(other.start(handle.clone()).map_err(|e| panic!("Other server failed: {}", e)));
+
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let other_addr = SocketAddr::new(other_conf.host, other_conf.port);
+  let h = handle.clone();
+  let h3 = handle.clone();
+  let start = reactor::Timeout::new(time::Duration::new(1, 0), &handle).unwrap();
+  handle.spawn(start.map_err(|e| p2p::Error::IOErr(e)).and_then(move |_| {
+    // first inbound peer takes the only inbound slot and stays connected
+    h.spawn(run_client(addr, h.clone()).map(|_| ()).map_err(|_| ()));
+    let wait = reactor::Timeout::new(time::Duration::new(1, 0), &h).unwrap();
+    let h2 = h.clone();
+    wait.map_err(|e| p2p::Error::IOErr(e)).and_then(move |_| run_client(addr, h2))
+  }).and_then(move |hung_up| {
+    assert!(hung_up);
+    assert_eq!(server.peer_count(), 1);
+
+    // outbound connections are still allowed
+    let connect = server.connect_peer(other_addr, h3.clone());
+    h3.spawn(connect.map_err(|e| panic!("Outbound connection failed: {}", e)));
+    let wait = reactor::Timeout::new(time::Duration::new(1, 0), &h3).unwrap();
+    wait.map_err(|e| p2p::Error::IOErr(e)).map(move |_| server)
+  }).and_then(|server| {
+    assert_eq!(server.peer_count(), 2);
+    server.stop();
+    Ok(())
+  }).map_err(|e| {
+    panic!("Client connection failed: {}", e);
+  }));
+
+  evtlp.run(run_server).unwrap();
+}
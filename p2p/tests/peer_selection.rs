@@ -0,0 +1,73 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+extern crate grin_p2p as p2p;
+
+use std::net::SocketAddr;
+
+use p2p::{addr_group, select_outbound};
+
+fn addr(s: &str) -> SocketAddr {
+  s.parse().unwrap()
+}
+
+#[test]
+fn network_groups() {
+  assert_eq!(addr_group(&addr("10.1.2.3:13414")), addr_group(&addr("10.1.200.1:80")));
+  assert!(addr_group(&addr("10.1.2.3:13414")) != addr_group(&addr("10.2.2.3:13414")));
+  assert_eq!(addr_group(&addr("[2001:db8:1::1]:13414")),
+             addr_group(&addr("[2001:db8:ffff::2]:13414")));
+  assert!(addr_group(&addr("[2001:db8::1]:13414")) != addr_group(&addr("[2001:db9::1]:13414")));
+  // mapped IPv4 addresses are just IPv4
+  assert_eq!(addr_group(&addr("[::ffff:10.1.2.3]:13414")), addr_group(&addr("10.1.9.9:13414")));
+  assert!(addr_group(&addr("[::1]:13414")) != addr_group(&addr("0.0.0.1:13414")));
+}
+
+// Most of the candidates are in the same /16, only a couple of them should
+// be picked with the others going to different groups.
+#[test]
+fn diversify_dominated_candidates() {
+  let mut candidates = (1..20).map(|n| addr(&format!("10.1.0.{}:13414", n))).collect::<Vec<_>>();
+  candidates.insert(5, addr("10.2.0.1:13414"));
+  candidates.push(addr("10.3.0.1:13414"));
+
+  let selected = select_outbound(vec![candidates.clone()], &[], 2, 8);
+  assert_eq!(selected,
+             vec![addr("10.1.0.1:13414"),
+                  addr("10.1.0.2:13414"),
+                  addr("10.2.0.1:13414"),
+                  addr("10.3.0.1:13414")]);
+
+  // a connection we already have counts toward its group
+  let connected = vec![addr("10.1.0.1:13414")];
+  let selected = select_outbound(vec![candidates.clone()], &connected, 2, 8);
+  assert_eq!(selected,
+             vec![addr("10.1.0.2:13414"), addr("10.2.0.1:13414"), addr("10.3.0.1:13414")]);
+
+  let selected = select_outbound(vec![candidates], &[], 2, 2);
+  assert_eq!(selected.len(), 2);
+}
+
+// Candidates from different sources are taken in turn, a source with many
+// of them doesn't get to fill all the slots.
+#[test]
+fn spread_across_sources() {
+  let big = (1..10).map(|n| addr(&format!("10.{}.0.1:13414", n))).collect::<Vec<_>>();
+  let small = vec![addr("20.1.0.1:13414"), addr("20.2.0.1:13414")];
+  let selected = select_outbound(vec![big, small, vec![]], &[], 1, 4);
+  assert_eq!(selected,
+             vec![addr("10.1.0.1:13414"),
+                  addr("20.1.0.1:13414"),
+                  addr("10.2.0.1:13414"),
+                  addr("20.2.0.1:13414")]);
+}
@@ -0,0 +1,88 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time;
+
+use futures::future::Future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::hash::ZERO_HASH;
+use core::core::target::Difficulty;
+use core::ser;
+use p2p::{Peer, Type};
+
+// Pings a server and checks both sides accounted for the exact size of the
+// ping and pong messages exchanged, as well as for their type.
+#[test]
+fn ping_stats() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13428;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let net_adapter = Arc::new(p2p::DummyAdapter {});
+  let server = p2p::Server::new("target/p2p-stats".to_string(),
+                                p2p_conf,
+                                net_adapter.clone(),
+                                ZERO_HASH)
+    .unwrap();
+  let run_server = server.start(handle.clone());
+
+  // both ping and pong carry a difficulty of one
+  let mut diff_data = vec![];
+  ser::serialize(&mut diff_data, &Difficulty::one()).unwrap();
+  let msg_len = p2p::HEADER_LEN + diff_data.len() as u64;
+
+  let h = handle.clone();
+  let rhandle = handle.clone();
+  let start = reactor::Timeout::new(time::Duration::new(1, 0), &handle).unwrap();
+  handle.spawn(start.map_err(|e| p2p::Error::IOErr(e)).and_then(move |_| {
+    TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e))
+  }).and_then(|socket| {
+    Peer::connect(socket, Difficulty::one(), 0, &p2p::handshake::Handshake::new(ZERO_HASH))
+  }).and_then(move |(socket, peer)| {
+    let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+    let limiter = p2p::RateLimiter::new(100, 100);
+    rhandle.spawn(peer.run(socket, net_adapter, announces, limiter).map_err(|_| ()));
+    peer.send_ping(Difficulty::one(), 0).unwrap();
+    let wait = reactor::Timeout::new(time::Duration::new(1, 0), &rhandle).unwrap();
+    wait.map_err(|e| p2p::Error::IOErr(e)).map(move |_| peer)
+  }).and_then(move |peer| {
+    let stats = peer.stats();
+    assert_eq!(stats.sent(Type::Ping), 1);
+    assert_eq!(stats.received(Type::Pong), 1);
+    assert_eq!(stats.received(Type::Ping), 0);
+    assert_eq!(stats.sent_bytes, msg_len);
+    assert_eq!(stats.received_bytes, msg_len);
+    assert_eq!(server.total_bandwidth(), (msg_len, msg_len));
+
+    // the pong is a reply straight from the handler, still counted
+    let remote = server.connected_peers()[0].stats();
+    assert_eq!(remote.received(Type::Ping), 1);
+    assert_eq!(remote.sent(Type::Pong), 1);
+    server.stop();
+    Ok(())
+  }).map_err(|e| panic!("Client connection failed: {}", e)));
+
+  evtlp.run(run_server).unwrap();
+}
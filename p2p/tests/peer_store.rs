@@ -0,0 +1,63 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_p2p as p2p;
+
+use std::fs;
+
+use p2p::{PeerStore, PeerData, State};
+
+fn peer(port: u16, last_seen: i64) -> PeerData {
+  PeerData {
+    addr: format!("10.0.0.1:{}", port).parse().unwrap(),
+    last_seen: last_seen,
+    success_count: 1,
+    flags: State::Healthy,
+    ban_expiry: 0,
+    handshake_timeouts: 0,
+  }
+}
+
+#[test]
+fn peer_store_roundtrip() {
+  let _ = fs::remove_dir_all("target/p2p-peer-store");
+  let store = PeerStore::new("target/p2p-peer-store".to_string()).unwrap();
+
+  let mut p = peer(13414, 1000);
+  p.success_count = 3;
+  p.flags = State::Banned;
+  store.save_peer(&p).unwrap();
+  assert_eq!(store.get_peer(p.addr).unwrap(), p);
+  assert!(store.get_peer(peer(1, 0).addr).is_err());
+
+  store.save_peer(&peer(2000, 50)).unwrap();
+  store.save_peer(&peer(3000, 5000)).unwrap();
+  let seen = store.all_peers().iter().map(|p| p.last_seen).collect::<Vec<_>>();
+  assert_eq!(seen, vec![5000, 1000, 50]);
+}
+
+// Reputations are kept by IP address, whatever the port, and don't add to
+// the peers we know of.
+#[test]
+fn reputation_by_ip() {
+  let _ = fs::remove_dir_all("target/p2p-peer-store-rep");
+  let store = PeerStore::new("target/p2p-peer-store-rep".to_string()).unwrap();
+
+  let ip = peer(41000, 0).addr.ip();
+  assert!(store.get_reputation(ip).is_err());
+  store.save_reputation(ip, -25).unwrap();
+  store.save_reputation(peer(41001, 0).addr.ip(), -30).unwrap();
+  assert_eq!(store.get_reputation(ip).unwrap(), -30);
+  assert!(store.all_peers().is_empty());
+}
@@ -0,0 +1,153 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time;
+
+use futures::future::Future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::hash::ZERO_HASH;
+use core::core::target::Difficulty;
+use p2p::{MsgCategory, MsgRateLimit, Peer, RateLimiter};
+
+// Each category of messages has its own allowance, a transaction flood
+// shouldn't get in the way of blocks. Past the burst, reading has to wait
+// for the bucket to refill.
+#[test]
+fn category_burst_allowed() {
+  let limiter = RateLimiter::new(10, 5);
+  for _ in 0..5 {
+    assert!(limiter.take(MsgCategory::Transactions).is_none());
+  }
+  let wait = limiter.take(MsgCategory::Transactions).unwrap();
+  assert!(wait > time::Duration::from_millis(50));
+  assert!(limiter.take(MsgCategory::Blocks).is_none());
+  assert_eq!(limiter.ban_score(), 0);
+
+  // the allowance comes back with time
+  thread::sleep(time::Duration::from_millis(200));
+  assert!(limiter.take(MsgCategory::Transactions).is_none());
+}
+
+// Staying under the rate never has to wait.
+#[test]
+fn steady_peer_not_throttled() {
+  let limiter = RateLimiter::new(100, 2);
+  for _ in 0..10 {
+    assert!(limiter.take(MsgCategory::Control).is_none());
+    thread::sleep(time::Duration::from_millis(20));
+  }
+  assert_eq!(limiter.ban_score(), 0);
+}
+
+// Every few dropped messages cost a point of ban score and points get
+// forgiven over time, so a peer that misbehaved once isn't banned forever.
+#[test]
+fn ban_score_decays() {
+  let limiter = RateLimiter::with_decay(10, 5, time::Duration::from_millis(200));
+  for _ in 0..25 {
+    limiter.dropped();
+  }
+  assert_eq!(limiter.ban_score(), 2);
+  limiter.penalize(10);
+  assert_eq!(limiter.ban_score(), 12);
+
+  thread::sleep(time::Duration::from_millis(500));
+  assert_eq!(limiter.ban_score(), 10);
+  thread::sleep(time::Duration::from_millis(2000));
+  assert_eq!(limiter.ban_score(), 0);
+}
+
+// A peer pinging us way over its rate should get read from no faster than
+// the rate, the pings it keeps sending meanwhile being dropped.
+#[test]
+fn flooding_peer_throttled() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13443;
+  p2p_conf.msg_rate_limit = MsgRateLimit {
+    rate: 100,
+    burst: 10,
+  };
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server = Arc::new(p2p::Server::new("target/p2p-msg-rate".to_string(),
+                                         p2p_conf,
+                                         Arc::new(p2p::DummyAdapter {}),
+                                         ZERO_HASH)
+    .unwrap());
+  let run_server = server.start(handle.clone());
+
+  let h = handle.clone();
+  let s = server.clone();
+  let client = wait(&handle, 500)
+    .and_then(move |_| {
+      TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e)).map(|socket| (socket, h))
+    })
+    .and_then(|(socket, h)| {
+      Peer::connect(socket,
+                    Difficulty::one(),
+                    0,
+                    &p2p::handshake::Handshake::new(ZERO_HASH))
+        .map(move |res| (res, h))
+    })
+    .and_then(move |((socket, peer), h)| {
+      let local = socket.local_addr().unwrap();
+      let peer = Arc::new(peer);
+      let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+      h.spawn(peer.run(socket,
+                       Arc::new(p2p::DummyAdapter {}),
+                       announces,
+                       RateLimiter::new(10000, 10000))
+        .map_err(|_| ()));
+      wait(&h, 200).map(move |_| (peer, local, h))
+    })
+    .and_then(move |(peer, local, h)| {
+      let remote = s.connected_peers()[0].clone();
+      for _ in 0..2000 {
+        let _ = peer.send_ping(Difficulty::one(), 0);
+      }
+      wait(&h, 1000).map(move |_| (remote, local, s))
+    })
+    .and_then(|(remote, local, s)| {
+      // a burst and a second worth of pings, give or take timer ticks
+      let read = remote.stats().received(p2p::Type::Ping);
+      assert!(read > 10 && read < 500, "read {} pings", read);
+      // throttled rather than banned
+      assert!(remote.ban_score() < p2p::MAX_BAN_SCORE);
+      assert!(!s.is_banned(local));
+      assert_eq!(s.peer_count(), 1);
+      s.stop();
+      Ok(())
+    });
+  handle.spawn(client.map_err(|e| panic!("Client failed: {}", e)));
+
+  evtlp.run(run_server).unwrap();
+}
+
+fn wait(h: &reactor::Handle, ms: u64) -> Box<Future<Item = (), Error = p2p::Error>> {
+  Box::new(reactor::Timeout::new(time::Duration::from_millis(ms), h)
+    .unwrap()
+    .map_err(|e| p2p::Error::IOErr(e)))
+}
@@ -0,0 +1,66 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_p2p as p2p;
+extern crate futures;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::Future;
+use p2p::{Reconnector, Sleep};
+
+// Timer that doesn't wait, only records what it was asked for.
+struct MockSleep {
+  sleeps: Arc<Mutex<Vec<Duration>>>,
+}
+
+impl Sleep for MockSleep {
+  fn sleep(&self, d: Duration) -> Box<Future<Item = (), Error = p2p::Error>> {
+    self.sleeps.lock().unwrap().push(d);
+    Box::new(futures::finished(()))
+  }
+}
+
+// Walks through a peer failing consecutively, the delay should double up to
+// the max delay and the reconnector should give up after max failures. A
+// successful handshake starts over.
+#[test]
+fn reconnect_backoff() {
+  let sleeps = Arc::new(Mutex::new(vec![]));
+  let reconnects = Reconnector::new(Duration::from_secs(5),
+                                    Duration::from_secs(30),
+                                    5,
+                                    Box::new(MockSleep { sleeps: sleeps.clone() }));
+  let addr: SocketAddr = "10.0.0.1:13414".parse().unwrap();
+  let other: SocketAddr = "10.0.0.2:13414".parse().unwrap();
+
+  for _ in 0..5 {
+    reconnects.wait(addr).unwrap().wait().unwrap();
+    reconnects.failed(addr);
+  }
+  assert!(reconnects.wait(addr).is_none());
+  assert_eq!(*sleeps.lock().unwrap(),
+             vec![5, 10, 20, 30, 30].into_iter().map(Duration::from_secs).collect::<Vec<_>>());
+
+  // other peers aren't affected
+  assert_eq!(reconnects.delay(other), Some(Duration::from_secs(5)));
+
+  // a successful handshake resets the backoff
+  reconnects.connected(addr);
+  assert_eq!(reconnects.delay(addr), Some(Duration::from_secs(5)));
+  reconnects.failed(addr);
+  assert_eq!(reconnects.delay(addr), Some(Duration::from_secs(10)));
+}
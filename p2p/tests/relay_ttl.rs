@@ -0,0 +1,117 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time;
+
+use futures::future::Future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::Block;
+use core::core::hash::{Hash, ZERO_HASH};
+use core::core::target::Difficulty;
+use p2p::{Peer, RelayCache, Type};
+
+// Relays the same block twice to a connected peer, only the first one should
+// go out until the relay TTL expires.
+#[test]
+fn relay_once_within_ttl() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13449;
+  p2p_conf.relay_ttl = 1;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server = Arc::new(p2p::Server::new("target/p2p-relay-ttl".to_string(),
+                                         p2p_conf,
+                                         Arc::new(p2p::DummyAdapter {}),
+                                         ZERO_HASH)
+    .unwrap());
+  let run_server = server.start(handle.clone());
+
+  let b = Block::default();
+  let h = handle.clone();
+  let s = server.clone();
+  let client = wait(&handle, 500)
+    .and_then(move |_| {
+      TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e)).map(|socket| (socket, h))
+    })
+    .and_then(|(socket, h)| {
+      Peer::connect(socket, Difficulty::one(), 0, &p2p::handshake::Handshake::new(ZERO_HASH))
+        .map(move |res| (res, h))
+    })
+    .and_then(move |((socket, peer), h)| {
+      let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+      let limiter = p2p::RateLimiter::new(100, 100);
+      h.spawn(peer.run(socket, Arc::new(p2p::DummyAdapter {}), announces, limiter)
+        .map_err(|_| ()));
+      wait(&h, 200).map(move |_| (peer, h))
+    })
+    .and_then(move |(peer, h)| {
+      s.broadcast_block(&b);
+      s.broadcast_block(&b);
+      assert_eq!(sent_blocks(&s), 1);
+
+      wait(&h, 1100).map(move |_| {
+        s.broadcast_block(&b);
+        assert_eq!(sent_blocks(&s), 2);
+        s.stop();
+        drop(peer);
+      })
+    });
+  handle.spawn(client.map_err(|e| panic!("Client failed: {}", e)));
+
+  evtlp.run(run_server).unwrap();
+}
+
+// Relayed hashes are forgotten once they go past the TTL or get evicted by
+// newer ones when the cache is full.
+#[test]
+fn relayed_hashes_expire() {
+  let cache = RelayCache::new(10, 1);
+  assert!(cache.relaying(Hash([1; 32])));
+  assert!(!cache.relaying(Hash([1; 32])));
+  assert!(cache.relayed(&Hash([1; 32])));
+  thread::sleep(time::Duration::from_millis(1100));
+  assert!(!cache.relayed(&Hash([1; 32])));
+  assert!(cache.relaying(Hash([1; 32])));
+  assert_eq!(cache.len(), 1);
+
+  let cache = RelayCache::new(2, 60);
+  for n in 1..4 {
+    assert!(cache.relaying(Hash([n; 32])));
+  }
+  assert_eq!(cache.len(), 2);
+  assert!(!cache.relayed(&Hash([1; 32])));
+  assert!(cache.relayed(&Hash([3; 32])));
+}
+
+// Blocks the server sent to its only peer.
+fn sent_blocks(s: &p2p::Server) -> u64 {
+  s.connected_peers()[0].stats().sent(Type::Block)
+}
+
+fn wait(h: &reactor::Handle, ms: u64) -> Box<Future<Item = (), Error = p2p::Error>> {
+  Box::new(reactor::Timeout::new(time::Duration::from_millis(ms), h)
+    .unwrap()
+    .map_err(|e| p2p::Error::IOErr(e)))
+}
@@ -0,0 +1,113 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time;
+
+use futures::future::Future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::hash::ZERO_HASH;
+use core::core::target::Difficulty;
+use p2p::Peer;
+
+// Fills up the server with two inbound peers, the one with the lowest
+// reputation should get dropped when a new one with a better reputation
+// shows up.
+#[test]
+fn evict_lowest_reputation() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let _ = fs::remove_dir_all("target/p2p-reputation");
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13442;
+  p2p_conf.max_inbound = 2;
+  p2p_conf.max_peers = 2;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server = Arc::new(p2p::Server::new("target/p2p-reputation".to_string(),
+                                         p2p_conf,
+                                         Arc::new(p2p::DummyAdapter {}),
+                                         ZERO_HASH)
+    .unwrap());
+  let run_server = server.start(handle.clone());
+
+  let h = handle.clone();
+  let s = server.clone();
+  let client = wait(&handle, 500)
+    .and_then(move |_| connect(addr, h.clone()).map(move |first| (first, h)))
+    .and_then(move |(first, h)| {
+      connect(addr, h.clone()).map(move |second| (first, second, h, s))
+    })
+    .and_then(|((_first, first_addr), (_second, second_addr), h, s)| {
+      assert_eq!(s.peer_count(), 2);
+      for p in s.connected_peers() {
+        if p.info.addr == first_addr {
+          p.adjust_reputation(p2p::BLOCK_REPUTATION);
+        } else {
+          p.adjust_reputation(p2p::INVALID_MSG_REPUTATION);
+        }
+      }
+      connect(addr, h.clone()).and_then(move |(_third, third_addr)| {
+        wait(&h, 500).map(move |_| (first_addr, second_addr, third_addr, s))
+      })
+    })
+    .and_then(|(first_addr, second_addr, third_addr, s)| {
+      let addrs = s.connected_peers().iter().map(|p| p.info.addr).collect::<Vec<_>>();
+      assert_eq!(addrs, vec![first_addr, third_addr]);
+      assert!(!addrs.contains(&second_addr));
+      s.stop();
+      Ok(())
+    });
+  handle.spawn(client.map_err(|e| panic!("Client failed: {}", e)));
+
+  evtlp.run(run_server).unwrap();
+}
+
+fn wait(h: &reactor::Handle, ms: u64) -> Box<Future<Item = (), Error = p2p::Error>> {
+  Box::new(reactor::Timeout::new(time::Duration::from_millis(ms), h)
+    .unwrap()
+    .map_err(|e| p2p::Error::IOErr(e)))
+}
+
+// Handshakes with the server and gives it a moment to register the peer.
+// Returns the client peer, kept running, and its local address.
+fn connect(addr: SocketAddr,
+           h: reactor::Handle)
+           -> Box<Future<Item = (Arc<Peer>, SocketAddr), Error = p2p::Error>> {
+  let socket = TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e));
+  Box::new(socket.and_then(move |socket| {
+      Peer::connect(socket,
+                    Difficulty::one(),
+                    0,
+                    &p2p::handshake::Handshake::new(ZERO_HASH))
+    })
+    .and_then(move |(socket, peer)| {
+      let local = socket.local_addr().unwrap();
+      let peer = Arc::new(peer);
+      let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+      let limiter = p2p::RateLimiter::new(100, 100);
+      h.spawn(peer.run(socket, Arc::new(p2p::DummyAdapter {}), announces, limiter)
+        .map_err(|_| ()));
+      wait(&h, 200).map(move |_| (peer, local))
+    }))
+}
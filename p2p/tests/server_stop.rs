@@ -0,0 +1,84 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::{self, SocketAddr};
+use std::sync::Arc;
+use std::time;
+
+use futures::future::Future;
+use futures::sync::oneshot;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::hash::ZERO_HASH;
+use core::core::target::Difficulty;
+use p2p::Peer;
+
+// Connects a client peer to a server and stops the server. The client should
+// get disconnected, the server should forget about it and the server port
+// should be free to bind again.
+#[test]
+fn graceful_stop() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13427;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let net_adapter = Arc::new(p2p::DummyAdapter {});
+  let server = Arc::new(p2p::Server::new("target/p2p-stop".to_string(),
+                                         p2p_conf,
+                                         net_adapter.clone(),
+                                         ZERO_HASH)
+    .unwrap());
+  let run_server = server.start(handle.clone());
+
+  let (done_tx, done_rx) = oneshot::channel();
+  let h = handle.clone();
+  let rhandle = handle.clone();
+  let stop_server = server.clone();
+  let start = reactor::Timeout::new(time::Duration::new(1, 0), &handle).unwrap();
+  handle.spawn(start.map_err(|e| p2p::Error::IOErr(e)).and_then(move |_| {
+    TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e))
+  }).and_then(|socket| {
+    Peer::connect(socket, Difficulty::one(), 0, &p2p::handshake::Handshake::new(ZERO_HASH))
+  }).and_then(move |(socket, peer)| {
+    // the client task ends once the server hung up on us
+    let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+    let limiter = p2p::RateLimiter::new(100, 100);
+    let run = peer.run(socket, net_adapter, announces, limiter);
+    rhandle.spawn(run.then(move |_| {
+      drop(peer);
+      done_tx.complete(());
+      Ok(())
+    }));
+    let wait = reactor::Timeout::new(time::Duration::new(1, 0), &rhandle).unwrap();
+    wait.map_err(|e| p2p::Error::IOErr(e))
+  }).and_then(move |_| {
+    assert_eq!(stop_server.peer_count(), 1);
+    stop_server.stop();
+    Ok(())
+  }).map_err(|e| panic!("Client connection failed: {}", e)));
+
+  let client_done = done_rx.map_err(|_| p2p::Error::Timeout);
+  evtlp.run(run_server.join(client_done)).unwrap();
+
+  assert_eq!(server.peer_count(), 0);
+  net::TcpListener::bind(addr).unwrap();
+}
@@ -0,0 +1,194 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time;
+
+use futures::future::Future;
+use futures::Stream;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{self, Core};
+
+use core::core::{Block, BlockHeader, Transaction};
+use core::core::hash::{Hash, ZERO_HASH};
+use core::core::target::Difficulty;
+use p2p::{NetAdapter, Peer};
+
+// Fees telling the adapter to drop or refuse a transaction.
+const DROPPED_FEE: u64 = 10;
+const INVALID_FEE: u64 = 20;
+
+// Adapter counting the transactions it gets.
+struct TxAdapter {
+  txs: AtomicUsize,
+}
+
+impl NetAdapter for TxAdapter {
+  fn total_difficulty(&self) -> Difficulty {
+    Difficulty::one()
+  }
+  fn transaction_received(&self, tx: Transaction) -> p2p::TxStatus {
+    self.txs.fetch_add(1, Ordering::SeqCst);
+    match tx.fee {
+      DROPPED_FEE => p2p::TxStatus::Dropped,
+      INVALID_FEE => p2p::TxStatus::Invalid,
+      _ => p2p::TxStatus::Accepted,
+    }
+  }
+  fn block_received(&self, _: Block) -> bool {
+    true
+  }
+  fn headers_received(&self, _: Vec<BlockHeader>) -> bool {
+    true
+  }
+  fn locate_headers(&self, _: Vec<Hash>) -> Vec<BlockHeader> {
+    vec![]
+  }
+  fn get_block(&self, _: Hash) -> Option<Block> {
+    None
+  }
+  fn get_transaction(&self, _: u64) -> Option<Transaction> {
+    None
+  }
+  fn find_peer_addrs(&self, _: p2p::Capabilities) -> Vec<p2p::PeerAddr> {
+    vec![]
+  }
+  fn peer_addrs_received(&self, _: Vec<p2p::PeerAddr>) {}
+}
+
+// Relays the same transaction several times, the receiving end should only
+// hand it over once.
+#[test]
+fn duplicate_transactions() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+  let addr: SocketAddr = "127.0.0.1:13426".parse().unwrap();
+  let adapter = Arc::new(TxAdapter { txs: AtomicUsize::new(0) });
+
+  let listener = TcpListener::bind(&addr, &handle).unwrap();
+  let lhandle = handle.clone();
+  let server_adapter = adapter.clone();
+  handle.spawn(listener.incoming().take(1).for_each(move |(conn, _)| {
+    let adapter = server_adapter.clone();
+    let run_handle = lhandle.clone();
+    let hs = p2p::handshake::Handshake::new(ZERO_HASH);
+    let accept = Peer::accept(conn, Difficulty::one(), 0, &hs)
+      .and_then(move |(conn, peer)| {
+        let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+        let limiter = p2p::RateLimiter::new(100, 100);
+        let run = peer.run(conn, adapter, announces, limiter);
+        run_handle.spawn(run.then(move |_| {
+          drop(peer);
+          Ok(())
+        }));
+        Ok(())
+      })
+      .map_err(|e| panic!("Accept failed: {}", e));
+    lhandle.spawn(accept);
+    Ok(())
+  }).map_err(|e| panic!("Listener failed: {}", e)));
+
+  let rhandle = handle.clone();
+  let wait = reactor::Timeout::new(time::Duration::new(1, 0), &handle).unwrap();
+  let socket = TcpStream::connect(&addr, &handle).map_err(|e| p2p::Error::IOErr(e));
+  let client = socket.and_then(move |socket| {
+    Peer::connect(socket, Difficulty::one(), 0, &p2p::handshake::Handshake::new(ZERO_HASH))
+  }).and_then(move |(socket, peer)| {
+    let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+    let limiter = p2p::RateLimiter::new(100, 100);
+    let client_adapter = Arc::new(p2p::DummyAdapter {});
+    rhandle.spawn(peer.run(socket, client_adapter, announces, limiter).map_err(|e| {
+      panic!("Client run failed: {}", e);
+    }));
+    let (tx1, tx2) = (Transaction::new(vec![], vec![], 1), Transaction::new(vec![], vec![], 2));
+    peer.send_transaction(&tx1).unwrap();
+    peer.send_transaction(&tx1).unwrap();
+    peer.send_transaction(&tx2).unwrap();
+    peer.send_transaction(&tx1).unwrap();
+    wait.map_err(|e| p2p::Error::IOErr(e)).map(move |_| drop(peer))
+  });
+  evtlp.run(client).unwrap();
+
+  assert_eq!(adapter.txs.load(Ordering::SeqCst), 2);
+}
+
+// Transactions that just don't fit the receiving chain are dropped without
+// holding it against the peer, invalid ones count against its ban score.
+#[test]
+fn only_invalid_transactions_penalized() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+  let addr: SocketAddr = "127.0.0.1:13462".parse().unwrap();
+  let adapter = Arc::new(TxAdapter { txs: AtomicUsize::new(0) });
+  let accepted = Arc::new(Mutex::new(None));
+
+  let listener = TcpListener::bind(&addr, &handle).unwrap();
+  let lhandle = handle.clone();
+  let server_adapter = adapter.clone();
+  let server_peer = accepted.clone();
+  handle.spawn(listener.incoming().take(1).for_each(move |(conn, _)| {
+    let adapter = server_adapter.clone();
+    let server_peer = server_peer.clone();
+    let run_handle = lhandle.clone();
+    let hs = p2p::handshake::Handshake::new(ZERO_HASH);
+    let accept = Peer::accept(conn, Difficulty::one(), 0, &hs)
+      .and_then(move |(conn, peer)| {
+        let peer = Arc::new(peer);
+        let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+        let limiter = p2p::RateLimiter::new(100, 100);
+        run_handle.spawn(peer.run(conn, adapter, announces, limiter).map_err(|_| ()));
+        *server_peer.lock().unwrap() = Some(peer);
+        Ok(())
+      })
+      .map_err(|e| panic!("Accept failed: {}", e));
+    lhandle.spawn(accept);
+    Ok(())
+  }).map_err(|e| panic!("Listener failed: {}", e)));
+
+  let rhandle = handle.clone();
+  let socket = TcpStream::connect(&addr, &handle).map_err(|e| p2p::Error::IOErr(e));
+  let client = socket.and_then(move |socket| {
+    Peer::connect(socket, Difficulty::one(), 0, &p2p::handshake::Handshake::new(ZERO_HASH))
+  }).and_then(move |(socket, peer)| {
+    let announces = Arc::new(p2p::AnnounceWindow::new(10, 10));
+    let limiter = p2p::RateLimiter::new(100, 100);
+    let client_adapter = Arc::new(p2p::DummyAdapter {});
+    rhandle.spawn(peer.run(socket, client_adapter, announces, limiter).map_err(|_| ()));
+    peer.send_transaction(&Transaction::new(vec![], vec![], DROPPED_FEE)).unwrap();
+    let wait = reactor::Timeout::new(time::Duration::from_millis(500), &rhandle).unwrap();
+    wait.map_err(|e| p2p::Error::IOErr(e)).map(move |_| (peer, rhandle))
+  }).and_then(move |(peer, rhandle)| {
+    {
+      let server_peer = accepted.lock().unwrap();
+      assert_eq!(server_peer.as_ref().unwrap().ban_score(), 0);
+    }
+    peer.send_transaction(&Transaction::new(vec![], vec![], INVALID_FEE)).unwrap();
+    let wait = reactor::Timeout::new(time::Duration::from_millis(500), &rhandle).unwrap();
+    wait.map_err(|e| p2p::Error::IOErr(e)).map(move |_| (peer, accepted))
+  }).map(|(peer, accepted)| {
+    let server_peer = accepted.lock().unwrap();
+    assert!(server_peer.as_ref().unwrap().ban_score() > 0);
+    drop(peer);
+  });
+  evtlp.run(client).unwrap();
+
+  assert_eq!(adapter.txs.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,114 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_p2p as p2p;
+extern crate futures;
+extern crate tokio_core;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time;
+
+use futures::future::Future;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{self, Core};
+
+use core::core::hash::{Hash, ZERO_HASH};
+use core::core::target::Difficulty;
+use p2p::{DisconnectReason, Peer};
+use p2p::handshake::Handshake;
+
+// Connects to a server on the chain starting at ZERO_HASH twice, first with
+// the same genesis block and then with another one. Only the first peer
+// makes it through the handshake, the other one is told apart as being on
+// another network.
+#[test]
+fn genesis_mismatch_refused() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13453;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server = Arc::new(p2p::Server::new("target/p2p-wrong-network".to_string(),
+                                         p2p_conf,
+                                         Arc::new(p2p::DummyAdapter {}),
+                                         ZERO_HASH)
+    .unwrap());
+  handle.spawn(server.start(handle.clone()).map_err(|e| panic!("Server failed: {}", e)));
+
+  let h = handle.clone();
+  let same = wait(&handle, 500)
+    .and_then(move |_| TcpStream::connect(&addr, &h).map_err(|e| p2p::Error::IOErr(e)))
+    .and_then(|socket| Peer::connect(socket, Difficulty::one(), 0, &Handshake::new(ZERO_HASH)));
+  let (_conn, peer) = evtlp.run(same).unwrap();
+  assert_eq!(peer.info.genesis, ZERO_HASH);
+  evtlp.run(wait(&handle, 200)).unwrap();
+  assert_eq!(server.peer_count(), 1);
+
+  let h = handle.clone();
+  let other = TcpStream::connect(&addr, &h)
+    .map_err(|e| p2p::Error::IOErr(e))
+    .and_then(|socket| {
+      let local = socket.local_addr().unwrap();
+      Peer::connect(socket, Difficulty::one(), 0, &Handshake::new(Hash([1; 32])))
+        .then(move |res| Ok::<_, p2p::Error>((res.is_ok(), local)))
+    });
+  let (connected, local) = evtlp.run(other).unwrap();
+  assert!(!connected);
+  evtlp.run(wait(&handle, 200)).unwrap();
+  assert_eq!(server.peer_count(), 1);
+  assert!(server.recent_disconnects().contains(&(local, DisconnectReason::WrongNetwork)));
+  // it's only on another network, nothing to ban it for
+  assert!(!server.is_banned(local));
+}
+
+// A server dialing its own address ends up talking to itself, which gets
+// noticed and dropped without banning our own address.
+#[test]
+fn self_connection_dropped() {
+  let mut evtlp = Core::new().unwrap();
+  let handle = evtlp.handle();
+
+  let mut p2p_conf = p2p::P2PConfig::default();
+  p2p_conf.port = 13458;
+  let addr = SocketAddr::new(p2p_conf.host, p2p_conf.port);
+  let server = Arc::new(p2p::Server::new("target/p2p-self-connection".to_string(),
+                                         p2p_conf,
+                                         Arc::new(p2p::DummyAdapter {}),
+                                         ZERO_HASH)
+    .unwrap());
+  handle.spawn(server.start(handle.clone()).map_err(|e| panic!("Server failed: {}", e)));
+
+  let dial = wait(&handle, 500).and_then({
+    let (s, h) = (server.clone(), handle.clone());
+    move |_| {
+      h.spawn(s.connect_peer(addr, h.clone()).map_err(|_| ()));
+      Ok(())
+    }
+  });
+  evtlp.run(dial).unwrap();
+  evtlp.run(wait(&handle, 500)).unwrap();
+  assert_eq!(server.peer_count(), 0);
+  assert!(server.recent_disconnects().iter().any(|&(_, r)| r == DisconnectReason::SelfConnection));
+  assert!(!server.is_banned(addr));
+  server.stop();
+}
+
+fn wait(h: &reactor::Handle, ms: u64) -> Box<Future<Item = (), Error = p2p::Error>> {
+  Box::new(reactor::Timeout::new(time::Duration::from_millis(ms), h)
+    .unwrap()
+    .map_err(|e| p2p::Error::IOErr(e)))
+}
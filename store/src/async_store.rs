@@ -0,0 +1,71 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Asynchronous access to a key-value store, for code running on an event
+//! loop that can't afford to block on disk reads and writes.
+
+use std::sync::Arc;
+
+use futures_cpupool::{CpuFuture, CpuPool};
+
+use core::ser;
+use {Error, KeyValueStore};
+
+/// Wraps a key-value store, running its operations on a thread pool and
+/// returning futures resolving once they're done. The store itself is still
+/// reachable for synchronous access.
+#[derive(Clone)]
+pub struct AsyncStore {
+	store: Arc<KeyValueStore>,
+	pool: CpuPool,
+}
+
+impl AsyncStore {
+	/// Wraps the provided store, its operations running on a pool of the
+	/// provided number of threads.
+	pub fn new(store: Arc<KeyValueStore>, threads: usize) -> AsyncStore {
+		AsyncStore {
+			store: store,
+			pool: CpuPool::new(threads),
+		}
+	}
+
+	/// The underlying store, for synchronous access.
+	pub fn store(&self) -> &KeyValueStore {
+		self.store.as_ref()
+	}
+
+	/// Gets a value, provided its key.
+	pub fn get(&self, key: &[u8]) -> CpuFuture<Option<Vec<u8>>, Error> {
+		let store = self.store.clone();
+		let key = key.to_vec();
+		self.pool.spawn_fn(move || store.get(&key))
+	}
+
+	/// Writes a single key/value pair.
+	pub fn put(&self, key: &[u8], value: Vec<u8>) -> CpuFuture<(), Error> {
+		let store = self.store.clone();
+		let key = key.to_vec();
+		self.pool.spawn_fn(move || store.put(&key, value))
+	}
+
+	/// Gets a `Readable` value, provided its key.
+	pub fn get_ser<T>(&self, key: &[u8]) -> CpuFuture<Option<T>, Error>
+		where T: ser::Readable<T> + Send + 'static
+	{
+		let store = self.store.clone();
+		let key = key.to_vec();
+		self.pool.spawn_fn(move || store.get_ser(&key))
+	}
+}
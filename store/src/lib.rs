@@ -21,29 +21,77 @@
 #![warn(missing_docs)]
 
 extern crate byteorder;
+extern crate futures_cpupool;
 extern crate grin_core as core;
+extern crate librocksdb_sys as ffi;
 extern crate rocksdb;
 
+mod async_store;
+mod mem;
+
 const SEP: u8 = ':' as u8;
 
+/// Target of the logs about our stores, so their level can be set apart
+/// from the other subsystems'.
+pub const LOG_TARGET: &'static str = "grin::store";
+
+use std::ffi::{CStr, CString};
 use std::fmt;
-use std::sync::RwLock;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::{Mutex, RwLock, RwLockReadGuard};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use rocksdb::{DB, WriteBatch, WriteOptions, DBIterator, IteratorMode, Direction};
 
-use byteorder::{WriteBytesExt, BigEndian};
-use rocksdb::{DB, WriteBatch, DBCompactionStyle};
+pub use rocksdb::{ColumnFamily, DBCompactionStyle};
 
+use core::core::hash::Hash;
 use core::ser;
 
+pub use async_store::AsyncStore;
+pub use mem::MemStore;
+
 /// Main error type for this crate.
 #[derive(Debug)]
 pub enum Error {
 	/// Couldn't find what we were looking for
 	NotFoundErr,
+	/// Couldn't find what we were looking for, described by the provided
+	/// key description
+	NotFoundKeyErr(String),
 	/// Wraps an error originating from RocksDB (which unfortunately returns
 	/// string errors).
 	RocksDbErr(String),
+	/// RocksDB found its data corrupted and couldn't repair it, wraps the
+	/// error it reported
+	CorruptionErr(String),
 	/// Wraps a serialization error for Writeable or Readable
 	SerErr(ser::Error),
+	/// Serialization error for the value at the provided height in a range
+	RangeSerErr(u64, ser::Error),
+	/// The stored value is shorter than what was asked to be read, it's
+	/// likely been partially written. Holds the stored length.
+	TruncatedErr(usize),
+	/// The number of items a stored list declares doesn't match what the
+	/// value holds. Holds the declared count and the stored length.
+	CountErr(u64, usize),
+	/// The data asked to be written doesn't hold together and was refused,
+	/// with a description of why
+	InconsistentErr(String),
+	/// A write was attempted on a store opened read-only
+	ReadOnlyErr,
+	/// The stored value deserialized into something invalid, wraps the
+	/// serialization error
+	CorruptErr(ser::Error),
+	/// The stored value isn't laid out like what was asked to be read, wraps
+	/// the serialization error
+	FormatErr(ser::Error),
 }
 
 
@@ -51,44 +99,521 @@ impl fmt::Display for Error {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match self {
       &Error::NotFoundErr => write!(f, "Not Found"),
+			&Error::NotFoundKeyErr(ref k) => write!(f, "Not Found: {}", k),
 			&Error::RocksDbErr(ref s) => write!(f, "RocksDb Error: {}", s),
+			&Error::CorruptionErr(ref s) => write!(f, "Corrupted Database: {}", s),
 			&Error::SerErr(ref e) => write!(f, "Serialization Error: {}", e.to_string()),
+			&Error::RangeSerErr(h, ref e) => {
+				write!(f, "Serialization Error at height {}: {}", h, e.to_string())
+			}
+			&Error::TruncatedErr(len) => write!(f, "Truncated value of {} bytes", len),
+			&Error::CountErr(count, len) => {
+				write!(f, "Value of {} bytes doesn't hold the {} items declared", len, count)
+			}
+			&Error::InconsistentErr(ref s) => write!(f, "Inconsistent Data: {}", s),
+			&Error::ReadOnlyErr => write!(f, "Store opened read-only"),
+			&Error::CorruptErr(ref e) => write!(f, "Corrupted Value: {}", e.to_string()),
+			&Error::FormatErr(ref e) => write!(f, "Unexpected Value Format: {}", e.to_string()),
+		}
+	}
+}
+
+impl Error {
+	/// Whether the error is for something that couldn't be found, with or
+	/// without a description of its key.
+	pub fn is_not_found(&self) -> bool {
+		match *self {
+			Error::NotFoundErr | Error::NotFoundKeyErr(_) => true,
+			_ => false,
+		}
+	}
+
+	/// Maps an error deserializing a stored value of the provided length to
+	/// our errors. Running out of bytes means the value was truncated, while
+	/// invalid or unexpected data means it's corrupted or of another format.
+	/// Anything else stays a plain `SerErr`.
+	pub fn from_ser(e: ser::Error, len: usize) -> Error {
+		match e {
+			ser::Error::IOErr(ref ioe) if ioe.kind() == io::ErrorKind::UnexpectedEof => {
+				Error::TruncatedErr(len)
+			}
+			ser::Error::CorruptedData |
+			ser::Error::TooLargeReadErr => Error::CorruptErr(e),
+			ser::Error::UnexpectedData { .. } => Error::FormatErr(e),
+			_ => Error::SerErr(e),
+		}
+	}
+
+	/// Maps an error message from RocksDB to our errors. RocksDB prefixes
+	/// messages with the class of the error, data corruptions getting their
+	/// own variant so they can be told apart and repaired.
+	pub fn from_rocksdb(msg: String) -> Error {
+		if msg.starts_with("Corruption:") {
+			Error::CorruptionErr(msg)
+		} else {
+			Error::RocksDbErr(msg)
 		}
 	}
 }
 
 impl From<rocksdb::Error> for Error {
 	fn from(e: rocksdb::Error) -> Error {
-		Error::RocksDbErr(e.to_string())
+		Error::from_rocksdb(e.to_string())
+	}
+}
+
+/// Options the underlying RocksDB is opened with.
+#[derive(Debug, Clone, Copy)]
+pub struct StoreConfig {
+	/// Maximum number of files RocksDB keeps open at once
+	pub max_open_files: i32,
+	/// Whether to fsync on writes instead of just fdatasync-ing
+	pub use_fsync: bool,
+	/// Compaction strategy for the LSM tree
+	pub compaction_style: DBCompactionStyle,
+	/// Create the db if it doesn't exist yet
+	pub create_if_missing: bool,
+	/// Have RocksDB collect statistics, reported through `Store::stats`
+	pub enable_statistics: bool,
+	/// Size in bytes of a single in-memory write buffer (memtable) before
+	/// it gets flushed to disk, RocksDB defaults to 64MB
+	pub write_buffer_size: usize,
+	/// Maximum number of write buffers kept in memory, including the ones
+	/// being flushed, RocksDB defaults to 2
+	pub max_write_buffer_number: i32,
+	/// Size in bytes of the LRU cache of uncompressed blocks read from disk,
+	/// RocksDB defaults to 8MB
+	pub block_cache_size: usize,
+}
+
+impl Default for StoreConfig {
+	fn default() -> StoreConfig {
+		StoreConfig {
+			max_open_files: 256,
+			use_fsync: false,
+			compaction_style: DBCompactionStyle::Universal,
+			create_if_missing: true,
+			enable_statistics: false,
+			write_buffer_size: 64 * 1024 * 1024,
+			max_write_buffer_number: 2,
+			block_cache_size: 8 * 1024 * 1024,
+		}
+	}
+}
+
+/// Single operation staged in a batch.
+pub enum BatchOp {
+	/// Writes the value under the key
+	Put(Vec<u8>, Vec<u8>),
+	/// Deletes the key
+	Delete(Vec<u8>),
+}
+
+/// Minimal set of operations a key-value backend for our storage needs to
+/// provide. Implemented by the RocksDB backed `Store` and by `MemStore` for
+/// tests.
+pub trait KeyValueStore: Sync + Send {
+	/// Gets a value, provided its key
+	fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+
+	/// Whether there's a value for the provided key, backends able to tell
+	/// without reading the value should do so
+	fn exists(&self, key: &[u8]) -> Result<bool, Error> {
+		self.get(key).map(|v| v.is_some())
+	}
+
+	/// Writes a single key/value pair
+	fn put(&self, key: &[u8], value: Vec<u8>) -> Result<(), Error>;
+
+	/// Deletes a key/value pair
+	fn delete(&self, key: &[u8]) -> Result<(), Error>;
+
+	/// Atomically applies all the provided operations
+	fn write(&self, ops: Vec<BatchOp>) -> Result<(), Error>;
+
+	/// Atomically applies all the provided operations and only returns once
+	/// they've been synced to disk. Backends that don't persist anything
+	/// just apply them.
+	fn write_sync(&self, ops: Vec<BatchOp>) -> Result<(), Error> {
+		self.write(ops)
 	}
+
+	/// Makes sure all the writes done so far are synced to disk.
+	fn flush(&self) -> Result<(), Error> {
+		Ok(())
+	}
+
+	/// Compacts all the data, reclaiming the space left by deleted and
+	/// overwritten values. Backends that don't compact have nothing to do.
+	fn compact(&self) -> Result<(), Error> {
+		Ok(())
+	}
+
+	/// How long ago the last write was, None if nothing got written since
+	/// the store was opened or last compacted.
+	fn write_idle_time(&self) -> Option<Duration> {
+		None
+	}
+
+	/// Iterates over all key/value pairs whose key starts with the provided
+	/// prefix, in key order.
+	fn iter_raw<'a>(&'a self,
+	                prefix: &[u8])
+	                -> Result<Box<Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>, Error>;
+}
+
+impl KeyValueStore {
+	/// Writes a single key and its `Writeable` value. Encapsulates
+	/// serialization.
+	pub fn put_ser(&self, key: &[u8], value: &ser::Writeable) -> Result<(), Error> {
+		let data = try!(ser::ser_vec(value).map_err(Error::SerErr));
+		self.put(key, data)
+	}
+
+	/// Gets a `Readable` value, provided its key. Encapsulates
+	/// serialization. Fails with `TruncatedErr` if the stored value is empty
+	/// or cut short, like `Store::get_ser`.
+	pub fn get_ser<T: ser::Readable<T>>(&self, key: &[u8]) -> Result<Option<T>, Error> {
+		let data = try!(self.get(key));
+		match data {
+			Some(val) => {
+				let (r, _) = try!(deserialize_value(&val, 0));
+				Ok(Some(r))
+			}
+			None => Ok(None),
+		}
+	}
+
+	/// Produces an iterator of `Readable` types for all keys starting with
+	/// the provided prefix.
+	pub fn iter<T: ser::Readable<T>>(&self, prefix: &[u8]) -> Result<SerIterator<T>, Error> {
+		let iter = try!(self.iter_raw(prefix));
+		Ok(SerIterator {
+			iter: iter,
+			_marker: PhantomData,
+		})
+	}
+
+	/// Builds a new batch to be used with this store.
+	pub fn batch(&self) -> Batch {
+		Batch {
+			store: self,
+			ops: vec![],
+		}
+	}
+}
+
+/// Statistics about the underlying RocksDB, mostly useful for diagnostics.
+#[derive(Debug, Clone, Default)]
+pub struct StoreStats {
+	/// Number of keys in the db as estimated by RocksDB, none if it
+	/// couldn't tell
+	pub estimate_num_keys: Option<u64>,
+	/// Total size of the live SST files, in bytes
+	pub live_sst_files_size: u64,
+	/// Block cache hits, only collected with statistics enabled
+	pub block_cache_hits: u64,
+	/// Block cache misses, only collected with statistics enabled
+	pub block_cache_misses: u64,
+	/// When the db was last compacted, as a UTC timestamp in seconds
+	pub last_compaction: Option<i64>,
 }
 
 /// Thread-safe rocksdb wrapper
 pub struct Store {
-	rdb: RwLock<DB>,
+	db: DB,
+	// RocksDB is thread safe, the lock just keeps reads from happening in the
+	// middle of our own writes, and writes from interleaving
+	lock: RwLock<()>,
+	// snapshot copy of the db a read-only store got opened from, removed
+	// after the db closes
+	checkpoint: Option<Checkpoint>,
+	// kept around as RocksDB statistics are only reachable through them
+	opts: rocksdb::Options,
+	read_only: bool,
+	// last write since we opened or compacted the db
+	last_write: Mutex<Option<Instant>>,
+	last_compaction: Mutex<Option<i64>>,
 }
 
 unsafe impl Sync for Store {}
 unsafe impl Send for Store {}
 
+// Tells the checkpoints taken by this process apart
+static CHECKPOINTS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// How many times we try to copy a db that keeps changing under us before
+// giving up on opening it read-only
+const CHECKPOINT_ATTEMPTS: usize = 5;
+
+/// Copy of the files of a db at some point in time, in a directory of its
+/// own that gets removed when dropped. Unlike RocksDB checkpoints, table
+/// files aren't hard linked: opening the copy can write tables of its own,
+/// which would truncate a file shared with the db if it was still being
+/// written there under the same number.
+///
+/// The files are copied one by one while the db may still be written to, so
+/// a compaction can remove a table file before we get to it or add one the
+/// copied manifest refers to, both making the copy unusable. The last write
+/// ahead log record can also be cut short, which RocksDB drops on recovery.
+/// Unlike a RocksDB checkpoint this isn't atomic, callers have to check the
+/// copy opens and take another one otherwise.
+struct Checkpoint(PathBuf);
+
+impl Checkpoint {
+	fn take(path: &str) -> Result<Checkpoint, Error> {
+		let nanos = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map(|d| d.subsec_nanos())
+			.unwrap_or(0);
+		let n = CHECKPOINTS.fetch_add(1, Ordering::Relaxed);
+		let dir = PathBuf::from(format!("{}.ro-{}-{}", path, nanos, n));
+		let checkpoint = Checkpoint(dir);
+		try!(checkpoint.copy_from(Path::new(path)).map_err(|e| {
+			Error::RocksDbErr(format!("Could not checkpoint {}: {}", path, e))
+		}));
+		Ok(checkpoint)
+	}
+
+	fn copy_from(&self, src: &Path) -> io::Result<()> {
+		let entries = try!(fs::read_dir(src));
+		try!(fs::create_dir(&self.0));
+		for entry in entries {
+			let entry = try!(entry);
+			let name = entry.file_name();
+			let name_str = name.to_string_lossy();
+			// the lock is what we're avoiding, info logs are of no use
+			if name_str == "LOCK" || name_str.starts_with("LOG") {
+				continue;
+			}
+			try!(fs::copy(entry.path(), self.0.join(&name)));
+		}
+		Ok(())
+	}
+}
+
+impl Drop for Checkpoint {
+	fn drop(&mut self) {
+		// nothing to do about a failure there, the copy is just left behind
+		let _ = fs::remove_dir_all(&self.0);
+	}
+}
+
 impl Store {
 	/// Opens a new RocksDB at the specified location.
 	pub fn open(path: &str) -> Result<Store, Error> {
-		let mut opts = rocksdb::Options::default();
-		opts.create_if_missing(true);
-		opts.set_compaction_style(DBCompactionStyle::Universal);
-		opts.set_max_open_files(256);
-		opts.set_use_fsync(false);
+		Store::open_with_config(path, StoreConfig::default())
+	}
+
+	/// Opens a new RocksDB at the specified location with the provided
+	/// configuration.
+	pub fn open_with_config(path: &str, config: StoreConfig) -> Result<Store, Error> {
+		let opts = rocks_options(&config);
 		let db = try!(DB::open(&opts, &path));
-		Ok(Store { rdb: RwLock::new(db) })
+		Ok(Store {
+			db: db,
+			lock: RwLock::new(()),
+			opts: opts,
+			read_only: false,
+			last_write: Mutex::new(None),
+			last_compaction: Mutex::new(None),
+			checkpoint: None,
+		})
+	}
+
+	/// Opens a snapshot copy of an existing RocksDB at the specified location
+	/// for reading only, all writes failing with `ReadOnlyErr`. Works while
+	/// the db is held open elsewhere, by another handle or process.
+	///
+	/// This isn't the RocksDB read-only open, which the rocksdb bindings we
+	/// use don't expose. The db files are instead all copied to a
+	/// `<path>.ro-*` directory next to the db, which takes as much space as
+	/// the db itself and requires write access to its parent directory. The copy gets opened
+	/// without touching the lock of the original and is taken again if the
+	/// db changed too much while copying. The store only sees the data
+	/// written until it was opened, the copy being removed once the store is
+	/// dropped. A process dying with the store open leaves the copy behind,
+	/// it can be removed once no read-only store uses it.
+	pub fn open_read_only(path: &str, config: StoreConfig) -> Result<Store, Error> {
+		let mut opts = rocks_options(&config);
+		opts.create_if_missing(false);
+		let mut attempt = 1;
+		loop {
+			let res = Checkpoint::take(path).and_then(|checkpoint| {
+				DB::open(&opts, &checkpoint.0).map(|db| (db, checkpoint)).map_err(From::from)
+			});
+			match res {
+				Ok((db, checkpoint)) => {
+					return Ok(Store {
+						db: db,
+						lock: RwLock::new(()),
+						opts: opts,
+						read_only: true,
+						last_write: Mutex::new(None),
+						last_compaction: Mutex::new(None),
+						checkpoint: Some(checkpoint),
+					})
+				}
+				Err(_) if attempt < CHECKPOINT_ATTEMPTS => attempt += 1,
+				Err(e) => return Err(e),
+			}
+		}
+	}
+
+	/// Whether the store was opened with `open_read_only`.
+	pub fn is_read_only(&self) -> bool {
+		self.read_only
+	}
+
+	/// Opens a RocksDB at the specified location like `open_with_config`, but
+	/// runs the RocksDB repair routine and tries again if the data turns out
+	/// to be corrupted. Whatever can't be salvaged is dropped. Also returns
+	/// the corruption that got repaired, if any, for the caller to report.
+	pub fn open_or_repair(path: &str,
+	                      config: StoreConfig)
+	                      -> Result<(Store, Option<String>), Error> {
+		let corruption = match Store::open_with_config(path, config) {
+			Ok(store) => return Ok((store, None)),
+			Err(Error::CorruptionErr(msg)) => msg,
+			Err(e) => return Err(e),
+		};
+		if let Err(e) = DB::repair(rocks_options(&config), &path) {
+			return Err(Error::CorruptionErr(e.to_string()));
+		}
+		let store = try!(Store::open_with_config(path, config));
+		Ok((store, Some(corruption)))
+	}
+
+	/// Destroys the RocksDB at the specified location, removing all its
+	/// files including the lock. Any store opened there has to be dropped
+	/// first, which closes its handle.
+	pub fn destroy(path: &str) -> Result<(), Error> {
+		let opts = rocks_options(&StoreConfig::default());
+		DB::destroy(&opts, &path).map_err(From::from)
+	}
+
+	/// Opens a new RocksDB at the specified location with the provided column
+	/// families, creating them if the db has none yet. The default column
+	/// family is always opened as well, so data written by a store opened
+	/// without column families is still read by `get`. Any other failure to
+	/// open the db is returned as a `RocksDbErr`.
+	pub fn with_column_families(path: &str, names: &[&str]) -> Result<Store, Error> {
+		let opts = rocks_options(&StoreConfig::default());
+		let db = match DB::open_cf(&opts, &path, names) {
+			Ok(db) => db,
+			Err(ref e) if e.to_string().contains("Column family not found") => {
+				// new db or one without any column family yet
+				let mut db = try!(DB::open(&opts, &path));
+				for name in names {
+					try!(db.create_cf(name, &opts));
+				}
+				db
+			}
+			Err(e) => {
+				return Err(Error::RocksDbErr(format!("Could not open {} with column families: {}",
+				                                     path,
+				                                     e)))
+			}
+		};
+		Ok(Store {
+			db: db,
+			lock: RwLock::new(()),
+			opts: opts,
+			read_only: false,
+			last_write: Mutex::new(None),
+			last_compaction: Mutex::new(None),
+			checkpoint: None,
+		})
+	}
+
+	/// Handle to one of the column families the store was opened with.
+	pub fn cf_handle(&self, name: &str) -> Option<ColumnFamily> {
+		let _lock = self.lock.read().unwrap();
+		let db = &self.db;
+		db.cf_handle(name)
+	}
+
+	/// Writes a single key/value pair to the provided column family
+	pub fn put_cf(&self, cf: ColumnFamily, key: &[u8], value: Vec<u8>) -> Result<(), Error> {
+		try!(self.start_write());
+		let _lock = self.lock.write().unwrap();
+		let db = &self.db;
+		db.put_cf(cf, key, &value[..]).map_err(&From::from)
+	}
+
+	/// Gets a value from the provided column family, provided its key
+	pub fn get_cf(&self, cf: ColumnFamily, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+		let _lock = self.lock.read().unwrap();
+		let db = &self.db;
+		db.get_cf(cf, key).map(|r| r.map(|o| o.to_vec())).map_err(From::from)
+	}
+
+	/// Gathers statistics about the db, cheaply enough to be called often.
+	/// The rocksdb bindings we use don't expose properties, so SST files are
+	/// sized from the db directory, and there's no estimate of the number of
+	/// keys, see `count_keys` for that.
+	pub fn stats(&self) -> Result<StoreStats, Error> {
+		let db = &self.db;
+		let mut stats = StoreStats::default();
+		let files = try!(fs::read_dir(db.path()).map_err(|e| Error::RocksDbErr(e.to_string())));
+		for file in files {
+			let file = try!(file.map_err(|e| Error::RocksDbErr(e.to_string())));
+			if file.path().extension().map(|ext| ext == "sst").unwrap_or(false) {
+				// a compaction can remove files while we go through them
+				match file.metadata() {
+					Ok(meta) => stats.live_sst_files_size += meta.len(),
+					Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+					Err(e) => return Err(Error::RocksDbErr(e.to_string())),
+				}
+			}
+		}
+
+		stats.estimate_num_keys = property_value(db.path(), "rocksdb.estimate-num-keys")
+			.and_then(|v| v.trim().parse().ok());
+
+		if let Some(rstats) = self.opts.get_statistics() {
+			stats.block_cache_hits = ticker_count(&rstats, "rocksdb.block.cache.hit");
+			stats.block_cache_misses = ticker_count(&rstats, "rocksdb.block.cache.miss");
+		}
+		stats.last_compaction = *self.last_compaction.lock().unwrap();
+		Ok(stats)
+	}
+
+	/// Counts the keys in the db by going through all of them, which takes
+	/// a while on a large db.
+	pub fn count_keys(&self) -> u64 {
+		let _lock = self.lock.read().unwrap();
+		let db = &self.db;
+		db.iterator(IteratorMode::Start).count() as u64
 	}
 
 	/// Writes a single key/value pair to the db
 	pub fn put(&self, key: &[u8], value: Vec<u8>) -> Result<(), Error> {
-		let db = self.rdb.write().unwrap();
+		try!(self.start_write());
+		let _lock = self.lock.write().unwrap();
+		let db = &self.db;
 		db.put(key, &value[..]).map_err(&From::from)
 	}
 
+	/// Compare-and-swap, writes the new value only if the current one is
+	/// byte-for-byte equal to the expected one (or absent if None is
+	/// expected). Returns whether the swap happened.
+	pub fn cas(&self, key: &[u8], expected: Option<&[u8]>, new: Vec<u8>) -> Result<bool, Error> {
+		try!(self.start_write());
+		let _lock = self.lock.write().unwrap();
+		let db = &self.db;
+		let current = try!(db.get(key));
+		let matches = match (current, expected) {
+			(Some(ref c), Some(e)) => &c[..] == e,
+			(None, None) => true,
+			_ => false,
+		};
+		if matches {
+			try!(db.put(key, &new[..]));
+		}
+		Ok(matches)
+	}
+
 	/// Writes a single key and its `Writeable` value to the db. Encapsulates
 	/// serialization.
 	pub fn put_ser(&self, key: &[u8], value: &ser::Writeable) -> Result<(), Error> {
@@ -101,19 +626,63 @@ impl Store {
 
 	/// Gets a value from the db, provided its key
 	pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
-		let db = self.rdb.read().unwrap();
+		let _lock = self.lock.read().unwrap();
+		let db = &self.db;
 		db.get(key).map(|r| r.map(|o| o.to_vec())).map_err(From::from)
 	}
 
+	/// Gets a `Readable` value from the db, provided its key, along with
+	/// whatever bytes are left in the value after it. Useful when several
+	/// records are packed under a single key.
+	pub fn get_ser_remainder<T: ser::Readable<T>>(&self,
+	                                              key: &[u8])
+	                                              -> Result<Option<(T, Vec<u8>)>, Error> {
+		let data = try!(self.get(key));
+		match data {
+			Some(val) => {
+				let (r, rest) = try!(deserialize_value(&val, 0));
+				Ok(Some((r, rest.to_vec())))
+			}
+			None => Ok(None),
+		}
+	}
+
+	/// Whether the db has a value for the provided key. This is a plain
+	/// lookup: RocksDB still reads the value and its C API hands over a copy
+	/// of it, which is just dropped, saving callers from deserializing. The
+	/// bloom filter based key_may_exist fast path isn't available, neither
+	/// the rocksdb bindings we use nor their RocksDB C API expose it.
+	pub fn exists(&self, key: &[u8]) -> Result<bool, Error> {
+		let _lock = self.lock.read().unwrap();
+		let db = &self.db;
+		db.get(key).map(|r| r.is_some()).map_err(From::from)
+	}
+
+	/// Gets multiple values from the db at once, only taking the read lock
+	/// once. Results are positionally aligned with the provided keys.
+	pub fn multi_get(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+		let _lock = self.lock.read().unwrap();
+		let db = &self.db;
+		let mut values = Vec::with_capacity(keys.len());
+		for key in keys {
+			let value = try!(db.get(&key[..]));
+			values.push(value.map(|v| v.to_vec()));
+		}
+		Ok(values)
+	}
+
 	/// Gets a `Readable` value from the db, provided its key. Encapsulates
-	/// serialization.
+	/// serialization. Fails with `TruncatedErr` if the stored value is empty
+	/// or cut short.
 	pub fn get_ser<T: ser::Readable<T>>(&self, key: &[u8]) -> Result<Option<T>, Error> {
 		self.get_ser_limited(key, 0)
 	}
 
 	/// Gets a `Readable` value from the db, provided its key, allowing to
 	/// extract only partial data. The underlying Readable size must align
-	/// accordingly. Encapsulates serialization.
+	/// accordingly, a len of 0 reading the whole value. Encapsulates
+	/// serialization. Fails with `TruncatedErr` if the stored value is empty
+	/// or shorter than len.
 	pub fn get_ser_limited<T: ser::Readable<T>>(&self,
 	                                            key: &[u8],
 	                                            len: usize)
@@ -121,61 +690,477 @@ impl Store {
 		let data = try!(self.get(key));
 		match data {
 			Some(val) => {
-				let mut lval = if len > 0 { &val[..len] } else { &val[..] };
-				let r = try!(ser::deserialize(&mut lval).map_err(Error::SerErr));
+				let (r, _) = try!(deserialize_value(&val, len));
 				Ok(Some(r))
 			}
 			None => Ok(None),
 		}
 	}
 
+	/// Writes a list of `Writeable` values under a single key, as a u64 count
+	/// followed by each of the values. Read back with `get_ser_vec`.
+	pub fn put_ser_vec<T: ser::Writeable>(&self, key: &[u8], values: &[T]) -> Result<(), Error> {
+		let mut data = vec![];
+		try!(data.write_u64::<BigEndian>(values.len() as u64)
+			.map_err(|e| Error::SerErr(ser::Error::IOErr(e))));
+		for value in values {
+			data.append(&mut try!(ser::ser_vec(value).map_err(Error::SerErr)));
+		}
+		self.put(key, data)
+	}
+
+	/// Gets a list of `Readable` values stored under a single key, the value
+	/// starting with their count as a u64. Fails with `CountErr` if the value
+	/// holds more or fewer items than declared.
+	pub fn get_ser_vec<T: ser::Readable<T>>(&self, key: &[u8]) -> Result<Option<Vec<T>>, Error> {
+		let data = try!(self.get(key));
+		match data {
+			Some(val) => {
+				let mut lval = &val[..];
+				let count = try!(lval.read_u64::<BigEndian>()
+					.map_err(|_| Error::TruncatedErr(val.len())));
+				// the count can't be trusted to size the vec upfront
+				let mut items = vec![];
+				for _ in 0..count {
+					// reading from a slice only fails on io when running out of bytes
+					match ser::deserialize(&mut lval) {
+						Ok(item) => items.push(item),
+						Err(ser::Error::IOErr(_)) => return Err(Error::CountErr(count, val.len())),
+						Err(e) => return Err(Error::from_ser(e, val.len())),
+					}
+				}
+				if !lval.is_empty() {
+					return Err(Error::CountErr(count, val.len()));
+				}
+				Ok(Some(items))
+			}
+			None => Ok(None),
+		}
+	}
+
+	/// Produces an iterator of `Readable` types moving forward from the
+	/// provided key prefix, in key order. Iteration stops at the first key
+	/// not starting with the prefix. The store read lock is held until the
+	/// iterator is dropped.
+	pub fn iter<T: ser::Readable<T>>(&self, prefix: &[u8]) -> Result<SerIterator<T>, Error> {
+		(self as &KeyValueStore).iter(prefix)
+	}
+
+	/// Gets all the `Readable` values stored under numeric keys with the
+	/// provided prefix, from height `from` to height `to` inclusive. Unlike
+	/// the chain `get_block_headers`, `to` is included. Gaps are skipped and
+	/// an empty range produces an empty vector.
+	pub fn range_ser<T: ser::Readable<T>>(&self,
+	                                      prefix: u8,
+	                                      from: u64,
+	                                      to: u64)
+	                                      -> Result<Vec<T>, Error> {
+		let mut values = vec![];
+		if from > to {
+			return Ok(values);
+		}
+		let start = u64_to_key(prefix, from);
+		let end = u64_to_key(prefix, to);
+
+		let _lock = self.lock.read().unwrap();
+		let db = &self.db;
+		for (k, v) in db.iterator(IteratorMode::From(&start, Direction::Forward)) {
+			if &k[..] > &end[..] {
+				break;
+			}
+			if k.len() != end.len() {
+				// not a numeric key, sharing the prefix by accident
+				continue;
+			}
+			match ser::deserialize(&mut &v[..]) {
+				Ok(t) => values.push(t),
+				Err(e) => {
+					let height = (&k[2..]).read_u64::<BigEndian>().unwrap();
+					return Err(Error::RangeSerErr(height, e));
+				}
+			}
+		}
+		Ok(values)
+	}
+
 	/// Deletes a key/value pair from the db
 	pub fn delete(&self, key: &[u8]) -> Result<(), Error> {
-		let db = self.rdb.write().unwrap();
+		try!(self.start_write());
+		let _lock = self.lock.write().unwrap();
+		let db = &self.db;
 		db.delete(key).map_err(From::from)
 	}
 
+	/// Deletes all the keys from start included up to end excluded, in a
+	/// single atomic write. The rocksdb bindings we use don't expose
+	/// DeleteRange, the keys in range are looked up and deleted in a batch.
+	pub fn delete_range(&self, start: &[u8], end: &[u8]) -> Result<(), Error> {
+		try!(self.start_write());
+		let mut batch = WriteBatch::default();
+		// held throughout so no write of ours sneaks in between
+		let _lock = self.lock.write().unwrap();
+		let db = &self.db;
+		for (k, _) in db.iterator(IteratorMode::From(start, Direction::Forward)) {
+			if &k[..] >= end {
+				break;
+			}
+			try!(batch.delete(&k));
+		}
+		db.write(batch).map_err(From::from)
+	}
+
+	/// Takes a consistent, read-only view of the store at this point in time.
+	/// Writes made after the snapshot is taken aren't visible through it. The
+	/// snapshot can't outlive the store.
+	pub fn snapshot(&self) -> Snapshot {
+		// the lock only keeps the snapshot from landing in the middle of our
+		// own writes, the snapshot itself borrows the store
+		let _lock = self.lock.read().unwrap();
+		Snapshot { snapshot: self.db.snapshot() }
+	}
+
 	/// Builds a new batch to be used with this store.
 	pub fn batch(&self) -> Batch {
-		Batch {
-			store: self,
-			batch: WriteBatch::default(),
+		(self as &KeyValueStore).batch()
+	}
+
+	/// Syncs the RocksDB write-ahead log to disk, making all the writes done
+	/// so far durable even without fsync enabled in the configuration.
+	pub fn flush(&self) -> Result<(), Error> {
+		if self.read_only {
+			// nothing could have been written
+			return Ok(());
 		}
+		// the RocksDB bindings don't expose FlushWAL, an empty synced write
+		// has the same effect
+		self.write_sync(vec![])
 	}
 
-	fn write(&self, batch: WriteBatch) -> Result<(), Error> {
-		let db = self.rdb.write().unwrap();
-		db.write(batch).map_err(From::from)
+	fn write_opt(&self, ops: Vec<BatchOp>, opts: &WriteOptions) -> Result<(), Error> {
+		try!(self.start_write());
+		let mut batch = WriteBatch::default();
+		for op in ops {
+			match op {
+				BatchOp::Put(k, v) => try!(batch.put(&k[..], &v[..])),
+				BatchOp::Delete(k) => try!(batch.delete(&k[..])),
+			}
+		}
+		let _lock = self.lock.write().unwrap();
+		let db = &self.db;
+		db.write_opt(batch, opts).map_err(From::from)
+	}
+
+	/// Compacts the whole db, flushing what's still in memory and
+	/// reclaiming the space left by deleted and overwritten values. Blocks
+	/// until done but doesn't keep reads and writes waiting, although it's
+	/// best run while the db is idle.
+	pub fn compact(&self) -> Result<(), Error> {
+		if self.read_only {
+			return Err(Error::ReadOnlyErr);
+		}
+		// RocksDB compacts while being read and written to, our lock isn't
+		// needed and holding it would have writes wait
+		let started = Instant::now();
+		self.db.compact_range(None, None);
+		{
+			// writes that happened while compacting still count
+			let mut last_write = self.last_write.lock().unwrap();
+			if last_write.map(|t| t <= started).unwrap_or(false) {
+				*last_write = None;
+			}
+		}
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+		*self.last_compaction.lock().unwrap() = Some(now as i64);
+		Ok(())
+	}
+
+	/// How long ago the last write was, None if nothing got written since
+	/// the db was opened or last compacted.
+	pub fn write_idle_time(&self) -> Option<Duration> {
+		self.last_write.lock().unwrap().map(|t| t.elapsed())
+	}
+
+	// Refuses writes on a read-only store, otherwise remembers one is
+	// happening
+	fn start_write(&self) -> Result<(), Error> {
+		if self.read_only {
+			return Err(Error::ReadOnlyErr);
+		}
+		*self.last_write.lock().unwrap() = Some(Instant::now());
+		Ok(())
 	}
 }
 
-/// Batch to write multiple Writeables to RocksDb in an atomic manner.
+impl KeyValueStore for Store {
+	fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+		Store::get(self, key)
+	}
+
+	fn exists(&self, key: &[u8]) -> Result<bool, Error> {
+		Store::exists(self, key)
+	}
+
+	fn put(&self, key: &[u8], value: Vec<u8>) -> Result<(), Error> {
+		Store::put(self, key, value)
+	}
+
+	fn delete(&self, key: &[u8]) -> Result<(), Error> {
+		Store::delete(self, key)
+	}
+
+	fn write(&self, ops: Vec<BatchOp>) -> Result<(), Error> {
+		self.write_opt(ops, &WriteOptions::default())
+	}
+
+	fn write_sync(&self, ops: Vec<BatchOp>) -> Result<(), Error> {
+		let mut opts = WriteOptions::default();
+		opts.set_sync(true);
+		self.write_opt(ops, &opts)
+	}
+
+	fn flush(&self) -> Result<(), Error> {
+		Store::flush(self)
+	}
+
+	fn compact(&self) -> Result<(), Error> {
+		Store::compact(self)
+	}
+
+	fn write_idle_time(&self) -> Option<Duration> {
+		Store::write_idle_time(self)
+	}
+
+	fn iter_raw<'a>(&'a self,
+	                prefix: &[u8])
+	                -> Result<Box<Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>, Error> {
+		let lock = self.lock.read().unwrap();
+		let iter = self.db.iterator(IteratorMode::From(prefix, Direction::Forward));
+		Ok(Box::new(PrefixIterator {
+			iter: iter,
+			prefix: prefix.to_vec(),
+			_lock: lock,
+		}))
+	}
+}
+
+// Iterates over the RocksDB keys starting with a prefix, holding the read
+// lock for as long as it lives.
+struct PrefixIterator<'a> {
+	// declared before the lock guard so it's dropped first
+	iter: DBIterator,
+	prefix: Vec<u8>,
+	_lock: RwLockReadGuard<'a, ()>,
+}
+
+impl<'a> Iterator for PrefixIterator<'a> {
+	type Item = (Vec<u8>, Vec<u8>);
+
+	fn next(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+		match self.iter.next() {
+			Some((ref k, ref v)) if k.starts_with(&self.prefix) => Some((k.to_vec(), v.to_vec())),
+			_ => None,
+		}
+	}
+}
+
+/// Point in time view of the store, released on drop.
+pub struct Snapshot<'a> {
+	snapshot: rocksdb::Snapshot<'a>,
+}
+
+impl<'a> Snapshot<'a> {
+	/// Gets a value from the snapshot, provided its key
+	pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+		self.snapshot.get(key).map(|r| r.map(|o| o.to_vec())).map_err(From::from)
+	}
+
+	/// Gets a `Readable` value from the snapshot, provided its key.
+	/// Encapsulates serialization.
+	pub fn get_ser<T: ser::Readable<T>>(&self, key: &[u8]) -> Result<Option<T>, Error> {
+		let data = try!(self.get(key));
+		match data {
+			Some(val) => {
+				let (r, _) = try!(deserialize_value(&val, 0));
+				Ok(Some(r))
+			}
+			None => Ok(None),
+		}
+	}
+}
+
+/// Batch to write multiple Writeables to the store in an atomic manner.
 pub struct Batch<'a> {
-	store: &'a Store,
-	batch: WriteBatch,
+	store: &'a KeyValueStore,
+	ops: Vec<BatchOp>,
 }
 
 impl<'a> Batch<'a> {
 	/// Writes a single key and its `Writeable` value to the batch. The write
 	/// function must be called to "commit" the batch to storage.
 	pub fn put_ser(mut self, key: &[u8], value: &ser::Writeable) -> Result<Batch<'a>, Error> {
-		let ser_value = ser::ser_vec(value);
-		match ser_value {
-			Ok(data) => {
-				self.batch.put(key, &data[..])?;
-				Ok(self)
-			}
-			Err(err) => Err(Error::SerErr(err)),
-		}
+		try!(self.put_ser_ref(key, value));
+		Ok(self)
+	}
+
+	/// Same as `put_ser` but adds to the batch in place, convenient when
+	/// staging entries in a loop.
+	pub fn put_ser_ref(&mut self, key: &[u8], value: &ser::Writeable) -> Result<(), Error> {
+		let data = try!(ser::ser_vec(value).map_err(Error::SerErr));
+		self.ops.push(BatchOp::Put(key.to_vec(), data));
+		Ok(())
 	}
 
-	/// Writes the batch to RocksDb.
+	/// Deletes a key/value pair as part of the batch. The write function must
+	/// be called to "commit" the batch to storage.
+	pub fn delete(mut self, key: &[u8]) -> Result<Batch<'a>, Error> {
+		self.ops.push(BatchOp::Delete(key.to_vec()));
+		Ok(self)
+	}
+
+	/// Writes the batch to the underlying store.
 	pub fn write(self) -> Result<(), Error> {
-		self.store.write(self.batch)
+		self.store.write(self.ops)
+	}
+
+	/// Writes the batch to the underlying store, only returning once it's
+	/// synced to disk regardless of the store configuration.
+	pub fn write_sync(self) -> Result<(), Error> {
+		self.store.write_sync(self.ops)
+	}
+}
+
+/// An iterator that produces Readable instances back. Wraps the lower level
+/// key/value iterator and deserializes the returned values, producing an
+/// error for the ones that can't be read and moving on to the next.
+pub struct SerIterator<'a, T> {
+	iter: Box<Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>,
+	_marker: PhantomData<T>,
+}
+
+impl<'a, T: ser::Readable<T>> Iterator for SerIterator<'a, T> {
+	type Item = Result<T, Error>;
+
+	fn next(&mut self) -> Option<Result<T, Error>> {
+		self.iter
+			.next()
+			.map(|(_, v)| deserialize_value(&v, 0).map(|(r, _)| r))
+	}
+}
+
+// Deserializes a stored value, only its first len bytes unless len is 0,
+// along with what's left of it. Empty values and values shorter than len are
+// reported as truncated, the same way whatever the store.
+fn deserialize_value<T: ser::Readable<T>>(val: &[u8], len: usize) -> Result<(T, &[u8]), Error> {
+	if val.is_empty() || len > val.len() {
+		return Err(Error::TruncatedErr(val.len()));
+	}
+	let mut lval = if len > 0 { &val[..len] } else { &val[..] };
+	let r = try!(ser::deserialize(&mut lval).map_err(|e| Error::from_ser(e, val.len())));
+	Ok((r, lval))
+}
+
+// Builds the RocksDB options matching our store configuration
+fn rocks_options(config: &StoreConfig) -> rocksdb::Options {
+	let mut opts = rocksdb::Options::default();
+	opts.create_if_missing(config.create_if_missing);
+	opts.set_compaction_style(config.compaction_style);
+	opts.set_max_open_files(config.max_open_files);
+	opts.set_use_fsync(config.use_fsync);
+	opts.set_write_buffer_size(config.write_buffer_size);
+	opts.set_max_write_buffer_number(config.max_write_buffer_number);
+	let mut block_opts = rocksdb::BlockBasedOptions::default();
+	block_opts.set_lru_cache(config.block_cache_size);
+	opts.set_block_based_table_factory(&block_opts);
+	if config.enable_statistics {
+		opts.enable_statistics();
+	}
+	opts
+}
+
+// Reads a RocksDB property of the db at the provided path. The rocksdb
+// bindings we use don't give access to properties, so the db gets opened
+// read-only on the side through the RocksDB C API, which doesn't need its
+// lock. That open isn't free, but it's only for diagnostics. A db with
+// column families or that can't be opened has nothing to tell.
+fn property_value(path: &Path, name: &str) -> Option<String> {
+	let c_path = match CString::new(path.to_string_lossy().into_owned()) {
+		Ok(c_path) => c_path,
+		Err(_) => return None,
+	};
+	let c_name = match CString::new(name) {
+		Ok(c_name) => c_name,
+		Err(_) => return None,
+	};
+	unsafe {
+		let opts = ffi::rocksdb_options_create();
+		let mut err = ptr::null_mut();
+		let db = ffi::rocksdb_open_for_read_only(opts, c_path.as_ptr(), 0, &mut err);
+		ffi::rocksdb_options_destroy(opts);
+		if !err.is_null() {
+			ffi::rocksdb_free(err as *mut _);
+			return None;
+		}
+		let value = ffi::rocksdb_property_value(db, c_name.as_ptr());
+		let res = if value.is_null() {
+			None
+		} else {
+			let res = CStr::from_ptr(value).to_string_lossy().into_owned();
+			ffi::rocksdb_free(value as *mut _);
+			Some(res)
+		};
+		ffi::rocksdb_close(db);
+		res
+	}
+}
+
+// Extracts a ticker count from the RocksDB statistics dump, formatted as
+// "<name> COUNT : <count>" lines.
+fn ticker_count(stats: &str, name: &str) -> u64 {
+	stats.lines()
+		.find(|l| l.starts_with(name) && l[name.len()..].starts_with(" COUNT"))
+		.and_then(|l| l.split(':').nth(1))
+		.and_then(|c| c.trim().parse().ok())
+		.unwrap_or(0)
+}
+
+/// Builder for db keys, laid out as a prefix, a separator and the bytes of
+/// the identifiers appended to it. Never modifies anything it's given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Key {
+	bytes: Vec<u8>,
+}
+
+impl Key {
+	/// Starts a key with the provided prefix.
+	pub fn prefix(prefix: u8) -> Key {
+		Key { bytes: vec![prefix, SEP] }
+	}
+
+	/// Appends a big-endian number, so keys sort in numeric order.
+	pub fn append_u64(mut self, n: u64) -> Key {
+		self.bytes.write_u64::<BigEndian>(n).unwrap();
+		self
+	}
+
+	/// Appends the bytes of a hash.
+	pub fn append_hash(self, h: &Hash) -> Key {
+		self.append_bytes(h.to_slice())
+	}
+
+	/// Appends raw bytes.
+	pub fn append_bytes(mut self, bytes: &[u8]) -> Key {
+		self.bytes.extend_from_slice(bytes);
+		self
+	}
+
+	/// The key bytes.
+	pub fn build(self) -> Vec<u8> {
+		self.bytes
 	}
 }
 
 /// Build a db key from a prefix and a byte vector identifier.
+#[deprecated(note = "modifies the identifier in place, use Key instead")]
 pub fn to_key(prefix: u8, id: &mut Vec<u8>) -> &mut Vec<u8> {
 	id.insert(0, SEP);
 	id.insert(0, prefix);
@@ -199,3 +1184,16 @@ pub fn option_to_not_found<T>(res: Result<Option<T>, Error>) -> Result<T, Error>
 		Err(e) => Err(e),
 	}
 }
+
+/// Like `option_to_not_found` but the error produced when nothing was found
+/// includes the provided description of the key, for example
+/// "block_header@height=42".
+pub fn option_to_not_found_ctx<T>(res: Result<Option<T>, Error>,
+                                  key_desc: &str)
+                                  -> Result<T, Error> {
+	match res {
+		Ok(None) => Err(Error::NotFoundKeyErr(key_desc.to_string())),
+		Ok(Some(o)) => Ok(o),
+		Err(e) => Err(e),
+	}
+}
@@ -0,0 +1,79 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! In-memory key-value store, mostly useful for tests that don't want to
+//! deal with a real RocksDB on disk.
+
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use {BatchOp, Error, KeyValueStore};
+
+/// Key-value store backed by an ordered map. Keys are kept in the same
+/// lexicographic order RocksDB uses so iteration behaves identically.
+pub struct MemStore {
+	data: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemStore {
+	/// Creates a new empty store.
+	pub fn new() -> MemStore {
+		MemStore { data: RwLock::new(BTreeMap::new()) }
+	}
+}
+
+impl KeyValueStore for MemStore {
+	fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+		let data = self.data.read().unwrap();
+		Ok(data.get(key).cloned())
+	}
+
+	fn put(&self, key: &[u8], value: Vec<u8>) -> Result<(), Error> {
+		let mut data = self.data.write().unwrap();
+		data.insert(key.to_vec(), value);
+		Ok(())
+	}
+
+	fn delete(&self, key: &[u8]) -> Result<(), Error> {
+		let mut data = self.data.write().unwrap();
+		data.remove(key);
+		Ok(())
+	}
+
+	fn write(&self, ops: Vec<BatchOp>) -> Result<(), Error> {
+		let mut data = self.data.write().unwrap();
+		for op in ops {
+			match op {
+				BatchOp::Put(k, v) => {
+					data.insert(k, v);
+				}
+				BatchOp::Delete(k) => {
+					data.remove(&k);
+				}
+			}
+		}
+		Ok(())
+	}
+
+	fn iter_raw<'a>(&'a self,
+	                prefix: &[u8])
+	                -> Result<Box<Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a>, Error> {
+		let data = self.data.read().unwrap();
+		let entries = data.range(prefix.to_vec()..)
+			.take_while(|&(k, _)| k.starts_with(prefix))
+			.map(|(k, v)| (k.clone(), v.clone()))
+			.collect::<Vec<_>>();
+		Ok(Box::new(entries.into_iter()))
+	}
+}
@@ -0,0 +1,64 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate futures;
+extern crate grin_core as core;
+extern crate grin_store as store;
+
+use std::fs;
+use std::sync::Arc;
+
+use futures::Future;
+use futures::future::join_all;
+
+use core::core::BlockHeader;
+use store::{AsyncStore, Store, u64_to_key};
+
+const HEIGHT_PREFIX: u8 = '8' as u8;
+
+fn new_store(name: &str) -> AsyncStore {
+	let path = format!("target/{}", name);
+	let _ = fs::remove_dir_all(&path);
+	AsyncStore::new(Arc::new(Store::open(&path).unwrap()), 4)
+}
+
+#[test]
+fn concurrent_async_gets() {
+	let astore = new_store("store-async-gets");
+	for n in 0..100 {
+		astore.store().put(&u64_to_key(HEIGHT_PREFIX, n), vec![n as u8]).unwrap();
+	}
+
+	let gets = (0..100).map(|n| astore.get(&u64_to_key(HEIGHT_PREFIX, n))).collect::<Vec<_>>();
+	let values = join_all(gets).wait().unwrap();
+	assert_eq!(values, (0..100).map(|n| Some(vec![n as u8])).collect::<Vec<_>>());
+	assert_eq!(astore.get(&u64_to_key(HEIGHT_PREFIX, 100)).wait().unwrap(), None);
+}
+
+#[test]
+fn async_put_then_get() {
+	let astore = new_store("store-async-put");
+	let header = BlockHeader { height: 12, ..Default::default() };
+	astore.store().put_ser(&u64_to_key(HEIGHT_PREFIX, 12), &header).unwrap();
+
+	let reader = astore.clone();
+	let value = astore.put(&u64_to_key(HEIGHT_PREFIX, 1), vec![1, 2, 3])
+		.and_then(move |_| reader.get(&u64_to_key(HEIGHT_PREFIX, 1)))
+		.wait()
+		.unwrap();
+	assert_eq!(value, Some(vec![1, 2, 3]));
+
+	let h = astore.get_ser::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 12)).wait().unwrap();
+	assert_eq!(h.unwrap().height, 12);
+}
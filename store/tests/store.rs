@@ -0,0 +1,720 @@
+// Copyright 2016 The Grin Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+extern crate grin_core as core;
+extern crate grin_store as store;
+
+use std::fs;
+use std::io::{self, Write};
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+use core::core::BlockHeader;
+use core::core::hash::Hashed;
+use core::ser;
+use store::{Error, Key, KeyValueStore, MemStore, Store, StoreConfig, u64_to_key,
+            option_to_not_found_ctx};
+
+const HEIGHT_PREFIX: u8 = '8' as u8;
+
+fn new_store(name: &str) -> Store {
+	let path = format!("target/{}", name);
+	let _ = fs::remove_dir_all(&path);
+	Store::open(&path).unwrap()
+}
+
+fn header(height: u64) -> BlockHeader {
+	BlockHeader { height: height, ..Default::default() }
+}
+
+#[test]
+fn iter_height_prefix() {
+	let store = new_store("store-iter");
+	// insert in reverse to make sure we get key order back
+	for n in (1..101).rev() {
+		store.put_ser(&u64_to_key(HEIGHT_PREFIX, n), &header(n)).unwrap();
+	}
+	store.put_ser(&u64_to_key('9' as u8, 1), &header(1000)).unwrap();
+
+	let heights = store.iter::<BlockHeader>(&Key::prefix(HEIGHT_PREFIX).build())
+		.unwrap()
+		.map(|h| h.unwrap().height)
+		.collect::<Vec<_>>();
+	assert_eq!(heights, (1..101).collect::<Vec<_>>());
+}
+
+// A value that can't be read back comes out as an error, without hiding the
+// ones after it.
+#[test]
+fn iter_unreadable_value() {
+	let store = new_store("store-iter-unreadable");
+	store.put_ser(&u64_to_key(HEIGHT_PREFIX, 1), &header(1)).unwrap();
+	store.put(&u64_to_key(HEIGHT_PREFIX, 2), vec![0; 3]).unwrap();
+	store.put_ser(&u64_to_key(HEIGHT_PREFIX, 3), &header(3)).unwrap();
+
+	let headers = store.iter::<BlockHeader>(&[HEIGHT_PREFIX]).unwrap().collect::<Vec<_>>();
+	assert_eq!(headers.len(), 3);
+	assert_eq!(headers[0].as_ref().unwrap().height, 1);
+	match headers[1] {
+		Err(Error::TruncatedErr(3)) => {}
+		_ => panic!("expected a truncated value"),
+	}
+	assert_eq!(headers[2].as_ref().unwrap().height, 3);
+}
+
+#[test]
+fn batch_put_delete() {
+	let store = new_store("store-batch");
+	store.put_ser(&u64_to_key(HEIGHT_PREFIX, 1), &header(1)).unwrap();
+
+	store.batch()
+		.put_ser(&u64_to_key(HEIGHT_PREFIX, 2), &header(2))
+		.unwrap()
+		.put_ser(&u64_to_key(HEIGHT_PREFIX, 3), &header(3))
+		.unwrap()
+		.delete(&u64_to_key(HEIGHT_PREFIX, 1))
+		.unwrap()
+		.write()
+		.unwrap();
+
+	assert!(store.get(&u64_to_key(HEIGHT_PREFIX, 1)).unwrap().is_none());
+	let h2 = store.get_ser::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 2)).unwrap();
+	assert_eq!(h2.unwrap().height, 2);
+	let h3 = store.get_ser::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 3)).unwrap();
+	assert_eq!(h3.unwrap().height, 3);
+}
+
+#[test]
+fn batch_put_in_loop() {
+	let store = new_store("store-batch-loop");
+	let mut batch = store.batch();
+	for n in 1..101 {
+		batch.put_ser_ref(&u64_to_key(HEIGHT_PREFIX, n), &header(n)).unwrap();
+	}
+	// nothing's written until the batch is
+	assert!(store.get(&u64_to_key(HEIGHT_PREFIX, 1)).unwrap().is_none());
+	batch.write().unwrap();
+
+	let heights = store.iter::<BlockHeader>(&Key::prefix(HEIGHT_PREFIX).build())
+		.unwrap()
+		.map(|h| h.unwrap().height)
+		.collect::<Vec<_>>();
+	assert_eq!(heights, (1..101).collect::<Vec<_>>());
+}
+
+#[test]
+fn multi_get_aligned() {
+	let store = new_store("store-multi-get");
+	store.put(&u64_to_key(HEIGHT_PREFIX, 1), vec![1]).unwrap();
+	store.put(&u64_to_key(HEIGHT_PREFIX, 3), vec![3]).unwrap();
+
+	let keys = (0..5).map(|n| u64_to_key(HEIGHT_PREFIX, n)).collect::<Vec<_>>();
+	let values = store.multi_get(&keys).unwrap();
+	assert_eq!(values, vec![None, Some(vec![1]), None, Some(vec![3]), None]);
+	assert!(store.multi_get(&[]).unwrap().is_empty());
+}
+
+#[test]
+fn exists_check() {
+	let store = new_store("store-exists");
+	store.put(&u64_to_key(HEIGHT_PREFIX, 1), vec![1]).unwrap();
+	store.put(&u64_to_key(HEIGHT_PREFIX, 2), vec![7; 1_000_000]).unwrap();
+
+	assert!(store.exists(&u64_to_key(HEIGHT_PREFIX, 1)).unwrap());
+	assert!(store.exists(&u64_to_key(HEIGHT_PREFIX, 2)).unwrap());
+	assert!(!store.exists(&u64_to_key(HEIGHT_PREFIX, 3)).unwrap());
+}
+
+#[test]
+fn open_with_config() {
+	let path = "target/store-config";
+	let _ = fs::remove_dir_all(path);
+	let config = StoreConfig {
+		max_open_files: 16,
+		use_fsync: true,
+		..Default::default()
+	};
+	let store = Store::open_with_config(path, config).unwrap();
+	store.put_ser(&u64_to_key(HEIGHT_PREFIX, 1), &header(1)).unwrap();
+	let h = store.get_ser::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 1)).unwrap();
+	assert_eq!(h.unwrap().height, 1);
+}
+
+// Tiny buffers and cache for small devices and large ones for fast syncing
+// should both work, the tiny write buffer getting flushed a few times.
+#[test]
+fn open_with_memory_sizes() {
+	let tiny = StoreConfig {
+		write_buffer_size: 64 * 1024,
+		max_write_buffer_number: 2,
+		block_cache_size: 32 * 1024,
+		..Default::default()
+	};
+	let large = StoreConfig {
+		write_buffer_size: 256 * 1024 * 1024,
+		max_write_buffer_number: 6,
+		block_cache_size: 512 * 1024 * 1024,
+		..Default::default()
+	};
+	for (name, config) in vec![("store-mem-tiny", tiny), ("store-mem-large", large)] {
+		let path = format!("target/{}", name);
+		let _ = fs::remove_dir_all(&path);
+		let store = Store::open_with_config(&path, config).unwrap();
+		for n in 0..1000 {
+			store.put_ser(&u64_to_key(HEIGHT_PREFIX, n), &header(n)).unwrap();
+		}
+		let heights = store.iter::<BlockHeader>(&Key::prefix(HEIGHT_PREFIX).build())
+			.unwrap()
+			.map(|h| h.unwrap().height)
+			.collect::<Vec<_>>();
+		assert_eq!(heights, (0..1000).collect::<Vec<_>>());
+	}
+}
+
+#[test]
+fn snapshot_isolation() {
+	let store = new_store("store-snapshot");
+	let key = u64_to_key(HEIGHT_PREFIX, 1);
+	store.put_ser(&key, &header(1)).unwrap();
+
+	let snapshot = store.snapshot();
+	store.put_ser(&key, &header(2)).unwrap();
+	store.put_ser(&u64_to_key(HEIGHT_PREFIX, 2), &header(2)).unwrap();
+
+	assert_eq!(snapshot.get_ser::<BlockHeader>(&key).unwrap().unwrap().height, 1);
+	assert!(snapshot.get(&u64_to_key(HEIGHT_PREFIX, 2)).unwrap().is_none());
+	assert_eq!(store.get_ser::<BlockHeader>(&key).unwrap().unwrap().height, 2);
+}
+
+#[test]
+fn get_ser_remainder() {
+	let store = new_store("store-remainder");
+	let mut data = ser::ser_vec(&header(1)).unwrap();
+	store.put(&u64_to_key(HEIGHT_PREFIX, 1), data.clone()).unwrap();
+	data.extend_from_slice(&[1, 2, 3]);
+	store.put(&u64_to_key(HEIGHT_PREFIX, 2), data.clone()).unwrap();
+	store.put(&u64_to_key(HEIGHT_PREFIX, 3), data[..10].to_vec()).unwrap();
+
+	let (h, rest) = store.get_ser_remainder::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 1))
+		.unwrap()
+		.unwrap();
+	assert_eq!(h.height, 1);
+	assert!(rest.is_empty());
+
+	let (h, rest) = store.get_ser_remainder::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 2))
+		.unwrap()
+		.unwrap();
+	assert_eq!(h.height, 1);
+	assert_eq!(rest, vec![1, 2, 3]);
+
+	match store.get_ser_remainder::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 3)) {
+		Err(Error::TruncatedErr(10)) => {}
+		_ => panic!("truncated value should fail deserialization"),
+	}
+	assert!(store.get_ser_remainder::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 4))
+		.unwrap()
+		.is_none());
+}
+
+// Reading more than what's stored, or anything from an empty value, should
+// error instead of panicking, whatever the store.
+#[test]
+fn get_ser_limited_truncated() {
+	let store = new_store("store-limited");
+	let data = ser::ser_vec(&header(1)).unwrap();
+	let len = data.len();
+	store.put(&u64_to_key(HEIGHT_PREFIX, 1), data).unwrap();
+	store.put(&u64_to_key(HEIGHT_PREFIX, 2), vec![]).unwrap();
+
+	let h = store.get_ser_limited::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 1), len)
+		.unwrap()
+		.unwrap();
+	assert_eq!(h.height, 1);
+	match store.get_ser_limited::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 1), len + 1) {
+		Err(Error::TruncatedErr(l)) => assert_eq!(l, len),
+		_ => panic!("reading past the value should fail"),
+	}
+	for limit in vec![0, 8] {
+		match store.get_ser_limited::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 2), limit) {
+			Err(Error::TruncatedErr(0)) => {}
+			_ => panic!("empty value should be reported as truncated"),
+		}
+	}
+	match store.get_ser::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 2)) {
+		Err(Error::TruncatedErr(0)) => {}
+		_ => panic!("empty value should be reported as truncated"),
+	}
+
+	// any other store reports it the same
+	let mem: Box<KeyValueStore> = Box::new(MemStore::new());
+	mem.put(&u64_to_key(HEIGHT_PREFIX, 2), vec![]).unwrap();
+	match mem.get_ser::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 2)) {
+		Err(Error::TruncatedErr(0)) => {}
+		_ => panic!("empty value should be reported as truncated"),
+	}
+}
+
+// Lists of headers under a single key come back as they were written,
+// whatever their length, as long as the count matches what's stored.
+#[test]
+fn ser_vec_round_trip() {
+	let store = new_store("store-ser-vec");
+	for len in vec![0, 1, 50] {
+		let headers = (0..len).map(header).collect::<Vec<_>>();
+		store.put_ser_vec(&u64_to_key(HEIGHT_PREFIX, len), &headers).unwrap();
+		let read = store.get_ser_vec::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, len))
+			.unwrap()
+			.unwrap();
+		assert_eq!(read.iter().map(|h| h.hash()).collect::<Vec<_>>(),
+		           headers.iter().map(|h| h.hash()).collect::<Vec<_>>());
+	}
+	assert!(store.get_ser_vec::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 2)).unwrap().is_none());
+
+	// claiming one more header than stored, and one fewer
+	let headers = vec![header(1), header(2)];
+	let mut data = ser::ser_vec(&header(1)).unwrap();
+	data.append(&mut ser::ser_vec(&header(2)).unwrap());
+	for count in vec![3u8, 1] {
+		let mut value = vec![0, 0, 0, 0, 0, 0, 0, count];
+		value.extend_from_slice(&data);
+		let len = value.len();
+		store.put(&u64_to_key(HEIGHT_PREFIX, 3), value).unwrap();
+		match store.get_ser_vec::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 3)) {
+			Err(Error::CountErr(c, l)) => assert_eq!((c, l), (count as u64, len)),
+			_ => panic!("count mismatch should fail"),
+		}
+	}
+	store.put_ser_vec(&u64_to_key(HEIGHT_PREFIX, 3), &headers).unwrap();
+	let read = store.get_ser_vec::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 3)).unwrap().unwrap();
+	assert_eq!(read.len(), 2);
+}
+
+#[test]
+fn mem_store_iter_like_rocksdb() {
+	let rocks = new_store("store-mem-iter");
+	let mem = MemStore::new();
+	let stores: Vec<&KeyValueStore> = vec![&rocks, &mem];
+
+	for store in &stores {
+		for n in vec![300, 2, 256, 1, 70000] {
+			store.put_ser(&u64_to_key(HEIGHT_PREFIX, n), &header(n)).unwrap();
+		}
+		store.put_ser(&u64_to_key('7' as u8, 5), &header(5)).unwrap();
+		store.put_ser(&u64_to_key('9' as u8, 5), &header(5)).unwrap();
+		store.batch().delete(&u64_to_key(HEIGHT_PREFIX, 256)).unwrap().write().unwrap();
+	}
+
+	let heights = stores.iter()
+		.map(|store| {
+			store.iter::<BlockHeader>(&Key::prefix(HEIGHT_PREFIX).build())
+				.unwrap()
+				.map(|h| h.unwrap().height)
+				.collect::<Vec<_>>()
+		})
+		.collect::<Vec<_>>();
+	assert_eq!(heights[0], vec![1, 2, 300, 70000]);
+	assert_eq!(heights[0], heights[1]);
+}
+
+#[test]
+fn store_stats() {
+	let path = "target/store-stats";
+	let _ = fs::remove_dir_all(path);
+	let config = StoreConfig { enable_statistics: true, ..Default::default() };
+	let store = Store::open_with_config(path, config).unwrap();
+	for n in 0..1000 {
+		store.put(&u64_to_key(HEIGHT_PREFIX, n), vec![0; 100]).unwrap();
+	}
+	for n in 0..10 {
+		store.get(&u64_to_key(HEIGHT_PREFIX, n)).unwrap();
+	}
+
+	// only an estimate, still in the ballpark
+	let keys = store.stats().unwrap().estimate_num_keys.unwrap();
+	assert!(keys >= 500 && keys <= 2000, "estimated {} keys", keys);
+	assert_eq!(store.count_keys(), 1000);
+}
+
+#[test]
+fn column_families() {
+	let path = "target/store-cf";
+	{
+		let store = new_store("store-cf");
+		store.put(&u64_to_key(HEIGHT_PREFIX, 1), vec![1]).unwrap();
+	}
+
+	let store = Store::with_column_families(path, &["blocks", "headers"]).unwrap();
+	let blocks = store.cf_handle("blocks").unwrap();
+	let headers = store.cf_handle("headers").unwrap();
+	let key = u64_to_key(HEIGHT_PREFIX, 2);
+	store.put_cf(blocks, &key, vec![2]).unwrap();
+	store.put_cf(headers, &key, vec![3]).unwrap();
+
+	assert_eq!(store.get_cf(blocks, &key).unwrap(), Some(vec![2]));
+	assert_eq!(store.get_cf(headers, &key).unwrap(), Some(vec![3]));
+	assert!(store.get(&key).unwrap().is_none());
+	// data from before column families lives in the default one
+	assert_eq!(store.get(&u64_to_key(HEIGHT_PREFIX, 1)).unwrap(), Some(vec![1]));
+	assert!(store.cf_handle("other").is_none());
+
+	// failing to open for another reason than missing column families
+	// isn't hidden
+	match Store::with_column_families(path, &["blocks", "headers"]) {
+		Err(Error::RocksDbErr(msg)) => assert!(msg.contains("lock")),
+		_ => panic!("a db held open elsewhere should fail to open"),
+	}
+
+	// reopening finds the existing column families
+	drop(store);
+	let store = Store::with_column_families(path, &["blocks", "headers"]).unwrap();
+	let blocks = store.cf_handle("blocks").unwrap();
+	assert_eq!(store.get_cf(blocks, &key).unwrap(), Some(vec![2]));
+}
+
+#[test]
+fn concurrent_cas() {
+	let store = Arc::new(new_store("store-cas"));
+	let key = vec![HEIGHT_PREFIX];
+	assert!(store.cas(&key, None, vec![0]).unwrap());
+	assert!(!store.cas(&key, None, vec![0]).unwrap());
+
+	let barrier = Arc::new(Barrier::new(2));
+	let handles = (1..3)
+		.map(|n| {
+			let store = store.clone();
+			let barrier = barrier.clone();
+			let key = key.clone();
+			thread::spawn(move || {
+				barrier.wait();
+				store.cas(&key, Some(&[0]), vec![n]).unwrap()
+			})
+		})
+		.collect::<Vec<_>>();
+	let swapped = handles.into_iter().map(|h| h.join().unwrap()).filter(|s| *s).count();
+	assert_eq!(swapped, 1);
+	assert!(store.get(&key).unwrap().unwrap() != vec![0]);
+}
+
+#[test]
+fn range_ser_sparse() {
+	let store = new_store("store-range");
+	for n in vec![1, 2, 5, 8, 9, 20] {
+		store.put_ser(&u64_to_key(HEIGHT_PREFIX, n), &header(n)).unwrap();
+	}
+	store.put_ser(&u64_to_key('9' as u8, 3), &header(3)).unwrap();
+
+	let heights = |from, to| {
+		store.range_ser::<BlockHeader>(HEIGHT_PREFIX, from, to)
+			.unwrap()
+			.iter()
+			.map(|h| h.height)
+			.collect::<Vec<_>>()
+	};
+	// both ends are included
+	assert_eq!(heights(2, 9), vec![2, 5, 8, 9]);
+	assert_eq!(heights(2, 8), vec![2, 5, 8]);
+	assert_eq!(heights(0, 100), vec![1, 2, 5, 8, 9, 20]);
+	assert!(heights(10, 19).is_empty());
+	assert_eq!(heights(9, 9), vec![9]);
+	assert!(heights(9, 2).is_empty());
+
+	store.put(&u64_to_key(HEIGHT_PREFIX, 6), vec![1, 2]).unwrap();
+	match store.range_ser::<BlockHeader>(HEIGHT_PREFIX, 2, 9) {
+		Err(Error::RangeSerErr(6, _)) => {}
+		_ => panic!("expected a deserialization error at height 6"),
+	}
+}
+
+#[test]
+fn corruption_error_mapping() {
+	match Error::from_rocksdb("Corruption: block checksum mismatch".to_string()) {
+		Error::CorruptionErr(msg) => assert!(msg.contains("checksum")),
+		e => panic!("corruption not detected: {:?}", e),
+	}
+	match Error::from_rocksdb("IO error: lock held by current process".to_string()) {
+		Error::RocksDbErr(_) => {}
+		e => panic!("unexpected mapping: {:?}", e),
+	}
+}
+
+#[test]
+fn open_or_repair() {
+	let path = "target/store-repair";
+	let _ = fs::remove_dir_all(path);
+	{
+		let (store, repaired) = Store::open_or_repair(path, StoreConfig::default()).unwrap();
+		assert!(repaired.is_none());
+		store.put_ser(&u64_to_key(HEIGHT_PREFIX, 1), &header(1)).unwrap();
+	}
+
+	// a CURRENT file that doesn't point to a valid manifest
+	fs::File::create(format!("{}/CURRENT", path)).unwrap().write_all(b"garbage").unwrap();
+	match Store::open(path) {
+		Err(Error::CorruptionErr(_)) => {}
+		r => panic!("corruption not detected: {:?}", r.err()),
+	}
+
+	let (store, repaired) = Store::open_or_repair(path, StoreConfig::default()).unwrap();
+	assert!(repaired.is_some());
+	let h = store.get_ser::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 1)).unwrap();
+	assert_eq!(h.unwrap().height, 1);
+}
+
+#[test]
+fn not_found_with_key_description() {
+	let store: Box<KeyValueStore> = Box::new(MemStore::new());
+	let res = store.get_ser::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 42));
+	let res = option_to_not_found_ctx(res, "block_header@height=42");
+	match res {
+		Err(ref e) => {
+			assert!(e.is_not_found());
+			assert!(e.to_string().contains("block_header@height=42"));
+		}
+		Ok(_) => panic!("found a header that was never saved"),
+	}
+}
+
+#[test]
+fn sync_write_survives_reopen() {
+	let path = "target/store-sync";
+	let _ = fs::remove_dir_all(path);
+	{
+		let store = Store::open(path).unwrap();
+		let batch = store.batch().put_ser(&u64_to_key(HEIGHT_PREFIX, 1), &header(1)).unwrap();
+		batch.write_sync().unwrap();
+		store.put_ser(&u64_to_key(HEIGHT_PREFIX, 2), &header(2)).unwrap();
+		store.flush().unwrap();
+	}
+	let store = Store::open(path).unwrap();
+	for height in 1..3 {
+		let h = store.get_ser::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, height)).unwrap();
+		assert_eq!(h.unwrap().height, height);
+	}
+}
+
+#[test]
+#[allow(deprecated)]
+fn key_builder_layout() {
+	let h = header(3).hash();
+	assert_eq!(Key::prefix(HEIGHT_PREFIX).append_u64(42).build(),
+	           u64_to_key(HEIGHT_PREFIX, 42));
+	assert_eq!(Key::prefix(HEIGHT_PREFIX).append_hash(&h).build(),
+	           store::to_key(HEIGHT_PREFIX, &mut h.to_vec()).clone());
+	assert_eq!(Key::prefix(HEIGHT_PREFIX).append_bytes(b"peer").build(),
+	           store::to_key(HEIGHT_PREFIX, &mut b"peer".to_vec()).clone());
+	assert_eq!(Key::prefix(HEIGHT_PREFIX).build(),
+	           store::to_key(HEIGHT_PREFIX, &mut vec![]).clone());
+}
+
+#[test]
+fn destroy_and_reopen() {
+	let path = "target/store-destroy";
+	let _ = fs::remove_dir_all(path);
+	{
+		let store = Store::open(path).unwrap();
+		store.put_ser(&u64_to_key(HEIGHT_PREFIX, 1), &header(1)).unwrap();
+	}
+	Store::destroy(path).unwrap();
+
+	let store = Store::open(path).unwrap();
+	assert!(store.get(&u64_to_key(HEIGHT_PREFIX, 1)).unwrap().is_none());
+	assert_eq!(store.iter::<BlockHeader>(&[HEIGHT_PREFIX]).unwrap().count(), 0);
+}
+
+#[test]
+fn read_only() {
+	let path = "target/store-read-only";
+	let _ = fs::remove_dir_all(path);
+	assert!(Store::open_read_only(path, StoreConfig::default()).is_err());
+	{
+		let store = Store::open(path).unwrap();
+		store.put_ser(&u64_to_key(HEIGHT_PREFIX, 1), &header(1)).unwrap();
+	}
+
+	let store = Store::open_read_only(path, StoreConfig::default()).unwrap();
+	assert!(store.is_read_only());
+	let h = store.get_ser::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 1)).unwrap();
+	assert_eq!(h.unwrap().height, 1);
+	assert_eq!(store.iter::<BlockHeader>(&[HEIGHT_PREFIX]).unwrap().count(), 1);
+	store.flush().unwrap();
+
+	let key = u64_to_key(HEIGHT_PREFIX, 2);
+	let results = vec![store.put(&key, vec![1]),
+	                   store.put_ser(&key, &header(2)),
+	                   store.delete(&u64_to_key(HEIGHT_PREFIX, 1)),
+	                   store.batch().delete(&key).unwrap().write(),
+	                   store.cas(&key, None, vec![1]).map(|_| ())];
+	for res in results {
+		match res {
+			Err(Error::ReadOnlyErr) => {}
+			res => panic!("write on a read-only store: {:?}", res),
+		}
+	}
+	assert!(store.get(&key).unwrap().is_none());
+	assert!(store.exists(&u64_to_key(HEIGHT_PREFIX, 1)).unwrap());
+}
+
+// The db stays open for writing while it gets opened read-only, which sees
+// what was written until then.
+#[test]
+fn read_only_while_held_open() {
+	let path = "target/store-read-only-held";
+	let _ = fs::remove_dir_all(path);
+	let store = Store::open(path).unwrap();
+	store.put_ser(&u64_to_key(HEIGHT_PREFIX, 1), &header(1)).unwrap();
+
+	{
+		let ro_store = Store::open_read_only(path, StoreConfig::default()).unwrap();
+		let h = ro_store.get_ser::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 1)).unwrap();
+		assert_eq!(h.unwrap().height, 1);
+		match ro_store.put_ser(&u64_to_key(HEIGHT_PREFIX, 2), &header(2)) {
+			Err(Error::ReadOnlyErr) => {}
+			res => panic!("write on a read-only store: {:?}", res),
+		}
+
+		// the other handle still writes, the read-only one doesn't see it
+		store.put_ser(&u64_to_key(HEIGHT_PREFIX, 2), &header(2)).unwrap();
+		assert!(!ro_store.exists(&u64_to_key(HEIGHT_PREFIX, 2)).unwrap());
+	}
+	assert!(store.exists(&u64_to_key(HEIGHT_PREFIX, 2)).unwrap());
+
+	// nothing left behind of the read-only store
+	let leftovers = fs::read_dir("target")
+		.unwrap()
+		.filter(|e| {
+			let name = e.as_ref().unwrap().file_name();
+			name.to_string_lossy().starts_with("store-read-only-held.ro-")
+		})
+		.count();
+	assert_eq!(leftovers, 0);
+}
+
+// Copies of the db keep opening while another handle writes and compacts
+// away the table files being copied.
+#[test]
+fn read_only_during_writes() {
+	let path = "target/store-read-only-writes";
+	let _ = fs::remove_dir_all(path);
+	let store = Arc::new(Store::open(path).unwrap());
+	store.put_ser(&u64_to_key(HEIGHT_PREFIX, 0), &header(0)).unwrap();
+	store.flush().unwrap();
+
+	let writer_store = store.clone();
+	let writer = thread::spawn(move || for n in 1..2000 {
+		writer_store.put_ser(&u64_to_key(HEIGHT_PREFIX, n), &header(n)).unwrap();
+		if n % 100 == 0 {
+			writer_store.flush().unwrap();
+			writer_store.compact().unwrap();
+		}
+	});
+
+	for _ in 0..20 {
+		let ro_store = Store::open_read_only(path, StoreConfig::default()).unwrap();
+		let h = ro_store.get_ser::<BlockHeader>(&u64_to_key(HEIGHT_PREFIX, 0)).unwrap();
+		assert_eq!(h.unwrap().height, 0);
+	}
+	writer.join().unwrap();
+}
+
+#[test]
+fn delete_range() {
+	let store = new_store("store-delete-range");
+	for height in 1..10 {
+		store.put_ser(&u64_to_key(HEIGHT_PREFIX, height), &header(height)).unwrap();
+	}
+	// key of the next prefix, sorting right after all the heights
+	store.put(&[HEIGHT_PREFIX + 1], vec![1]).unwrap();
+
+	store.delete_range(&u64_to_key(HEIGHT_PREFIX, 3), &u64_to_key(HEIGHT_PREFIX, 7)).unwrap();
+	let heights = store.iter::<BlockHeader>(&[HEIGHT_PREFIX])
+		.unwrap()
+		.map(|h| h.unwrap().height)
+		.collect::<Vec<_>>();
+	assert_eq!(heights, vec![1, 2, 7, 8, 9]);
+
+	// the end of the range is excluded even when it's past every key
+	store.delete_range(&u64_to_key(HEIGHT_PREFIX, 9), &[HEIGHT_PREFIX + 1]).unwrap();
+	assert_eq!(store.iter::<BlockHeader>(&[HEIGHT_PREFIX]).unwrap().count(), 4);
+	assert!(store.exists(&[HEIGHT_PREFIX + 1]).unwrap());
+
+	// nothing in an empty range
+	store.delete_range(&u64_to_key(HEIGHT_PREFIX, 2), &u64_to_key(HEIGHT_PREFIX, 2)).unwrap();
+	assert!(store.exists(&u64_to_key(HEIGHT_PREFIX, 2)).unwrap());
+}
+
+// Deserialization failures get told apart by what they say about the
+// stored value, keeping the original error.
+#[test]
+fn ser_error_mapping() {
+	let eof = io::Error::new(io::ErrorKind::UnexpectedEof, "eof");
+	match Error::from_ser(ser::Error::IOErr(eof), 10) {
+		Error::TruncatedErr(10) => {}
+		e => panic!("running out of bytes should be a truncation: {:?}", e),
+	}
+	for e in vec![ser::Error::CorruptedData, ser::Error::TooLargeReadErr] {
+		match Error::from_ser(e, 10) {
+			Error::CorruptErr(ser::Error::CorruptedData) |
+			Error::CorruptErr(ser::Error::TooLargeReadErr) => {}
+			e => panic!("invalid data should be a corruption: {:?}", e),
+		}
+	}
+	let unexpected = ser::Error::UnexpectedData {
+		expected: vec![1],
+		received: vec![2],
+	};
+	match Error::from_ser(unexpected, 10) {
+		Error::FormatErr(ser::Error::UnexpectedData { ref expected, ref received }) => {
+			assert_eq!((expected.clone(), received.clone()), (vec![1], vec![2]))
+		}
+		e => panic!("unexpected data should be a format error: {:?}", e),
+	}
+	let denied = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+	match Error::from_ser(ser::Error::IOErr(denied), 10) {
+		Error::SerErr(ser::Error::IOErr(ref e)) => {
+			assert_eq!(e.kind(), io::ErrorKind::PermissionDenied)
+		}
+		e => panic!("other io errors should be kept as is: {:?}", e),
+	}
+}
+
+// Compacting after deleting most of what was written gives the space back,
+// and only reports writes made since as something left to compact.
+#[test]
+fn compact() {
+	let store = new_store("store-compact");
+	assert!(store.write_idle_time().is_none());
+	for n in 0..2000 {
+		store.put(&u64_to_key(HEIGHT_PREFIX, n), vec![n as u8; 500]).unwrap();
+	}
+	assert!(store.write_idle_time().is_some());
+	store.compact().unwrap();
+	let full = store.stats().unwrap();
+	assert!(full.live_sst_files_size > 0);
+	assert!(full.last_compaction.is_some());
+	assert!(store.write_idle_time().is_none());
+
+	store.delete_range(&u64_to_key(HEIGHT_PREFIX, 0), &u64_to_key(HEIGHT_PREFIX, 1900)).unwrap();
+	for n in 1900..1950 {
+		store.delete(&u64_to_key(HEIGHT_PREFIX, n)).unwrap();
+	}
+	store.compact().unwrap();
+	let compacted = store.stats().unwrap();
+	assert!(compacted.live_sst_files_size < full.live_sst_files_size / 10,
+	        "{} bytes left out of {}",
+	        compacted.live_sst_files_size,
+	        full.live_sst_files_size);
+	assert_eq!(store.count_keys(), 50);
+	assert_eq!(store.get(&u64_to_key(HEIGHT_PREFIX, 1960)).unwrap(),
+	           Some(vec![1960u64 as u8; 500]));
+}